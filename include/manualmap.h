@@ -0,0 +1,47 @@
+/* C interface of jector, exported by the cdylib built with: cargo rustc --lib --release --features ffi --crate-type cdylib */
+#ifndef MANUALMAP_H
+#define MANUALMAP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Injection methods */
+#define MM_METHOD_MANUALMAP 0
+#define MM_METHOD_LOADLIBRARY 1
+#define MM_METHOD_WINDOWSHOOK 2
+
+/*
+ * Functions return 0 on success and -1 on failure, mm_last_error describes the failure.
+ * Strings are null-terminated UTF-8.
+ */
+
+/*
+ * Injects the library at dll_path into the process, image_base may be NULL.
+ * hook_procedure names the export MM_METHOD_WINDOWSHOOK installs as the hook, the other methods
+ * ignore it and accept NULL.
+ */
+int mm_inject_pid(uint32_t pid, const char *dll_path, uint32_t method, const char *hook_procedure,
+                  size_t *image_base);
+
+/* Injects the library at dll_path into the first process whose executable has the file name */
+int mm_inject_name(const char *process_name, const char *dll_path, uint32_t method,
+                   const char *hook_procedure, size_t *image_base);
+
+/* Ejects a library that was manually mapped at image_base */
+int mm_eject(uint32_t pid, size_t image_base);
+
+/*
+ * Message of the last failed call on the calling thread, NULL if no call has failed.
+ * The string stays valid until the next call fails on the same thread.
+ */
+const char *mm_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
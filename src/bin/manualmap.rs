@@ -0,0 +1,238 @@
+use anyhow::{anyhow, bail, ensure};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use cli::{execution_arg, terminate_on_timeout_arg, timeout_arg};
+use jector::winapiwrapper::process::Process;
+use jector::winapiwrapper::snapshot::Snapshot;
+use jector::{EjectOptions, ExecutionTimeout};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[path = "../cli.rs"]
+mod cli;
+
+// How often --wait-for-process looks for the process
+const WAIT_INTERVAL_MS: u64 = 100;
+
+fn main() -> anyhow::Result<()> {
+    let matches = App::new("manualmap")
+        .version("1.0")
+        .about("Injects and ejects libraries, lists processes and their modules")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("inject")
+                .about("Injects a library into a process")
+                .group(target_group())
+                .arg(pid_arg())
+                .arg(name_arg())
+                .arg(
+                    Arg::with_name("dll")
+                        .long("dll")
+                        .value_name("dll_file_path")
+                        .help("The DLL file to inject")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .args(&cli::inject_args("manualmap"))
+                .arg(
+                    Arg::with_name("wait_for_process")
+                        .long("wait-for-process")
+                        .value_name("milliseconds")
+                        .help(
+                            "Wait for a process with --name to start, forever if no time is given",
+                        )
+                        .takes_value(true)
+                        .min_values(0)
+                        .requires("name"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("eject")
+                .about("Ejects a library that was manually mapped into a process")
+                .group(target_group())
+                .arg(pid_arg())
+                .arg(name_arg())
+                .arg(
+                    Arg::with_name("base")
+                        .short("b")
+                        .long("base")
+                        .value_name("hex_address")
+                        .help("The address the library was mapped at, as printed by inject")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(execution_arg())
+                .arg(timeout_arg())
+                .arg(terminate_on_timeout_arg())
+                .arg(
+                    Arg::with_name("skip_entry_point")
+                        .long("skip-entry-point")
+                        .help("Free the image without calling DllMain and the TLS callbacks"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("ps").about("Lists the running processes"))
+        .subcommand(
+            SubCommand::with_name("modules")
+                .about("Lists the modules the loader has loaded into a process")
+                .arg(
+                    Arg::with_name("pid")
+                        .value_name("pid")
+                        .help("The PID of the process")
+                        .required(true),
+                ),
+        )
+        .get_matches();
+
+    cli::enable_debug_privilege();
+
+    match matches.subcommand() {
+        ("inject", Some(matches)) => inject(matches),
+        ("eject", Some(matches)) => eject(matches),
+        ("ps", Some(_)) => ps(),
+        ("modules", Some(matches)) => modules(matches.value_of("pid").unwrap().parse()?),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+fn inject(matches: &ArgMatches) -> anyhow::Result<()> {
+    let dll = Path::new(matches.value_of("dll").unwrap());
+
+    let injector = cli::injector(matches)?;
+
+    let pid = if matches.is_present("wait_for_process") {
+        let timeout = match matches.value_of("wait_for_process") {
+            Some(millis) => Some(Duration::from_millis(millis.parse()?)),
+            None => None,
+        };
+
+        wait_for_process(matches.value_of("name").unwrap(), timeout)?
+    } else {
+        target_pid(matches)?
+    };
+
+    let report = injector.inject_pid(pid, dll)?;
+
+    println!(
+        "Injected {} into {} at {:x} with {:?}",
+        dll.display(),
+        pid,
+        report.image_base,
+        report.method
+    );
+
+    Ok(())
+}
+
+fn eject(matches: &ArgMatches) -> anyhow::Result<()> {
+    let base = matches.value_of("base").unwrap();
+    let image_base = usize::from_str_radix(base.trim_start_matches("0x"), 16)
+        .map_err(|e| anyhow!("Invalid base address {}: {}", base, e))?;
+
+    let mut timeout = ExecutionTimeout::default();
+    if let Some(millis) = matches.value_of("timeout") {
+        timeout.millis = millis.parse()?;
+    }
+    timeout.terminate = matches.is_present("terminate_on_timeout");
+
+    let options = EjectOptions {
+        execution: matches.value_of("execution").unwrap().parse()?,
+        call_entry_point: !matches.is_present("skip_entry_point"),
+        timeout,
+    };
+
+    let pid = target_pid(matches)?;
+    let report = jector::eject_pid(pid, image_base, &options)?;
+
+    println!("Ejected the library at {:x} from {}", image_base, pid);
+    if report.static_tls_leaked {
+        println!("The library has static TLS, its memory stays allocated");
+    }
+
+    Ok(())
+}
+
+fn ps() -> anyhow::Result<()> {
+    println!("{:>8} {:>8} {:>7}  NAME", "PID", "PPID", "THREADS");
+
+    for process in Snapshot::processes()?.process_entries() {
+        println!(
+            "{:>8} {:>8} {:>7}  {}",
+            process.pid, process.parent_pid, process.thread_count, process.exe_file
+        );
+    }
+
+    Ok(())
+}
+
+fn modules(pid: u32) -> anyhow::Result<()> {
+    println!("{:>16} {:>10}  {:<24} PATH", "BASE", "SIZE", "NAME");
+
+    for module in Snapshot::modules(pid)?.module_entries() {
+        println!(
+            "{:>16x} {:>10x}  {:<24} {}",
+            module.base, module.size, module.name, module.path
+        );
+    }
+
+    Ok(())
+}
+
+fn target_pid(matches: &ArgMatches) -> anyhow::Result<u32> {
+    if let Some(pid) = matches.value_of("pid") {
+        return Ok(pid.parse()?);
+    }
+
+    let name = matches.value_of("name").unwrap();
+    match Process::find_by_name(name)?.first() {
+        Some(&pid) => Ok(pid),
+        None => bail!("No process with name '{}'", name),
+    }
+}
+
+// Polls until a process with the name is running, None waits forever
+fn wait_for_process(name: &str, timeout: Option<Duration>) -> anyhow::Result<u32> {
+    println!("Waiting for {} to start", name);
+
+    let started = Instant::now();
+    loop {
+        if let Some(&pid) = Process::find_by_name(name)?.first() {
+            return Ok(pid);
+        }
+
+        if let Some(timeout) = timeout {
+            ensure!(
+                started.elapsed() < timeout,
+                "No process with name '{}' started within {} ms",
+                name,
+                timeout.as_millis()
+            );
+        }
+
+        thread::sleep(Duration::from_millis(WAIT_INTERVAL_MS));
+    }
+}
+
+fn target_group() -> ArgGroup<'static> {
+    ArgGroup::with_name("target")
+        .arg("pid")
+        .arg("name")
+        .required(true)
+}
+
+fn pid_arg() -> Arg<'static, 'static> {
+    Arg::with_name("pid")
+        .short("p")
+        .long("pid")
+        .value_name("pid")
+        .help("The PID of the target process")
+        .takes_value(true)
+}
+
+fn name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("name")
+        .short("n")
+        .long("name")
+        .value_name("process_name")
+        .help("The process file name of the target process")
+        .takes_value(true)
+}
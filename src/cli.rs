@@ -0,0 +1,138 @@
+// Arguments shared by the jector and manualmap binaries
+// Included by both as a module, so everything here is used by each of them
+use anyhow::anyhow;
+use clap::{Arg, ArgMatches};
+use jector::winapiwrapper::privileges;
+use jector::Injector;
+
+// Options of an injection, the binaries add their own target and library arguments
+pub fn inject_args(default_method: &'static str) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("method")
+            .short("m")
+            .long("method")
+            .value_name("loadlibrary/manualmap/windowshook")
+            .help("The injection method to use")
+            .takes_value(true)
+            .default_value(default_method),
+        execution_arg(),
+        Arg::with_name("dependencies")
+            .short("d")
+            .long("dependencies")
+            .value_name("load/requireloaded/manualmap")
+            .help("What to do with imported libraries that aren't loaded in the target (manualmap only)")
+            .takes_value(true)
+            .default_value("load"),
+        Arg::with_name("system_dependencies")
+            .long("system-dependencies")
+            .value_name("load/requireloaded/manualmap")
+            .help("What to do with missing libraries from the system directory, overrides --dependencies (manualmap only)")
+            .takes_value(true),
+        Arg::with_name("dependency_override")
+            .long("dependency-override")
+            .value_name("dll_name=policy")
+            .help("What to do with a specific missing library, overrides the other policies (manualmap only)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("mapping")
+            .long("mapping")
+            .value_name("virtualalloc/section")
+            .help("How memory for the image is created in the target (manualmap only)")
+            .takes_value(true)
+            .default_value("virtualalloc"),
+        Arg::with_name("hook_procedure")
+            .long("hook-procedure")
+            .value_name("export_name")
+            .help("The exported hook procedure installed for WH_GETMESSAGE (windowshook only)")
+            .takes_value(true),
+        timeout_arg(),
+        terminate_on_timeout_arg(),
+        Arg::with_name("no_protect")
+            .long("no-protect")
+            .help("Leave the whole mapped image writable and executable (manualmap only)"),
+        Arg::with_name("fallback")
+            .long("fallback")
+            .help("Fall back to LoadLibrary if manual mapping fails (manualmap only)"),
+        Arg::with_name("map_only")
+            .long("map-only")
+            .help("Map the image without calling TLS callbacks or DllMain (manualmap only)"),
+        Arg::with_name("verify_writes")
+            .long("verify-writes")
+            .help("Read back memory written to the target and fail if it differs"),
+        Arg::with_name("verify_checksum")
+            .long("verify-checksum")
+            .help("Fail if the checksum of the image doesn't match (manualmap only)"),
+    ]
+}
+
+// Builds the injector from the arguments of inject_args
+pub fn injector(matches: &ArgMatches) -> anyhow::Result<Injector> {
+    let mut injector = Injector::new()
+        .method(matches.value_of("method").unwrap().parse()?)
+        .execution(matches.value_of("execution").unwrap().parse()?)
+        .protect_sections(!matches.is_present("no_protect"))
+        .fallback_to_loadlibrary(matches.is_present("fallback"))
+        .call_entry_point(!matches.is_present("map_only"))
+        .dependencies(matches.value_of("dependencies").unwrap().parse()?)
+        .mapping(matches.value_of("mapping").unwrap().parse()?)
+        .verify_writes(matches.is_present("verify_writes"))
+        .verify_checksum(matches.is_present("verify_checksum"))
+        .terminate_on_timeout(matches.is_present("terminate_on_timeout"));
+
+    if let Some(timeout) = matches.value_of("timeout") {
+        injector = injector.timeout(timeout.parse()?);
+    }
+
+    if let Some(policy) = matches.value_of("system_dependencies") {
+        injector = injector.system_dependencies(policy.parse()?);
+    }
+
+    if let Some(dependency_overrides) = matches.values_of("dependency_override") {
+        for dependency_override in dependency_overrides {
+            let (name, policy) = dependency_override
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected dll_name=policy, got {}", dependency_override))?;
+
+            injector = injector.dependency_override(name, policy.parse()?);
+        }
+    }
+
+    if let Some(hook_procedure) = matches.value_of("hook_procedure") {
+        injector = injector.hook_procedure(hook_procedure);
+    }
+
+    Ok(injector)
+}
+
+// Not fatal, most targets can be opened without it
+pub fn enable_debug_privilege() {
+    if let Err(e) = privileges::enable_debug_privilege() {
+        println!("Failed to enable SeDebugPrivilege: {}", e);
+    }
+}
+
+pub fn execution_arg() -> Arg<'static, 'static> {
+    Arg::with_name("execution")
+        .short("e")
+        .long("execution")
+        .value_name("createremotethread/ntcreatethreadex/apc/hijack")
+        .help("How code is executed in the target process")
+        .takes_value(true)
+        .default_value("createremotethread")
+}
+
+pub fn timeout_arg() -> Arg<'static, 'static> {
+    Arg::with_name("timeout")
+        .short("t")
+        .long("timeout")
+        .value_name("milliseconds")
+        .help("How long to wait for code executed in the target process, waits until it returns by default")
+        .takes_value(true)
+}
+
+pub fn terminate_on_timeout_arg() -> Arg<'static, 'static> {
+    Arg::with_name("terminate_on_timeout")
+        .long("terminate-on-timeout")
+        .help("Terminate the thread of code that timed out and free its memory")
+}
@@ -0,0 +1,72 @@
+use crate::winapiwrapper::error::WinApiError;
+use crate::winapiwrapper::process::{Architecture, ProtectionLevel};
+
+// Error returned by the public injection API
+// Internally errors are propagated with anyhow, they are sorted into a category
+// on the way out so that callers can match on them
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    WinApi(#[from] WinApiError),
+    #[error("Failed to parse PE: {0}")]
+    Pe(#[from] pelite::Error),
+    #[error("Invalid PE image: {0}")]
+    InvalidImage(String),
+    #[error("Image has no relocations and its preferred base {image_base:#x} is not available in the target")]
+    RelocationsStripped { image_base: usize },
+    #[error("Entry point of the library returned FALSE for fdwReason {reason}")]
+    EntryPointFailed { reason: u32 },
+    #[error("Library signature is not trusted: {0}")]
+    UntrustedImage(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Library is {payload} but target process is {target}")]
+    ArchitectureMismatch {
+        payload: Architecture,
+        target: Architecture,
+    },
+    #[error("Failed to find target: {0}")]
+    TargetNotFound(String),
+    #[error("Timed out waiting for the {stage} to finish in the target")]
+    Timeout {
+        stage: String,
+        // Whether the code was stopped, otherwise it may still run and its memory was kept
+        terminated: bool,
+    },
+    #[error("Memory at {address:#x} (offset {offset:#x} of the write) reads back as {actual:#04x} instead of {expected:#04x}")]
+    WriteMismatch {
+        address: usize,
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+    #[error("Target is a protected process ({level}), it can't be opened for injection")]
+    ProcessProtected { level: ProtectionLevel },
+    #[error("The target prohibits dynamic code, no executable memory can be written in it")]
+    DynamicCodeProhibited,
+    #[error("The target only loads libraries signed by Microsoft, the library has to be manually mapped")]
+    SignedLibrariesOnly,
+    #[error("Injection was aborted")]
+    Aborted,
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+
+        let error = match error.downcast::<WinApiError>() {
+            Ok(error) => return Self::WinApi(error),
+            Err(error) => error,
+        };
+
+        match error.downcast::<pelite::Error>() {
+            Ok(error) => Self::Pe(error),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
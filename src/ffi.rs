@@ -0,0 +1,146 @@
+// C interface of the cdylib, declared in include/manualmap.h
+// Functions return 0 on success and -1 on failure, mm_last_error describes the failure.
+// Strings are null-terminated UTF-8.
+use crate::{EjectOptions, Error, InjectOptions, InjectionMethod};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+// InjectionMethod as passed by C callers
+const MM_METHOD_MANUALMAP: u32 = 0;
+const MM_METHOD_LOADLIBRARY: u32 = 1;
+const MM_METHOD_WINDOWSHOOK: u32 = 2;
+
+thread_local! {
+    // Message of the last failed call on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+// Injects the library at dll_path into the process
+// hook_procedure names the export MM_METHOD_WINDOWSHOOK installs as the hook, it may be null
+// for the other methods
+// image_base receives the address the library was injected at if it isn't null
+/// # Safety
+/// The strings must be null or null-terminated, `image_base` must be null or writable
+#[no_mangle]
+pub unsafe extern "C" fn mm_inject_pid(
+    pid: u32,
+    dll_path: *const c_char,
+    method: u32,
+    hook_procedure: *const c_char,
+    image_base: *mut usize,
+) -> c_int {
+    ffi_call(|| {
+        let dll_path = str_arg(dll_path, "dll_path")?;
+        let options = inject_options(method, hook_procedure)?;
+        let report = crate::inject_pid(pid, Path::new(dll_path), &options)?;
+
+        if !image_base.is_null() {
+            *image_base = report.image_base;
+        }
+
+        Ok(())
+    })
+}
+
+// Injects the library at dll_path into the first process whose executable has the file name
+// The other arguments are the same as for mm_inject_pid
+/// # Safety
+/// The strings must be null or null-terminated, `image_base` must be null or writable
+#[no_mangle]
+pub unsafe extern "C" fn mm_inject_name(
+    process_name: *const c_char,
+    dll_path: *const c_char,
+    method: u32,
+    hook_procedure: *const c_char,
+    image_base: *mut usize,
+) -> c_int {
+    ffi_call(|| {
+        let process_name = str_arg(process_name, "process_name")?;
+        let dll_path = str_arg(dll_path, "dll_path")?;
+        let options = inject_options(method, hook_procedure)?;
+        let report = crate::inject_process_name(process_name, Path::new(dll_path), &options)?;
+
+        if !image_base.is_null() {
+            *image_base = report.image_base;
+        }
+
+        Ok(())
+    })
+}
+
+// Ejects a library that was manually mapped at image_base, calling DllMain with DLL_PROCESS_DETACH
+#[no_mangle]
+pub extern "C" fn mm_eject(pid: u32, image_base: usize) -> c_int {
+    ffi_call(|| {
+        crate::eject_pid(pid, image_base, &EjectOptions::default())?;
+        Ok(())
+    })
+}
+
+// Message of the last failed call on the calling thread, null if no call has failed
+// The string is owned by the library and stays valid until the next call fails on the thread
+#[no_mangle]
+pub extern "C" fn mm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+// Runs f, storing its error for mm_last_error
+// Panics are caught since unwinding into C is undefined behavior
+fn ffi_call<F: FnOnce() -> Result<(), Error>>(f: F) -> c_int {
+    let message = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return 0,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "Panicked during the call".to_string(),
+    };
+
+    // Interior nulls would truncate the message, drop them instead
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+
+    -1
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if arg.is_null() {
+        return Err(Error::InvalidArgument(format!("{} is null", name)));
+    }
+
+    CStr::from_ptr(arg)
+        .to_str()
+        .map_err(|_| Error::InvalidArgument(format!("{} is not valid UTF-8", name)))
+}
+
+unsafe fn inject_options(
+    method: u32,
+    hook_procedure: *const c_char,
+) -> Result<InjectOptions, Error> {
+    let method = match method {
+        MM_METHOD_MANUALMAP => InjectionMethod::ManualMap,
+        MM_METHOD_LOADLIBRARY => InjectionMethod::LoadLibrary,
+        MM_METHOD_WINDOWSHOOK => InjectionMethod::WindowsHook,
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "Unknown injection method {}",
+                method
+            )))
+        }
+    };
+
+    let hook_procedure = match hook_procedure.is_null() {
+        true => None,
+        false => Some(str_arg(hook_procedure, "hook_procedure")?.to_string()),
+    };
+
+    Ok(InjectOptions {
+        method,
+        hook_procedure,
+        ..Default::default()
+    })
+}
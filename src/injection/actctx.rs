@@ -0,0 +1,136 @@
+use super::execution::ExecutionMethod;
+use super::loadlibrary;
+use super::remotecall::RemoteCall;
+use crate::winapiwrapper::module::Module;
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use pelite::PeFile;
+use std::fs;
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+
+// sizeof(ACTCTXW) for 32-bit and 64-bit processes
+const ACTCTXW_SIZE32: usize = 32;
+const ACTCTXW_SIZE64: usize = 56;
+
+// An activation context created in the target from the manifest embedded in an image
+// The loader stub activates it around the TLS callbacks and DllMain, the same way the Windows
+// loader does for images with a manifest. The context is only released if the mapping fails, so
+// code of the image that runs later can still activate it.
+pub struct ActivationContext {
+    pub handle: usize,
+    // ActivateActCtx, DeactivateActCtx and ReleaseActCtx in the target
+    pub activate: usize,
+    pub deactivate: usize,
+    pub release: usize,
+}
+
+impl ActivationContext {
+    // Returns None if the image has no manifest
+    pub fn create(
+        process: &Process,
+        pe: PeFile,
+        is_wow64: bool,
+        execution: ExecutionMethod,
+    ) -> anyhow::Result<Option<Self>> {
+        let manifest = match pe.resources().ok().and_then(|res| res.manifest().ok()) {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+
+        trace!("Creating activation context from manifest:\n{}", manifest);
+
+        // CreateActCtxW only reads manifests from files, the image only exists in the target.
+        // The manifest is parsed by CreateActCtxW, so the file isn't needed afterwards.
+        let manifest_path = loadlibrary::write_temp_file(manifest.as_bytes(), "manifest")?;
+        let result = create_act_ctx(process, &manifest_path, is_wow64, execution);
+
+        if let Err(e) = fs::remove_file(&manifest_path) {
+            trace!("Failed to remove {}: {}", manifest_path.display(), e);
+        }
+
+        let handle = result?;
+
+        let kernel32 = Module::find_or_load_external(process.pid()?, Path::new("kernel32.dll"))?;
+
+        Ok(Some(Self {
+            handle,
+            activate: kernel32.proc_address("ActivateActCtx")?,
+            deactivate: kernel32.proc_address("DeactivateActCtx")?,
+            release: kernel32.proc_address("ReleaseActCtx")?,
+        }))
+    }
+}
+
+// Calls CreateActCtxW in the target with an ACTCTXW whose lpSource is the manifest file
+fn create_act_ctx(
+    process: &Process,
+    manifest_path: &Path,
+    is_wow64: bool,
+    execution: ExecutionMethod,
+) -> anyhow::Result<usize> {
+    let source: Vec<u8> = manifest_path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .flat_map(|c| c.to_ne_bytes())
+        .collect();
+
+    let actctx_size = if is_wow64 {
+        ACTCTXW_SIZE32
+    } else {
+        ACTCTXW_SIZE64
+    };
+
+    // ACTCTXW is followed by the string lpSource points to
+    let actctx_mem = VirtualMem::alloc(
+        process,
+        0,
+        actctx_size + source.len(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    ensure!(
+        !is_wow64 || actctx_mem.address() + actctx_mem.size() <= u32::MAX as usize,
+        "Memory for WOW64 process was allocated at {:x}, outside of the 32-bit address space",
+        actctx_mem.address()
+    );
+
+    // Every member after lpSource stays zeroed, dwFlags included
+    let source_address = actctx_mem.address() + actctx_size;
+    let mut actctx = vec![0_u8; actctx_size];
+    actctx[..4].copy_from_slice(&(actctx_size as u32).to_ne_bytes());
+    if is_wow64 {
+        actctx[8..12].copy_from_slice(&(source_address as u32).to_ne_bytes());
+    } else {
+        actctx[8..16].copy_from_slice(&(source_address as u64).to_ne_bytes());
+    }
+
+    actctx_mem.write_memory(&actctx, 0)?;
+    actctx_mem.write_memory(&source, actctx_size)?;
+
+    let kernel32 = Module::find_or_load_external(process.pid()?, Path::new("kernel32.dll"))?;
+    let ret = RemoteCall::new(kernel32.proc_address("CreateActCtxW")?)
+        .arg(actctx_mem.address())
+        .execution(execution)
+        .call(process)?;
+
+    // Failure returns INVALID_HANDLE_VALUE, only eax is valid for 32-bit processes
+    // The last error is set in the target, so it can't be reported here
+    let (handle, invalid_handle) = if is_wow64 {
+        (ret as u32 as usize, u32::MAX as usize)
+    } else {
+        (ret as usize, usize::MAX)
+    };
+
+    ensure!(
+        handle != invalid_handle,
+        "CreateActCtxW failed in the target, the manifest of the image may be invalid"
+    );
+
+    trace!("Created activation context {:x}", handle);
+
+    Ok(handle)
+}
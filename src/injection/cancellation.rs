@@ -0,0 +1,33 @@
+use crate::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Lets another thread, e.g. the UI thread of a frontend, abort an injection in flight
+// Clones share the same state, so a clone can be kept to cancel while the original is passed on.
+// Cancelled injections fail with Error::Aborted and free what they allocated in the target.
+// Code already running in the target, such as DllMain, can't be interrupted and is waited for.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Fails with Error::Aborted once the token was cancelled
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        ensure!(!self.is_cancelled(), Error::Aborted);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use super::ensure_not_protected;
+use super::execution::ExecutionMethod;
+use super::remotecall::{RemoteArg, RemoteCall};
+use crate::winapiwrapper::module::Module;
+use crate::winapiwrapper::process::{Process, ProcessAccess};
+use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use std::iter;
+use std::path::Path;
+
+// {9280188d-0e8e-4867-b30c-7fa83884e8de}
+const CLSID_CLR_META_HOST: Guid = Guid(
+    0x9280188d,
+    0x0e8e,
+    0x4867,
+    [0xb3, 0x0c, 0x7f, 0xa8, 0x38, 0x84, 0xe8, 0xde],
+);
+// {d332db9e-b9b3-4125-8207-a14884f53216}
+const IID_ICLR_META_HOST: Guid = Guid(
+    0xd332db9e,
+    0xb9b3,
+    0x4125,
+    [0x82, 0x07, 0xa1, 0x48, 0x84, 0xf5, 0x32, 0x16],
+);
+// {bd39d1d2-ba2f-486a-89b0-b4b0cb466891}
+const IID_ICLR_RUNTIME_INFO: Guid = Guid(
+    0xbd39d1d2,
+    0xba2f,
+    0x486a,
+    [0x89, 0xb0, 0xb4, 0xb0, 0xcb, 0x46, 0x68, 0x91],
+);
+// {90f1a06e-7712-4762-86b5-7a5eba6bdb02}
+const CLSID_CLR_RUNTIME_HOST: Guid = Guid(
+    0x90f1a06e,
+    0x7712,
+    0x4762,
+    [0x86, 0xb5, 0x7a, 0x5e, 0xba, 0x6b, 0xdb, 0x02],
+);
+// {90f1a06c-7712-4762-86b5-7a5eba6bdb02}
+const IID_ICLR_RUNTIME_HOST: Guid = Guid(
+    0x90f1a06c,
+    0x7712,
+    0x4762,
+    [0x86, 0xb5, 0x7a, 0x5e, 0xba, 0x6b, 0xdb, 0x02],
+);
+
+// Vtable indices of the methods that are called, the first three belong to IUnknown
+const IUNKNOWN_RELEASE: usize = 2;
+const ICLR_META_HOST_GET_RUNTIME: usize = 3;
+const ICLR_RUNTIME_INFO_GET_INTERFACE: usize = 9;
+const ICLR_RUNTIME_HOST_START: usize = 3;
+const ICLR_RUNTIME_HOST_EXECUTE_IN_DEFAULT_APP_DOMAIN: usize = 11;
+
+// Out parameters of the hosting calls, each one gets a pointer-sized slot
+const OFFSET_META_HOST: usize = 0x0;
+const OFFSET_RUNTIME_INFO: usize = 0x8;
+const OFFSET_RUNTIME_HOST: usize = 0x10;
+const OFFSET_RETURN_VALUE: usize = 0x18;
+const OUT_DATA_SIZE: usize = 0x20;
+
+// A COM GUID, serialized the way it is laid out in memory
+struct Guid(u32, u16, u16, [u8; 8]);
+
+impl Guid {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.0.to_le_bytes());
+        bytes.extend_from_slice(&self.1.to_le_bytes());
+        bytes.extend_from_slice(&self.2.to_le_bytes());
+        bytes.extend_from_slice(&self.3);
+
+        bytes
+    }
+}
+
+// Options controlling how a managed assembly is run in the target
+pub struct ClrOptions {
+    // Version of the runtime to load, as passed to ICLRMetaHost::GetRuntime
+    pub runtime_version: String,
+    // How the hosting calls are executed in the target
+    pub execution: ExecutionMethod,
+}
+
+impl Default for ClrOptions {
+    fn default() -> Self {
+        Self {
+            runtime_version: "v4.0.30319".to_string(),
+            execution: ExecutionMethod::CreateRemoteThread,
+        }
+    }
+}
+
+// Loads the .NET Framework runtime into the target, or attaches to the one that is already
+// running, and calls a method of a managed assembly in the default app domain
+// The method must have the signature `static int Method(string argument)`, which is what
+// ICLRRuntimeHost::ExecuteInDefaultAppDomain requires. Its return value is returned.
+pub fn execute_assembly(
+    pid: u32,
+    assembly: &Path,
+    type_name: &str,
+    method: &str,
+    argument: &str,
+    options: &ClrOptions,
+) -> anyhow::Result<u32> {
+    ensure_not_protected(pid)?;
+
+    let process = Process::from_pid(
+        pid,
+        ProcessAccess::PROCESS_CREATE_THREAD
+            | ProcessAccess::PROCESS_QUERY_INFORMATION
+            | ProcessAccess::PROCESS_VM_OPERATION
+            | ProcessAccess::PROCESS_VM_READ
+            | ProcessAccess::PROCESS_VM_WRITE
+            | ProcessAccess::SYNCHRONIZE,
+        false,
+    )?;
+
+    let host = ClrHost {
+        process: &process,
+        is_wow64: process.is_wow64()?,
+        execution: options.execution,
+    };
+
+    let assembly = assembly
+        .to_str()
+        .ok_or_else(|| anyhow!("Failed to convert Path to str"))?;
+
+    let mscoree = Module::find_or_load_external(pid, Path::new("mscoree.dll"))?;
+    let clr_create_instance = mscoree.proc_address("CLRCreateInstance")?;
+
+    let out = VirtualMem::alloc(
+        &process,
+        0,
+        OUT_DATA_SIZE,
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    // Interfaces are released in reverse order once the method returned or a call failed
+    let mut interfaces = Vec::new();
+    let result = host.execute(
+        clr_create_instance,
+        &out,
+        &mut interfaces,
+        &options.runtime_version,
+        vec![
+            wide_string(assembly),
+            wide_string(type_name),
+            wide_string(method),
+            wide_string(argument),
+            (out.address() + OFFSET_RETURN_VALUE).into(),
+        ],
+    );
+
+    for &interface in interfaces.iter().rev() {
+        host.release(interface);
+    }
+
+    result?;
+
+    let ret = out.read_value::<u32>(OFFSET_RETURN_VALUE)?;
+
+    trace!("{}.{} returned {:x}", type_name, method, ret);
+
+    Ok(ret)
+}
+
+// Calls methods of COM interfaces living in the target
+struct ClrHost<'a> {
+    process: &'a Process,
+    is_wow64: bool,
+    execution: ExecutionMethod,
+}
+
+impl ClrHost<'_> {
+    // Creates the runtime host and calls ExecuteInDefaultAppDomain with args
+    // Starting a runtime that is already running succeeds with S_FALSE
+    fn execute(
+        &self,
+        clr_create_instance: usize,
+        out: &VirtualMem,
+        interfaces: &mut Vec<usize>,
+        runtime_version: &str,
+        args: Vec<RemoteArg>,
+    ) -> anyhow::Result<()> {
+        let hr = RemoteCall::new(clr_create_instance)
+            .arg(CLSID_CLR_META_HOST.to_bytes())
+            .arg(IID_ICLR_META_HOST.to_bytes())
+            .arg(out.address() + OFFSET_META_HOST)
+            .execution(self.execution)
+            .call(self.process)? as u32;
+        check_hresult("CLRCreateInstance", hr)?;
+        let meta_host = self.read_pointer(out, OFFSET_META_HOST)?;
+        interfaces.push(meta_host);
+
+        let hr = self.call(
+            meta_host,
+            ICLR_META_HOST_GET_RUNTIME,
+            vec![
+                wide_string(runtime_version),
+                IID_ICLR_RUNTIME_INFO.to_bytes().into(),
+                (out.address() + OFFSET_RUNTIME_INFO).into(),
+            ],
+        )?;
+        check_hresult("ICLRMetaHost::GetRuntime", hr)?;
+        let runtime_info = self.read_pointer(out, OFFSET_RUNTIME_INFO)?;
+        interfaces.push(runtime_info);
+
+        let hr = self.call(
+            runtime_info,
+            ICLR_RUNTIME_INFO_GET_INTERFACE,
+            vec![
+                CLSID_CLR_RUNTIME_HOST.to_bytes().into(),
+                IID_ICLR_RUNTIME_HOST.to_bytes().into(),
+                (out.address() + OFFSET_RUNTIME_HOST).into(),
+            ],
+        )?;
+        check_hresult("ICLRRuntimeInfo::GetInterface", hr)?;
+        let runtime_host = self.read_pointer(out, OFFSET_RUNTIME_HOST)?;
+        interfaces.push(runtime_host);
+
+        let hr = self.call(runtime_host, ICLR_RUNTIME_HOST_START, Vec::new())?;
+        check_hresult("ICLRRuntimeHost::Start", hr)?;
+
+        let hr = self.call(
+            runtime_host,
+            ICLR_RUNTIME_HOST_EXECUTE_IN_DEFAULT_APP_DOMAIN,
+            args,
+        )?;
+        check_hresult("ICLRRuntimeHost::ExecuteInDefaultAppDomain", hr)
+    }
+
+    // Calls the method at index in the vtable of interface, which is passed as the this pointer
+    // COM methods are stdcall on x86, so RemoteCall can call them like any other function
+    fn call(&self, interface: usize, index: usize, args: Vec<RemoteArg>) -> anyhow::Result<u32> {
+        let pointer_size = if self.is_wow64 { 4 } else { 8 };
+        let vtable = self.read_pointer_at(interface)?;
+        let method = self.read_pointer_at(vtable + index * pointer_size)?;
+
+        let call = args.into_iter().fold(
+            RemoteCall::new(method)
+                .arg(interface)
+                .execution(self.execution),
+            |call, arg| call.arg(arg),
+        );
+
+        Ok(call.call(self.process)? as u32)
+    }
+
+    // Failing to release only leaks the interface
+    fn release(&self, interface: usize) {
+        if let Err(e) = self.call(interface, IUNKNOWN_RELEASE, Vec::new()) {
+            trace!("Failed to release interface at {:x}: {}", interface, e);
+        }
+    }
+
+    fn read_pointer(&self, mem: &VirtualMem, offset: usize) -> anyhow::Result<usize> {
+        self.read_pointer_at(mem.address() + offset)
+    }
+
+    fn read_pointer_at(&self, address: usize) -> anyhow::Result<usize> {
+        let pointer = if self.is_wow64 {
+            self.process.read_value::<u32>(address)? as usize
+        } else {
+            self.process.read_value::<u64>(address)? as usize
+        };
+
+        ensure!(pointer != 0, "Interface pointer at {:x} is null", address);
+
+        Ok(pointer)
+    }
+}
+
+// Negative HRESULTs are errors
+fn check_hresult(function: &str, hr: u32) -> anyhow::Result<()> {
+    ensure!(
+        (hr as i32) >= 0,
+        "Function call to {} failed [HRESULT = 0x{:x}]",
+        function,
+        hr
+    );
+
+    Ok(())
+}
+
+// Null-terminated UTF-16 string copied into the target
+fn wide_string(str: &str) -> RemoteArg {
+    RemoteArg::Buffer(
+        str.encode_utf16()
+            .chain(iter::once(0))
+            .flat_map(|c| c.to_ne_bytes())
+            .collect(),
+    )
+}
@@ -0,0 +1,26 @@
+use std::str::FromStr;
+
+// What the manual mapper does with imported libraries that aren't loaded in the target
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DependencyPolicy {
+    // Load them with LoadLibrary
+    Load,
+    // Fail the injection, nothing besides the image is added to the target
+    RequireLoaded,
+    // Manually map them from disk, their own missing imports are handled the same way
+    ManualMap,
+}
+
+impl FromStr for DependencyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str.to_ascii_lowercase().trim() {
+            "load" => Ok(DependencyPolicy::Load),
+            "requireloaded" => Ok(DependencyPolicy::RequireLoaded),
+            "manualmap" => Ok(DependencyPolicy::ManualMap),
+            _ => Err(anyhow!("Unknown dependency policy: {}", str)),
+        }
+    }
+}
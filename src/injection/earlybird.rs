@@ -0,0 +1,129 @@
+use super::cancellation::CancellationToken;
+use super::injectoptions::InjectOptions;
+use super::report::InjectionReport;
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::thread::{instruction_pointer, Thread};
+use crate::winapiwrapper::virtualmem::ProtectFlag;
+use pelite::image::{IMAGE_DOS_HEADER, IMAGE_NT_HEADERS32, IMAGE_NT_HEADERS64};
+use pelite::PeFile;
+use std::thread as std_thread;
+use std::time::{Duration, Instant};
+use winapi::um::winbase::INFINITE;
+use winapi::um::winnt::{CONTEXT_CONTROL, WOW64_CONTEXT_CONTROL};
+
+// jmp $, the encoding is the same on x86 and x64
+const SPIN_LOOP: [u8; 2] = [0xeb, 0xfe];
+
+// Injects into a process that was created suspended before its entry point runs
+// The entry point is patched with a spin loop and the primary thread is resumed, once the thread
+// reaches the loop the loader has initialized the process and the image is injected as usual.
+// TLS callbacks of the executable are called by the loader and run before the image is injected.
+pub fn inject(
+    process: &Process,
+    thread: &Thread,
+    pe: PeFile,
+    image: &[u8],
+    options: &InjectOptions,
+) -> anyhow::Result<InjectionReport> {
+    let is_wow64 = process.is_wow64()?;
+    let entry_point = entry_point(process, is_wow64)?;
+
+    let original = process.read_value::<[u8; 2]>(entry_point)?;
+    let old_protect = process.virtual_protect(
+        entry_point,
+        SPIN_LOOP.len(),
+        ProtectFlag::PAGE_EXECUTE_READWRITE,
+    )?;
+    process.write_memory(&SPIN_LOOP, entry_point)?;
+
+    thread.resume()?;
+
+    let result = wait_for_entry_point(
+        process,
+        thread,
+        is_wow64,
+        entry_point,
+        options.timeout.millis,
+        &options.cancellation,
+    )
+    .and_then(|()| super::inject(process.pid()?, pe, image, options));
+
+    // The thread is still spinning at the entry point, it continues normally once the bytes are restored
+    thread.suspend()?;
+    process.write_memory(&original, entry_point)?;
+    process.virtual_protect(
+        entry_point,
+        SPIN_LOOP.len(),
+        ProtectFlag::from_bits_truncate(old_protect),
+    )?;
+    thread.resume()?;
+
+    result
+}
+
+// The kernel fills in the image base of the PEB before the process starts
+fn entry_point(process: &Process, is_wow64: bool) -> anyhow::Result<usize> {
+    let image_base = process.peb()?.image_base_address;
+
+    let dos_header = process.read_value::<IMAGE_DOS_HEADER>(image_base)?;
+    let nt_headers = image_base + dos_header.e_lfanew as usize;
+
+    let entry_point_offset = if is_wow64 {
+        process
+            .read_value::<IMAGE_NT_HEADERS32>(nt_headers)?
+            .OptionalHeader
+            .AddressOfEntryPoint
+    } else {
+        process
+            .read_value::<IMAGE_NT_HEADERS64>(nt_headers)?
+            .OptionalHeader
+            .AddressOfEntryPoint
+    };
+
+    ensure!(entry_point_offset != 0, "Executable has no entry point");
+
+    Ok(image_base + entry_point_offset as usize)
+}
+
+// INFINITE waits until the process reaches its entry point or exits
+fn wait_for_entry_point(
+    process: &Process,
+    thread: &Thread,
+    is_wow64: bool,
+    entry_point: usize,
+    timeout: u32,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    loop {
+        cancellation.check()?;
+
+        ensure!(
+            process.exit_code()?.is_none(),
+            "Process exited before reaching its entry point"
+        );
+
+        thread.suspend()?;
+        let current = if is_wow64 {
+            thread
+                .wow64_context(WOW64_CONTEXT_CONTROL)
+                .map(|context| context.Eip as usize)
+        } else {
+            thread
+                .context(CONTEXT_CONTROL)
+                .map(|mut context| *instruction_pointer(&mut context) as usize)
+        };
+        thread.resume()?;
+
+        if current? == entry_point {
+            return Ok(());
+        }
+
+        ensure!(
+            timeout == INFINITE || started.elapsed() < Duration::from_millis(timeout as u64),
+            "Timed out waiting for the process to reach its entry point"
+        );
+
+        std_thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,363 @@
+use super::ensure_not_protected;
+use super::execution::{self, execute, ExecutionMethod, ExecutionTimeout};
+use super::exportcache;
+use super::registry;
+use crate::winapiwrapper::module::Module;
+use crate::winapiwrapper::process::{Process, ProcessAccess};
+use crate::winapiwrapper::virtualmem::{AllocType, FreeType, MemType, ProtectFlag, VirtualMem};
+use dynasmrt::{dynasm, DynasmApi, ExecutableBuffer};
+use pelite::image::{
+    IMAGE_DATA_DIRECTORY, IMAGE_DIRECTORY_ENTRY_EXCEPTION, IMAGE_DIRECTORY_ENTRY_TLS,
+    IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_NT_HEADERS32, IMAGE_NT_HEADERS64,
+    IMAGE_NT_HEADERS_SIGNATURE, IMAGE_TLS_DIRECTORY32, IMAGE_TLS_DIRECTORY64,
+};
+use pelite::Pod;
+use std::mem;
+use std::path::Path;
+use winapi::shared::minwindef::TRUE;
+use winapi::um::winnt::DLL_PROCESS_DETACH;
+
+// Upper bound for the TLS callback array, it is null-terminated
+const MAX_TLS_CALLBACKS: usize = 0x100;
+
+// Options controlling how a manually mapped module is ejected
+pub struct EjectOptions {
+    // How the unload stub is executed in the target
+    pub execution: ExecutionMethod,
+    // Call DllMain and the TLS callbacks with DLL_PROCESS_DETACH before freeing the image
+    pub call_entry_point: bool,
+    // How long to wait for the unload stub
+    pub timeout: ExecutionTimeout,
+}
+
+impl Default for EjectOptions {
+    fn default() -> Self {
+        Self {
+            execution: ExecutionMethod::CreateRemoteThread,
+            call_entry_point: true,
+            timeout: ExecutionTimeout::default(),
+        }
+    }
+}
+
+// What was torn down when ejecting a module
+#[derive(Debug, Default)]
+pub struct EjectReport {
+    pub entry_point_called: bool,
+    pub tls_callbacks_called: usize,
+    pub exception_table_removed: bool,
+    // False for images with static TLS, see static_tls_leaked
+    pub image_freed: bool,
+    // Static TLS set up by LdrpHandleTlsData can't be released without the loader's
+    // internal bookkeeping, so the TLS index and data block stay allocated. The loader's entry
+    // keeps pointing at the TLS template of the image, which every new thread copies, so the
+    // image isn't freed either.
+    pub static_tls_leaked: bool,
+}
+
+// Ejects an image that was manually mapped at image_base
+// Modules loaded with LoadLibrary are owned by the loader and should be freed with FreeLibrary
+pub fn eject(pid: u32, image_base: usize, options: &EjectOptions) -> anyhow::Result<EjectReport> {
+    ensure_not_protected(pid)?;
+
+    let process = Process::from_pid(
+        pid,
+        ProcessAccess::PROCESS_CREATE_THREAD
+            | ProcessAccess::PROCESS_QUERY_INFORMATION
+            | ProcessAccess::PROCESS_VM_OPERATION
+            | ProcessAccess::PROCESS_VM_READ
+            | ProcessAccess::PROCESS_VM_WRITE
+            | ProcessAccess::SYNCHRONIZE,
+        false,
+    )?;
+    let process_key = process.key()?;
+
+    let is_wow64 = process.is_wow64()?;
+
+    // The headers are still mapped, use them to find what the loader set up
+    let dos_header = process.read_value::<IMAGE_DOS_HEADER>(image_base)?;
+    ensure!(
+        dos_header.e_magic == IMAGE_DOS_SIGNATURE,
+        "No image mapped at {:x}",
+        image_base
+    );
+
+    let nt_headers = image_base + dos_header.e_lfanew as usize;
+    let (entry_point_offset, number_of_directories, data_directories) = if is_wow64 {
+        let headers = process.read_value::<IMAGE_NT_HEADERS32>(nt_headers)?;
+        ensure!(
+            headers.Signature == IMAGE_NT_HEADERS_SIGNATURE,
+            "Bad NT signature"
+        );
+
+        (
+            headers.OptionalHeader.AddressOfEntryPoint as usize,
+            headers.OptionalHeader.NumberOfRvaAndSizes as usize,
+            nt_headers + mem::size_of::<IMAGE_NT_HEADERS32>(),
+        )
+    } else {
+        let headers = process.read_value::<IMAGE_NT_HEADERS64>(nt_headers)?;
+        ensure!(
+            headers.Signature == IMAGE_NT_HEADERS_SIGNATURE,
+            "Bad NT signature"
+        );
+
+        (
+            headers.OptionalHeader.AddressOfEntryPoint as usize,
+            headers.OptionalHeader.NumberOfRvaAndSizes as usize,
+            nt_headers + mem::size_of::<IMAGE_NT_HEADERS64>(),
+        )
+    };
+
+    // The data directories follow the optional header
+    let read_data_directory = |index: usize| {
+        if index < number_of_directories {
+            process.read_value::<IMAGE_DATA_DIRECTORY>(
+                data_directories + index * mem::size_of::<IMAGE_DATA_DIRECTORY>(),
+            )
+        } else {
+            Ok(IMAGE_DATA_DIRECTORY::zeroed())
+        }
+    };
+
+    let tls_directory = read_data_directory(IMAGE_DIRECTORY_ENTRY_TLS)?;
+    let tls_callbacks = if options.call_entry_point {
+        read_tls_callbacks(&process, is_wow64, image_base, &tls_directory)?
+    } else {
+        Vec::new()
+    };
+
+    // Only 64-bit images have their function table registered
+    let exception_directory = read_data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION)?;
+    let exception_fn_table = if !is_wow64 && exception_directory.VirtualAddress != 0 {
+        image_base + exception_directory.VirtualAddress as usize
+    } else {
+        0
+    };
+
+    let mut report = EjectReport {
+        static_tls_leaked: tls_directory.VirtualAddress != 0,
+        ..Default::default()
+    };
+
+    let entry_point = if options.call_entry_point && entry_point_offset != 0 {
+        Some(image_base + entry_point_offset)
+    } else {
+        None
+    };
+
+    if entry_point.is_some() || !tls_callbacks.is_empty() || exception_fn_table != 0 {
+        let unloader = if is_wow64 {
+            get_unloader32(image_base, entry_point, &tls_callbacks)
+        } else {
+            let rtl_delete_function_table = if exception_fn_table != 0 {
+                Module::find_or_load_external(pid, Path::new("ntdll.dll"))?
+                    .proc_address("RtlDeleteFunctionTable")?
+            } else {
+                0
+            };
+
+            get_unloader64(
+                image_base,
+                entry_point,
+                &tls_callbacks,
+                exception_fn_table,
+                rtl_delete_function_table,
+            )
+        }?;
+
+        let mut unloader_mem = VirtualMem::alloc(
+            &process,
+            0,
+            unloader.size(),
+            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+            ProtectFlag::PAGE_EXECUTE_READWRITE,
+        )?;
+
+        unloader_mem.write_memory(&unloader, 0)?;
+
+        let ret = match execute(
+            &process,
+            unloader_mem.address(),
+            0,
+            options.execution,
+            options.timeout,
+            "unloader",
+        ) {
+            Ok(ret) => ret,
+            Err(e) => {
+                // The image isn't freed either, DllMain may still be running
+                if execution::may_still_run(&e) {
+                    unloader_mem.set_free_on_drop(false);
+                }
+
+                return Err(e);
+            }
+        };
+
+        report.entry_point_called = entry_point.is_some();
+        report.tls_callbacks_called = tls_callbacks.len();
+        report.exception_table_removed = exception_fn_table != 0 && ret == TRUE as u32;
+    }
+
+    if report.static_tls_leaked {
+        trace!(
+            "The image at {:x} has static TLS, it stays allocated for new threads",
+            image_base
+        );
+    } else {
+        // The image was allocated as a single region starting at its base, or mapped as a view
+        // of a section, which VirtualFreeEx can't release
+        let is_view = process
+            .memory_region(image_base)
+            .is_some_and(|region| region.typ.contains(MemType::MEM_MAPPED));
+
+        if is_view {
+            process.unmap_view(image_base)?;
+        } else {
+            process.virtual_free(image_base, 0, FreeType::MEM_RELEASE)?;
+        }
+        report.image_freed = true;
+    }
+
+    registry::unregister(process_key, image_base);
+    exportcache::remove_module(pid, image_base);
+
+    Ok(report)
+}
+
+// The callback array holds absolute addresses which were relocated when the image was mapped
+fn read_tls_callbacks(
+    process: &Process,
+    is_wow64: bool,
+    image_base: usize,
+    tls_directory: &IMAGE_DATA_DIRECTORY,
+) -> anyhow::Result<Vec<usize>> {
+    if tls_directory.VirtualAddress == 0 {
+        return Ok(Vec::new());
+    }
+
+    let tls_address = image_base + tls_directory.VirtualAddress as usize;
+    let (address_of_callbacks, pointer_size) = if is_wow64 {
+        let tls = process.read_value::<IMAGE_TLS_DIRECTORY32>(tls_address)?;
+        (tls.AddressOfCallBacks as usize, 4)
+    } else {
+        let tls = process.read_value::<IMAGE_TLS_DIRECTORY64>(tls_address)?;
+        (tls.AddressOfCallBacks as usize, 8)
+    };
+
+    let mut callbacks = Vec::new();
+    if address_of_callbacks == 0 {
+        return Ok(callbacks);
+    }
+
+    for i in 0..MAX_TLS_CALLBACKS {
+        let address = address_of_callbacks + i * pointer_size;
+        let callback = if is_wow64 {
+            process.read_value::<u32>(address)? as usize
+        } else {
+            process.read_value::<u64>(address)? as usize
+        };
+
+        if callback == 0 {
+            break;
+        }
+
+        callbacks.push(callback);
+    }
+
+    Ok(callbacks)
+}
+
+// Calls DllMain and then the TLS callbacks, the reverse of the order used when loading
+fn get_unloader32(
+    image_base: usize,
+    entry_point: Option<usize>,
+    tls_callbacks: &[usize],
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x86::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x86
+        ; push ebp
+        ; mov ebp, esp
+    );
+
+    for &routine in entry_point.iter().chain(tls_callbacks) {
+        dynasm!(assembler
+            ; .arch x86
+            ; push 0
+            ; push DLL_PROCESS_DETACH as _
+            ; push DWORD image_base as _
+            ; mov eax, DWORD routine as _
+            ; call eax
+        );
+    }
+
+    dynasm!(assembler
+        ; .arch x86
+        ; mov eax, TRUE
+        ; mov esp, ebp
+        ; pop ebp
+        ; ret 4
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
+
+fn get_unloader64(
+    image_base: usize,
+    entry_point: Option<usize>,
+    tls_callbacks: &[usize],
+    exception_fn_table: usize,
+    rtl_delete_function_table: usize,
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x64::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x64
+        ; push rbp
+        ; mov rbp, rsp
+
+        // Allocate 32 bytes of shadow space, the stack stays 16-byte aligned
+        ; sub rsp, 32
+    );
+
+    for &routine in entry_point.iter().chain(tls_callbacks) {
+        dynasm!(assembler
+            ; .arch x64
+            ; mov rcx, QWORD image_base as _
+            ; mov edx, DLL_PROCESS_DETACH as _
+            ; xor r8, r8
+            ; mov rax, QWORD routine as _
+            ; call rax
+        );
+    }
+
+    if exception_fn_table != 0 {
+        // RtlDeleteFunctionTable returns a BOOLEAN, so only al is valid
+        dynasm!(assembler
+            ; .arch x64
+            ; mov rcx, QWORD exception_fn_table as _
+            ; mov rax, QWORD rtl_delete_function_table as _
+            ; call rax
+            ; movzx eax, al
+        );
+    } else {
+        dynasm!(assembler
+            ; .arch x64
+            ; mov eax, TRUE
+        );
+    }
+
+    dynasm!(assembler
+        ; .arch x64
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
@@ -0,0 +1,650 @@
+use crate::winapiwrapper::handleowner::{HandleOwner, WaitResult};
+use crate::winapiwrapper::module::Module;
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::thread::{
+    self, instruction_pointer, ExtendedContext, Thread, ThreadAccess, ThreadCreationFlags,
+    ThreadCreationMethod, Threads,
+};
+use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use crate::Error;
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use std::ffi::c_void;
+use std::mem;
+use std::path::Path;
+use std::slice;
+use std::str::FromStr;
+use std::thread as std_thread;
+use std::time::{Duration, Instant};
+use winapi::um::winbase::INFINITE;
+use winapi::um::winnt::{WOW64_CONTEXT, WOW64_CONTEXT_ALL};
+
+// Exit code of threads terminated after a timeout
+const TIMEOUT_EXIT_CODE: u32 = 0xdead;
+
+// Ways of executing code inside the target process
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionMethod {
+    CreateRemoteThread,
+    NtCreateThreadEx,
+    // Queues a user APC on every thread of the target
+    // The code runs once one of the threads enters an alertable wait
+    QueueUserApc,
+    // Redirects an existing thread of the target to the code and restores it afterwards
+    // The thread only picks up the new context once it returns to user mode
+    ThreadHijack,
+}
+
+impl FromStr for ExecutionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str.to_ascii_lowercase().trim() {
+            "createremotethread" => Ok(ExecutionMethod::CreateRemoteThread),
+            "ntcreatethreadex" => Ok(ExecutionMethod::NtCreateThreadEx),
+            "apc" | "queueuserapc" => Ok(ExecutionMethod::QueueUserApc),
+            "hijack" | "threadhijack" => Ok(ExecutionMethod::ThreadHijack),
+            _ => Err(anyhow!("Unknown execution method: {}", str)),
+        }
+    }
+}
+
+// How long to wait for code executed in the target, by default until it returns like LoadLibrary
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExecutionTimeout {
+    // Milliseconds, INFINITE waits forever
+    pub millis: u32,
+    // Terminate the thread created for the code once the timeout elapses, so that the memory
+    // it uses can be freed. Code run by an APC or a hijacked thread can't be stopped this way.
+    pub terminate: bool,
+}
+
+impl Default for ExecutionTimeout {
+    fn default() -> Self {
+        Self {
+            millis: INFINITE,
+            terminate: false,
+        }
+    }
+}
+
+// Calls routine(param) inside the target and returns the value returned by the routine
+// The routine must follow the calling convention of a thread start routine
+// stage names the executed code in Error::Timeout
+pub fn execute(
+    process: &Process,
+    routine: usize,
+    param: usize,
+    method: ExecutionMethod,
+    timeout: ExecutionTimeout,
+    stage: &str,
+) -> anyhow::Result<u32> {
+    execute_with_thread(process, routine, param, method, timeout, stage)
+        .map(|executed| executed.result)
+}
+
+// The value returned by an executed routine and the thread that ran it
+pub struct Executed {
+    pub result: u32,
+    pub tid: u32,
+}
+
+// Like execute but also returns the thread that ran the routine, which is an existing thread of
+// the target for APCs and hijacked threads
+pub fn execute_with_thread(
+    process: &Process,
+    routine: usize,
+    param: usize,
+    method: ExecutionMethod,
+    timeout: ExecutionTimeout,
+    stage: &str,
+) -> anyhow::Result<Executed> {
+    trace!("Executing {:x}({:x}) with {:?}", routine, param, method);
+
+    let result = match method {
+        ExecutionMethod::CreateRemoteThread => execute_thread(
+            process,
+            routine,
+            param,
+            ThreadCreationMethod::CreateRemoteThread,
+            timeout,
+        ),
+        ExecutionMethod::NtCreateThreadEx => execute_thread(
+            process,
+            routine,
+            param,
+            ThreadCreationMethod::NtCreateThreadEx,
+            timeout,
+        ),
+        ExecutionMethod::QueueUserApc => execute_apc(process, routine, param, timeout.millis),
+        ExecutionMethod::ThreadHijack => execute_hijack(process, routine, param, timeout.millis),
+    }?;
+
+    result.map_err(|terminated| {
+        Error::Timeout {
+            stage: stage.to_string(),
+            terminated,
+        }
+        .into()
+    })
+}
+
+// Whether code that failed to execute may still run in the target, in which case nothing it
+// uses may be freed. Only code that timed out and was stopped is known to be gone.
+pub fn may_still_run(error: &anyhow::Error) -> bool {
+    !matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::Timeout {
+            terminated: true,
+            ..
+        })
+    )
+}
+
+// Err(terminated) if the timeout elapsed
+type ExecutionResult = Result<Executed, bool>;
+
+fn execute_thread(
+    process: &Process,
+    routine: usize,
+    param: usize,
+    thread_creation: ThreadCreationMethod,
+    timeout: ExecutionTimeout,
+) -> anyhow::Result<ExecutionResult> {
+    let routine = unsafe { mem::transmute::<usize, thread::StartRoutine>(routine) };
+
+    let thread = Thread::spawn_remote_using(
+        thread_creation,
+        process,
+        routine,
+        Some(param as *mut c_void),
+        ThreadCreationFlags::IMMEDIATE,
+    )?;
+
+    if thread.wait(timeout.millis)? == WaitResult::Signaled {
+        return Ok(Ok(Executed {
+            result: thread.exit_code()?,
+            tid: thread.tid()?,
+        }));
+    }
+
+    if !timeout.terminate {
+        return Ok(Err(false));
+    }
+
+    thread.terminate(TIMEOUT_EXIT_CODE)?;
+    thread.wait(INFINITE)?;
+
+    trace!("Terminated remote thread after {} ms", timeout.millis);
+
+    Ok(Err(true))
+}
+
+// Layout of the data shared between the injector and the APC and hijack stubs
+const OFFSET_STUB_CLAIMED: usize = 0x0;
+const OFFSET_STUB_DONE: usize = 0x4;
+const OFFSET_STUB_RESULT: usize = 0x8;
+const OFFSET_STUB_TID: usize = 0xc;
+const STUB_DATA_SIZE: usize = 0x10;
+// The hijack stub data is followed by the saved context of the thread
+const OFFSET_HIJACK_CONTEXT: usize = 0x40;
+
+// Values of the stub data block, see the offsets above
+struct StubData {
+    claimed: bool,
+    done: bool,
+    result: u32,
+    tid: u32,
+}
+
+fn read_stub_data(data: &VirtualMem) -> anyhow::Result<StubData> {
+    let mut buf = [0_u8; STUB_DATA_SIZE];
+    data.read_memory(&mut buf, 0)?;
+
+    let read_u32 = |offset: usize| {
+        u32::from_ne_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ])
+    };
+
+    Ok(StubData {
+        claimed: read_u32(OFFSET_STUB_CLAIMED) != 0,
+        done: read_u32(OFFSET_STUB_DONE) != 0,
+        result: read_u32(OFFSET_STUB_RESULT),
+        tid: read_u32(OFFSET_STUB_TID),
+    })
+}
+
+// Polls the stub data until the stub is done
+// Returns None if the timeout expired first, INFINITE polls until the stub is done
+fn wait_for_stub(data: &VirtualMem, timeout: u32) -> anyhow::Result<Option<StubData>> {
+    let started = Instant::now();
+    loop {
+        let stub_data = read_stub_data(data)?;
+
+        if stub_data.done {
+            return Ok(Some(stub_data));
+        }
+
+        if timeout != INFINITE && started.elapsed() >= Duration::from_millis(timeout as u64) {
+            return Ok(None);
+        }
+
+        std_thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn execute_apc(
+    process: &Process,
+    routine: usize,
+    param: usize,
+    timeout: u32,
+) -> anyhow::Result<ExecutionResult> {
+    let pid = process.pid()?;
+    let is_wow64 = process.is_wow64()?;
+
+    let mut data = VirtualMem::alloc(
+        process,
+        0,
+        STUB_DATA_SIZE,
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    let get_current_thread_id = Module::find_or_load_external(pid, Path::new("kernel32.dll"))?
+        .proc_address("GetCurrentThreadId")?;
+
+    // A 32-bit injector only queues APCs on 32-bit threads
+    let stub = if is_wow64 || cfg!(target_pointer_width = "32") {
+        create_apc_stub32(routine, param, data.address(), get_current_thread_id)
+    } else {
+        create_apc_stub64(routine, param, data.address(), get_current_thread_id)
+    }?;
+
+    let mut stub_mem = VirtualMem::alloc(
+        process,
+        0,
+        stub.size(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_EXECUTE_READWRITE,
+    )?;
+
+    stub_mem.write_memory(&stub, 0)?;
+
+    // A 32-bit injector can only target 32-bit processes, which take the routine as is
+    let apc_routine = if is_wow64 && cfg!(target_pointer_width = "64") {
+        thread::encode_wow64_apc_routine(stub_mem.address())
+    } else {
+        stub_mem.address()
+    };
+
+    let mut queued = 0;
+    for tid in Threads::new(pid)? {
+        let thread = match Thread::from_tid(tid, ThreadAccess::THREAD_SET_CONTEXT, false) {
+            Ok(thread) => thread,
+            Err(_e) => continue, // The thread may have exited since the snapshot was taken
+        };
+
+        if thread.queue_apc(apc_routine, [0; 3]).is_ok() {
+            queued += 1;
+        }
+    }
+
+    ensure!(queued != 0, "Failed to queue an APC on any thread");
+
+    // APCs that are still queued on other threads run the stub after we return,
+    // so the stub and its data have to stay alive for the lifetime of the target
+    data.set_free_on_drop(false);
+    stub_mem.set_free_on_drop(false);
+
+    trace!("Queued APC on {} threads", queued);
+
+    // The APCs stay queued, so the routine may still run later
+    let stub_data = match wait_for_stub(&data, timeout)? {
+        Some(stub_data) => stub_data,
+        None => return Ok(Err(false)),
+    };
+
+    trace!("APC executed by thread {}", stub_data.tid);
+
+    Ok(Ok(Executed {
+        result: stub_data.result,
+        tid: stub_data.tid,
+    }))
+}
+
+// The APC may be delivered to several threads, the first one to claim it calls the routine
+fn create_apc_stub64(
+    routine: usize,
+    param: usize,
+    data_address: usize,
+    get_current_thread_id: usize,
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x64::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x64
+        ; push rbx
+        ; sub rsp, 32
+        ; mov rbx, QWORD data_address as _
+
+        // Claim the APC
+        ; xor eax, eax
+        ; mov ecx, 1
+        ; lock cmpxchg [rbx + OFFSET_STUB_CLAIMED as _], ecx
+        ; jnz ->done
+
+        ; mov rcx, QWORD param as _
+        ; mov rax, QWORD routine as _
+        ; call rax
+        ; mov [rbx + OFFSET_STUB_RESULT as _], eax
+
+        ; mov rax, QWORD get_current_thread_id as _
+        ; call rax
+        ; mov [rbx + OFFSET_STUB_TID as _], eax
+
+        ; mov DWORD [rbx + OFFSET_STUB_DONE as _], 1
+
+        ; ->done:
+        ; add rsp, 32
+        ; pop rbx
+        ; ret
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
+
+// WOW64 APC routines are stdcall with three arguments
+fn create_apc_stub32(
+    routine: usize,
+    param: usize,
+    data_address: usize,
+    get_current_thread_id: usize,
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x86::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x86
+        ; push ebp
+        ; mov ebp, esp
+        ; push ebx
+        ; mov ebx, DWORD data_address as _
+
+        // Claim the APC
+        ; xor eax, eax
+        ; mov ecx, 1
+        ; lock cmpxchg [ebx + OFFSET_STUB_CLAIMED as _], ecx
+        ; jnz ->done
+
+        ; push DWORD param as _
+        ; mov eax, DWORD routine as _
+        ; call eax
+        ; mov [ebx + OFFSET_STUB_RESULT as _], eax
+
+        ; mov eax, DWORD get_current_thread_id as _
+        ; call eax
+        ; mov [ebx + OFFSET_STUB_TID as _], eax
+
+        ; mov DWORD [ebx + OFFSET_STUB_DONE as _], 1
+
+        // The routine may not have cleaned up its argument, so restore the stack from the frame
+        ; ->done:
+        ; mov ebx, [ebp - 4]
+        ; mov esp, ebp
+        ; pop ebp
+        ; ret 12
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
+
+fn execute_hijack(
+    process: &Process,
+    routine: usize,
+    param: usize,
+    timeout: u32,
+) -> anyhow::Result<ExecutionResult> {
+    let pid = process.pid()?;
+    let is_wow64 = process.is_wow64()?;
+
+    // Hijack the first thread that can be opened
+    let (tid, thread) = Threads::new(pid)?
+        .find_map(|tid| {
+            Thread::from_tid(
+                tid,
+                ThreadAccess::THREAD_SUSPEND_RESUME
+                    | ThreadAccess::THREAD_GET_CONTEXT
+                    | ThreadAccess::THREAD_SET_CONTEXT,
+                false,
+            )
+            .ok()
+            .map(|thread| (tid, thread))
+        })
+        .ok_or_else(|| anyhow!("Failed to open a thread to hijack"))?;
+
+    let nt_continue =
+        Module::find_or_load_external(pid, Path::new("ntdll.dll"))?.proc_address("NtContinue")?;
+
+    let suspended = thread.suspend_guarded()?;
+
+    // The routine clobbers the floating point and vector registers as well, so those are saved too
+    let mut original = if is_wow64 {
+        SavedContext::Wow64(Box::new(thread.wow64_context(WOW64_CONTEXT_ALL)?))
+    } else {
+        SavedContext::Native(thread.extended_context()?)
+    };
+
+    // The stub passes the saved context to NtContinue once the routine returns, so the thread is
+    // put back where it was even if the routine only returns after the timeout
+    let context = original.as_bytes();
+    let context_offset = OFFSET_HIJACK_CONTEXT + context.as_ptr() as usize % 64;
+
+    let mut data = VirtualMem::alloc(
+        process,
+        0,
+        context_offset + context.len(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    data.write_memory(context, context_offset)?;
+
+    // A 32-bit injector only hijacks 32-bit threads
+    let stub = if is_wow64 || cfg!(target_pointer_width = "32") {
+        create_hijack_stub32(
+            routine,
+            param,
+            data.address(),
+            nt_continue,
+            data.address() + context_offset,
+        )
+    } else {
+        create_hijack_stub64(
+            routine,
+            param,
+            data.address(),
+            nt_continue,
+            data.address() + context_offset,
+        )
+    }?;
+
+    let mut stub_mem = VirtualMem::alloc(
+        process,
+        0,
+        stub.size(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_EXECUTE_READWRITE,
+    )?;
+
+    stub_mem.write_memory(&stub, 0)?;
+
+    original.redirect(&thread, stub_mem.address())?;
+
+    // The thread may run the stub from now on and is still inside it for a moment after it
+    // reports being done, so the stub and its data stay allocated
+    data.set_free_on_drop(false);
+    stub_mem.set_free_on_drop(false);
+
+    suspended.resume()?;
+
+    trace!("Hijacked thread {}", tid);
+
+    if let Some(stub_data) = wait_for_stub(&data, timeout)? {
+        return Ok(Ok(Executed {
+            result: stub_data.result,
+            tid,
+        }));
+    }
+
+    let suspended = thread.suspend_guarded()?;
+
+    let stub_data = read_stub_data(&data)?;
+    if stub_data.done {
+        return Ok(Ok(Executed {
+            result: stub_data.result,
+            tid,
+        }));
+    }
+
+    // The routine is still running and the stub restores the thread once it returns
+    if stub_data.claimed {
+        return Ok(Err(false));
+    }
+
+    // A stub that never started is as good as stopped, the thread is simply put back
+    original.restore(&thread)?;
+
+    data.set_free_on_drop(true);
+    stub_mem.set_free_on_drop(true);
+
+    suspended.resume()?;
+
+    Ok(Err(true))
+}
+
+enum SavedContext {
+    Native(ExtendedContext),
+    Wow64(Box<WOW64_CONTEXT>),
+}
+
+impl SavedContext {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            SavedContext::Native(context) => context.as_bytes(),
+            SavedContext::Wow64(context) => unsafe {
+                slice::from_raw_parts(
+                    &**context as *const WOW64_CONTEXT as *const u8,
+                    mem::size_of::<WOW64_CONTEXT>(),
+                )
+            },
+        }
+    }
+
+    // Points the thread at address, the saved context itself is left as it was
+    fn redirect(&mut self, thread: &Thread, address: usize) -> anyhow::Result<()> {
+        match self {
+            SavedContext::Native(context) => {
+                // The extended context can't be copied, so its instruction pointer is swapped
+                // in place and put back afterwards
+                let original =
+                    mem::replace(instruction_pointer(context.context_mut()), address as _);
+                let result = thread.set_extended_context(context);
+                *instruction_pointer(context.context_mut()) = original;
+
+                result
+            }
+            SavedContext::Wow64(context) => {
+                let mut hijacked = **context;
+                hijacked.Eip = address as u32;
+
+                thread.set_wow64_context(&hijacked)
+            }
+        }
+    }
+
+    fn restore(&self, thread: &Thread) -> anyhow::Result<()> {
+        match self {
+            SavedContext::Native(context) => thread.set_extended_context(context),
+            SavedContext::Wow64(context) => thread.set_wow64_context(context),
+        }
+    }
+}
+
+// The stub doesn't preserve any registers, once the routine returns it hands the saved context
+// to NtContinue, which restores all of them and resumes the thread where it was hijacked
+fn create_hijack_stub64(
+    routine: usize,
+    param: usize,
+    data_address: usize,
+    nt_continue: usize,
+    context_address: usize,
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x64::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x64
+        ; mov rbx, QWORD data_address as _
+        ; mov DWORD [rbx + OFFSET_STUB_CLAIMED as _], 1
+
+        // The thread may have been suspended anywhere, align the stack for the call
+        ; and rsp, -16
+        ; sub rsp, 32
+
+        ; mov rcx, QWORD param as _
+        ; mov rax, QWORD routine as _
+        ; call rax
+        ; mov [rbx + OFFSET_STUB_RESULT as _], eax
+
+        ; mov DWORD [rbx + OFFSET_STUB_DONE as _], 1
+
+        // NtContinue(context, FALSE) doesn't return
+        ; mov rcx, QWORD context_address as _
+        ; xor edx, edx
+        ; mov rax, QWORD nt_continue as _
+        ; call rax
+        ; int3
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
+
+fn create_hijack_stub32(
+    routine: usize,
+    param: usize,
+    data_address: usize,
+    nt_continue: usize,
+    context_address: usize,
+) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x86::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x86
+        ; mov ebx, DWORD data_address as _
+        ; mov DWORD [ebx + OFFSET_STUB_CLAIMED as _], 1
+
+        ; and esp, -16
+
+        ; push DWORD param as _
+        ; mov eax, DWORD routine as _
+        ; call eax
+        ; mov [ebx + OFFSET_STUB_RESULT as _], eax
+
+        ; mov DWORD [ebx + OFFSET_STUB_DONE as _], 1
+
+        ; push 0
+        ; push DWORD context_address as _
+        ; mov eax, DWORD nt_continue as _
+        ; call eax
+        ; int3
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
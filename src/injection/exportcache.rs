@@ -0,0 +1,60 @@
+use crate::winapiwrapper::process::ProcessKey;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// An export looked up by name or by ordinal, owned so it can be kept in the cache
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExportKey {
+    Name(String),
+    Ordinal(u16),
+}
+
+struct CachedExport {
+    // Path of the module, or the name of a manually mapped module, when the export was resolved
+    // A different module loaded at the same base in the meantime doesn't match
+    module: String,
+    address: usize,
+}
+
+// Addresses of exports resolved in other processes, keyed by process and module base
+// Resolving an export of a module loaded by the target reads and parses its file, which is done
+// for every import of every image that is injected. Entries are dropped when the module is
+// ejected or when the cache of the process is cleared.
+// Only addresses inside the module itself are cached, forwarded exports are cached under the
+// base of the forward target instead.
+static EXPORTS: Mutex<BTreeMap<(ProcessKey, usize, ExportKey), CachedExport>> =
+    Mutex::new(BTreeMap::new());
+
+pub fn find(process: ProcessKey, base: usize, module: &str, export: &ExportKey) -> Option<usize> {
+    EXPORTS
+        .lock()
+        .unwrap()
+        .get(&(process, base, export.clone()))
+        .filter(|cached| cached.module == module)
+        .map(|cached| cached.address)
+}
+
+pub fn insert(process: ProcessKey, base: usize, module: &str, export: ExportKey, address: usize) {
+    EXPORTS.lock().unwrap().insert(
+        (process, base, export),
+        CachedExport {
+            module: module.to_string(),
+            address,
+        },
+    );
+}
+
+// Forgets the exports of the module at base
+pub fn remove_module(pid: u32, base: usize) {
+    EXPORTS
+        .lock()
+        .unwrap()
+        .retain(|(process, cached_base, _), _| (process.pid, *cached_base) != (pid, base));
+}
+
+pub fn clear(pid: u32) {
+    EXPORTS
+        .lock()
+        .unwrap()
+        .retain(|(process, _, _), _| process.pid != pid);
+}
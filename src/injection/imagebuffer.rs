@@ -0,0 +1,81 @@
+use pelite::{PeFile, Pod};
+use std::ops::Range;
+
+// The image laid out the way it is mapped, built in our own process
+// Relocations, imports and the other fixups are applied to the buffer, so the fixed-up image is
+// written to the target with a single WriteProcessMemory instead of one per section and fixup.
+pub struct ImageBuffer {
+    data: Vec<u8>,
+}
+
+impl ImageBuffer {
+    // Copies the headers and the raw data of each section to their RVAs
+    // Memory not backed by the file, such as uninitialized data, stays zeroed
+    pub fn new(
+        pe: PeFile,
+        image: &[u8],
+        size_of_image: usize,
+        size_of_headers: usize,
+    ) -> anyhow::Result<Self> {
+        let mut buffer = Self {
+            data: vec![0; size_of_image],
+        };
+
+        buffer.copy(&image[..size_of_headers], 0)?;
+
+        for section in pe.section_headers() {
+            let start = section.PointerToRawData as usize;
+            let end = start.wrapping_add(section.SizeOfRawData as usize);
+            let raw_data = image
+                .get(start..end)
+                .ok_or_else(|| anyhow!("Section {:?} is outside of the file", section.name()))?;
+
+            // Raw data is padded to the file alignment and may reach past SizeOfImage
+            let address = section.VirtualAddress as usize;
+            let len = raw_data
+                .len()
+                .min(buffer.data.len().saturating_sub(address));
+
+            buffer.copy(&raw_data[..len], address)?;
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn read_value<T: Pod>(&self, offset: usize) -> anyhow::Result<T> {
+        let mut value = T::zeroed();
+        let range = self.range(offset, value.as_bytes().len())?;
+        value.as_bytes_mut().copy_from_slice(&self.data[range]);
+
+        Ok(value)
+    }
+
+    pub fn write_value<T: Pod>(&mut self, value: &T, offset: usize) -> anyhow::Result<()> {
+        self.copy(value.as_bytes(), offset)
+    }
+
+    fn copy(&mut self, data: &[u8], offset: usize) -> anyhow::Result<()> {
+        let range = self.range(offset, data.len())?;
+        self.data[range].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn range(&self, offset: usize, len: usize) -> anyhow::Result<Range<usize>> {
+        ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= self.data.len()),
+            "{:x} bytes at {:x} are outside of the image with size {:x}",
+            len,
+            offset,
+            self.data.len()
+        );
+
+        Ok(offset..offset + len)
+    }
+}
@@ -1,8 +1,12 @@
 use std::str::FromStr;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum InjectionMethod {
     ManualMap,
     LoadLibrary,
+    // Loaded by the system through a WH_GETMESSAGE hook, see InjectOptions::hook_procedure
+    WindowsHook,
 }
 
 impl FromStr for InjectionMethod {
@@ -12,6 +16,7 @@ impl FromStr for InjectionMethod {
         match str.to_ascii_lowercase().trim() {
             "manualmap" => Ok(InjectionMethod::ManualMap),
             "loadlibrary" => Ok(InjectionMethod::LoadLibrary),
+            "windowshook" => Ok(InjectionMethod::WindowsHook),
             _ => Err(anyhow!("Unknown injection method: {}", str)),
         }
     }
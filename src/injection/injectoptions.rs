@@ -0,0 +1,106 @@
+use super::cancellation::CancellationToken;
+use super::dependencypolicy::DependencyPolicy;
+use super::execution::{ExecutionMethod, ExecutionTimeout};
+use super::injectionmethod::InjectionMethod;
+use super::mappingbackend::MappingBackend;
+use super::observer::MapObserver;
+use super::placement::AllocationPlacement;
+use super::virtualfs::VirtualFs;
+use std::collections::HashMap;
+use winapi::um::winnt::DLL_PROCESS_ATTACH;
+
+// Options controlling how a library is injected
+// Fields missing when deserializing keep their defaults, the observer, the cancellation token
+// and the virtual filesystem are never serialized
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct InjectOptions {
+    pub method: InjectionMethod,
+    // How the injection stubs are executed in the target
+    pub execution: ExecutionMethod,
+    // How long to wait for the injection stubs, and whether to stop them when they hang
+    // Also bounds the wait for a hooked window to load the library and for a spawned process to
+    // reach its entry point
+    pub timeout: ExecutionTimeout,
+    // Manual map only: apply memory protection derived from section characteristics
+    // Otherwise the whole image is left PAGE_EXECUTE_READWRITE
+    pub protect_sections: bool,
+    // Manual map only: retry with LoadLibrary if manual mapping fails
+    pub fallback_to_loadlibrary: bool,
+    // Manual map only: call the TLS callbacks and DllMain once the image is mapped
+    // When disabled the image is only mapped
+    pub call_entry_point: bool,
+    // Manual map only: fdwReason and lpvReserved passed to the TLS callbacks and DllMain
+    pub entry_point_reason: u32,
+    pub entry_point_reserved: usize,
+    // Manual map only: call the entry point with DLL_PROCESS_ATTACH before a custom
+    // entry_point_reason, so images linked against the CRT have it initialized
+    pub initialize_crt: bool,
+    // Manual map only: name the mapped module is registered under for module lookups
+    // Defaults to the name in the export directory
+    pub module_name: Option<String>,
+    // Manual map only: where the image is allocated if its preferred base is taken
+    pub placement: AllocationPlacement,
+    // Manual map only: whether the image is allocated or mapped from a section
+    pub mapping: MappingBackend,
+    // Manual map only: how imported libraries missing from the target are handled
+    pub dependencies: DependencyPolicy,
+    // Manual map only: policies for specific libraries by file name, compared case-insensitively
+    // They take precedence over system_dependencies and dependencies
+    pub dependency_overrides: HashMap<String, DependencyPolicy>,
+    // Manual map only: policy for missing libraries found in the system directory of the target,
+    // e.g. to load them with LoadLibrary while manually mapping everything else
+    pub system_dependencies: Option<DependencyPolicy>,
+    // Manual map only: libraries in memory that missing dependencies are mapped from before the
+    // disk is searched. They are manually mapped whatever the policy, since the Windows loader
+    // can only load files.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub virtual_fs: VirtualFs,
+    // Read back the image, the stubs and the library path after writing them to the target and
+    // fail at the first byte that differs
+    pub verify_writes: bool,
+    // Manual map only: fail if the checksum in the optional header doesn't match the file
+    // Like the Windows loader, a mismatch is ignored otherwise
+    pub verify_checksum: bool,
+    // Manual map only: notified after each stage of the mapping, can abort it
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Box<dyn MapObserver>>,
+    // Aborts the injection when cancelled, checked between the stages of an injection
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cancellation: CancellationToken,
+    // Windows hook only: name of the exported hook procedure installed for WH_GETMESSAGE
+    pub hook_procedure: Option<String>,
+    // Only inject libraries read from a file with a valid Authenticode signature
+    #[cfg(feature = "authenticode")]
+    pub require_signature: bool,
+}
+
+impl Default for InjectOptions {
+    fn default() -> Self {
+        Self {
+            method: InjectionMethod::LoadLibrary,
+            execution: ExecutionMethod::CreateRemoteThread,
+            timeout: ExecutionTimeout::default(),
+            protect_sections: true,
+            fallback_to_loadlibrary: false,
+            call_entry_point: true,
+            entry_point_reason: DLL_PROCESS_ATTACH,
+            entry_point_reserved: 0,
+            initialize_crt: false,
+            module_name: None,
+            placement: AllocationPlacement::Default,
+            mapping: MappingBackend::VirtualAlloc,
+            dependencies: DependencyPolicy::Load,
+            dependency_overrides: HashMap::new(),
+            system_dependencies: None,
+            virtual_fs: VirtualFs::default(),
+            verify_writes: false,
+            verify_checksum: false,
+            observer: None,
+            cancellation: CancellationToken::default(),
+            hook_procedure: None,
+            #[cfg(feature = "authenticode")]
+            require_signature: false,
+        }
+    }
+}
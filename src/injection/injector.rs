@@ -0,0 +1,199 @@
+use super::cancellation::CancellationToken;
+use super::dependencypolicy::DependencyPolicy;
+use super::execution::ExecutionMethod;
+use super::injectionmethod::InjectionMethod;
+use super::injectoptions::InjectOptions;
+use super::mappingbackend::MappingBackend;
+use super::observer::MapObserver;
+use super::pesource::PeSource;
+use super::placement::AllocationPlacement;
+use super::report::InjectionReport;
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::processbuilder::ProcessBuilder;
+use crate::Error;
+
+// Builds the injection options step by step and injects with them
+// Settings which aren't set keep the defaults of InjectOptions
+#[derive(Default)]
+pub struct Injector {
+    options: InjectOptions,
+}
+
+impl Injector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn method(mut self, method: InjectionMethod) -> Self {
+        self.options.method = method;
+        self
+    }
+
+    pub fn execution(mut self, execution: ExecutionMethod) -> Self {
+        self.options.execution = execution;
+        self
+    }
+
+    // Milliseconds to wait for each stub executed in the target, as well as for a hooked window to
+    // load the library and for a spawned process to reach its entry point
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.options.timeout.millis = timeout;
+        self
+    }
+
+    // Terminate the thread of a stub that timed out and free its memory
+    // Otherwise the stub is left running and everything it uses stays allocated
+    pub fn terminate_on_timeout(mut self, terminate: bool) -> Self {
+        self.options.timeout.terminate = terminate;
+        self
+    }
+
+    pub fn protect_sections(mut self, protect_sections: bool) -> Self {
+        self.options.protect_sections = protect_sections;
+        self
+    }
+
+    pub fn fallback_to_loadlibrary(mut self, fallback: bool) -> Self {
+        self.options.fallback_to_loadlibrary = fallback;
+        self
+    }
+
+    pub fn call_entry_point(mut self, call_entry_point: bool) -> Self {
+        self.options.call_entry_point = call_entry_point;
+        self
+    }
+
+    // fdwReason and lpvReserved passed to the TLS callbacks and DllMain
+    pub fn entry_point_args(mut self, reason: u32, reserved: usize) -> Self {
+        self.options.entry_point_reason = reason;
+        self.options.entry_point_reserved = reserved;
+        self
+    }
+
+    // Initialize the CRT with DLL_PROCESS_ATTACH before calling the entry point with a custom reason
+    pub fn initialize_crt(mut self, initialize_crt: bool) -> Self {
+        self.options.initialize_crt = initialize_crt;
+        self
+    }
+
+    pub fn module_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.options.module_name = Some(name.into());
+        self
+    }
+
+    pub fn placement(mut self, placement: AllocationPlacement) -> Self {
+        self.options.placement = placement;
+        self
+    }
+
+    pub fn mapping(mut self, mapping: MappingBackend) -> Self {
+        self.options.mapping = mapping;
+        self
+    }
+
+    pub fn dependencies(mut self, dependencies: DependencyPolicy) -> Self {
+        self.options.dependencies = dependencies;
+        self
+    }
+
+    // Handles the library with the file name according to policy instead of the defaults
+    pub fn dependency_override<S: Into<String>>(
+        mut self,
+        name: S,
+        policy: DependencyPolicy,
+    ) -> Self {
+        self.options
+            .dependency_overrides
+            .insert(name.into(), policy);
+        self
+    }
+
+    pub fn system_dependencies(mut self, policy: DependencyPolicy) -> Self {
+        self.options.system_dependencies = Some(policy);
+        self
+    }
+
+    // Maps a dependency with the file name from image rather than from the disk
+    pub fn virtual_file<S: AsRef<str>, B: Into<Vec<u8>>>(mut self, name: S, image: B) -> Self {
+        self.options.virtual_fs.insert(name, image);
+        self
+    }
+
+    pub fn verify_writes(mut self, verify_writes: bool) -> Self {
+        self.options.verify_writes = verify_writes;
+        self
+    }
+
+    pub fn verify_checksum(mut self, verify_checksum: bool) -> Self {
+        self.options.verify_checksum = verify_checksum;
+        self
+    }
+
+    pub fn observer<O: MapObserver + 'static>(mut self, observer: O) -> Self {
+        self.options.observer = Some(Box::new(observer));
+        self
+    }
+
+    // Keep a clone of the token to cancel the injection from another thread
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.options.cancellation = cancellation;
+        self
+    }
+
+    pub fn hook_procedure<S: Into<String>>(mut self, name: S) -> Self {
+        self.options.hook_procedure = Some(name.into());
+        self
+    }
+
+    #[cfg(feature = "authenticode")]
+    pub fn require_signature(mut self, require_signature: bool) -> Self {
+        self.options.require_signature = require_signature;
+        self
+    }
+
+    pub fn options(&self) -> &InjectOptions {
+        &self.options
+    }
+
+    pub fn inject_pid<S: PeSource + ?Sized>(
+        &self,
+        pid: u32,
+        dll: &S,
+    ) -> Result<InjectionReport, Error> {
+        crate::inject_pid(pid, dll, &self.options)
+    }
+
+    pub fn inject_window<S: PeSource + ?Sized>(
+        &self,
+        window_name: &str,
+        dll: &S,
+    ) -> Result<InjectionReport, Error> {
+        crate::inject_window(window_name, dll, &self.options)
+    }
+
+    pub fn inject_process_name<S: PeSource + ?Sized>(
+        &self,
+        process_name: &str,
+        dll: &S,
+    ) -> Result<InjectionReport, Error> {
+        crate::inject_process_name(process_name, dll, &self.options)
+    }
+
+    pub fn inject_shellcode(&self, pid: u32, code: &[u8]) -> Result<u32, Error> {
+        crate::inject_shellcode(pid, code, &self.options)
+    }
+
+    pub fn inject_spawn<S: PeSource + ?Sized>(
+        &self,
+        builder: ProcessBuilder,
+        dll: &S,
+    ) -> Result<(Process, InjectionReport), Error> {
+        crate::inject_spawn(builder, dll, &self.options)
+    }
+}
+
+impl From<InjectOptions> for Injector {
+    fn from(options: InjectOptions) -> Self {
+        Self { options }
+    }
+}
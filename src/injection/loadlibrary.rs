@@ -1,20 +1,35 @@
+use super::execution::{self, execute, ExecutionMethod, ExecutionTimeout};
+use super::injectionmethod::InjectionMethod;
+use super::injectoptions::InjectOptions;
+use super::report::{InjectionPhase, InjectionReport};
+use crate::winapiwrapper::error::{format_message, WinApiError};
 use crate::winapiwrapper::module::Module;
 use crate::winapiwrapper::process::{Process, ProcessAccess};
-use crate::winapiwrapper::thread::{self, Thread, ThreadCreationFlags};
 use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
-use dynasmrt::{dynasm, mmap::ExecutableBuffer, DynasmApi};
-use pelite::PeFile;
+use crate::Error;
+use dynasmrt::{dynasm, mmap::ExecutableBuffer, DynasmApi, DynasmLabelApi};
+use pelite::{PeFile, Wrap};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use std::env;
-use std::ffi::CString;
 use std::fs::File;
 use std::io::Write;
-use std::mem::{size_of, transmute};
-use std::path::Path;
+use std::iter;
+use std::mem::size_of;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use winapi::shared::minwindef::MAX_PATH;
+use winapi::shared::winerror::ERROR_DLL_INIT_FAILED;
+use winapi::um::winnt::DLL_PROCESS_ATTACH;
 
-pub fn inject_library(pid: u32, path: &Path) -> anyhow::Result<usize> {
+pub fn inject_library(
+    pid: u32,
+    path: &Path,
+    execution: ExecutionMethod,
+    timeout: ExecutionTimeout,
+    verify_writes: bool,
+) -> anyhow::Result<usize> {
     // Open a handle to the target process
     let process = Process::from_pid(
         pid,
@@ -34,31 +49,38 @@ pub fn inject_library(pid: u32, path: &Path) -> anyhow::Result<usize> {
         size_of::<u64>()
     };
 
-    // Allocate a buffer inside the target process to contain the path of dll
-    let buffer = VirtualMem::alloc(
+    // Encode the path as a null terminated UTF-16 string for LoadLibraryW
+    let path_bytes: Vec<u8> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .flat_map(|c| c.to_ne_bytes())
+        .collect();
+
+    // Allocate a buffer inside the target process to contain the returned handle and the path of dll
+    let mut buffer = VirtualMem::alloc(
         &process,
         0,
-        MAX_PATH as usize + remote_process_ptr_size,
+        remote_process_ptr_size + path_bytes.len(),
         AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
         ProtectFlag::PAGE_READWRITE,
     )?;
 
     // Write file path to buffer
-    let path_bytes =
-        CString::new(path.to_str().ok_or_else(|| anyhow!("Failed to convert"))?)?.into_bytes();
-    buffer.write_memory(path_bytes.as_slice(), remote_process_ptr_size)?;
+    super::write_memory(&buffer, &path_bytes, remote_process_ptr_size, verify_writes)?;
 
     // Obtain the address of LoadLibrary
     let libkernel32 = Module::find_or_load_external(process.pid()?, Path::new("kernel32.dll"))?;
-    let loadlibrary = libkernel32.proc_address("LoadLibraryA")?;
+    let loadlibrary = libkernel32.proc_address("LoadLibraryW")?;
+    let getlasterror = libkernel32.proc_address("GetLastError")?;
 
     let stub = match is_wow64 {
-        true => create_stub_32(loadlibrary, buffer.address()),
-        false => create_stub_64(loadlibrary, buffer.address()),
+        true => create_stub_32(loadlibrary, getlasterror, buffer.address()),
+        false => create_stub_64(loadlibrary, getlasterror, buffer.address()),
     }?;
 
     // Allocate a buffer for the stub code
-    let stub_buffer = VirtualMem::alloc(
+    let mut stub_buffer = VirtualMem::alloc(
         &process,
         0,
         stub.size(),
@@ -67,85 +89,149 @@ pub fn inject_library(pid: u32, path: &Path) -> anyhow::Result<usize> {
     )?;
 
     // Write stub to buffer
-    stub_buffer.write_memory(&stub, 0)?;
+    super::write_memory(&stub_buffer, &stub, 0, verify_writes)?;
 
-    // Transmute dynamic asm to a function pointer
-    let stub_fn = unsafe { transmute::<usize, thread::StartRoutine>(stub_buffer.address()) };
-
-    // Spawn a remote thread to execute the stub
-    let thr = Thread::spawn_remote(
+    // Execute the stub and wait for it to finish
+    let ret = match execute(
         &process,
-        None,
-        stub_fn,
-        None,
-        ThreadCreationFlags::IMMEDIATE,
-        None,
-    )?;
+        stub_buffer.address(),
+        0,
+        execution,
+        timeout,
+        "LoadLibraryW stub",
+    ) {
+        Ok(ret) => ret,
+        Err(e) => {
+            // The stub writes the handle to the buffer once LoadLibraryW returns
+            if execution::may_still_run(&e) {
+                buffer.set_free_on_drop(false);
+                stub_buffer.set_free_on_drop(false);
+            }
 
-    // Wait for the thread to finish execution
-    thr.wait(99999999)?;
+            return Err(e);
+        }
+    };
 
     // Read handle from the buffer that was written by the stub
-    let handle = {
-        if is_wow64 {
-            let mut buf = [0; size_of::<u32>()];
-            buffer.read_memory(&mut buf, 0)?;
-
-            u32::from_ne_bytes(buf) as usize
-        } else {
-            let mut buf = [0; size_of::<u64>()];
-            buffer.read_memory(&mut buf, 0)?;
+    let handle = if is_wow64 {
+        buffer.read_value::<u32>(0)? as usize
+    } else {
+        buffer.read_value::<u64>(0)? as usize
+    };
 
-            u64::from_ne_bytes(buf) as usize
+    // The stub returns the last error of the target if LoadLibraryW failed
+    // DllMain returning FALSE for DLL_PROCESS_ATTACH makes the loader unload the library again
+    if ret == ERROR_DLL_INIT_FAILED {
+        return Err(Error::EntryPointFailed {
+            reason: DLL_PROCESS_ATTACH,
         }
-    };
+        .into());
+    }
 
-    ensure!(thr.exit_code()? == 0);
-    ensure!(handle != 0, "LoadLibraryA returned a NULL handle");
+    ensure!(
+        ret == 0,
+        WinApiError::FunctionCallFailure {
+            function: "LoadLibraryW".to_string(),
+            code: ret,
+            message: format_message(ret),
+        }
+    );
+    ensure!(handle != 0, "LoadLibraryW returned a NULL handle");
 
     Ok(handle)
 }
 
-pub fn inject(pid: u32, _pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
-    // Determine file path for library
-    let mut file_name: String = rand::thread_rng()
+pub fn inject(
+    pid: u32,
+    pe: PeFile,
+    image: &[u8],
+    options: &InjectOptions,
+) -> anyhow::Result<InjectionReport> {
+    options.cancellation.check()?;
+
+    let started = Instant::now();
+    let file_path = write_temp_library(image)?;
+    let write_duration = started.elapsed();
+
+    let started = Instant::now();
+    let base = inject_library(
+        pid,
+        &file_path,
+        options.execution,
+        options.timeout,
+        options.verify_writes,
+    )?;
+    let execute_duration = started.elapsed();
+
+    let mut report = InjectionReport::new(InjectionMethod::LoadLibrary, base, image_size(pe));
+    report.record_phase(InjectionPhase::Write, write_duration);
+    report.record_phase(InjectionPhase::Execute, execute_duration);
+
+    Ok(report)
+}
+
+pub fn image_size(pe: PeFile) -> usize {
+    match pe.optional_header() {
+        Wrap::T32(header) => header.SizeOfImage as usize,
+        Wrap::T64(header) => header.SizeOfImage as usize,
+    }
+}
+
+// Writes the image to a randomly named file in the temp directory so that the loader can use it
+pub fn write_temp_library(image: &[u8]) -> anyhow::Result<PathBuf> {
+    write_temp_file(image, "dll")
+}
+
+// Writes data to a randomly named file with the given extension in the temp directory
+pub fn write_temp_file(data: &[u8], extension: &str) -> anyhow::Result<PathBuf> {
+    // Determine file path
+    let file_name: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(16)
         .collect();
-    file_name.push_str(".dll");
 
     let mut file_path = env::temp_dir();
     file_path.push(&file_name);
+    file_path.set_extension(extension);
 
     ensure!(file_path.as_os_str().len() < MAX_PATH);
 
-    let file_path = file_path.as_path();
-
-    // Write the file to disk so that LoadLibraryA can use it
+    // Write the file to disk so that the loader can use it
     {
-        // Enclosed in braces so the lock on this file is freed for LoadLibrary to acquire
-        let mut file = File::create(file_path)?;
-        file.write_all(image)?;
+        // Enclosed in braces so the lock on this file is freed for the loader to acquire
+        let mut file = File::create(&file_path)?;
+        file.write_all(data)?;
         file.sync_data()?;
     }
 
-    inject_library(pid, file_path)
+    Ok(file_path)
 }
 
-// Create the assembly for the stub that is responsible for calling LoadLibrary
-fn create_stub_64(loadlibrary: usize, buffer_address: usize) -> anyhow::Result<ExecutableBuffer> {
+// Create the assembly for the stub that is responsible for calling LoadLibraryW
+fn create_stub_64(
+    loadlibrary: usize,
+    getlasterror: usize,
+    buffer_address: usize,
+) -> anyhow::Result<ExecutableBuffer> {
     let mut assembler = dynasmrt::x64::Assembler::new()?;
     dynasm!(assembler
         ; .arch x64
-        ; mov r8, QWORD loadlibrary as _                        // Move LoadLibraryA into r8
+        ; mov r8, QWORD loadlibrary as _                        // Move LoadLibraryW into r8
         ; mov rcx, QWORD (buffer_address + size_of::<u64>()) as _     // Move library name to rcx
         ; sub rsp, 40                                         // Allocate 32 bytes of shadow space
         // Had to add 8 bytes to it because a movaps ins was crashing because of stack misalignment
-        ; call r8                                               // Call LoadLibraryA
-        ; add rsp, 40                                         // Reclaim shadow space
+        ; call r8                                               // Call LoadLibraryW
         ; mov rcx, QWORD buffer_address as _                  // Move buffer address (handle dest)
         ; mov [rcx], rax                                        // Put returned handle in handle dest
+        ; test rax, rax                                         // Check if LoadLibraryW failed
+        ; jz ->failed
         ; xor rax, rax                                          // set rax to = 0 as ret val
+        ; jmp ->done
+        ; ->failed:
+        ; mov rax, QWORD getlasterror as _                      // Return GetLastError() instead
+        ; call rax
+        ; ->done:
+        ; add rsp, 40                                         // Reclaim shadow space
         ; ret                                                   // Return to caller
     );
 
@@ -154,7 +240,11 @@ fn create_stub_64(loadlibrary: usize, buffer_address: usize) -> anyhow::Result<E
     Ok(assembler.finalize().unwrap())
 }
 
-fn create_stub_32(loadlibrary: usize, buffer_address: usize) -> anyhow::Result<ExecutableBuffer> {
+fn create_stub_32(
+    loadlibrary: usize,
+    getlasterror: usize,
+    buffer_address: usize,
+) -> anyhow::Result<ExecutableBuffer> {
     let mut assembler = dynasmrt::x86::Assembler::new()?;
     dynasm!(assembler
         ; .arch x86
@@ -166,7 +256,15 @@ fn create_stub_32(loadlibrary: usize, buffer_address: usize) -> anyhow::Result<E
         ; call eax
         ; lea ecx, [buffer_address as _]
         ; mov [ecx], eax
+        // Return GetLastError() if LoadLibraryW failed, 0 otherwise
+        ; test eax, eax
+        ; jz ->failed
         ; xor eax, eax
+        ; jmp ->done
+        ; ->failed:
+        ; mov eax, DWORD getlasterror as _
+        ; call eax
+        ; ->done:
         ; mov esp, ebp
         ; pop ebp
         ; ret
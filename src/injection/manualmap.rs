@@ -1,28 +1,172 @@
+use super::actctx::ActivationContext;
+use super::dependencypolicy::DependencyPolicy;
+use super::execution::{self, execute, execute_with_thread, ExecutionMethod, ExecutionTimeout};
+use super::imagebuffer::ImageBuffer;
+use super::injectionmethod::InjectionMethod;
+use super::injectoptions::InjectOptions;
+use super::mappingbackend::MappingBackend;
+use super::observer::MapStage;
+use super::registry;
+use super::remotecall::RemoteCall;
+use super::report::{InjectionPhase, InjectionReport, SectionReport};
+use super::rollback::Rollback;
+use super::searchpath::SearchPath;
+use super::validation;
 use crate::winapiwrapper::module::Module;
-use crate::winapiwrapper::process::{Process, ProcessAccess};
-use crate::winapiwrapper::thread::{self, Thread, ThreadCreationFlags};
+use crate::winapiwrapper::process::{Process, ProcessAccess, ProcessKey};
+use crate::winapiwrapper::section::Section;
 use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use crate::Error;
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, ExecutableBuffer};
-use pelite::{image::IMAGE_DIRECTORY_ENTRY_EXCEPTION, pe64::imports::Import, PeFile, Wrap};
-use std::{ffi::c_void, mem, path::Path, slice};
-use winapi::ctypes::c_void as winapic_void;
-use winapi::shared::minwindef::{BOOL, DWORD, HINSTANCE, LPVOID, TRUE};
+use field_offset::offset_of;
+use pelite::{
+    image::{
+        IMAGE_DATA_DIRECTORY, IMAGE_DIRECTORY_ENTRY_BASERELOC, IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT,
+        IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT, IMAGE_DIRECTORY_ENTRY_EXCEPTION,
+        IMAGE_DIRECTORY_ENTRY_IMPORT, IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG, IMAGE_DIRECTORY_ENTRY_TLS,
+        IMAGE_FILE_RELOCS_STRIPPED, IMAGE_IMPORT_DESCRIPTOR, IMAGE_NT_HEADERS32,
+        IMAGE_NT_HEADERS64, IMAGE_ORDINAL_FLAG32, IMAGE_ORDINAL_FLAG64,
+    },
+    pe64::exports::Export,
+    PeFile, Pod, Wrap,
+};
+use std::time::Instant;
+use std::{fs, mem, path::Path, ptr, slice};
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, HINSTANCE, LPVOID, TRUE};
 use winapi::um::winnt::{
-    DLL_PROCESS_ATTACH, IMAGE_REL_BASED_ABSOLUTE, IMAGE_REL_BASED_DIR64, IMAGE_REL_BASED_HIGH,
-    IMAGE_REL_BASED_HIGHADJ, IMAGE_REL_BASED_HIGHLOW, IMAGE_REL_BASED_LOW, IMAGE_SCN_MEM_EXECUTE,
+    DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH, IMAGE_REL_BASED_ABSOLUTE, IMAGE_REL_BASED_DIR64,
+    IMAGE_REL_BASED_HIGH, IMAGE_REL_BASED_HIGHLOW, IMAGE_REL_BASED_LOW, IMAGE_SCN_MEM_EXECUTE,
     IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE, PRUNTIME_FUNCTION,
 };
 
+// Attribute of IMAGE_DELAYLOAD_DESCRIPTOR indicating that its fields are RVAs
+const DLAD_RVA_BASED: u32 = 1;
+
+// Security cookies the compiler initializes __security_cookie with
+const DEFAULT_SECURITY_COOKIE32: u32 = 0xbb40e64e;
+const DEFAULT_SECURITY_COOKIE64: u64 = 0x00002b992ddfa232;
+
+// Offset of SecurityCookie in IMAGE_LOAD_CONFIG_DIRECTORY32 and IMAGE_LOAD_CONFIG_DIRECTORY64
+const OFFSET_LOAD_CONFIG_SECURITY_COOKIE32: usize = 0x3c;
+const OFFSET_LOAD_CONFIG_SECURITY_COOKIE64: usize = 0x58;
+
 type FnDllMain = unsafe extern "system" fn(HINSTANCE, DWORD, LPVOID) -> BOOL;
 type FnRtlAddFunctionTable = unsafe extern "system" fn(PRUNTIME_FUNCTION, u32, u64) -> u8;
 
-#[repr(C)]
-struct LDR_DATA_TABLE_ENTRY_BASE {
-    pad: [u8; 0x30],
-    dll_base: usize,
+// LdrpHandleTlsData only reads DllBase from the LDR_DATA_TABLE_ENTRY, the rest is left zeroed
+const LDR_DATA_TABLE_ENTRY_SIZE: usize = 0x120;
+const OFFSET_LDR_DATA_TABLE_ENTRY_DLLBASE32: usize = 0x18;
+const OFFSET_LDR_DATA_TABLE_ENTRY_DLLBASE64: usize = 0x30;
+
+pub fn inject(
+    pid: u32,
+    pe: PeFile,
+    image: &[u8],
+    options: &InjectOptions,
+) -> anyhow::Result<InjectionReport> {
+    map(pid, pe, image, options, None)
+}
+
+// An image whose imports are being resolved, it isn't written to the target yet
+// Libraries in a cycle, A imports B imports A, are handled like the Windows loader does: each is
+// allocated before its dependencies are mapped, and a dependency that imports an image of the
+// chain resolves against its exports at the address it was allocated at. The image is written
+// once its own imports are resolved, so entry points in a cycle must not call into the images
+// that import them, as with the Windows loader.
+struct PendingImage<'a> {
+    // Lowercase, None if the image has no name to be imported by
+    name: Option<String>,
+    base: usize,
+    pe: PeFile<'a>,
+    // The image that imports this one, None for the library being injected
+    importer: Option<&'a PendingImage<'a>>,
+}
+
+impl PendingImage<'_> {
+    // The image of the chain with the lowercase file name
+    fn find(&self, name: &str) -> Option<&Self> {
+        let mut image = Some(self);
+        while let Some(pending) = image {
+            if pending.name.as_deref() == Some(name) {
+                return Some(pending);
+            }
+
+            image = pending.importer;
+        }
+
+        None
+    }
+}
+
+// A library whose exports imports are resolved against
+enum Dependency<'a> {
+    Module(Module),
+    Pending(&'a PendingImage<'a>),
+}
+
+impl Dependency<'_> {
+    fn base(&self) -> anyhow::Result<usize> {
+        match self {
+            Dependency::Module(module) => Ok(module.info()?.lpBaseOfDll as usize),
+            Dependency::Pending(pending) => Ok(pending.base),
+        }
+    }
+
+    fn proc_address(&self, pid: u32, proc_name: &str) -> anyhow::Result<usize> {
+        match self {
+            Dependency::Module(module) => module.proc_address(proc_name),
+            Dependency::Pending(pending) => {
+                let export = pending.pe.exports()?.by()?.name(proc_name)?;
+                pending_export_address(pid, pending, export)
+            }
+        }
+    }
+
+    fn proc_address_by_ordinal(&self, pid: u32, ordinal: u16) -> anyhow::Result<usize> {
+        match self {
+            Dependency::Module(module) => module.proc_address_by_ordinal(ordinal),
+            Dependency::Pending(pending) => {
+                let export = pending.pe.exports()?.by()?.ordinal(ordinal)?;
+                pending_export_address(pid, pending, export)
+            }
+        }
+    }
+}
+
+// Forwarders of a pending image are resolved like those of a loaded module
+fn pending_export_address(
+    pid: u32,
+    pending: &PendingImage,
+    export: Export,
+) -> anyhow::Result<usize> {
+    let name = match export {
+        Export::Symbol(&rva) => return Ok(pending.base + rva as usize),
+        Export::Forward(name) => name.to_str()?,
+    };
+
+    let (dll, fwd_proc) = name
+        .rsplit_once('.')
+        .ok_or_else(|| anyhow!("Forwarded export {} is malformed", name))?;
+
+    let module = Module::find_or_load_external(pid, Path::new(&dll.to_ascii_lowercase()))?;
+
+    match fwd_proc.strip_prefix('#') {
+        Some(ordinal) => module.proc_address_by_ordinal(ordinal.parse()?),
+        None => module.proc_address(fwd_proc),
+    }
 }
 
-pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
+fn map(
+    pid: u32,
+    pe: PeFile,
+    image: &[u8],
+    options: &InjectOptions,
+    importer: Option<&PendingImage>,
+) -> anyhow::Result<InjectionReport> {
+    let started = Instant::now();
+
+    validation::validate(pe, image, options.verify_checksum)?;
+
     let (is_wow64, pe_size, pref_image_base, size_of_headers, entry_point_offset) =
         match pe.optional_header() {
             Wrap::T32(header32) => (
@@ -41,6 +185,21 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
             ),
         };
 
+    // The image is fixed up locally and written to the target once it is complete
+    let mut image_buffer = ImageBuffer::new(pe, image, pe_size, size_of_headers)?;
+
+    let mut injection_report = InjectionReport::new(InjectionMethod::ManualMap, 0, pe_size);
+    injection_report.record_phase(InjectionPhase::Parse, started.elapsed());
+
+    report(
+        options,
+        &mut injection_report,
+        started,
+        MapStage::ParsedHeaders {
+            image_size: pe_size,
+        },
+    )?;
+
     // Obtain target process handle
     let process = Process::from_pid(
         pid,
@@ -52,61 +211,108 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
             | ProcessAccess::SYNCHRONIZE,
         false,
     )?;
+    let process_key = process.key()?;
 
-    // Allocate a buffer inside target process for the image
-    // Tries to allocate at the preferred base first. Allocates elsewhere if that fails.
-    let mut image_mem = match VirtualMem::alloc(
-        &process,
-        pref_image_base,
-        pe_size,
-        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
-        ProtectFlag::PAGE_EXECUTE_READWRITE,
-    ) {
-        Ok(mem) => Ok(mem),
-        Err(_) => VirtualMem::alloc(
-            &process,
-            0,
+    let phase_started = Instant::now();
+
+    // The section backend maps views of a section into the target instead of allocating
+    let section = match options.mapping {
+        MappingBackend::VirtualAlloc => None,
+        MappingBackend::Section => Some(Section::create(
             pe_size,
-            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+            ProtectFlag::PAGE_EXECUTE_READWRITE,
+        )?),
+    };
+
+    let alloc_at = |address: usize, top_down: bool| match &section {
+        Some(section) => section.map_view(
+            &process,
+            address,
+            top_down,
             ProtectFlag::PAGE_EXECUTE_READWRITE,
         ),
-    }?;
+        None => {
+            let alloc_type = if top_down {
+                AllocType::MEM_COMMIT | AllocType::MEM_RESERVE | AllocType::MEM_TOP_DOWN
+            } else {
+                AllocType::MEM_COMMIT | AllocType::MEM_RESERVE
+            };
 
-    image_mem.set_free_on_drop(false);
+            VirtualMem::alloc(
+                &process,
+                address,
+                pe_size,
+                alloc_type,
+                ProtectFlag::PAGE_EXECUTE_READWRITE,
+            )
+        }
+    };
+
+    // Allocate a buffer inside target process for the image
+    // Tries to allocate at the preferred base first. Allocates according to the placement option
+    // if that fails.
+    let mut image_mem = match alloc_at(pref_image_base, false) {
+        Ok(mem) => Ok(mem),
+        // Without relocations the image only works at its preferred base
+        Err(_) if !is_relocatable(pe) => Err(anyhow!(Error::RelocationsStripped {
+            image_base: pref_image_base
+        })),
+        Err(_) => options.placement.alloc_with(&process, pe_size, alloc_at),
+    }?;
 
     let image_base = image_mem.address();
+    check_addressable(is_wow64, &image_mem)?;
+
+    // Undoes what is registered in the target if a later step fails, the memory of the image is
+    // freed by image_mem. Declared after image_mem so it is dropped first.
+    let mut rollback = Rollback::new(&process, options.execution);
+
+    injection_report.record_phase(InjectionPhase::Allocate, phase_started.elapsed());
+
     let image_delta = image_base.wrapping_sub(pref_image_base);
 
-    println!(
+    trace!(
         "Allocated image buffer at {:x} with size {:x}",
         image_base,
         image_mem.size(),
     );
 
-    // Write image headers
-    image_mem.write_memory(&image[..size_of_headers], 0)?;
+    injection_report.image_base = image_base;
+
+    // Registered under this name once it is mapped
+    let module_name = match &options.module_name {
+        Some(name) => Some(name.clone()),
+        None => pe
+            .exports()
+            .and_then(|exports| exports.dll_name())
+            .ok()
+            .and_then(|name| name.to_str().ok())
+            .map(|name| name.to_string()),
+    };
 
-    // Write image sections
-    for section in pe.section_headers() {
-        let start = section.PointerToRawData as usize;
-        let end = start.wrapping_add(section.SizeOfRawData as usize);
+    let pending = PendingImage {
+        name: module_name.as_ref().map(|name| name.to_ascii_lowercase()),
+        base: image_base,
+        pe,
+        importer,
+    };
 
-        image_mem.write_memory(&image[start..end], section.VirtualAddress as usize)?;
+    report(
+        options,
+        &mut injection_report,
+        started,
+        MapStage::AllocatedImage { base: image_base },
+    )?;
 
-        println!(
-            "Section {} -> {:x} with size {:x}",
-            section.name().unwrap(),
-            image_base.wrapping_add(section.VirtualAddress as usize),
-            section.VirtualSize,
-        );
-    }
+    let phase_started = Instant::now();
 
     // Do base relocation
+    let mut relocation_count = 0;
     if image_delta != 0 {
-        println!("Performing base relocation");
+        trace!("Performing base relocation");
 
         for block in pe.base_relocs()?.iter_blocks() {
-            println!("Beginning block {:x}", block.image().VirtualAddress);
+            trace!("Beginning block {:x}", block.image().VirtualAddress);
 
             for word in block.words() {
                 let typ = block.type_of(word) as u16;
@@ -114,89 +320,128 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
 
                 match typ {
                     IMAGE_REL_BASED_ABSOLUTE => {}
-                    IMAGE_REL_BASED_HIGH | IMAGE_REL_BASED_HIGHADJ => {
-                        let mut buf = [0_u8; 2];
-                        image_mem.read_memory(&mut buf, rva)?;
-
-                        let p = u16::from_ne_bytes(buf).wrapping_add((image_delta >> 48) as u16);
-                        image_mem.write_memory(&p.to_ne_bytes(), rva)?;
+                    IMAGE_REL_BASED_HIGH => {
+                        let p = image_buffer
+                            .read_value::<u16>(rva)?
+                            .wrapping_add((image_delta >> 16) as u16);
+                        image_buffer.write_value(&p, rva)?;
                     }
                     IMAGE_REL_BASED_LOW => {
-                        let mut buf = [0_u8; 2];
-                        image_mem.read_memory(&mut buf, rva)?;
-
-                        let p = u16::from_ne_bytes(buf).wrapping_add((image_delta & 0xffff) as u16);
-                        image_mem.write_memory(&p.to_ne_bytes(), rva)?;
+                        let p = image_buffer
+                            .read_value::<u16>(rva)?
+                            .wrapping_add((image_delta & 0xffff) as u16);
+                        image_buffer.write_value(&p, rva)?;
                     }
                     IMAGE_REL_BASED_HIGHLOW => {
-                        let mut buf = [0_u8; 4];
-                        image_mem.read_memory(&mut buf, rva)?;
-
-                        let p = u32::from_ne_bytes(buf).wrapping_add(image_delta as u32);
-                        image_mem.write_memory(&p.to_ne_bytes(), rva)?;
+                        let p = image_buffer
+                            .read_value::<u32>(rva)?
+                            .wrapping_add(image_delta as u32);
+                        image_buffer.write_value(&p, rva)?;
                     }
                     IMAGE_REL_BASED_DIR64 => {
-                        let mut buf = [0_u8; 8];
-                        image_mem.read_memory(&mut buf, rva)?;
-
-                        let p = u64::from_ne_bytes(buf).wrapping_add(image_delta as u64);
-                        image_mem.write_memory(&p.to_ne_bytes(), rva)?;
+                        let p = image_buffer
+                            .read_value::<u64>(rva)?
+                            .wrapping_add(image_delta as u64);
+                        image_buffer.write_value(&p, rva)?;
                     }
-                    _ => unimplemented!("Base relocation type: {:x}", typ),
+                    _ => bail!("Unsupported base relocation type: {:x}", typ),
                 };
+
+                if typ != IMAGE_REL_BASED_ABSOLUTE {
+                    relocation_count += 1;
+                }
             }
         }
     } else {
-        println!("Base relocation not necessary");
+        trace!("Base relocation not necessary");
     }
 
+    injection_report.record_phase(InjectionPhase::Relocate, phase_started.elapsed());
+
+    injection_report.relocations_applied = relocation_count;
+
+    report(
+        options,
+        &mut injection_report,
+        started,
+        MapStage::AppliedRelocations {
+            count: relocation_count,
+        },
+    )?;
+
+    // Images built with /GS expect the loader to replace their default security cookie
+    if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG) {
+        initialize_security_cookie(pe, &mut image_buffer, pref_image_base)?;
+    }
+
+    let phase_started = Instant::now();
+
     // Resolve imports
+    let mut import_count = 0;
     for descriptor in pe.imports()? {
+        // Loading a dependency can take a while
+        options.cancellation.check()?;
+
         let module_path = descriptor.dll_name()?.to_str()?.to_ascii_lowercase();
         let module_path = Path::new(&module_path);
-        let module = Module::find_or_load_external(pid, &module_path)?;
-
-        let mut thunk = descriptor.image().FirstThunk as usize;
-        for import in descriptor.int()? {
-            let import_address = match import? {
-                Import::ByName { hint: _, name } => {
-                    let proc_name = name.to_str()?;
-                    let proc_addr = module.proc_address(proc_name)?;
-
-                    if is_wow64 {
-                        ensure!(
-                            proc_addr <= u32::max_value() as usize,
-                            anyhow!(
-                                "Received 64-bit proc address for wow64 process: {:?}:{} at {:x}",
-                                module_path,
-                                proc_name,
-                                proc_addr
-                            )
-                        );
-                    }
+        let module = find_dependency(
+            pid,
+            module_path,
+            is_wow64,
+            options,
+            &pending,
+            &mut injection_report.dependencies,
+        )?;
 
-                    println!(
-                        "Import {:?}:{} at {:x} written to {:x} (abs: {:x})",
-                        module_path,
-                        name,
-                        proc_addr,
-                        thunk,
-                        image_base + thunk as usize,
-                    );
+        // Old linkers only emit the IAT, which then holds the names until it is resolved
+        // A bound IAT without a name table holds addresses instead, which can't be resolved again
+        let desc = descriptor.image();
+        let name_table = if desc.OriginalFirstThunk != 0 {
+            desc.OriginalFirstThunk
+        } else {
+            ensure!(
+                desc.TimeDateStamp == 0,
+                "Imports from {:?} are bound but have no import name table",
+                module_path
+            );
 
-                    Ok(proc_addr)
-                }
-                Import::ByOrdinal { ord: _ } => {
-                    Err(anyhow!("Import by ordinal is not implemented"))
-                }
-            }?;
+            trace!(
+                "Imports from {:?} have no import name table, reading names from the IAT",
+                module_path
+            );
+
+            desc.FirstThunk
+        };
+
+        let mut thunk = desc.FirstThunk as usize;
+        for name_thunk in read_thunks(pe, is_wow64, name_table)? {
+            let (proc_name, import_address) =
+                resolve_thunk(pe, pid, is_wow64, &module, name_thunk)?;
 
             if is_wow64 {
-                image_mem.write_memory(&(import_address as u32).to_ne_bytes(), thunk)?;
-            } else {
-                image_mem.write_memory(&(import_address as u64).to_ne_bytes(), thunk)?;
+                ensure!(
+                    import_address <= u32::MAX as usize,
+                    anyhow!(
+                        "Received 64-bit proc address for wow64 process: {:?}:{} at {:x}",
+                        module_path,
+                        proc_name,
+                        import_address
+                    )
+                );
             }
 
+            trace!(
+                "Import {:?}:{} at {:x} written to {:x} (abs: {:x})",
+                module_path,
+                proc_name,
+                import_address,
+                thunk,
+                image_base + thunk,
+            );
+
+            write_pointer(&mut image_buffer, is_wow64, import_address, thunk)?;
+            import_count += 1;
+
             thunk += if is_wow64 {
                 mem::size_of::<u32>()
             } else {
@@ -205,147 +450,233 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
         }
     }
 
-    // Initialize static TLS
-    {
-        let stub_data = VirtualMem::alloc(
-            &process,
-            0,
-            mem::size_of::<LDR_DATA_TABLE_ENTRY_BASE>(),
-            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
-            ProtectFlag::PAGE_READWRITE,
+    // Every IAT slot was rewritten, so bindings to the addresses of other libraries are stale
+    if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_IMPORT) {
+        invalidate_bindings(pe, is_wow64, &mut image_buffer)?;
+    }
+
+    // Resolve delay-load imports
+    if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT) {
+        import_count += resolve_delay_imports(
+            pe,
+            pid,
+            is_wow64,
+            &mut image_buffer,
+            options,
+            &pending,
+            &mut injection_report.dependencies,
         )?;
+    }
 
-        let ldr_data = LDR_DATA_TABLE_ENTRY_BASE {
-            pad: [0; 0x30],
-            dll_base: image_base,
-        };
+    injection_report.imports_resolved = import_count;
+    injection_report.record_phase(InjectionPhase::ResolveImports, phase_started.elapsed());
 
-        let ldr_data_bytes = unsafe {
-            slice::from_raw_parts(
-                &ldr_data as *const LDR_DATA_TABLE_ENTRY_BASE as *const u8,
-                mem::size_of::<LDR_DATA_TABLE_ENTRY_BASE>(),
-            )
-        };
+    report(
+        options,
+        &mut injection_report,
+        started,
+        MapStage::ResolvedImports {
+            count: import_count,
+        },
+    )?;
 
-        stub_data.write_memory(ldr_data_bytes, 0)?;
+    let phase_started = Instant::now();
 
-        let stub = if is_wow64 {
-            create_stub_ldrphandletlsdata32(stub_data.address(), &process)
-        } else {
-            create_stub_ldrphandletlsdata64(stub_data.address(), &process)
-        }?;
+    // Write the fixed-up headers and sections at once
+    match &section {
+        // The view in the target shares its memory with a view in our own process
+        Some(section) => {
+            let current = Process::from_current();
+            let local_view = section.map_view(&current, 0, false, ProtectFlag::PAGE_READWRITE)?;
+            let data = image_buffer.as_bytes();
 
-        let stub_mem = VirtualMem::alloc(
-            &process,
-            0,
-            stub.size(),
-            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
-            ProtectFlag::PAGE_EXECUTE_READWRITE,
-        )?;
+            unsafe {
+                ptr::copy_nonoverlapping(data.as_ptr(), local_view.address() as *mut u8, data.len())
+            };
+
+            // Read back through the view in the target
+            if options.verify_writes {
+                super::verify_memory(&image_mem, data, 0)?;
+            }
+        }
+        None => {
+            super::write_memory(
+                &image_mem,
+                image_buffer.as_bytes(),
+                0,
+                options.verify_writes,
+            )?;
+        }
+    }
+
+    for section in pe.section_headers() {
+        trace!(
+            "Section {} -> {:x} with size {:x}",
+            section.name().unwrap(),
+            image_base.wrapping_add(section.VirtualAddress as usize),
+            section.VirtualSize,
+        );
+    }
 
-        stub_mem.write_memory(&stub, 0)?;
+    injection_report.record_phase(InjectionPhase::Write, phase_started.elapsed());
 
-        let stub_fn = unsafe { mem::transmute::<usize, thread::StartRoutine>(stub_mem.address()) };
+    report(
+        options,
+        &mut injection_report,
+        started,
+        MapStage::WroteSections {
+            count: pe.section_headers().as_slice().len(),
+        },
+    )?;
 
-        let thr = Thread::spawn_remote(
+    // Initialize static TLS so the image receives a TLS index and TLS data block
+    if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_TLS) {
+        let result = initialize_static_tls(
             &process,
-            None,
-            stub_fn,
-            None,
-            ThreadCreationFlags::IMMEDIATE,
-            None,
-        )?;
+            is_wow64,
+            image_base,
+            options.execution,
+            options.timeout,
+        );
 
-        thr.wait(10000)?;
+        // The loader's TLS entry can't be removed and points at the TLS template of the image,
+        // which every new thread copies. From here on the image and what was registered for it
+        // are kept, even if mapping fails.
+        let registered = match &result {
+            Ok(()) => true,
+            Err(e) => execution::may_still_run(e),
+        };
+        if registered {
+            image_mem.set_free_on_drop(false);
+            rollback.commit();
+        }
 
-        ensure!(thr.exit_code()? == 0, "LdrpHandleTlsData thread failed");
-    }
+        result?;
 
-    // Set proper memory protection for image sections
-    for sh in pe.section_headers() {
-        let ch = sh.Characteristics;
-        let read = ch & IMAGE_SCN_MEM_READ != 0;
-        let write = ch & IMAGE_SCN_MEM_WRITE != 0;
-        let exec = ch & IMAGE_SCN_MEM_EXECUTE != 0;
-
-        let protect = if read && write && exec {
-            ProtectFlag::PAGE_EXECUTE_READWRITE
-        } else if read && exec {
-            ProtectFlag::PAGE_EXECUTE_READ
-        } else if read && write {
-            ProtectFlag::PAGE_READWRITE
-        } else if read {
-            ProtectFlag::PAGE_READONLY
-        } else if exec {
-            ProtectFlag::PAGE_EXECUTE
-        } else {
-            ProtectFlag::PAGE_NOACCESS
+        let address_of_index = match pe.tls()? {
+            Wrap::T32(tls) => tls.image().AddressOfIndex as usize,
+            Wrap::T64(tls) => tls.image().AddressOfIndex as usize,
         };
 
-        let old_protect = image_mem.virtual_protect(
-            sh.VirtualAddress as usize,
-            sh.VirtualSize as usize,
-            protect,
-        )?;
+        if address_of_index != 0 {
+            let index =
+                image_mem.read_value::<u32>(address_of_index.wrapping_sub(pref_image_base))?;
 
-        println!(
-            "Set memory protection for {} to {:?} (was {:?})",
-            sh.name().unwrap(),
-            protect,
-            ProtectFlag::from_bits_truncate(old_protect)
-        );
+            trace!("Static TLS initialized with index {}", index);
+        }
     }
 
-    // We estimate the size of the loader function + LoaderInfo struct
-    // We could place a function after the loader to calculate the
-    // actual size, but compiling in release mode doesn't guarantee
-    // that the loader_end function is placed directly after the loader function
-    let loader_size = 0x200;
+    // Set proper memory protection for image sections, the image is left RWX otherwise
+    if options.protect_sections {
+        let phase_started = Instant::now();
+        protect_sections(pe, &mut image_mem, size_of_headers)?;
+        injection_report.record_phase(InjectionPhase::Protect, phase_started.elapsed());
+    }
 
-    let loader_mem = VirtualMem::alloc(
-        &process,
-        0,
-        loader_size,
-        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
-        ProtectFlag::PAGE_EXECUTE_READWRITE,
-    )?;
+    injection_report.sections = pe
+        .section_headers()
+        .iter()
+        .map(|sh| SectionReport {
+            name: String::from_utf8_lossy(sh.name_bytes()).into_owned(),
+            address: image_base + sh.VirtualAddress as usize,
+            size: sh.VirtualSize as usize,
+            protection: if options.protect_sections {
+                section_protection(sh.Characteristics)
+            } else {
+                ProtectFlag::PAGE_EXECUTE_READWRITE
+            },
+        })
+        .collect();
 
-    println!(
-        "Allocated loader buffer at {:x} with size {:x}",
-        loader_mem.address() as usize,
-        loader_mem.size(),
-    );
+    // CFG would terminate the target on indirect calls into the image, e.g. callbacks it
+    // registers or DllMain being called by the loader stub
+    if process.is_cfg_enabled()? {
+        register_call_targets(pe, &process, &image_mem, &mut rollback)?;
+    } else {
+        trace!("Target doesn't enforce CFG");
+    }
+
+    // TLS callbacks must be invoked by the loader before DllMain
+    let tls_callbacks = if options.call_entry_point {
+        get_tls_callbacks(pe, pref_image_base, image_base)?
+    } else {
+        Vec::new()
+    };
+
+    for callback in &tls_callbacks {
+        trace!("TLS callback at {:x}", callback);
+    }
+
+    // Images with a manifest expect its activation context to be active while they initialize
+    let activation_context = if options.call_entry_point {
+        ActivationContext::create(&process, pe, is_wow64, options.execution)?
+    } else {
+        None
+    };
+
+    if let Some(activation_context) = &activation_context {
+        rollback.activation_context(activation_context.handle, activation_context.release);
+    }
 
     // Construct LoaderInfo and retrieve loader function
     let (loader_info, loader) = if is_wow64 {
+        ensure!(
+            options.entry_point_reserved <= u32::MAX as usize,
+            Error::InvalidArgument("lpvReserved does not fit into a 32-bit pointer".to_string())
+        );
+
         let loader_info = LoaderInfo32 {
             image_base: image_base as u32,
-            entry_point: unsafe {
-                mem::transmute::<usize, FnDllMain>(image_base + entry_point_offset)
-            },
+            entry_point: (image_base + entry_point_offset) as u32,
+            activation_cookie: 0,
+            entry_point_reason: 0,
+            entry_point_result: TRUE as u32,
         };
 
-        (Wrap::T32(loader_info), get_loader32()?)
+        (
+            Wrap::T32(loader_info),
+            get_loader32(&tls_callbacks, activation_context.as_ref(), options)?,
+        )
     } else {
-        let (exception_fn_table, exception_fn_count) = {
-            let exception = match pe.exception()? {
-                Wrap::T32(_except32) => panic!(), // This should never happen
-                Wrap::T64(except64) => except64,
+        // Locate the image's function table (.pdata) so the loader can register it for unwinding
+        let (exception_fn_table, exception_fn_count) =
+            if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_EXCEPTION) {
+                let exception = match pe.exception()? {
+                    Wrap::T32(_except32) => panic!(), // This should never happen
+                    Wrap::T64(except64) => except64,
+                };
+
+                ensure!(
+                    exception.check_sorted(),
+                    "Exception routines are not sorted"
+                );
+
+                let exception_data_directory = pe.data_directory()[IMAGE_DIRECTORY_ENTRY_EXCEPTION];
+                let exception_fn_table = (exception_data_directory.VirtualAddress as usize
+                    + image_base) as PRUNTIME_FUNCTION;
+                let exception_fn_count = exception.functions().count();
+
+                (exception_fn_table, exception_fn_count)
+            } else {
+                (ptr::null_mut(), 0)
             };
 
-            ensure!(
-                exception.check_sorted(),
-                "Exception routines are not sorted"
-            );
+        trace!(
+            "Registering {} exception routines at {:x}",
+            exception_fn_count,
+            exception_fn_table as usize
+        );
 
-            let exception_data_directory =
-                pe.data_directory()[IMAGE_DIRECTORY_ENTRY_EXCEPTION as usize];
-            let exception_fn_table = (exception_data_directory.VirtualAddress as usize + image_base)
-                as PRUNTIME_FUNCTION;
-            let exception_fn_count = exception.functions().count();
+        // Resolve RtlAddFunctionTable in the target rather than in our own process
+        let ntdll = Module::find_or_load_external(pid, Path::new("ntdll.dll"))?;
 
-            (exception_fn_table, exception_fn_count)
-        };
+        // The loader registers the table, so it has to be removed if the entry point fails
+        if exception_fn_count != 0 {
+            rollback.function_table(
+                exception_fn_table as usize,
+                ntdll.proc_address("RtlDeleteFunctionTable")?,
+            );
+        }
 
         let loader_info = LoaderInfo64 {
             image_base,
@@ -356,13 +687,18 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
             exception_fn_count,
             rtl_add_function_table: unsafe {
                 mem::transmute::<usize, FnRtlAddFunctionTable>(
-                    Module::find_or_load_internal("kernel32.dll")?
-                        .proc_address("RtlAddFunctionTable")?,
+                    ntdll.proc_address("RtlAddFunctionTable")?,
                 )
             },
+            activation_cookie: 0,
+            entry_point_reason: 0,
+            entry_point_result: TRUE as u32,
         };
 
-        (Wrap::T64(loader_info), get_loader64()?)
+        (
+            Wrap::T64(loader_info),
+            get_loader64(&tls_callbacks, activation_context.as_ref(), options)?,
+        )
     };
 
     // Write LoaderInfo to loader buffer
@@ -381,66 +717,258 @@ pub fn inject(pid: u32, pe: PeFile, image: &[u8]) -> anyhow::Result<usize> {
         },
     };
 
-    loader_mem.write_memory(loaderinfo_bytes, 0)?;
+    let mut loader_mem = VirtualMem::alloc(
+        &process,
+        0,
+        loaderinfo_bytes.len() + loader.size(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_EXECUTE_READWRITE,
+    )?;
+
+    trace!(
+        "Allocated loader buffer at {:x} with size {:x}",
+        loader_mem.address(),
+        loader_mem.size(),
+    );
+
+    check_addressable(is_wow64, &loader_mem)?;
+
+    super::write_memory(&loader_mem, loaderinfo_bytes, 0, options.verify_writes)?;
 
     // Write loader to loader buffer
-    loader_mem.write_memory(&loader, loaderinfo_bytes.len())?;
+    super::write_memory(
+        &loader_mem,
+        &loader,
+        loaderinfo_bytes.len(),
+        options.verify_writes,
+    )?;
 
-    // Transmute the loader buffer into a function pointer
-    let loader_mem_as_fn = unsafe {
-        mem::transmute::<*const winapic_void, thread::StartRoutine>(
-            (loader_mem.address() as usize + loaderinfo_bytes.len()) as *const winapic_void,
-        )
-    };
+    let loader_routine = loader_mem.address() + loaderinfo_bytes.len();
+
+    trace!("Loader routine at {:x}", loader_routine);
 
-    println!("Loader routine at {:x}", loader_mem_as_fn as usize);
+    // Last chance to cancel, the loader can't be stopped once it runs
+    options.cancellation.check()?;
 
-    // Spawn a thread to execute the loader buffer in the target process
-    let thread = Thread::spawn_remote(
+    // Execute the loader buffer in the target process
+    let phase_started = Instant::now();
+    let ret = match execute_with_thread(
         &process,
-        None,
-        loader_mem_as_fn,
-        Some(loader_mem.address() as *mut c_void),
-        ThreadCreationFlags::IMMEDIATE,
-        None,
-    )?;
+        loader_routine,
+        loader_mem.address(),
+        options.execution,
+        options.timeout,
+        "loader",
+    ) {
+        Ok(executed) => {
+            injection_report.loader_thread = Some(executed.tid);
+            executed.result
+        }
+        Err(e) => {
+            // A loader that is still running needs both the image and itself
+            if execution::may_still_run(&e) {
+                image_mem.set_free_on_drop(false);
+                loader_mem.set_free_on_drop(false);
+                rollback.commit();
+            }
+
+            return Err(e);
+        }
+    };
+
+    injection_report.record_phase(InjectionPhase::Execute, phase_started.elapsed());
+
+    // The loader only fails before calling the entry point
+    ensure!(
+        ret == TRUE as u32,
+        "Loader failed to register the exception handlers or activate the activation context"
+    );
+
+    // Like the Windows loader, a library whose DllMain returns FALSE isn't kept, any other value
+    // counts as success
+    let (reason_offset, result_offset) = entry_point_offsets(is_wow64);
+
+    let entry_point_result = loader_mem.read_value::<u32>(result_offset)?;
+    if entry_point_result == FALSE as u32 {
+        let reason = loader_mem.read_value::<u32>(reason_offset)?;
+
+        // The Windows loader lets the library clean up what it set up before failing, such as
+        // threads or callbacks, before unloading it
+        let detach = RemoteCall::new(image_base + entry_point_offset)
+            .arg(image_base)
+            .arg(DLL_PROCESS_DETACH)
+            .arg(0_usize)
+            .execution(options.execution)
+            .timeout(options.timeout.millis)
+            .terminate_on_timeout(options.timeout.terminate)
+            .call(&process);
+
+        if let Err(e) = detach {
+            trace!(
+                "Failed to call the entry point with DLL_PROCESS_DETACH: {}",
+                e
+            );
+
+            if execution::may_still_run(&e) {
+                image_mem.set_free_on_drop(false);
+                rollback.commit();
+            }
+        }
 
-    thread.wait(9999999)?;
+        return Err(Error::EntryPointFailed { reason }.into());
+    }
 
-    ensure!(thread.exit_code()? == TRUE as u32);
+    if options.call_entry_point {
+        trace!("Entry point returned {:x}", entry_point_result);
+        injection_report.entry_point = Some(image_base + entry_point_offset);
+        injection_report.entry_point_result = Some(entry_point_result);
+    }
 
-    Ok(image_base)
+    injection_report
+        .timings
+        .push((MapStage::ExecutedEntryPoint, started.elapsed()));
+
+    if let Some(observer) = &options.observer {
+        let _ = observer.on_stage(MapStage::ExecutedEntryPoint);
+    }
+
+    // The image is now owned by the target process, only free it if mapping failed
+    image_mem.set_free_on_drop(false);
+    rollback.commit();
+
+    // Remember the image so later lookups by name can find it
+    match module_name {
+        Some(name) => registry::register(process_key, &name, image_base, pe_size),
+        None => trace!("Image has no name, it won't be found by module lookups"),
+    }
+
+    Ok(injection_report)
+}
+
+// Offsets of entry_point_reason and entry_point_result in LoaderInfo32 or LoaderInfo64, which the
+// loader writes and the injector reads back
+fn entry_point_offsets(is_wow64: bool) -> (usize, usize) {
+    if is_wow64 {
+        (
+            offset_of!(LoaderInfo32 => entry_point_reason).get_byte_offset(),
+            offset_of!(LoaderInfo32 => entry_point_result).get_byte_offset(),
+        )
+    } else {
+        (
+            offset_of!(LoaderInfo64 => entry_point_reason).get_byte_offset(),
+            offset_of!(LoaderInfo64 => entry_point_result).get_byte_offset(),
+        )
+    }
 }
 
 // Loader for WoW64 (32-bit)
+// Read by 32-bit code, so pointers have to be stored as u32 rather than as our own pointers
 #[repr(C)]
 struct LoaderInfo32 {
     image_base: u32,
-    entry_point: FnDllMain,
+    entry_point: u32,
+    // Written by ActivateActCtx
+    activation_cookie: u32,
+    // Written by the loader, the fdwReason of the last entry point call and what it returned
+    entry_point_reason: u32,
+    entry_point_result: u32,
 }
 
-fn get_loader32() -> anyhow::Result<ExecutableBuffer> {
+fn get_loader32(
+    tls_callbacks: &[usize],
+    activation_context: Option<&ActivationContext>,
+    options: &InjectOptions,
+) -> anyhow::Result<ExecutableBuffer> {
+    let reserved = options.entry_point_reserved as u32;
+    let (reason_offset, result_offset) = entry_point_offsets(true);
+
     let mut assembler = dynasmrt::x86::Assembler::new()?;
     dynasm!(assembler
         ; .arch x86
         ; push ebp
         ; mov ebp, esp
+    );
 
-        // Put LoaderInfo32 into ecx
-        ; mov ecx, [ebp + 8]
+    if let Some(activation_context) = activation_context {
+        dynasm!(assembler
+            ; .arch x86
+            // ActivateActCtx(handle, &LoaderInfo32.activation_cookie)
+            ; mov ecx, [ebp + 8]
+            ; lea eax, [ecx + 8]
+            ; push eax
+            ; push DWORD activation_context.handle as _
+            ; mov eax, DWORD activation_context.activate as _
+            ; call eax
+
+            // Return 0 if the activation context couldn't be activated
+            ; test eax, eax
+            ; jz ->done
+        );
+    }
 
-        // Push DllMain args
-        ; push 0
-        ; push DLL_PROCESS_ATTACH as _
-        ; push DWORD [ecx]
+    for reason in entry_point_reasons(options) {
+        // Call each TLS callback with the same args as DllMain
+        for &callback in tls_callbacks {
+            dynasm!(assembler
+                ; .arch x86
+                ; mov ecx, [ebp + 8]
+                ; push DWORD reserved as _
+                ; push DWORD reason as _
+                ; push DWORD [ecx]
+                ; mov eax, DWORD callback as _
+                ; call eax
+            );
+        }
 
-        // Call DllMain
-        ; mov eax, [ecx + 8] // Why is image_base 8 bytes large as a u32?
-        ; call eax
+        dynasm!(assembler
+            ; .arch x86
+            // Put LoaderInfo32 into ecx
+            ; mov ecx, [ebp + 8]
+            ; mov DWORD [ecx + reason_offset as _], reason as _
+
+            // Push DllMain args
+            ; push DWORD reserved as _
+            ; push DWORD reason as _
+            ; push DWORD [ecx]
+
+            // Call DllMain
+            ; mov eax, [ecx + 4]
+            ; call eax
+
+            // Store the result, the remaining calls are skipped if it failed
+            ; mov ecx, [ebp + 8]
+            ; mov [ecx + result_offset as _], eax
+            ; test eax, eax
+            ; jz ->finish
+        );
+    }
+
+    dynasm!(assembler
+        ; .arch x86
+        ; ->finish:
+    );
+
+    if let Some(activation_context) = activation_context {
+        dynasm!(assembler
+            ; .arch x86
+            // DeactivateActCtx(0, cookie)
+            ; mov ecx, [ebp + 8]
+            ; push DWORD [ecx + 8]
+            ; push DWORD 0
+            ; mov eax, DWORD activation_context.deactivate as _
+            ; call eax
+        );
+    }
 
+    // The loader returns TRUE once it ran, DllMain's result is read from LoaderInfo32
+    // Thread start routines are stdcall, so the parameter is popped on return
+    dynasm!(assembler
+        ; .arch x86
+        ; mov eax, TRUE
+        ; ->done:
         ; mov esp, ebp
         ; pop ebp
-        ; ret
+        ; ret 4
     );
 
     assembler.commit()?;
@@ -457,17 +985,39 @@ struct LoaderInfo64 {
     exception_fn_table: PRUNTIME_FUNCTION,
     exception_fn_count: usize,
     rtl_add_function_table: FnRtlAddFunctionTable,
+    // Written by ActivateActCtx
+    activation_cookie: usize,
+    // Written by the loader, the fdwReason of the last entry point call and what it returned
+    entry_point_reason: u32,
+    entry_point_result: u32,
 }
 
-fn get_loader64() -> anyhow::Result<ExecutableBuffer> {
+fn get_loader64(
+    tls_callbacks: &[usize],
+    activation_context: Option<&ActivationContext>,
+    options: &InjectOptions,
+) -> anyhow::Result<ExecutableBuffer> {
+    let reserved = options.entry_point_reserved;
+    let (reason_offset, result_offset) = entry_point_offsets(false);
+
     let mut assembler = dynasmrt::x64::Assembler::new()?;
     dynasm!(assembler
         ; .arch x64
         ; push rbp
         ; mov rbp, rsp
 
+        // rsi is non-volatile so preserve it for the caller
+        ; push rsi
+
+        // Allocate 32 bytes of shadow space, plus 8 bytes to keep the stack 16-byte aligned
+        ; sub rsp, 40
+
         // Put LoaderInfo64 struct into rsi
-        ; lea rsi, [rcx]
+        ; mov rsi, rcx
+
+        // Skip registering the function table if the image has none
+        ; cmp QWORD [rsi + 24], 0
+        ; je ->initialize
 
         // Prep args for RtlAddFunctionTable
         ; mov rcx, [rsi + 16]
@@ -476,28 +1026,85 @@ fn get_loader64() -> anyhow::Result<ExecutableBuffer> {
 
         // Call RtlAddFunctionTable
         ; mov rax, [rsi + 32]
-        ; sub rsp, 32
         ; call rax
-        ; add rsp, 32
 
-        // Return 0 if RtlAddFunctionTable fails
-        ; test rax, rax
-        ; jnz ->dllmain
-        ; xor rax, rax
-        ; mov rsp, rbp
-        ; pop rbp
-        ; ret
+        // Return 0 if RtlAddFunctionTable fails (it returns a BOOLEAN, so only al is valid)
+        ; test al, al
+        ; jnz ->initialize
+        ; xor eax, eax
+        ; jmp ->done
 
-        // Prep DllMain args and call it
-        ; ->dllmain:
-        ; mov rcx, [rsi]
-        ; mov rdx, 1
-        ; mov r8, 0
-        ; mov rax, [rsi + 8]
-        ; sub rsp, 32
-        ; call rax
-        ; add rsp, 32
+        ; ->initialize:
+    );
+
+    if let Some(activation_context) = activation_context {
+        dynasm!(assembler
+            ; .arch x64
+            // ActivateActCtx(handle, &LoaderInfo64.activation_cookie)
+            ; mov rcx, QWORD activation_context.handle as _
+            ; lea rdx, [rsi + 40]
+            ; mov rax, QWORD activation_context.activate as _
+            ; call rax
+
+            // Return 0 if the activation context couldn't be activated
+            ; test eax, eax
+            ; jz ->done
+        );
+    }
+
+    for reason in entry_point_reasons(options) {
+        // Call each TLS callback with the same args as DllMain
+        for &callback in tls_callbacks {
+            dynasm!(assembler
+                ; .arch x64
+                ; mov rcx, [rsi]
+                ; mov edx, DWORD reason as _
+                ; mov r8, QWORD reserved as _
+                ; mov rax, QWORD callback as _
+                ; call rax
+            );
+        }
+
+        dynasm!(assembler
+            ; .arch x64
+            // Prep DllMain args and call it
+            ; mov DWORD [rsi + reason_offset as _], reason as _
+            ; mov rcx, [rsi]
+            ; mov edx, DWORD reason as _
+            ; mov r8, QWORD reserved as _
+            ; mov rax, [rsi + 8]
+            ; call rax
+
+            // Store the result, the remaining calls are skipped if it failed
+            ; mov [rsi + result_offset as _], eax
+            ; test eax, eax
+            ; jz ->finish
+        );
+    }
+
+    dynasm!(assembler
+        ; .arch x64
+        ; ->finish:
+    );
+
+    if let Some(activation_context) = activation_context {
+        dynasm!(assembler
+            ; .arch x64
+            // DeactivateActCtx(0, cookie)
+            ; xor ecx, ecx
+            ; mov rdx, [rsi + 40]
+            ; mov rax, QWORD activation_context.deactivate as _
+            ; call rax
+        );
+    }
 
+    // The loader returns TRUE once it ran, DllMain's result is read from LoaderInfo64
+    dynasm!(assembler
+        ; .arch x64
+        ; mov eax, TRUE
+        ; ->done:
+        ; add rsp, 40
+        ; pop rsi
         ; mov rsp, rbp
         ; pop rbp
         ; ret
@@ -508,6 +1115,647 @@ fn get_loader64() -> anyhow::Result<ExecutableBuffer> {
     Ok(assembler.finalize().unwrap())
 }
 
+// Marks every 16-byte aligned address in the executable sections as a valid call target
+// Images don't describe all of their indirect call targets (only CFG-aware images have a
+// GuardCFFunctionTable, and it lacks targets in hand-written code), so none are left out
+fn register_call_targets(
+    pe: PeFile,
+    process: &Process,
+    image_mem: &VirtualMem,
+    rollback: &mut Rollback,
+) -> anyhow::Result<()> {
+    let offsets: Vec<usize> = pe
+        .section_headers()
+        .iter()
+        .filter(|sh| sh.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+        .flat_map(|sh| {
+            let start = sh.VirtualAddress as usize;
+            (start..start + sh.VirtualSize as usize).step_by(16)
+        })
+        .collect();
+
+    process.set_valid_call_targets(image_mem.address(), image_mem.size(), &offsets, true)?;
+    rollback.call_targets(image_mem.address(), image_mem.size(), offsets);
+
+    Ok(())
+}
+
+// The fdwReason values the loader calls the TLS callbacks and DllMain with, in order
+// The CRT entry point only initializes the CRT (security cookie, onexit tables, static
+// constructors) on DLL_PROCESS_ATTACH, so a custom reason is preceded by it if requested
+fn entry_point_reasons(options: &InjectOptions) -> Vec<u32> {
+    if !options.call_entry_point {
+        Vec::new()
+    } else if options.initialize_crt && options.entry_point_reason != DLL_PROCESS_ATTACH {
+        vec![DLL_PROCESS_ATTACH, options.entry_point_reason]
+    } else {
+        vec![options.entry_point_reason]
+    }
+}
+
+// Applies memory protection derived from each section's characteristics
+// The headers are made read-only, like the Windows loader does
+fn protect_sections(
+    pe: PeFile,
+    image_mem: &mut VirtualMem,
+    size_of_headers: usize,
+) -> anyhow::Result<()> {
+    image_mem.virtual_protect(0, size_of_headers, ProtectFlag::PAGE_READONLY)?;
+
+    for sh in pe.section_headers() {
+        let protect = section_protection(sh.Characteristics);
+        let old_protect = image_mem.virtual_protect(
+            sh.VirtualAddress as usize,
+            sh.VirtualSize as usize,
+            protect,
+        )?;
+
+        trace!(
+            "Set memory protection for {} to {:?} (was {:?})",
+            sh.name().unwrap(),
+            protect,
+            ProtectFlag::from_bits_truncate(old_protect)
+        );
+    }
+
+    Ok(())
+}
+
+// The memory protection matching the characteristics of a section
+fn section_protection(characteristics: u32) -> ProtectFlag {
+    let read = characteristics & IMAGE_SCN_MEM_READ != 0;
+    let write = characteristics & IMAGE_SCN_MEM_WRITE != 0;
+    let exec = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+
+    if read && write && exec {
+        ProtectFlag::PAGE_EXECUTE_READWRITE
+    } else if read && exec {
+        ProtectFlag::PAGE_EXECUTE_READ
+    } else if read && write {
+        ProtectFlag::PAGE_READWRITE
+    } else if read {
+        ProtectFlag::PAGE_READONLY
+    } else if exec {
+        ProtectFlag::PAGE_EXECUTE
+    } else {
+        ProtectFlag::PAGE_NOACCESS
+    }
+}
+
+// Records the time the stage was reached and notifies the observer
+// Fails with Error::Aborted if the observer asks to stop or the injection was cancelled
+fn report(
+    options: &InjectOptions,
+    injection_report: &mut InjectionReport,
+    started: Instant,
+    stage: MapStage,
+) -> anyhow::Result<()> {
+    trace!("Reached {:?}", stage);
+
+    injection_report.timings.push((stage, started.elapsed()));
+
+    if let Some(observer) = &options.observer {
+        if observer.on_stage(stage).is_break() {
+            bail!(Error::Aborted);
+        }
+    }
+
+    options.cancellation.check()
+}
+
+// pending is the image whose imports are resolved, manually mapped dependencies are added to
+// mapped
+fn find_dependency<'a>(
+    pid: u32,
+    path: &Path,
+    is_wow64: bool,
+    options: &InjectOptions,
+    pending: &'a PendingImage<'a>,
+    mapped: &mut Vec<InjectionReport>,
+) -> anyhow::Result<Dependency<'a>> {
+    let (module, resolved) = Module::find_external_resolved(pid, path)?;
+
+    // API set contracts are resolved to their host, policies apply to the host
+    let name = resolved
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Dependency {} has no file name", resolved.display()))?
+        .to_ascii_lowercase();
+
+    // An image that imports itself, directly or through other dependencies, isn't mapped again
+    if let Some(importer) = pending.find(&name) {
+        trace!("Dependency {} is part of a cycle, it is being mapped", name);
+        return Ok(Dependency::Pending(importer));
+    }
+
+    if let Some(module) = module {
+        return Ok(Dependency::Module(module));
+    }
+
+    if let Some(image) = options.virtual_fs.get(&name) {
+        // Only files can be verified
+        #[cfg(feature = "authenticode")]
+        ensure!(
+            !options.require_signature,
+            Error::UntrustedImage(format!(
+                "Dependency {} is in memory, its signature can't be verified",
+                name
+            ))
+        );
+
+        trace!("Manually mapping dependency {} from memory", name);
+
+        let report = map_dependency(pid, &name, image, is_wow64, options, pending)?;
+        mapped.push(report);
+
+        return find_mapped(pid, &name);
+    }
+
+    // Only needed for missing dependencies, which are rare
+    let process = Process::from_pid(
+        pid,
+        ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+        false,
+    )?;
+    let search_path = SearchPath::from_process(&process)?;
+
+    match dependency_policy(options, &name, &search_path) {
+        DependencyPolicy::Load => Module::find_or_load_external(pid, path).map(Dependency::Module),
+        DependencyPolicy::RequireLoaded => {
+            bail!("Dependency {} is not loaded in the target", path.display())
+        }
+        DependencyPolicy::ManualMap => {
+            let path = search_path.find(Path::new(&name))?;
+
+            trace!(
+                "Manually mapping dependency {} from {}",
+                name,
+                path.display()
+            );
+
+            // A verified dependency is read from the same handle its signature was verified
+            // through
+            #[cfg(feature = "authenticode")]
+            let (image, signer) = if options.require_signature {
+                let (image, signer) = crate::winapiwrapper::authenticode::read_verified(&path)?;
+                (image, Some(signer))
+            } else {
+                (fs::read(&path)?, None)
+            };
+            #[cfg(not(feature = "authenticode"))]
+            let (image, signer) = (fs::read(&path)?, None);
+
+            let mut report = map_dependency(pid, &name, &image, is_wow64, options, pending)?;
+            if let Some(signer) = signer {
+                registry::set_signer(ProcessKey::from_pid(pid)?, report.image_base, &signer);
+                report.signer = Some(signer);
+            }
+            mapped.push(report);
+
+            find_mapped(pid, &name)
+        }
+    }
+}
+
+// Imports are resolved against the exports of the mapped copy
+fn find_mapped(pid: u32, name: &str) -> anyhow::Result<Dependency<'static>> {
+    registry::find_by_name(ProcessKey::from_pid(pid)?, name)
+        .map(|mapped| Dependency::Module(Module::from_mapped(mapped)))
+        .ok_or_else(|| anyhow!("Mapped dependency {} was not registered", name))
+}
+
+// The override for the file name, then the policy for system libraries, then the default
+fn dependency_policy(
+    options: &InjectOptions,
+    name: &str,
+    search_path: &SearchPath,
+) -> DependencyPolicy {
+    let policy = options
+        .dependency_overrides
+        .iter()
+        .find(|(library, _)| library.eq_ignore_ascii_case(name))
+        .map(|(_, &policy)| policy);
+
+    if let Some(policy) = policy {
+        return policy;
+    }
+
+    match options.system_dependencies {
+        Some(policy) if search_path.is_system_library(Path::new(name)) => policy,
+        _ => options.dependencies,
+    }
+}
+
+// Manually maps a dependency under its file name, so later imports from it resolve against the
+// same copy
+// It gets the options of the image that imports it, except that its entry point is called with
+// DLL_PROCESS_ATTACH and it isn't observed. It stays mapped if the image fails to map.
+fn map_dependency(
+    pid: u32,
+    name: &str,
+    image: &[u8],
+    is_wow64: bool,
+    options: &InjectOptions,
+    importer: &PendingImage,
+) -> anyhow::Result<InjectionReport> {
+    let pe = PeFile::from_bytes(image)?;
+
+    ensure!(
+        matches!(pe.optional_header(), Wrap::T32(_)) == is_wow64,
+        Error::InvalidImage(format!(
+            "Dependency {} doesn't match the architecture of the target",
+            name
+        ))
+    );
+
+    let dependency_options = InjectOptions {
+        method: InjectionMethod::ManualMap,
+        execution: options.execution,
+        timeout: options.timeout,
+        protect_sections: options.protect_sections,
+        module_name: Some(name.to_string()),
+        placement: options.placement.clone(),
+        mapping: options.mapping,
+        dependencies: options.dependencies,
+        dependency_overrides: options.dependency_overrides.clone(),
+        system_dependencies: options.system_dependencies,
+        virtual_fs: options.virtual_fs.clone(),
+        verify_writes: options.verify_writes,
+        verify_checksum: options.verify_checksum,
+        cancellation: options.cancellation.clone(),
+        #[cfg(feature = "authenticode")]
+        require_signature: options.require_signature,
+        ..Default::default()
+    };
+
+    map(pid, pe, image, &dependency_options, Some(importer))
+}
+
+// 32-bit code can't address memory above 4 GiB
+fn check_addressable(is_wow64: bool, mem: &VirtualMem) -> anyhow::Result<()> {
+    if is_wow64 {
+        ensure!(
+            mem.address() + mem.size() <= u32::MAX as usize,
+            "Memory for WOW64 process was allocated at {:x}, outside of the 32-bit address space",
+            mem.address()
+        );
+    }
+
+    Ok(())
+}
+
+// Writes a pointer sized for the target process into the image
+fn write_pointer(
+    image_buffer: &mut ImageBuffer,
+    is_wow64: bool,
+    value: usize,
+    offset: usize,
+) -> anyhow::Result<()> {
+    if is_wow64 {
+        image_buffer.write_value(&(value as u32), offset)
+    } else {
+        image_buffer.write_value(&(value as u64), offset)
+    }
+}
+
+// Eagerly resolves every delay-load import and writes it to the delay-load IAT,
+// so the delay-load helper never needs to run inside the target
+// Returns the number of resolved imports
+// https://docs.microsoft.com/en-us/windows/win32/debug/pe-format#delay-load-import-tables-image-only
+fn resolve_delay_imports(
+    pe: PeFile,
+    pid: u32,
+    is_wow64: bool,
+    image_buffer: &mut ImageBuffer,
+    options: &InjectOptions,
+    pending: &PendingImage,
+    mapped: &mut Vec<InjectionReport>,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut descriptor_rva = pe.data_directory()[IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT].VirtualAddress;
+
+    loop {
+        // IMAGE_DELAYLOAD_DESCRIPTOR is 8 DWORDs
+        let descriptor = pe.derva_slice::<u32>(descriptor_rva, 8)?;
+        let (attributes, dll_name_rva, module_handle_rva, iat_rva, int_rva) = (
+            descriptor[0],
+            descriptor[1],
+            descriptor[2],
+            descriptor[3],
+            descriptor[4],
+        );
+
+        // The table is terminated by a zeroed descriptor
+        if dll_name_rva == 0 {
+            break;
+        }
+
+        ensure!(
+            attributes & DLAD_RVA_BASED != 0,
+            "Delay-load descriptors that use virtual addresses are not supported"
+        );
+
+        let module_path = pe.derva_c_str(dll_name_rva)?.to_str()?.to_ascii_lowercase();
+        let module_path = Path::new(&module_path);
+        let module = find_dependency(pid, module_path, is_wow64, options, pending, mapped)?;
+
+        // Mark the module as loaded for the delay-load helper
+        if module_handle_rva != 0 {
+            write_pointer(
+                image_buffer,
+                is_wow64,
+                module.base()?,
+                module_handle_rva as usize,
+            )?;
+        }
+
+        let thunk_size = if is_wow64 {
+            mem::size_of::<u32>()
+        } else {
+            mem::size_of::<u64>()
+        };
+
+        for (i, thunk) in read_thunks(pe, is_wow64, int_rva)?.into_iter().enumerate() {
+            let (proc_name, proc_addr) = resolve_thunk(pe, pid, is_wow64, &module, thunk)?;
+
+            let iat_entry = iat_rva as usize + i * thunk_size;
+
+            trace!(
+                "Delay import {:?}:{} at {:x} written to {:x}",
+                module_path,
+                proc_name,
+                proc_addr,
+                iat_entry,
+            );
+
+            write_pointer(image_buffer, is_wow64, proc_addr, iat_entry)?;
+            count += 1;
+        }
+
+        descriptor_rva += 8 * mem::size_of::<u32>() as u32;
+    }
+
+    Ok(count)
+}
+
+// Replaces the default security cookie with a random one, like LdrpInitSecurityCookie does
+// The CRT only generates a cookie itself if the loader didn't, but older CRTs and images that
+// don't run the CRT entry point rely on the loader
+fn initialize_security_cookie(
+    pe: PeFile,
+    image_buffer: &mut ImageBuffer,
+    pref_image_base: usize,
+) -> anyhow::Result<()> {
+    // Older load config directories end before SecurityCookie
+    let (cookie_va, is_64bit) = match pe.load_config()? {
+        Wrap::T32(load_config)
+            if load_config.image().Size as usize >= OFFSET_LOAD_CONFIG_SECURITY_COOKIE32 + 4 =>
+        {
+            (load_config.image().SecurityCookie as usize, false)
+        }
+        Wrap::T64(load_config)
+            if load_config.image().Size as usize >= OFFSET_LOAD_CONFIG_SECURITY_COOKIE64 + 8 =>
+        {
+            (load_config.image().SecurityCookie as usize, true)
+        }
+        _ => (0, false),
+    };
+
+    if cookie_va == 0 {
+        trace!("Image has no security cookie");
+        return Ok(());
+    }
+
+    let cookie_rva = cookie_va.wrapping_sub(pref_image_base);
+
+    if is_64bit {
+        if image_buffer.read_value::<u64>(cookie_rva)? != DEFAULT_SECURITY_COOKIE64 {
+            return Ok(());
+        }
+
+        // The upper 16 bits are kept clear, like the Windows loader does
+        let cookie = loop {
+            let cookie = rand::random::<u64>() & 0x0000_ffff_ffff_ffff;
+            if cookie != 0 && cookie != DEFAULT_SECURITY_COOKIE64 {
+                break cookie;
+            }
+        };
+
+        image_buffer.write_value(&cookie, cookie_rva)?;
+        trace!("Security cookie at {:x} set to {:x}", cookie_rva, cookie);
+    } else {
+        if image_buffer.read_value::<u32>(cookie_rva)? != DEFAULT_SECURITY_COOKIE32 {
+            return Ok(());
+        }
+
+        let cookie = loop {
+            let cookie = rand::random::<u32>();
+            if cookie != 0 && cookie != DEFAULT_SECURITY_COOKIE32 {
+                break cookie;
+            }
+        };
+
+        image_buffer.write_value(&cookie, cookie_rva)?;
+        trace!("Security cookie at {:x} set to {:x}", cookie_rva, cookie);
+    }
+
+    Ok(())
+}
+
+// Reads a null-terminated table of import thunks
+fn read_thunks(pe: PeFile, is_wow64: bool, rva: u32) -> anyhow::Result<Vec<u64>> {
+    Ok(if is_wow64 {
+        pe.derva_slice_s::<u32>(rva, 0)?
+            .iter()
+            .map(|&thunk| thunk as u64)
+            .collect()
+    } else {
+        pe.derva_slice_s::<u64>(rva, 0)?.to_vec()
+    })
+}
+
+// Resolves an import name table thunk, returns (name, address)
+fn resolve_thunk(
+    pe: PeFile,
+    pid: u32,
+    is_wow64: bool,
+    module: &Dependency,
+    thunk: u64,
+) -> anyhow::Result<(String, usize)> {
+    let ordinal_flag = if is_wow64 {
+        IMAGE_ORDINAL_FLAG32 as u64
+    } else {
+        IMAGE_ORDINAL_FLAG64
+    };
+
+    if thunk & ordinal_flag != 0 {
+        let ordinal = (thunk & 0xffff) as u16;
+
+        Ok((
+            format!("#{}", ordinal),
+            module.proc_address_by_ordinal(pid, ordinal)?,
+        ))
+    } else {
+        // Skip the hint of the IMAGE_IMPORT_BY_NAME
+        let proc_name = pe.derva_c_str(thunk as u32 + 2)?.to_str()?;
+
+        Ok((proc_name.to_string(), module.proc_address(pid, proc_name)?))
+    }
+}
+
+// Clears the bound import directory and the TimeDateStamp of every import descriptor in the
+// mapped headers, like the Windows loader does when a binding turns out to be stale
+fn invalidate_bindings(
+    pe: PeFile,
+    is_wow64: bool,
+    image_buffer: &mut ImageBuffer,
+) -> anyhow::Result<()> {
+    if has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT) {
+        // IMAGE_NT_HEADERS up to DataDirectory
+        let data_directories = pe.dos_header().e_lfanew as usize
+            + if is_wow64 {
+                mem::size_of::<IMAGE_NT_HEADERS32>()
+            } else {
+                mem::size_of::<IMAGE_NT_HEADERS64>()
+            };
+
+        image_buffer.write_value(
+            &IMAGE_DATA_DIRECTORY::zeroed(),
+            data_directories
+                + IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT * mem::size_of::<IMAGE_DATA_DIRECTORY>(),
+        )?;
+
+        trace!("Cleared bound import directory");
+    }
+
+    let import_directory =
+        pe.data_directory()[IMAGE_DIRECTORY_ENTRY_IMPORT].VirtualAddress as usize;
+    for (i, descriptor) in pe.imports()?.image().iter().enumerate() {
+        if descriptor.TimeDateStamp != 0 {
+            let mut descriptor = *descriptor;
+            descriptor.TimeDateStamp = 0;
+
+            image_buffer.write_value(
+                &descriptor,
+                import_directory + i * mem::size_of::<IMAGE_IMPORT_DESCRIPTOR>(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// Images linked with /FIXED have IMAGE_FILE_RELOCS_STRIPPED set, others may just lack a .reloc
+fn is_relocatable(pe: PeFile) -> bool {
+    pe.file_header().Characteristics & IMAGE_FILE_RELOCS_STRIPPED == 0
+        && has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_BASERELOC)
+}
+
+fn has_data_directory(pe: PeFile, index: usize) -> bool {
+    pe.data_directory()
+        .get(index)
+        .is_some_and(|dir| dir.VirtualAddress != 0)
+}
+
+// Registers the image's static TLS with the loader by invoking LdrpHandleTlsData with a
+// fake loader entry. This allocates the TLS index and the TLS data block for every thread.
+fn initialize_static_tls(
+    process: &Process,
+    is_wow64: bool,
+    image_base: usize,
+    execution: ExecutionMethod,
+    timeout: ExecutionTimeout,
+) -> anyhow::Result<()> {
+    let mut ldr_data = vec![0_u8; LDR_DATA_TABLE_ENTRY_SIZE];
+
+    if is_wow64 {
+        ldr_data[OFFSET_LDR_DATA_TABLE_ENTRY_DLLBASE32..][..4]
+            .copy_from_slice(&(image_base as u32).to_ne_bytes());
+    } else {
+        ldr_data[OFFSET_LDR_DATA_TABLE_ENTRY_DLLBASE64..][..8]
+            .copy_from_slice(&(image_base as u64).to_ne_bytes());
+    }
+
+    let mut stub_data = VirtualMem::alloc(
+        process,
+        0,
+        ldr_data.len(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    check_addressable(is_wow64, &stub_data)?;
+
+    stub_data.write_memory(&ldr_data, 0)?;
+
+    let stub = if is_wow64 {
+        create_stub_ldrphandletlsdata32(stub_data.address(), process)
+    } else {
+        create_stub_ldrphandletlsdata64(stub_data.address(), process)
+    }?;
+
+    let mut stub_mem = VirtualMem::alloc(
+        process,
+        0,
+        stub.size(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_EXECUTE_READWRITE,
+    )?;
+
+    check_addressable(is_wow64, &stub_mem)?;
+
+    stub_mem.write_memory(&stub, 0)?;
+
+    let status = match execute(
+        process,
+        stub_mem.address(),
+        0,
+        execution,
+        timeout,
+        "static TLS initialization",
+    ) {
+        Ok(status) => status,
+        Err(e) => {
+            if execution::may_still_run(&e) {
+                stub_data.set_free_on_drop(false);
+                stub_mem.set_free_on_drop(false);
+            }
+
+            return Err(e);
+        }
+    };
+
+    ensure!(status == 0, "LdrpHandleTlsData thread failed");
+
+    Ok(())
+}
+
+// Retrieves the addresses of the image's TLS callbacks, relocated to the mapped image base
+fn get_tls_callbacks(
+    pe: PeFile,
+    pref_image_base: usize,
+    image_base: usize,
+) -> anyhow::Result<Vec<usize>> {
+    if !has_data_directory(pe, IMAGE_DIRECTORY_ENTRY_TLS) {
+        return Ok(Vec::new());
+    }
+
+    let callbacks: Vec<usize> = match pe.tls()? {
+        Wrap::T32(tls) if tls.image().AddressOfCallBacks != 0 => {
+            tls.callbacks()?.iter().map(|&va| va as usize).collect()
+        }
+        Wrap::T64(tls) if tls.image().AddressOfCallBacks != 0 => {
+            tls.callbacks()?.iter().map(|&va| va as usize).collect()
+        }
+        _ => Vec::new(),
+    };
+
+    Ok(callbacks
+        .into_iter()
+        .map(|va| va.wrapping_sub(pref_image_base).wrapping_add(image_base))
+        .collect())
+}
+
 // Functions for retrieving LdrpHandleTlsData across architectures
 // Credits to Blackbone for the signatures and offsets
 const SIG_LDRPHANDLETLSDATA32: &str = "33 f6 85 c0 79 3";
@@ -523,26 +1771,17 @@ fn get_ldrphandletlsdata(is_wow64: bool, process: &Process) -> anyhow::Result<us
         Module::find_or_load_internal("ntdll.dll")
     }?;
 
-    let ntdll_info = ntdll.info()?;
-
-    let data = {
-        let mut buf = vec![0; ntdll_info.SizeOfImage as usize];
-        process.read_memory(&mut buf, ntdll_info.lpBaseOfDll as usize)?;
-
-        buf
-    };
-
     let (sig, offset) = if is_wow64 {
         (SIG_LDRPHANDLETLSDATA32, OFFSET_LDRPHANDLETLSDATA32)
     } else {
         (SIG_LDRPHANDLETLSDATA64, OFFSET_LDRPHANDLETLSDATA64)
     };
 
-    Ok(patternscan::scan(&data, sig)?
+    Ok(process
+        .scan_module(&ntdll, sig)?
         .first()
         .ok_or_else(|| anyhow!("Failed to find function ntdll::LdrpHandleTlsData",))?
-        - offset
-        + ntdll_info.lpBaseOfDll as usize)
+        - offset)
 }
 
 // The asm stub that is responsible for invoking LdrpHandleTlsData
@@ -555,13 +1794,15 @@ fn create_stub_ldrphandletlsdata32(
         ; .arch x86
         ; push ebp
         ; mov ebp, esp
+        // LdrpHandleTlsData is stdcall on older versions of Windows and thiscall on newer ones
         ; mov eax, DWORD stub_data_address as _
+        ; mov ecx, eax
         ; push eax
         ; mov eax, DWORD get_ldrphandletlsdata(true, process)? as _
         ; call eax
         ; mov esp, ebp
         ; pop ebp
-        ; ret
+        ; ret 4
     );
 
     assembler.commit()?;
@@ -576,9 +1817,12 @@ fn create_stub_ldrphandletlsdata64(
     let mut assembler = dynasmrt::x64::Assembler::new()?;
     dynasm!(assembler
         ; .arch x64
+        // Allocate 32 bytes of shadow space, plus 8 bytes to keep the stack 16-byte aligned
+        ; sub rsp, 40
         ; mov rax, QWORD get_ldrphandletlsdata(false, process)? as _
         ; mov rcx, QWORD stub_data_address as _
         ; call rax
+        ; add rsp, 40
         ; ret
     );
 
@@ -0,0 +1,25 @@
+use std::str::FromStr;
+
+// How the manual mapper creates the memory the image is mapped into
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MappingBackend {
+    // Private memory allocated with VirtualAllocEx, the image is written with WriteProcessMemory
+    VirtualAlloc,
+    // A view of a section mapped with NtMapViewOfSection. The image is written to a view of the
+    // same section mapped into our own process, so nothing is written across processes, and the
+    // memory shows up as MEM_MAPPED instead of MEM_PRIVATE in the target.
+    Section,
+}
+
+impl FromStr for MappingBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str.to_ascii_lowercase().trim() {
+            "virtualalloc" => Ok(MappingBackend::VirtualAlloc),
+            "section" => Ok(MappingBackend::Section),
+            _ => Err(anyhow!("Unknown mapping backend: {}", str)),
+        }
+    }
+}
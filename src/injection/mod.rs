@@ -1,17 +1,141 @@
+pub mod actctx;
+pub mod cancellation;
+pub mod clr;
+pub mod dependencypolicy;
+pub mod earlybird;
+pub mod eject;
+pub mod execution;
+pub mod exportcache;
+pub mod imagebuffer;
 pub mod injectionmethod;
+pub mod injectoptions;
+pub mod injector;
 pub mod loadlibrary;
 pub mod manualmap;
+pub mod mappingbackend;
+pub mod observer;
+pub mod pesource;
+pub mod placement;
+pub mod registry;
+pub mod remotecall;
+pub mod report;
+pub mod rollback;
+pub mod searchpath;
+pub mod shellcode;
+pub mod validation;
+pub mod virtualfs;
+pub mod windowshook;
 
+use crate::winapiwrapper::process::{Mitigations, Process, ProcessAccess};
+use crate::winapiwrapper::virtualmem::VirtualMem;
 use injectionmethod::InjectionMethod;
+use injectoptions::InjectOptions;
+use report::InjectionReport;
 
 pub fn inject(
     pid: u32,
     pe: pelite::PeFile,
     image: &[u8],
+    options: &InjectOptions,
+) -> anyhow::Result<InjectionReport> {
+    ensure_not_protected(pid)?;
+
+    let mitigations =
+        Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_INFORMATION, false)?.mitigations()?;
+    trace!("Mitigations of the target: {:?}", mitigations);
+    check_mitigations(&mitigations, options.method)?;
+
+    match options.method {
+        InjectionMethod::LoadLibrary => loadlibrary::inject(pid, pe, image, options),
+        InjectionMethod::ManualMap => match manualmap::inject(pid, pe, image, options) {
+            // Aborting is a decision of the caller, not a failure to recover from, and a
+            // library whose entry point failed would fail the same way when loaded
+            Err(e) if options.fallback_to_loadlibrary && !is_final(&e) => {
+                trace!("Manual mapping failed, falling back to LoadLibrary: {}", e);
+                check_mitigations(&mitigations, InjectionMethod::LoadLibrary)?;
+
+                loadlibrary::inject(pid, pe, image, options)
+            }
+            result => result,
+        },
+        InjectionMethod::WindowsHook => windowshook::inject(pid, pe, image, options),
+    }
+}
+
+fn is_final(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<crate::Error>(),
+        Some(crate::Error::Aborted)
+            | Some(crate::Error::EntryPointFailed { .. })
+            | Some(crate::Error::DynamicCodeProhibited)
+    )
+}
+
+// Checked before the target is opened with the rights injection needs, which protected processes
+// deny to unprotected ones with an access denied
+pub(crate) fn ensure_not_protected(pid: u32) -> anyhow::Result<()> {
+    let process = Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)?;
+
+    if let Some(level) = process.protection()? {
+        bail!(crate::Error::ProcessProtected { level });
+    }
+
+    Ok(())
+}
+
+// Fails early on policies of the target that would otherwise make the injection fail part way
+// through, with an access denied that doesn't say why
+pub(crate) fn check_mitigations(
+    mitigations: &Mitigations,
     method: InjectionMethod,
-) -> anyhow::Result<usize> {
-    match method {
-        InjectionMethod::LoadLibrary => loadlibrary::inject(pid, pe, image),
-        InjectionMethod::ManualMap => manualmap::inject(pid, pe, image),
+) -> anyhow::Result<()> {
+    // Every method but hook injection writes code to the target, at least a stub
+    ensure!(
+        !mitigations.dynamic_code_prohibited || method == InjectionMethod::WindowsHook,
+        crate::Error::DynamicCodeProhibited
+    );
+
+    // The loader checks the signature of libraries it loads, manually mapped ones aren't
+    let uses_loader = matches!(
+        method,
+        InjectionMethod::LoadLibrary | InjectionMethod::WindowsHook
+    );
+    ensure!(
+        !uses_loader || !(mitigations.microsoft_signed_only || mitigations.store_signed_only),
+        crate::Error::SignedLibrariesOnly
+    );
+
+    Ok(())
+}
+
+// Writes data to the memory at offset, reading it back when verify is set
+// Fails with the first byte that differs, which points at something else changing the writes
+pub(crate) fn write_memory(
+    mem: &VirtualMem,
+    data: &[u8],
+    offset: usize,
+    verify: bool,
+) -> anyhow::Result<()> {
+    mem.write_memory(data, offset)?;
+
+    if verify {
+        verify_memory(mem, data, offset)?;
     }
+
+    Ok(())
+}
+
+// Compares the memory at offset against data
+pub(crate) fn verify_memory(mem: &VirtualMem, data: &[u8], offset: usize) -> anyhow::Result<()> {
+    if let Some((index, actual)) = mem.compare_memory(data, offset)? {
+        return Err(crate::Error::WriteMismatch {
+            address: mem.address() + offset + index,
+            offset: index,
+            expected: data[index],
+            actual,
+        }
+        .into());
+    }
+
+    Ok(())
 }
@@ -0,0 +1,29 @@
+use std::ops::ControlFlow;
+
+// Stages of a manual map in the order they are reported
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapStage {
+    ParsedHeaders { image_size: usize },
+    AllocatedImage { base: usize },
+    // Relocations and imports are applied to a local copy of the image
+    AppliedRelocations { count: usize },
+    // Includes delay-load imports
+    ResolvedImports { count: usize },
+    // The fixed-up image was written to the target
+    WroteSections { count: usize },
+    ExecutedEntryPoint,
+}
+
+// Receives progress while an image is manually mapped, e.g. to update a progress bar
+pub trait MapObserver {
+    // Returning Break aborts the injection and frees the image
+    // Nothing can be aborted once the entry point ran, so the result is ignored for ExecutedEntryPoint
+    fn on_stage(&self, stage: MapStage) -> ControlFlow<()>;
+}
+
+impl<F: Fn(MapStage) -> ControlFlow<()>> MapObserver for F {
+    fn on_stage(&self, stage: MapStage) -> ControlFlow<()> {
+        self(stage)
+    }
+}
@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Something the image to inject can be read from
+// Images in memory are used as is, manual mapping never writes them to disk
+pub trait PeSource {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>>;
+
+    // File the image is read from, required to verify its signature
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+impl PeSource for [u8] {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl<const N: usize> PeSource for [u8; N] {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl PeSource for Vec<u8> {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl PeSource for Path {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Owned(fs::read(self)?))
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self)
+    }
+}
+
+impl PeSource for PathBuf {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        self.as_path().image()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self)
+    }
+}
+
+// An image compressed with LZ4 in the size-prepended block format, e.g. by
+// lz4_flex::compress_prepend_size, it is decompressed when injecting
+// The path of the source isn't exposed since its signature would be that of the compressed file
+#[cfg(feature = "lz4")]
+pub struct Lz4Compressed<S>(pub S);
+
+#[cfg(feature = "lz4")]
+impl<S: PeSource> PeSource for Lz4Compressed<S> {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        let image = lz4_flex::decompress_size_prepended(&self.0.image()?)?;
+
+        Ok(Cow::Owned(image))
+    }
+}
+
+// An image compressed with zstd, it is decompressed when injecting
+#[cfg(feature = "zstd")]
+pub struct ZstdCompressed<S>(pub S);
+
+#[cfg(feature = "zstd")]
+impl<S: PeSource> PeSource for ZstdCompressed<S> {
+    fn image(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        let image = zstd::stream::decode_all(&self.0.image()?[..])?;
+
+        Ok(Cow::Owned(image))
+    }
+}
@@ -0,0 +1,222 @@
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::virtualmem::{AllocType, MemState, ProtectFlag, VirtualMem};
+use rand::Rng;
+
+// VirtualAllocEx rounds reserved addresses down to this
+const ALLOCATION_GRANULARITY: usize = 0x10000;
+
+// Distance a rel32 can reach in either direction
+const REL32_RANGE: usize = 0x7fff_0000;
+
+// Where the manual mapper allocates the image when it can't have its preferred base
+// Images without relocations always need their preferred base and ignore this
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum AllocationPlacement {
+    // Wherever VirtualAllocEx puts it, usually the lowest free address
+    Default,
+    // The highest free address, with MEM_TOP_DOWN
+    TopDown,
+    // A random free address in [start, end)
+    Random { start: usize, end: usize },
+    // As close as possible to a module of the target, at most 2 GiB away from any of its bytes
+    // so that rel32 jumps and calls can reach between the two, e.g. for hooks
+    NearModule(String),
+}
+
+impl AllocationPlacement {
+    pub fn alloc<'a>(
+        &self,
+        process: &'a Process,
+        size: usize,
+        protect: ProtectFlag,
+    ) -> anyhow::Result<VirtualMem<'a>> {
+        self.alloc_with(process, size, |address, top_down| {
+            let alloc_type = if top_down {
+                AllocType::MEM_COMMIT | AllocType::MEM_RESERVE | AllocType::MEM_TOP_DOWN
+            } else {
+                AllocType::MEM_COMMIT | AllocType::MEM_RESERVE
+            };
+
+            VirtualMem::alloc(process, address, size, alloc_type, protect)
+        })
+    }
+
+    // Picks addresses like alloc does but lets alloc_at create the memory, e.g. to map a view
+    // alloc_at receives the address, 0 for anywhere, and whether to prefer the highest address
+    pub fn alloc_with<'a, F>(
+        &self,
+        process: &'a Process,
+        size: usize,
+        mut alloc_at: F,
+    ) -> anyhow::Result<VirtualMem<'a>>
+    where
+        F: FnMut(usize, bool) -> anyhow::Result<VirtualMem<'a>>,
+    {
+        match self {
+            AllocationPlacement::Default => alloc_at(0, false),
+            AllocationPlacement::TopDown => alloc_at(0, true),
+            &AllocationPlacement::Random { start, end } => {
+                let mut candidates = free_ranges(process, start, end, size)?;
+                let mut rng = rand::thread_rng();
+
+                // Each attempt picks a random free range, retrying if another thread took it
+                while !candidates.is_empty() {
+                    let (first, last) = candidates.swap_remove(rng.gen_range(0, candidates.len()));
+                    let slots = (last - first) / ALLOCATION_GRANULARITY + 1;
+                    let address = first + rng.gen_range(0, slots) * ALLOCATION_GRANULARITY;
+
+                    if let Ok(mem) = alloc_at(address, false) {
+                        return Ok(mem);
+                    }
+                }
+
+                bail!(
+                    "No free memory for {:x} bytes between {:x} and {:x}",
+                    size,
+                    start,
+                    end
+                )
+            }
+            AllocationPlacement::NearModule(name) => {
+                let module = process
+                    .module_by_name(name)?
+                    .ok_or_else(|| anyhow!("Module {} is not loaded in the target", name))?;
+                let info = module.info()?;
+                let module_start = info.lpBaseOfDll as usize;
+                let module_end = module_start + info.SizeOfImage as usize;
+
+                // Every byte of the allocation has to reach every byte of the module
+                let start = (module_end + size).saturating_sub(REL32_RANGE);
+                let end = module_start.saturating_add(REL32_RANGE);
+
+                // Closest candidate first
+                let mut candidates: Vec<usize> = free_ranges(process, start, end, size)?
+                    .into_iter()
+                    .map(|(first, last)| {
+                        align_down(module_start, ALLOCATION_GRANULARITY).clamp(first, last)
+                    })
+                    .collect();
+                candidates.sort_by_key(|&address| {
+                    (address as isize)
+                        .wrapping_sub(module_start as isize)
+                        .unsigned_abs()
+                });
+
+                for address in candidates {
+                    if let Ok(mem) = alloc_at(address, false) {
+                        return Ok(mem);
+                    }
+                }
+
+                bail!(
+                    "No free memory for {:x} bytes within 2 GiB of {}",
+                    size,
+                    name
+                )
+            }
+        }
+    }
+}
+
+// Returns (first, last) allocation-aligned addresses a block of size can be allocated at
+// within free regions between start and end
+fn free_ranges(
+    process: &Process,
+    start: usize,
+    end: usize,
+    size: usize,
+) -> anyhow::Result<Vec<(usize, usize)>> {
+    ensure!(start < end, "Empty address range {:x}-{:x}", start, end);
+
+    // 32-bit processes can't address anything above 4 GiB
+    let end = if process.is_wow64()? {
+        end.min(u32::MAX as usize + 1)
+    } else {
+        end
+    };
+
+    Ok(process
+        .memory_regions()
+        .filter(|region| region.state.contains(MemState::MEM_FREE))
+        .filter_map(|region| free_range(region.base, region.size, start, end, size))
+        .collect())
+}
+
+// The (first, last) addresses of free_ranges within a single free region
+fn free_range(
+    base: usize,
+    region_size: usize,
+    start: usize,
+    end: usize,
+    size: usize,
+) -> Option<(usize, usize)> {
+    let first = align_up(base.max(start), ALLOCATION_GRANULARITY);
+    let limit = (base + region_size).min(end);
+    let last = align_down(limit.checked_sub(size)?, ALLOCATION_GRANULARITY);
+
+    if first <= last {
+        Some((first, last))
+    } else {
+        None
+    }
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+fn align_down(value: usize, alignment: usize) -> usize {
+    value - value % alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_range_is_aligned_to_the_granularity() {
+        // Region 0x15000-0x55000, the block fits at 0x20000 through 0x40000
+        assert_eq!(
+            free_range(0x15000, 0x40000, 0, usize::MAX, 0x10000),
+            Some((0x20000, 0x40000))
+        );
+    }
+
+    #[test]
+    fn free_range_is_clamped_to_start_and_end() {
+        assert_eq!(
+            free_range(0x10000, 0x100000, 0x30000, 0x80000, 0x20000),
+            Some((0x30000, 0x60000))
+        );
+        assert_eq!(
+            free_range(0x10000, 0x100000, 0x31000, 0x7f000, 0x20000),
+            Some((0x40000, 0x50000))
+        );
+    }
+
+    #[test]
+    fn free_range_rejects_regions_that_are_too_small() {
+        // Large enough, but not once aligned
+        assert_eq!(free_range(0x15000, 0x10000, 0, usize::MAX, 0x10000), None);
+        assert_eq!(free_range(0x10000, 0x10000, 0, usize::MAX, 0x20000), None);
+        // Block larger than the address the range ends at
+        assert_eq!(free_range(0, 0x10000, 0, 0x10000, 0x20000), None);
+    }
+
+    #[test]
+    fn free_range_of_an_exact_fit() {
+        assert_eq!(
+            free_range(0x10000, 0x10000, 0, usize::MAX, 0x10000),
+            Some((0x10000, 0x10000))
+        );
+    }
+
+    #[test]
+    fn align_rounds_to_the_granularity() {
+        assert_eq!(align_up(0x10001, 0x10000), 0x20000);
+        assert_eq!(align_up(0x20000, 0x10000), 0x20000);
+        assert_eq!(align_down(0x2ffff, 0x10000), 0x20000);
+        assert_eq!(align_down(0x20000, 0x10000), 0x20000);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::winapiwrapper::process::ProcessKey;
+use std::sync::Mutex;
+
+// A module that was manually mapped by this crate
+// The Windows loader doesn't know about these, so they don't show up in module snapshots
+#[derive(Clone, Debug)]
+pub struct MappedModule {
+    pub pid: u32,
+    // Lowercase file name, e.g. example.dll
+    pub name: String,
+    pub base: usize,
+    pub size: usize,
+    // Display name of the certificate the library was signed with, if its signature was verified
+    pub signer: Option<String>,
+}
+
+struct Entry {
+    process: ProcessKey,
+    module: MappedModule,
+}
+
+// Entries are not removed when the target exits, they are keyed by the creation time of the
+// process as well so that a process reusing its PID doesn't see them
+static MAPPED_MODULES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+pub fn register(process: ProcessKey, name: &str, base: usize, size: usize) {
+    MAPPED_MODULES.lock().unwrap().push(Entry {
+        process,
+        module: MappedModule {
+            pid: process.pid,
+            name: name.to_ascii_lowercase(),
+            base,
+            size,
+            signer: None,
+        },
+    });
+}
+
+pub fn set_signer(process: ProcessKey, base: usize, signer: &str) {
+    if let Some(entry) = MAPPED_MODULES
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|entry| entry.process == process && entry.module.base == base)
+    {
+        entry.module.signer = Some(signer.to_string());
+    }
+}
+
+pub fn unregister(process: ProcessKey, base: usize) -> Option<MappedModule> {
+    let mut modules = MAPPED_MODULES.lock().unwrap();
+    let index = modules
+        .iter()
+        .position(|entry| entry.process == process && entry.module.base == base)?;
+
+    Some(modules.remove(index).module)
+}
+
+pub fn find_by_name(process: ProcessKey, name: &str) -> Option<MappedModule> {
+    let name = name.to_ascii_lowercase();
+
+    MAPPED_MODULES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|entry| entry.process == process && entry.module.name == name)
+        .map(|entry| entry.module.clone())
+}
+
+pub fn modules(process: ProcessKey) -> Vec<MappedModule> {
+    MAPPED_MODULES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.process == process)
+        .map(|entry| entry.module.clone())
+        .collect()
+}
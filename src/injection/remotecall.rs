@@ -0,0 +1,243 @@
+use super::execution::{self, execute, ExecutionMethod, ExecutionTimeout};
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use dynasmrt::{dynasm, DynasmApi, ExecutableBuffer};
+use std::convert::TryFrom;
+use std::mem::size_of;
+
+// An argument passed to a function in another process
+pub enum RemoteArg {
+    Int(u64),
+    Pointer(usize),
+    // Copied into the target, the function receives a pointer to the copy
+    Buffer(Vec<u8>),
+}
+
+impl From<u32> for RemoteArg {
+    fn from(value: u32) -> Self {
+        RemoteArg::Int(value as u64)
+    }
+}
+
+impl From<u64> for RemoteArg {
+    fn from(value: u64) -> Self {
+        RemoteArg::Int(value)
+    }
+}
+
+impl From<usize> for RemoteArg {
+    fn from(value: usize) -> Self {
+        RemoteArg::Pointer(value)
+    }
+}
+
+impl From<&[u8]> for RemoteArg {
+    fn from(value: &[u8]) -> Self {
+        RemoteArg::Buffer(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for RemoteArg {
+    fn from(value: Vec<u8>) -> Self {
+        RemoteArg::Buffer(value)
+    }
+}
+
+// Calls a function in another process and returns its return value
+// 32-bit functions may be cdecl or stdcall, the stack pointer is restored from the frame pointer
+// after the call. 64-bit functions use the Microsoft x64 calling convention.
+pub struct RemoteCall {
+    function: usize,
+    args: Vec<RemoteArg>,
+    execution: ExecutionMethod,
+    timeout: ExecutionTimeout,
+}
+
+impl RemoteCall {
+    pub fn new(function: usize) -> Self {
+        Self {
+            function,
+            args: Vec::new(),
+            execution: ExecutionMethod::CreateRemoteThread,
+            timeout: ExecutionTimeout::default(),
+        }
+    }
+
+    pub fn arg<A: Into<RemoteArg>>(mut self, arg: A) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn execution(mut self, execution: ExecutionMethod) -> Self {
+        self.execution = execution;
+        self
+    }
+
+    // Milliseconds to wait for the function to return
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.timeout.millis = timeout;
+        self
+    }
+
+    // Terminate the thread running the function if it doesn't return in time
+    pub fn terminate_on_timeout(mut self, terminate: bool) -> Self {
+        self.timeout.terminate = terminate;
+        self
+    }
+
+    // 32-bit functions return edx:eax, so 64-bit return values are supported on both architectures
+    pub fn call(&self, process: &Process) -> anyhow::Result<u64> {
+        let is_wow64 = process.is_wow64()?;
+
+        // Buffers are written to the target first so that their addresses can be passed
+        let mut buffers = Vec::new();
+        let mut args = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            let value = match arg {
+                RemoteArg::Int(value) => *value,
+                RemoteArg::Pointer(address) => *address as u64,
+                RemoteArg::Buffer(data) => {
+                    let buffer = VirtualMem::alloc(
+                        process,
+                        0,
+                        data.len().max(1),
+                        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+                        ProtectFlag::PAGE_READWRITE,
+                    )?;
+                    buffer.write_memory(data, 0)?;
+
+                    let address = buffer.address() as u64;
+                    buffers.push(buffer);
+                    address
+                }
+            };
+
+            args.push(value);
+        }
+
+        // The stub stores the full return value here, the thread exit code only holds 32 bits
+        let mut result = VirtualMem::alloc(
+            process,
+            0,
+            size_of::<u64>(),
+            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+            ProtectFlag::PAGE_READWRITE,
+        )?;
+
+        let stub = if is_wow64 {
+            get_stub32(self.function, &args, result.address())
+        } else {
+            get_stub64(self.function, &args, result.address())
+        }?;
+
+        let mut stub_mem = VirtualMem::alloc(
+            process,
+            0,
+            stub.size(),
+            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+            ProtectFlag::PAGE_EXECUTE_READWRITE,
+        )?;
+        stub_mem.write_memory(&stub, 0)?;
+
+        if let Err(e) = execute(
+            process,
+            stub_mem.address(),
+            0,
+            self.execution,
+            self.timeout,
+            "remote call",
+        ) {
+            // The stub might still run later, so nothing it uses can be freed
+            if execution::may_still_run(&e) {
+                stub_mem.set_free_on_drop(false);
+                result.set_free_on_drop(false);
+                buffers
+                    .iter_mut()
+                    .for_each(|buffer| buffer.set_free_on_drop(false));
+            }
+
+            return Err(e);
+        }
+
+        result.read_value::<u64>(0)
+    }
+}
+
+fn get_stub32(function: usize, args: &[u64], result: usize) -> anyhow::Result<ExecutableBuffer> {
+    let mut assembler = dynasmrt::x86::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x86
+        ; push ebp
+        ; mov ebp, esp
+    );
+
+    // Arguments are pushed right to left
+    for &arg in args.iter().rev() {
+        let arg = u32::try_from(arg)
+            .map_err(|_e| anyhow!("Argument {:#x} doesn't fit in a 32-bit process", arg))?;
+
+        dynasm!(assembler
+            ; .arch x86
+            ; push DWORD arg as _
+        );
+    }
+
+    dynasm!(assembler
+        ; .arch x86
+        ; mov ecx, DWORD function as _
+        ; call ecx
+        ; mov ecx, DWORD result as _
+        ; mov [ecx], eax
+        ; mov [ecx + 4], edx
+        ; mov esp, ebp
+        ; pop ebp
+        ; ret 4
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
+
+fn get_stub64(function: usize, args: &[u64], result: usize) -> anyhow::Result<ExecutableBuffer> {
+    // 32 bytes of shadow space plus the stack arguments, rounded up to keep the stack 16-byte aligned
+    let stack_args = args.len().saturating_sub(4);
+    let stack_size = (32 + stack_args * 8 + 15) & !15;
+
+    let mut assembler = dynasmrt::x64::Assembler::new()?;
+    dynasm!(assembler
+        ; .arch x64
+        ; push rbp
+        ; mov rbp, rsp
+        ; sub rsp, stack_size as _
+    );
+
+    for (i, &arg) in args.iter().enumerate() {
+        match i {
+            0 => dynasm!(assembler ; .arch x64 ; mov rcx, QWORD arg as _),
+            1 => dynasm!(assembler ; .arch x64 ; mov rdx, QWORD arg as _),
+            2 => dynasm!(assembler ; .arch x64 ; mov r8, QWORD arg as _),
+            3 => dynasm!(assembler ; .arch x64 ; mov r9, QWORD arg as _),
+            _ => dynasm!(assembler
+                ; .arch x64
+                ; mov rax, QWORD arg as _
+                ; mov [rsp + (32 + (i - 4) * 8) as i32], rax
+            ),
+        }
+    }
+
+    dynasm!(assembler
+        ; .arch x64
+        ; mov rax, QWORD function as _
+        ; call rax
+        ; mov rcx, QWORD result as _
+        ; mov [rcx], rax
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+
+    assembler.commit()?;
+
+    Ok(assembler.finalize().unwrap())
+}
@@ -0,0 +1,121 @@
+use super::injectionmethod::InjectionMethod;
+use super::observer::MapStage;
+use crate::winapiwrapper::virtualmem::ProtectFlag;
+use std::time::Duration;
+
+// What an injection did, returned by the inject functions
+// Only manual mapping knows the details, the other methods leave them empty
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct InjectionReport {
+    // The method that injected the library, LoadLibrary if manual mapping fell back to it
+    pub method: InjectionMethod,
+    pub image_base: usize,
+    pub image_size: usize,
+    pub sections: Vec<SectionReport>,
+    // Includes delay-load imports
+    pub imports_resolved: usize,
+    pub relocations_applied: usize,
+    // Address DllMain was called at, None if the entry point wasn't called
+    pub entry_point: Option<usize>,
+    // BOOL DllMain returned for the last reason it was called with, None if it wasn't called
+    pub entry_point_result: Option<u32>,
+    // Thread the loader ran on, an existing thread of the target for APC and hijack execution
+    pub loader_thread: Option<u32>,
+    // Time from the start of the injection until each stage was reached
+    pub timings: Vec<(MapStage, Duration)>,
+    // How long each phase took, in the order they ran. Work between the phases, such as setting
+    // up static TLS, isn't included, so they don't add up to the duration of the injection.
+    pub phases: Vec<(InjectionPhase, Duration)>,
+    // Display name of the certificate the library was signed with, if its signature was verified
+    pub signer: Option<String>,
+    // Reports of the imported libraries that were manually mapped for the image, in the order
+    // they were mapped. Their own dependencies are listed in their reports.
+    pub dependencies: Vec<InjectionReport>,
+}
+
+impl InjectionReport {
+    pub(crate) fn new(method: InjectionMethod, image_base: usize, image_size: usize) -> Self {
+        Self {
+            method,
+            image_base,
+            image_size,
+            sections: Vec::new(),
+            imports_resolved: 0,
+            relocations_applied: 0,
+            entry_point: None,
+            entry_point_result: None,
+            loader_thread: None,
+            timings: Vec::new(),
+            phases: Vec::new(),
+            signer: None,
+            dependencies: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record_phase(&mut self, phase: InjectionPhase, duration: Duration) {
+        trace!("{:?} took {:?}", phase, duration);
+
+        self.phases.push((phase, duration));
+    }
+
+    // Total duration of the phase, zero if it didn't run
+    pub fn phase_duration(&self, phase: InjectionPhase) -> Duration {
+        self.phases
+            .iter()
+            .filter(|(p, _)| *p == phase)
+            .map(|(_, duration)| *duration)
+            .sum()
+    }
+}
+
+// Phases of an injection that are timed
+// LoadLibrary injections only report Write, for the temporary file, and Execute
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InjectionPhase {
+    // Validating and parsing the image and laying out its sections
+    Parse,
+    // Allocating the image in the target
+    Allocate,
+    // Applying base relocations
+    Relocate,
+    // Resolving imports and delay-load imports, including loading missing dependencies
+    ResolveImports,
+    // Writing the fixed-up headers and sections
+    Write,
+    // Applying the memory protection of the sections
+    Protect,
+    // Running the loader stub, which calls the TLS callbacks and DllMain
+    Execute,
+}
+
+// A section of a manually mapped image
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct SectionReport {
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
+    #[cfg_attr(feature = "serde", serde(with = "protect_flag_bits"))]
+    pub protection: ProtectFlag,
+}
+
+// ProtectFlag is serialized as the PAGE_* value it holds
+#[cfg(feature = "serde")]
+mod protect_flag_bits {
+    use crate::winapiwrapper::virtualmem::ProtectFlag;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(flags: &ProtectFlag, serializer: S) -> Result<S::Ok, S::Error> {
+        flags.bits().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ProtectFlag, D::Error> {
+        Ok(ProtectFlag::from_bits_truncate(u32::deserialize(
+            deserializer,
+        )?))
+    }
+}
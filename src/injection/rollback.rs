@@ -0,0 +1,129 @@
+use super::execution::ExecutionMethod;
+use super::remotecall::RemoteCall;
+use crate::winapiwrapper::process::Process;
+
+// Milliseconds the calls that undo a registration get, rollback runs while an error is returned
+// and must not hang the injector even though injections wait for their stubs indefinitely
+const UNDO_TIMEOUT: u32 = 5000;
+
+// Something a manual mapping registered in the target that outlives the memory of the image
+enum Registration {
+    // Offsets of the image marked as valid CFG call targets
+    CallTargets {
+        region: usize,
+        size: usize,
+        offsets: Vec<usize>,
+    },
+    // Activation context created from the manifest of the image, released with ReleaseActCtx
+    ActivationContext {
+        handle: usize,
+        release: usize,
+    },
+    // Function table registered by the loader stub, removed with RtlDeleteFunctionTable
+    FunctionTable {
+        table: usize,
+        delete: usize,
+    },
+}
+
+// Undoes the registrations of a manual mapping that fails part way through
+// The memory of the image and the stubs is released when their VirtualMem is dropped, which
+// also discards the protection applied to it. Registrations are undone in reverse order when the
+// rollback is dropped, unless the mapping completed and commit was called.
+// Once static TLS is registered the image can't be freed, like when ejecting, since the loader
+// keeps no way to release it. The mapping then commits the rollback and keeps the image.
+pub struct Rollback<'a> {
+    process: &'a Process,
+    execution: ExecutionMethod,
+    registrations: Vec<Registration>,
+    committed: bool,
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new(process: &'a Process, execution: ExecutionMethod) -> Self {
+        Self {
+            process,
+            execution,
+            registrations: Vec::new(),
+            committed: false,
+        }
+    }
+
+    pub fn call_targets(&mut self, region: usize, size: usize, offsets: Vec<usize>) {
+        self.registrations.push(Registration::CallTargets {
+            region,
+            size,
+            offsets,
+        });
+    }
+
+    pub fn activation_context(&mut self, handle: usize, release: usize) {
+        self.registrations
+            .push(Registration::ActivationContext { handle, release });
+    }
+
+    pub fn function_table(&mut self, table: usize, delete: usize) {
+        self.registrations
+            .push(Registration::FunctionTable { table, delete });
+    }
+
+    // Keeps the registrations, either because the mapping succeeded or because code that relies
+    // on them may still be running in the target
+    pub fn commit(&mut self) {
+        self.committed = true;
+    }
+
+    fn undo(&self, registration: &Registration) -> anyhow::Result<()> {
+        match registration {
+            Registration::CallTargets {
+                region,
+                size,
+                offsets,
+            } => self
+                .process
+                .set_valid_call_targets(*region, *size, offsets, false),
+            Registration::ActivationContext { handle, release } => {
+                RemoteCall::new(*release)
+                    .arg(*handle)
+                    .execution(self.execution)
+                    .timeout(UNDO_TIMEOUT)
+                    .terminate_on_timeout(true)
+                    .call(self.process)?;
+
+                Ok(())
+            }
+            Registration::FunctionTable { table, delete } => {
+                // RtlDeleteFunctionTable returns a BOOLEAN, so only the low byte is valid
+                let ret = RemoteCall::new(*delete)
+                    .arg(*table)
+                    .execution(self.execution)
+                    .timeout(UNDO_TIMEOUT)
+                    .terminate_on_timeout(true)
+                    .call(self.process)?;
+
+                ensure!(
+                    ret as u8 != 0,
+                    "RtlDeleteFunctionTable failed in the target for the table at {:x}",
+                    table
+                );
+
+                Ok(())
+            }
+        }
+    }
+}
+
+// Undoing is best effort, a failure to undo one registration doesn't stop the others
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        for registration in self.registrations.iter().rev() {
+            if let Err(e) = self.undo(registration) {
+                trace!("Failed to roll back a registration in the target: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::winapiwrapper::module;
+use crate::winapiwrapper::process::Process;
+use crate::winapiwrapper::regkey::{RegKey, HKEY_LOCAL_MACHINE};
+use std::env;
+use std::path::{Path, PathBuf};
+
+const SESSION_MANAGER_KEY: &str = r"SYSTEM\CurrentControlSet\Control\Session Manager";
+const KNOWN_DLLS_KEY: &str = r"SYSTEM\CurrentControlSet\Control\Session Manager\KnownDLLs";
+
+// Where the loader of a process looks for libraries that are imported by file name
+// Follows the standard search order for desktop applications:
+// https://docs.microsoft.com/en-us/windows/win32/dlls/dynamic-link-library-search-order
+// KnownDLLs come from the system directory. With SafeDllSearchMode, which is the default, the
+// application directory is followed by the system directory, the 16-bit system directory, the
+// Windows directory, the current directory and PATH. Without it the current directory comes
+// right after the application directory.
+pub struct SearchPath {
+    // System32, or SysWOW64 for WOW64 processes
+    system_dir: PathBuf,
+    // Lowercase file names, e.g. kernel32.dll
+    known_dlls: Vec<String>,
+    directories: Vec<PathBuf>,
+}
+
+impl SearchPath {
+    // The current directory and PATH of the target are read from its PEB
+    pub fn from_process(process: &Process) -> anyhow::Result<Self> {
+        let is_wow64 = process.is_wow64()?;
+        let system_dir = module::get_system_dir()?;
+        let windows_dir = system_dir
+            .parent()
+            .ok_or_else(|| anyhow!("System directory has no parent"))?
+            .to_path_buf();
+
+        let application_dir = process
+            .path()?
+            .parent()
+            .ok_or_else(|| anyhow!("Executable of the target has no directory"))?
+            .to_path_buf();
+
+        let current_dir = process.current_directory()?;
+        let path = process
+            .environment()?
+            .into_iter()
+            .find_map(|variable| {
+                let (name, value) = variable.split_once('=')?;
+                name.eq_ignore_ascii_case("PATH").then(|| value.to_string())
+            })
+            .unwrap_or_default();
+
+        let mut directories = vec![application_dir];
+        if safe_dll_search_mode()? {
+            directories.extend(vec![
+                system_dir.clone(),
+                windows_dir.join("system"),
+                windows_dir,
+                current_dir,
+            ]);
+        } else {
+            directories.extend(vec![
+                current_dir,
+                system_dir.clone(),
+                windows_dir.join("system"),
+                windows_dir,
+            ]);
+        }
+        directories.extend(env::split_paths(&path));
+
+        // The file system redirector maps System32 to SysWOW64 for WOW64 processes, wherever
+        // it appears in the search path
+        let directories = directories
+            .iter()
+            .map(|directory| redirect(directory, &system_dir, is_wow64))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            system_dir: redirect(&system_dir, &system_dir, is_wow64)?,
+            known_dlls: known_dlls()?,
+            directories,
+        })
+    }
+
+    // Returns the file the loader would load for the library
+    pub fn find(&self, name: &Path) -> anyhow::Result<PathBuf> {
+        if name.is_absolute() {
+            ensure!(name.is_file(), "Library {} doesn't exist", name.display());
+            return Ok(name.to_path_buf());
+        }
+
+        if self.is_known_dll(name) {
+            return Ok(self.system_dir.join(name));
+        }
+
+        self.directories
+            .iter()
+            .map(|directory| directory.join(name))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to find library {} on the search path of the target",
+                    name.display()
+                )
+            })
+    }
+
+    // Whether the library is a KnownDLL or in the system directory of the target's architecture
+    pub fn is_system_library(&self, name: &Path) -> bool {
+        self.is_known_dll(name) || self.system_dir.join(name).is_file()
+    }
+
+    fn is_known_dll(&self, name: &Path) -> bool {
+        name.to_str()
+            .is_some_and(|name| self.known_dlls.contains(&name.to_ascii_lowercase()))
+    }
+}
+
+// system_dir is lowercase, paths are compared case-insensitively
+fn redirect(path: &Path, system_dir: &Path, is_wow64: bool) -> anyhow::Result<PathBuf> {
+    let is_system_path = path
+        .to_str()
+        .map(|path| path.to_ascii_lowercase())
+        .is_some_and(|path| Path::new(&path).starts_with(system_dir));
+
+    if is_wow64 && is_system_path {
+        module::system_module_path_to_wow64_path(path)
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+// Enabled unless SafeDllSearchMode is set to 0
+fn safe_dll_search_mode() -> anyhow::Result<bool> {
+    let value = match RegKey::open(HKEY_LOCAL_MACHINE, SESSION_MANAGER_KEY)? {
+        Some(key) => key.dword("SafeDllSearchMode")?,
+        None => None,
+    };
+
+    Ok(value != Some(0))
+}
+
+// Besides the libraries, the values of the key include DllDirectory, a directory path
+fn known_dlls() -> anyhow::Result<Vec<String>> {
+    let key = match RegKey::open(HKEY_LOCAL_MACHINE, KNOWN_DLLS_KEY)? {
+        Some(key) => key,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(key
+        .strings()?
+        .into_iter()
+        .map(|(_, file_name)| file_name.to_ascii_lowercase())
+        .filter(|file_name| file_name.ends_with(".dll"))
+        .collect())
+}
@@ -0,0 +1,65 @@
+use super::ensure_not_protected;
+use super::execution::{self, execute};
+use super::injectoptions::InjectOptions;
+use crate::winapiwrapper::process::{Process, ProcessAccess};
+use crate::winapiwrapper::virtualmem::{AllocType, ProtectFlag, VirtualMem};
+use crate::Error;
+
+// Copies position-independent code into the target and runs it with the execution method of the
+// options. The code is called like a thread start routine with a null parameter and the value it
+// returns is returned. Its memory is freed once it returns, so it must not leave anything running
+// that still uses it.
+pub fn inject(pid: u32, code: &[u8], options: &InjectOptions) -> anyhow::Result<u32> {
+    ensure!(
+        !code.is_empty(),
+        Error::InvalidArgument("Shellcode is empty".to_string())
+    );
+
+    ensure_not_protected(pid)?;
+
+    let process = Process::from_pid(
+        pid,
+        ProcessAccess::PROCESS_CREATE_THREAD
+            | ProcessAccess::PROCESS_QUERY_INFORMATION
+            | ProcessAccess::PROCESS_VM_OPERATION
+            | ProcessAccess::PROCESS_VM_READ
+            | ProcessAccess::PROCESS_VM_WRITE
+            | ProcessAccess::SYNCHRONIZE,
+        false,
+    )?;
+
+    ensure!(
+        !process.mitigations()?.dynamic_code_prohibited,
+        Error::DynamicCodeProhibited
+    );
+
+    let mut code_mem = VirtualMem::alloc(
+        &process,
+        0,
+        code.len(),
+        AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+        ProtectFlag::PAGE_READWRITE,
+    )?;
+
+    code_mem.write_memory(code, 0)?;
+    code_mem.virtual_protect(0, code.len(), ProtectFlag::PAGE_EXECUTE_READ)?;
+
+    match execute(
+        &process,
+        code_mem.address(),
+        0,
+        options.execution,
+        options.timeout,
+        "shellcode",
+    ) {
+        Ok(ret) => Ok(ret),
+        Err(e) => {
+            // The code might still run later, so it can't be freed
+            if execution::may_still_run(&e) {
+                code_mem.set_free_on_drop(false);
+            }
+
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::Error;
+use pelite::image::IMAGE_DIRECTORY_ENTRY_SECURITY;
+use pelite::{PeFile, Wrap};
+
+// Offset of CheckSum in the optional header, the same for PE32 and PE32+
+const OFFSET_OPTIONAL_HEADER_CHECKSUM: usize = 0x40;
+const SIZE_OF_NT_SIGNATURE_AND_FILE_HEADER: usize = 0x18;
+
+// Checks the headers of a PE against the file before it is mapped
+// A corrupt or truncated file would otherwise fault or write garbage halfway through mapping
+// The Windows loader ignores the checksum of libraries, so a mismatch only fails with
+// verify_checksum, e.g. for libraries that were patched without updating it.
+pub fn validate(pe: PeFile, image: &[u8], verify_checksum: bool) -> Result<(), Error> {
+    let (size_of_image, size_of_headers, section_alignment, checksum) = match pe.optional_header() {
+        Wrap::T32(header32) => (
+            header32.SizeOfImage as u64,
+            header32.SizeOfHeaders as u64,
+            header32.SectionAlignment as u64,
+            header32.CheckSum,
+        ),
+        Wrap::T64(header64) => (
+            header64.SizeOfImage as u64,
+            header64.SizeOfHeaders as u64,
+            header64.SectionAlignment as u64,
+            header64.CheckSum,
+        ),
+    };
+
+    let file_size = image.len() as u64;
+
+    if size_of_headers > file_size {
+        return Err(invalid(format!(
+            "SizeOfHeaders {:x} exceeds the file size {:x}",
+            size_of_headers, file_size
+        )));
+    }
+
+    if size_of_headers > size_of_image {
+        return Err(invalid(format!(
+            "SizeOfHeaders {:x} exceeds SizeOfImage {:x}",
+            size_of_headers, size_of_image
+        )));
+    }
+
+    // Sections
+    let mut image_end = size_of_headers;
+    for section in pe.section_headers() {
+        let name = section.name().unwrap_or("<invalid>");
+
+        let raw_start = section.PointerToRawData as u64;
+        let raw_end = raw_start + section.SizeOfRawData as u64;
+        if section.SizeOfRawData != 0 && raw_end > file_size {
+            return Err(invalid(format!(
+                "Section {} raw data {:x}..{:x} exceeds the file size {:x}",
+                name, raw_start, raw_end, file_size
+            )));
+        }
+
+        let virtual_start = section.VirtualAddress as u64;
+        let virtual_end =
+            virtual_start + (section.VirtualSize as u64).max(section.SizeOfRawData as u64);
+        if virtual_start < size_of_headers {
+            return Err(invalid(format!(
+                "Section {} at {:x} overlaps the headers",
+                name, virtual_start
+            )));
+        }
+
+        if virtual_end > size_of_image {
+            return Err(invalid(format!(
+                "Section {} {:x}..{:x} exceeds SizeOfImage {:x}",
+                name, virtual_start, virtual_end, size_of_image
+            )));
+        }
+
+        image_end = image_end.max(virtual_end);
+    }
+
+    // The loader rounds the end of the last section up to the section alignment
+    let aligned_image_end = align_up(image_end, section_alignment);
+    if size_of_image > aligned_image_end {
+        return Err(invalid(format!(
+            "SizeOfImage {:x} is larger than the sections which end at {:x}",
+            size_of_image, aligned_image_end
+        )));
+    }
+
+    // Data directories
+    for (index, directory) in pe.data_directory().iter().enumerate() {
+        if directory.VirtualAddress == 0 && directory.Size == 0 {
+            continue;
+        }
+
+        let start = directory.VirtualAddress as u64;
+        let end = start + directory.Size as u64;
+
+        // The certificate table is the only directory that uses a file offset
+        let (limit, limit_name) = if index == IMAGE_DIRECTORY_ENTRY_SECURITY {
+            (file_size, "the file size")
+        } else {
+            (size_of_image, "SizeOfImage")
+        };
+
+        if end > limit {
+            return Err(invalid(format!(
+                "Data directory {} {:x}..{:x} exceeds {} {:x}",
+                index, start, end, limit_name, limit
+            )));
+        }
+    }
+
+    // A checksum of zero means the linker didn't set it
+    if checksum != 0 {
+        let checksum_offset = pe.dos_header().e_lfanew as usize
+            + SIZE_OF_NT_SIGNATURE_AND_FILE_HEADER
+            + OFFSET_OPTIONAL_HEADER_CHECKSUM;
+        let actual = compute_checksum(image, checksum_offset);
+
+        if actual != checksum {
+            let message = format!(
+                "Checksum {:x} does not match the computed checksum {:x}",
+                checksum, actual
+            );
+
+            if verify_checksum {
+                return Err(invalid(message));
+            }
+
+            trace!("{}", message);
+        }
+    }
+
+    Ok(())
+}
+
+// Same algorithm as CheckSumMappedFile, the checksum field itself is skipped
+fn compute_checksum(image: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+
+    for (index, word) in image.chunks(2).enumerate() {
+        let offset = index * 2;
+        if offset >= checksum_offset && offset < checksum_offset + 4 {
+            continue;
+        }
+
+        let word = match *word {
+            [low, high] => u16::from_le_bytes([low, high]),
+            [low] => low as u16,
+            _ => unreachable!(),
+        };
+
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum = (sum & 0xffff) + (sum >> 16);
+
+    sum as u32 + image.len() as u32
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    if alignment == 0 {
+        return value;
+    }
+
+    value.div_ceil(alignment) * alignment
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidImage(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_adds_the_file_size() {
+        assert_eq!(compute_checksum(&[], 0), 0);
+        // 1 + 2 + 4 bytes
+        assert_eq!(compute_checksum(&[0x01, 0x00, 0x02, 0x00], 0x100), 7);
+    }
+
+    #[test]
+    fn checksum_folds_carries() {
+        // 0xffff + 0x0002 = 0x10001, folded to 0x0002, plus 4 bytes
+        assert_eq!(compute_checksum(&[0xff, 0xff, 0x02, 0x00], 0x100), 6);
+    }
+
+    #[test]
+    fn checksum_of_odd_length() {
+        // The last byte is the low byte of a word
+        assert_eq!(compute_checksum(&[0x00, 0x00, 0x05], 0x100), 8);
+    }
+
+    #[test]
+    fn checksum_skips_the_checksum_field() {
+        let mut image = vec![0x11_u8; 0x20];
+        let without_field = compute_checksum(&image, 0x8);
+
+        image[0x8..0xc].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(compute_checksum(&image, 0x8), without_field);
+
+        // 14 words of 0x1111 outside the field, plus 32 bytes
+        assert_eq!(without_field, 14 * 0x1111 + 0x20);
+    }
+
+    #[test]
+    fn align_up_rounds_to_the_alignment() {
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_up(0x1234, 0), 0x1234);
+    }
+}
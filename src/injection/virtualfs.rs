@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Libraries kept in memory that manually mapped dependencies are read from instead of the disk,
+// e.g. payload libraries embedded in the injector with include_bytes!
+// Files are looked up by file name, compared case-insensitively, before the search path of the
+// target. Clones share the images.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualFs {
+    // Keyed by lowercase file name
+    files: HashMap<String, Arc<[u8]>>,
+}
+
+impl VirtualFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces an image that was added under the same name
+    pub fn insert<S: AsRef<str>, B: Into<Vec<u8>>>(&mut self, name: S, image: B) {
+        self.files
+            .insert(name.as_ref().to_ascii_lowercase(), Arc::from(image.into()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.files
+            .get(&name.to_ascii_lowercase())
+            .map(|image| &image[..])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
@@ -0,0 +1,168 @@
+use super::cancellation::CancellationToken;
+use super::injectionmethod::InjectionMethod;
+use super::injectoptions::InjectOptions;
+use super::loadlibrary;
+use super::report::InjectionReport;
+use crate::winapiwrapper::process::{Process, ProcessAccess};
+use crate::winapiwrapper::window::Window;
+use crate::Error;
+use pelite::PeFile;
+use std::ffi::CString;
+use std::iter;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::thread as std_thread;
+use std::time::{Duration, Instant};
+use winapi::shared::minwindef::HMODULE;
+use winapi::um::libloaderapi::{
+    FreeLibrary, GetProcAddress, LoadLibraryExW, DONT_RESOLVE_DLL_REFERENCES,
+};
+use winapi::um::winbase::INFINITE;
+use winapi::um::winuser::{
+    PostThreadMessageW, SetWindowsHookExW, UnhookWindowsHookEx, HOOKPROC, WH_GETMESSAGE, WM_NULL,
+};
+
+// Injects by installing a WH_GETMESSAGE hook on the thread owning a window of the target
+// The system loads the library into the target once the thread retrieves a message and calls the
+// hook procedure, the export named by options.hook_procedure. The hook is removed as soon as the
+// library is loaded, which makes the system unload it again unless the library pins itself, e.g.
+// with GetModuleHandleExW(GET_MODULE_HANDLE_EX_FLAG_PIN, ...) in DllMain.
+// The library is loaded into our own process to find the hook procedure, so the target has to
+// have the same architecture as the injector.
+pub fn inject(
+    pid: u32,
+    pe: PeFile,
+    image: &[u8],
+    options: &InjectOptions,
+) -> anyhow::Result<InjectionReport> {
+    let hook_procedure = options.hook_procedure.as_deref().ok_or_else(|| {
+        Error::InvalidArgument("A hook procedure is required for hook injection".to_string())
+    })?;
+
+    let process = Process::from_pid(
+        pid,
+        ProcessAccess::PROCESS_QUERY_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+        false,
+    )?;
+
+    ensure!(
+        process.architecture()? == Process::from_current().architecture()?,
+        Error::InvalidArgument(
+            "Hook injection requires the target to have the architecture of the injector"
+                .to_string()
+        )
+    );
+
+    let window = Window::find_by_pid(pid)
+        .ok_or_else(|| Error::TargetNotFound(format!("Process {} has no window", pid)))?;
+    let thread_id = window.thread_id();
+
+    let file_path = loadlibrary::write_temp_library(image)?;
+    let file_name = file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Failed to convert OsStr path to str"))?
+        .to_string();
+
+    let module = LocalLibrary::load(&file_path)?;
+    let hook_procedure = module.proc_address(hook_procedure)?;
+
+    let hook = unsafe {
+        SetWindowsHookExW(
+            WH_GETMESSAGE,
+            mem::transmute::<usize, HOOKPROC>(hook_procedure),
+            module.handle,
+            thread_id,
+        )
+    };
+
+    ensure!(!hook.is_null(), function_call_failure!("SetWindowsHookExW"));
+
+    trace!("Installed hook on thread {}", thread_id);
+
+    let result = wait_for_library(
+        &process,
+        thread_id,
+        &file_name,
+        options.timeout.millis,
+        &options.cancellation,
+    );
+
+    let ret = unsafe { UnhookWindowsHookEx(hook) };
+    ensure!(ret != 0, function_call_failure!("UnhookWindowsHookEx"));
+
+    Ok(InjectionReport::new(
+        InjectionMethod::WindowsHook,
+        result?,
+        loadlibrary::image_size(pe),
+    ))
+}
+
+// Posts messages to the thread until its process has loaded the library
+// Returns the base of the library in the target, INFINITE waits until it is loaded
+fn wait_for_library(
+    process: &Process,
+    thread_id: u32,
+    file_name: &str,
+    timeout: u32,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<usize> {
+    let started = Instant::now();
+    loop {
+        cancellation.check()?;
+
+        // GetMessage only calls the hook if there is a message to retrieve
+        let ret = unsafe { PostThreadMessageW(thread_id, WM_NULL, 0, 0) };
+        ensure!(ret != 0, function_call_failure!("PostThreadMessageW"));
+
+        if let Some(module) = process.module_by_name(file_name)? {
+            return Ok(module.info()?.lpBaseOfDll as usize);
+        }
+
+        ensure!(
+            timeout == INFINITE || started.elapsed() < Duration::from_millis(timeout as u64),
+            "Timed out waiting for the target to load the library"
+        );
+
+        std_thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// The library loaded into our process without running DllMain or resolving its imports
+struct LocalLibrary {
+    handle: HMODULE,
+}
+
+impl LocalLibrary {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let path: Vec<u16> = path
+            .as_os_str()
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect();
+
+        let handle =
+            unsafe { LoadLibraryExW(path.as_ptr(), ptr::null_mut(), DONT_RESOLVE_DLL_REFERENCES) };
+
+        ensure!(!handle.is_null(), function_call_failure!("LoadLibraryExW"));
+
+        Ok(Self { handle })
+    }
+
+    fn proc_address(&self, name: &str) -> anyhow::Result<usize> {
+        let name = CString::new(name)?;
+        let address = unsafe { GetProcAddress(self.handle, name.as_ptr()) };
+
+        ensure!(!address.is_null(), function_call_failure!("GetProcAddress"));
+
+        Ok(address as usize)
+    }
+}
+
+impl Drop for LocalLibrary {
+    fn drop(&mut self) {
+        unsafe { FreeLibrary(self.handle) };
+    }
+}
@@ -7,77 +7,221 @@ extern crate anyhow;
 #[macro_use]
 extern crate thiserror;
 
-use pelite::{PeFile, Wrap};
+use pelite::PeFile;
+use std::borrow::Cow;
+use std::path::Path;
 use winapi::um::winnt::IMAGE_FILE_DLL;
 
+#[macro_use]
+mod trace;
+
+mod error;
+#[cfg(feature = "ffi")]
+mod ffi;
 mod injection;
-mod winapiwrapper;
+pub mod pe;
+#[cfg(feature = "python")]
+mod python;
+pub mod winapiwrapper;
 
+pub use error::Error;
+pub use injection::cancellation::CancellationToken;
+pub use injection::clr::ClrOptions;
+pub use injection::dependencypolicy::DependencyPolicy;
+pub use injection::eject::{EjectOptions, EjectReport};
+pub use injection::execution::{ExecutionMethod, ExecutionTimeout};
 pub use injection::injectionmethod::InjectionMethod;
-use winapiwrapper::process::{Process, ProcessAccess, Processes};
+pub use injection::injectoptions::InjectOptions;
+pub use injection::injector::Injector;
+pub use injection::mappingbackend::MappingBackend;
+pub use injection::observer::{MapObserver, MapStage};
+#[cfg(feature = "lz4")]
+pub use injection::pesource::Lz4Compressed;
+pub use injection::pesource::PeSource;
+#[cfg(feature = "zstd")]
+pub use injection::pesource::ZstdCompressed;
+pub use injection::placement::AllocationPlacement;
+pub use injection::registry::MappedModule;
+pub use injection::remotecall::{RemoteArg, RemoteCall};
+pub use injection::report::{InjectionPhase, InjectionReport, SectionReport};
+pub use injection::virtualfs::VirtualFs;
+pub use winapiwrapper::process::{Architecture, Mitigations, ProtectionLevel, ProtectionSigner};
+use winapiwrapper::process::{Process, ProcessAccess, ProcessKey};
+use winapiwrapper::processbuilder::ProcessBuilder;
 use winapiwrapper::window::Window;
 
-pub fn inject_pid(pid: u32, dll: &[u8], method: InjectionMethod) -> anyhow::Result<usize> {
+pub fn inject_pid<S: PeSource + ?Sized>(
+    pid: u32,
+    dll: &S,
+    options: &InjectOptions,
+) -> Result<InjectionReport, Error> {
+    let (image, signer) = read_image(dll, options)?;
+    let dll = &image[..];
     let pe = PeFile::from_bytes(dll)?;
-    ensure!(pe.file_header().Characteristics & IMAGE_FILE_DLL != 0);
-
-    // If the library is 32-bit, ensure the target process is running under WOW64
-    if matches!(pe, Wrap::T32(_pe32)) {
-        let process =
-            Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)?;
-        ensure!(
-            process.is_wow64()?,
-            "Library is 32-bit but process is not running under WOW64"
-        );
+    let process = Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)?;
+    check_compatible(pe, &process)?;
+
+    let mut report = injection::inject(pid, pe, dll, options)?;
+    if let Some(signer) = signer {
+        injection::registry::set_signer(process.key()?, report.image_base, &signer);
+        report.signer = Some(signer);
     }
 
-    Ok(injection::inject(pid, pe, dll, method)?)
+    Ok(report)
 }
 
-pub fn inject_window(
+// Launches the process suspended and injects before its entry point runs
+// Returns the process along with the report of the injection, the process is
+// terminated if injection fails
+pub fn inject_spawn<S: PeSource + ?Sized>(
+    builder: ProcessBuilder,
+    dll: &S,
+    options: &InjectOptions,
+) -> Result<(Process, InjectionReport), Error> {
+    let (image, signer) = read_image(dll, options)?;
+    let dll = &image[..];
+    let pe = PeFile::from_bytes(dll)?;
+    let (process, thread) = builder.suspended(true).spawn()?;
+
+    let result = check_compatible(pe, &process).and_then(|()| {
+        Ok(injection::earlybird::inject(
+            &process, &thread, pe, dll, options,
+        )?)
+    });
+
+    match result {
+        Ok(mut report) => {
+            if let Some(signer) = signer {
+                injection::registry::set_signer(process.key()?, report.image_base, &signer);
+                report.signer = Some(signer);
+            }
+
+            Ok((process, report))
+        }
+        Err(e) => {
+            let _ = process.terminate(1);
+            Err(e)
+        }
+    }
+}
+
+// Reads the library, verifying its signature if the options require it, and returns the signer
+// A verified library is read from the same handle its signature was verified through
+#[cfg(feature = "authenticode")]
+fn read_image<'a, S: PeSource + ?Sized>(
+    dll: &'a S,
+    options: &InjectOptions,
+) -> Result<(Cow<'a, [u8]>, Option<String>), Error> {
+    if !options.require_signature {
+        return Ok((dll.image()?, None));
+    }
+
+    let path = dll.path().ok_or_else(|| {
+        Error::InvalidArgument("Signature verification requires a library file".to_string())
+    })?;
+
+    let (image, signer) = winapiwrapper::authenticode::read_verified(path)?;
+    trace!("Library is signed by {}", signer);
+
+    Ok((Cow::Owned(image), Some(signer)))
+}
+
+#[cfg(not(feature = "authenticode"))]
+fn read_image<'a, S: PeSource + ?Sized>(
+    dll: &'a S,
+    _options: &InjectOptions,
+) -> Result<(Cow<'a, [u8]>, Option<String>), Error> {
+    Ok((dll.image()?, None))
+}
+
+// Ensures the library is a DLL built for the architecture of the target process
+fn check_compatible(pe: PeFile, process: &Process) -> Result<(), Error> {
+    if pe.file_header().Characteristics & IMAGE_FILE_DLL == 0 {
+        return Err(Error::InvalidArgument("Library is not a DLL".to_string()));
+    }
+
+    let payload = Architecture::from_machine(pe.file_header().Machine);
+    let target = process.architecture()?;
+    if payload != target {
+        return Err(Error::ArchitectureMismatch { payload, target });
+    }
+
+    Ok(())
+}
+
+pub fn inject_window<S: PeSource + ?Sized>(
     window_name: &str,
-    dll: &[u8],
-    method: InjectionMethod,
-) -> anyhow::Result<usize> {
+    dll: &S,
+    options: &InjectOptions,
+) -> Result<InjectionReport, Error> {
     let window = Window::find(window_name)?;
 
     if let Some(window) = window {
-        inject_pid(window.pid(), dll, method)
+        inject_pid(window.pid(), dll, options)
     } else {
-        bail!("Failed to find window with name '{}'", window_name)
+        Err(Error::TargetNotFound(format!(
+            "No window with name '{}'",
+            window_name
+        )))
     }
 }
 
-pub fn inject_process_name(
+pub fn inject_process_name<S: PeSource + ?Sized>(
     process_name: &str,
-    dll: &[u8],
-    method: InjectionMethod,
-) -> anyhow::Result<usize> {
-    let process_name = process_name.to_ascii_lowercase();
-    let processes = Processes::new(None)?;
-
-    for pid in processes {
-        let proc =
-            match Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false) {
-                Ok(proc) => proc,
-                Err(_e) => continue, // It might be a system process which we can't open a handle for
-            };
-
-        let file_name = proc
-            .path()?
-            .file_name()
-            .ok_or_else(|| anyhow!("No file name for path"))?
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to convert OsStr to str"))?
-            .to_ascii_lowercase();
-
-        if file_name == process_name {
-            return inject_pid(pid, dll, method);
-        }
+    dll: &S,
+    options: &InjectOptions,
+) -> Result<InjectionReport, Error> {
+    if let Some(&pid) = Process::find_by_name(process_name)?.first() {
+        return inject_pid(pid, dll, options);
     }
 
-    Err(anyhow!(
-        "Failed to find process with name: '{}'",
+    Err(Error::TargetNotFound(format!(
+        "No process with name '{}'",
         process_name
-    ))
+    )))
+}
+
+// Runs position-independent code in the process and returns the value it returned
+// Only the execution method of the options applies
+pub fn inject_shellcode(pid: u32, code: &[u8], options: &InjectOptions) -> Result<u32, Error> {
+    Ok(injection::shellcode::inject(pid, code, options)?)
+}
+
+// Hosts the .NET Framework runtime in the process and calls `static int Method(string argument)`
+// of the managed assembly, returns the value returned by the method
+pub fn execute_assembly(
+    pid: u32,
+    assembly: &Path,
+    type_name: &str,
+    method: &str,
+    argument: &str,
+    options: &ClrOptions,
+) -> Result<u32, Error> {
+    Ok(injection::clr::execute_assembly(
+        pid, assembly, type_name, method, argument, options,
+    )?)
+}
+
+// Ejects a module that was manually mapped into the process at image_base
+pub fn eject_pid(
+    pid: u32,
+    image_base: usize,
+    options: &EjectOptions,
+) -> Result<EjectReport, Error> {
+    Ok(injection::eject::eject(pid, image_base, options)?)
+}
+
+// Lists the modules this crate has manually mapped into the process
+// A process that can't be opened, e.g. because it exited, has none
+pub fn mapped_modules(pid: u32) -> Vec<MappedModule> {
+    ProcessKey::from_pid(pid)
+        .map(injection::registry::modules)
+        .unwrap_or_default()
+}
+
+// Forgets the export addresses resolved in the process
+// They are checked against the module loaded at the same base when used, so this is only needed
+// if a library in the target was replaced by another one with the same path
+pub fn clear_export_cache(pid: u32) {
+    injection::exportcache::clear(pid)
 }
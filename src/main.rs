@@ -1,6 +1,8 @@
 use clap::{App, Arg, ArgGroup};
-use std::fs::File;
-use std::io::Read;
+use jector::winapiwrapper::processbuilder::ProcessBuilder;
+use std::path::Path;
+
+mod cli;
 
 fn main() -> anyhow::Result<()> {
     let matches = App::new("jector")
@@ -11,6 +13,7 @@ fn main() -> anyhow::Result<()> {
                 .arg("pid")
                 .arg("window")
                 .arg("name")
+                .arg("spawn")
                 .required(true),
         )
         .arg(
@@ -37,6 +40,14 @@ fn main() -> anyhow::Result<()> {
                 .help("The process file name to inject into")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("spawn")
+                .short("s")
+                .long("spawn")
+                .value_name("exe_file_path")
+                .help("Launch the executable and inject before its entry point runs")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("file")
                 .short("f")
@@ -46,34 +57,25 @@ fn main() -> anyhow::Result<()> {
                 .takes_value(true)
                 .required(true),
         )
-        .arg(
-            Arg::with_name("method")
-                .short("m")
-                .long("method")
-                .value_name("loadlibrary/manualmap")
-                .help("The injection method to use")
-                .takes_value(true)
-                .default_value("loadlibrary"),
-        )
+        .args(&cli::inject_args("loadlibrary"))
         .get_matches();
 
-    let file_bytes = {
-        let mut buf = Vec::new();
-        File::open(matches.value_of("file").unwrap())?.read_to_end(&mut buf)?;
+    let file = Path::new(matches.value_of("file").unwrap());
 
-        buf
-    };
+    cli::enable_debug_privilege();
 
-    let method = matches.value_of("method").unwrap().parse()?;
+    let injector = cli::injector(&matches)?;
 
     if let Some(pid) = matches.value_of("pid") {
-        jector::inject_pid(pid.parse()?, &file_bytes, method)?;
+        injector.inject_pid(pid.parse()?, file)?;
     } else if let Some(window_name) = matches.value_of("window") {
-        jector::inject_window(window_name, &file_bytes, method)?;
+        injector.inject_window(window_name, file)?;
     } else if let Some(process_name) = matches.value_of("name") {
-        jector::inject_process_name(process_name, &file_bytes, method)?;
+        injector.inject_process_name(process_name, file)?;
+    } else if let Some(path) = matches.value_of("spawn") {
+        injector.inject_spawn(ProcessBuilder::new(path), file)?;
     } else {
-        panic!("Expected either -p, -w or -n");
+        panic!("Expected either -p, -w, -n or -s");
     };
 
     Ok(())
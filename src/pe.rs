@@ -0,0 +1,631 @@
+// Read-only view of a PE image in its file layout, for inspecting a library before injecting it
+// Nothing is mapped or executed, so this works on images of any architecture
+use crate::winapiwrapper::module::{CodeViewInfo, ExportEntry};
+use crate::winapiwrapper::process::Architecture;
+use crate::Error;
+use pelite::image::{
+    IMAGE_DEBUG_TYPE_CODEVIEW, IMAGE_FILE_DLL, IMAGE_ORDINAL_FLAG32, IMAGE_ORDINAL_FLAG64,
+};
+use pelite::{PeFile, Wrap};
+
+pub use pelite::rich_structure::ObjectKind;
+
+pub use pelite::image::{
+    IMAGE_DIRECTORY_ENTRY_BASERELOC, IMAGE_DIRECTORY_ENTRY_DEBUG,
+    IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT, IMAGE_DIRECTORY_ENTRY_EXCEPTION,
+    IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DIRECTORY_ENTRY_IMPORT, IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG,
+    IMAGE_DIRECTORY_ENTRY_RESOURCE, IMAGE_DIRECTORY_ENTRY_SECURITY, IMAGE_DIRECTORY_ENTRY_TLS,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DosHeader {
+    pub magic: u16,
+    // e_lfanew
+    pub nt_headers_offset: u32,
+}
+
+// The file header and the fields of the optional header that PE32 and PE32+ share
+#[derive(Clone, Copy, Debug)]
+pub struct NtHeaders {
+    pub machine: u16,
+    pub number_of_sections: u16,
+    pub time_date_stamp: u32,
+    pub characteristics: u16,
+    // PE32+
+    pub is_64bit: bool,
+    pub image_base: u64,
+    pub entry_point: u32,
+    pub section_alignment: u32,
+    pub file_alignment: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub subsystem: u16,
+    pub dll_characteristics: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub pointer_to_raw_data: u32,
+    pub size_of_raw_data: u32,
+    pub characteristics: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+// A library imported by the image
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub library: String,
+    pub functions: Vec<ImportedFunction>,
+}
+
+// Functions are imported either by name or by ordinal
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportedFunction {
+    Name { hint: u16, name: String },
+    Ordinal(u16),
+}
+
+// The undocumented Rich header the Microsoft linker writes between the DOS stub and the NT headers
+// Each entry counts the objects a tool of the toolchain produced, identified by product and build
+#[derive(Clone, Debug)]
+pub struct RichHeader {
+    // Every value of the header is xored with this
+    pub key: u32,
+    // The key is a checksum of the DOS stub and the entries, a mismatch means either was edited
+    pub checksum_valid: bool,
+    pub entries: Vec<RichEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RichEntry {
+    pub product_id: u16,
+    pub build: u16,
+    pub count: u32,
+    // What the product produced, derived from product_id
+    pub kind: ObjectKind,
+}
+
+#[derive(Clone, Copy)]
+pub struct PeImage<'a> {
+    pe: PeFile<'a>,
+}
+
+impl<'a> PeImage<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, Error> {
+        Ok(Self {
+            pe: PeFile::from_bytes(image)?,
+        })
+    }
+
+    pub fn dos_header(&self) -> DosHeader {
+        let dos_header = self.pe.dos_header();
+
+        DosHeader {
+            magic: dos_header.e_magic,
+            nt_headers_offset: dos_header.e_lfanew,
+        }
+    }
+
+    pub fn nt_headers(&self) -> NtHeaders {
+        let file_header = self.pe.file_header();
+
+        let mut headers = NtHeaders {
+            machine: file_header.Machine,
+            number_of_sections: file_header.NumberOfSections,
+            time_date_stamp: file_header.TimeDateStamp,
+            characteristics: file_header.Characteristics,
+            is_64bit: false,
+            image_base: 0,
+            entry_point: 0,
+            section_alignment: 0,
+            file_alignment: 0,
+            size_of_image: 0,
+            size_of_headers: 0,
+            subsystem: 0,
+            dll_characteristics: 0,
+        };
+
+        match self.pe.optional_header() {
+            Wrap::T32(optional) => {
+                headers.image_base = optional.ImageBase as u64;
+                headers.entry_point = optional.AddressOfEntryPoint;
+                headers.section_alignment = optional.SectionAlignment;
+                headers.file_alignment = optional.FileAlignment;
+                headers.size_of_image = optional.SizeOfImage;
+                headers.size_of_headers = optional.SizeOfHeaders;
+                headers.subsystem = optional.Subsystem;
+                headers.dll_characteristics = optional.DllCharacteristics;
+            }
+            Wrap::T64(optional) => {
+                headers.is_64bit = true;
+                headers.image_base = optional.ImageBase;
+                headers.entry_point = optional.AddressOfEntryPoint;
+                headers.section_alignment = optional.SectionAlignment;
+                headers.file_alignment = optional.FileAlignment;
+                headers.size_of_image = optional.SizeOfImage;
+                headers.size_of_headers = optional.SizeOfHeaders;
+                headers.subsystem = optional.Subsystem;
+                headers.dll_characteristics = optional.DllCharacteristics;
+            }
+        }
+
+        headers
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        Architecture::from_machine(self.pe.file_header().Machine)
+    }
+
+    pub fn is_dll(&self) -> bool {
+        self.pe.file_header().Characteristics & IMAGE_FILE_DLL != 0
+    }
+
+    // (major, minor) version of the linker from the optional header
+    pub fn linker_version(&self) -> (u8, u8) {
+        match self.pe.optional_header() {
+            Wrap::T32(optional) => (optional.LinkerVersion.Major, optional.LinkerVersion.Minor),
+            Wrap::T64(optional) => (optional.LinkerVersion.Major, optional.LinkerVersion.Minor),
+        }
+    }
+
+    // None if the image wasn't linked by the Microsoft linker or the header was stripped
+    pub fn rich_header(&self) -> Option<RichHeader> {
+        let rich = self.pe.rich_structure().ok()?;
+
+        Some(RichHeader {
+            key: rich.xor_key(),
+            checksum_valid: rich.checksum() == rich.xor_key(),
+            entries: rich
+                .records()
+                .map(|record| RichEntry {
+                    product_id: record.product,
+                    build: record.build,
+                    count: record.count,
+                    kind: ObjectKind::from(record.product),
+                })
+                .collect(),
+        })
+    }
+
+    // RVA of the entry point, 0 if the image has none
+    pub fn entry_point(&self) -> u32 {
+        self.nt_headers().entry_point
+    }
+
+    pub fn sections(&self) -> Vec<Section> {
+        self.pe
+            .section_headers()
+            .iter()
+            .map(|sh| Section {
+                name: String::from_utf8_lossy(sh.name_bytes()).into_owned(),
+                virtual_address: sh.VirtualAddress,
+                virtual_size: sh.VirtualSize,
+                pointer_to_raw_data: sh.PointerToRawData,
+                size_of_raw_data: sh.SizeOfRawData,
+                characteristics: sh.Characteristics,
+            })
+            .collect()
+    }
+
+    // None if the headers don't have the directory or it is empty
+    // index is one of the IMAGE_DIRECTORY_ENTRY constants
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        self.pe
+            .data_directory()
+            .get(index)
+            .filter(|dir| dir.VirtualAddress != 0)
+            .map(|dir| DataDirectory {
+                virtual_address: dir.VirtualAddress,
+                size: dir.Size,
+            })
+    }
+
+    pub fn imports(&self) -> Result<Vec<Import>, Error> {
+        if self.data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT).is_none() {
+            return Ok(Vec::new());
+        }
+
+        let (thunk_size, ordinal_flag) = match self.pe {
+            Wrap::T32(_) => (4, IMAGE_ORDINAL_FLAG32 as u64),
+            Wrap::T64(_) => (8, IMAGE_ORDINAL_FLAG64),
+        };
+
+        let mut libraries = Vec::new();
+        for descriptor in self.pe.imports()? {
+            let desc = descriptor.image();
+
+            // Images from old linkers only have the IAT, which holds the names on disk
+            let name_table = match desc.OriginalFirstThunk {
+                0 => desc.FirstThunk,
+                rva => rva,
+            };
+
+            let mut functions = Vec::new();
+            for index in 0_u32.. {
+                // The tables are read from the file, so a crafted image may run past the last RVA
+                let rva = index
+                    .checked_mul(thunk_size)
+                    .and_then(|offset| name_table.checked_add(offset))
+                    .ok_or(pelite::Error::Overflow)?;
+                let thunk = match self.pe {
+                    Wrap::T32(_) => *self.pe.derva::<u32>(rva)? as u64,
+                    Wrap::T64(_) => *self.pe.derva::<u64>(rva)?,
+                };
+
+                if thunk == 0 {
+                    break;
+                }
+
+                functions.push(if thunk & ordinal_flag != 0 {
+                    ImportedFunction::Ordinal(thunk as u16)
+                } else {
+                    // IMAGE_IMPORT_BY_NAME, the name follows the hint
+                    let name = (thunk as u32)
+                        .checked_add(2)
+                        .ok_or(pelite::Error::Overflow)?;
+
+                    ImportedFunction::Name {
+                        hint: *self.pe.derva::<u16>(thunk as u32)?,
+                        name: self.pe.derva_c_str(name)?.to_string(),
+                    }
+                });
+            }
+
+            libraries.push(Import {
+                library: descriptor.dll_name()?.to_string(),
+                functions,
+            });
+        }
+
+        Ok(libraries)
+    }
+
+    // Name of the library in its export directory, which may differ from its file name
+    pub fn export_name(&self) -> Option<String> {
+        self.pe
+            .exports()
+            .and_then(|exports| exports.dll_name())
+            .ok()
+            .map(|name| name.to_string())
+    }
+
+    pub fn exports(&self) -> Result<Vec<ExportEntry>, Error> {
+        if self.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT).is_none() {
+            return Ok(Vec::new());
+        }
+
+        let exports = self.pe.exports()?;
+
+        let functions = exports.functions()?;
+        let mut names = vec![None; functions.len()];
+        for (&name, &index) in exports.names()?.iter().zip(exports.name_indices()?) {
+            if let Some(slot) = names.get_mut(index as usize) {
+                *slot = Some(self.pe.derva_c_str(name)?.to_string());
+            }
+        }
+
+        // Forwarders are strings inside the export directory instead of code
+        let directory = self.pe.data_directory()[IMAGE_DIRECTORY_ENTRY_EXPORT];
+        let directory_end = directory
+            .VirtualAddress
+            .checked_add(directory.Size)
+            .ok_or(pelite::Error::Overflow)?;
+        let is_forwarder = |rva: u32| rva >= directory.VirtualAddress && rva < directory_end;
+
+        let mut entries = Vec::new();
+        for (index, (&rva, name)) in functions.iter().zip(names).enumerate() {
+            if rva == 0 {
+                continue;
+            }
+
+            entries.push(ExportEntry {
+                name,
+                ordinal: exports.ordinal_base().wrapping_add(index as u16),
+                rva,
+                forwarder: if is_forwarder(rva) {
+                    Some(self.pe.derva_c_str(rva)?.to_string())
+                } else {
+                    None
+                },
+            });
+        }
+
+        Ok(entries)
+    }
+
+    // The CodeView record of the debug directory, None if the image has no PDB information
+    pub fn codeview(&self) -> Option<CodeViewInfo> {
+        self.data_directory(IMAGE_DIRECTORY_ENTRY_DEBUG)?;
+
+        self.pe
+            .debug()
+            .ok()?
+            .into_iter()
+            .filter(|dir| dir.image().Type == IMAGE_DEBUG_TYPE_CODEVIEW)
+            .find_map(|dir| CodeViewInfo::parse(dir.data()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (product_id, build, count) of the Rich header of the test image
+    const RICH_ENTRIES: [(u16, u16, u32); 2] = [(0x0104, 30133, 5), (0x0001, 0, 42)];
+
+    // RVA and size of the export directory, which spans the strings so that other.Beta is a forwarder
+    const EXPORT_DIRECTORY: u32 = 0x1000;
+    const EXPORT_DIRECTORY_SIZE: u32 = 0x80;
+
+    const PDB_GUID: [u8; 16] = [
+        0x67, 0x45, 0x23, 0x01, 0xab, 0x89, 0xef, 0xcd, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+    const PDB_PATH: &str = r"C:\build\test.pdb";
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_str(image: &mut [u8], offset: usize, value: &str) {
+        image[offset..offset + value.len()].copy_from_slice(value.as_bytes());
+    }
+
+    // The Microsoft linker's checksum over the DOS stub, without e_lfanew, and the entries
+    fn rich_checksum(dos_stub: &[u8]) -> u32 {
+        let mut checksum = dos_stub.len() as u32;
+
+        for (index, &byte) in dos_stub.iter().enumerate() {
+            let byte = if (0x3c..0x40).contains(&index) {
+                0
+            } else {
+                byte
+            };
+            checksum = checksum.wrapping_add((byte as u32).rotate_left(index as u32));
+        }
+
+        for &(product_id, build, count) in RICH_ENTRIES.iter() {
+            let value = (product_id as u32) << 16 | build as u32;
+            checksum = checksum.wrapping_add(value.rotate_left(count));
+        }
+
+        checksum
+    }
+
+    // A 64-bit DLL with a single .rdata section holding its export and debug directories
+    // Its exports are Alpha, an unnamed one at ordinal 3 and Beta forwarded to other.Beta
+    fn test_image(with_rich_header: bool) -> Vec<u8> {
+        let mut image = vec![0; 0x400];
+
+        // DOS header, the NT headers follow the Rich header
+        put_str(&mut image, 0, "MZ");
+        put_u32(&mut image, 0x3c, 0x80);
+
+        if with_rich_header {
+            let key = rich_checksum(&image[..0x40]);
+
+            put_u32(&mut image, 0x40, 0x536e_6144 ^ key);
+            put_u32(&mut image, 0x44, key);
+            put_u32(&mut image, 0x48, key);
+            put_u32(&mut image, 0x4c, key);
+            for (index, &(product_id, build, count)) in RICH_ENTRIES.iter().enumerate() {
+                let offset = 0x50 + index * 8;
+                put_u32(
+                    &mut image,
+                    offset,
+                    ((product_id as u32) << 16 | build as u32) ^ key,
+                );
+                put_u32(&mut image, offset + 4, count ^ key);
+            }
+            put_str(&mut image, 0x60, "Rich");
+            put_u32(&mut image, 0x64, key);
+        }
+
+        // File header
+        put_str(&mut image, 0x80, "PE\0\0");
+        put_u16(&mut image, 0x84, 0x8664);
+        put_u16(&mut image, 0x86, 1);
+        put_u32(&mut image, 0x88, 0x1234_5678);
+        put_u16(&mut image, 0x94, 0xf0);
+        put_u16(&mut image, 0x96, 0x2022);
+
+        // PE32+ optional header
+        put_u16(&mut image, 0x98, 0x20b);
+        image[0x9a] = 14;
+        image[0x9b] = 29;
+        image[0xb0..0xb8].copy_from_slice(&0x1_8000_0000_u64.to_le_bytes());
+        put_u32(&mut image, 0xb8, 0x1000);
+        put_u32(&mut image, 0xbc, 0x200);
+        put_u32(&mut image, 0xd0, 0x2000);
+        put_u32(&mut image, 0xd4, 0x200);
+        put_u16(&mut image, 0xdc, 2);
+        put_u32(&mut image, 0x104, 16);
+        put_u32(&mut image, 0x108, EXPORT_DIRECTORY);
+        put_u32(&mut image, 0x10c, EXPORT_DIRECTORY_SIZE);
+
+        // .rdata at RVA 0x1000, file offset 0x200
+        put_str(&mut image, 0x188, ".rdata");
+        put_u32(&mut image, 0x190, 0x200);
+        put_u32(&mut image, 0x194, 0x1000);
+        put_u32(&mut image, 0x198, 0x200);
+        put_u32(&mut image, 0x19c, 0x200);
+        put_u32(&mut image, 0x1ac, 0x4000_0040);
+
+        // Export directory, all RVAs are 0xe00 past their file offset
+        let rva = |file_offset: usize| (file_offset + 0xe00) as u32;
+        let directory = 0x200;
+        put_u32(&mut image, directory + 12, rva(0x250));
+        put_u32(&mut image, directory + 16, 1);
+        put_u32(&mut image, directory + 20, 4);
+        put_u32(&mut image, directory + 24, 2);
+        put_u32(&mut image, directory + 28, rva(0x228));
+        put_u32(&mut image, directory + 32, rva(0x238));
+        put_u32(&mut image, directory + 36, rva(0x240));
+
+        // Functions, the second one is a gap in the ordinals
+        put_u32(&mut image, 0x228, 0x1100);
+        put_u32(&mut image, 0x230, 0x1110);
+        put_u32(&mut image, 0x234, rva(0x270));
+        // Names and their indices into the functions
+        put_u32(&mut image, 0x238, rva(0x260));
+        put_u32(&mut image, 0x23c, rva(0x268));
+        put_u16(&mut image, 0x242, 3);
+
+        put_str(&mut image, 0x250, "test.dll");
+        put_str(&mut image, 0x260, "Alpha");
+        put_str(&mut image, 0x268, "Beta");
+        put_str(&mut image, 0x270, "other.Beta");
+
+        // Debug directory with a single CodeView entry
+        put_u32(&mut image, 0x138, rva(0x280));
+        put_u32(&mut image, 0x13c, 28);
+        put_u32(&mut image, 0x28c, IMAGE_DEBUG_TYPE_CODEVIEW);
+        put_u32(&mut image, 0x290, 24 + PDB_PATH.len() as u32 + 1);
+        put_u32(&mut image, 0x294, rva(0x2a0));
+        put_u32(&mut image, 0x298, 0x2a0);
+
+        // CV_INFO_PDB70
+        put_str(&mut image, 0x2a0, "RSDS");
+        image[0x2a4..0x2b4].copy_from_slice(&PDB_GUID);
+        put_u32(&mut image, 0x2b4, 3);
+        put_str(&mut image, 0x2b8, PDB_PATH);
+
+        image
+    }
+
+    #[test]
+    fn parses_the_headers() {
+        let image = test_image(false);
+        let pe = PeImage::parse(&image).unwrap();
+
+        let headers = pe.nt_headers();
+        assert!(headers.is_64bit);
+        assert_eq!(headers.machine, 0x8664);
+        assert_eq!(headers.image_base, 0x1_8000_0000);
+        assert_eq!(headers.size_of_image, 0x2000);
+        assert!(pe.is_dll());
+        assert_eq!(pe.linker_version(), (14, 29));
+        assert_eq!(pe.sections()[0].name, ".rdata");
+    }
+
+    #[test]
+    fn rich_header_entries_are_decoded() {
+        let image = test_image(true);
+        let rich = PeImage::parse(&image).unwrap().rich_header().unwrap();
+
+        assert_eq!(rich.key, rich_checksum(&image[..0x40]));
+        assert!(rich.checksum_valid);
+
+        let expected: Vec<RichEntry> = RICH_ENTRIES
+            .iter()
+            .map(|&(product_id, build, count)| RichEntry {
+                product_id,
+                build,
+                count,
+                kind: ObjectKind::from(product_id),
+            })
+            .collect();
+        assert_eq!(rich.entries, expected);
+    }
+
+    #[test]
+    fn rich_header_checksum_covers_the_dos_stub() {
+        let mut image = test_image(true);
+        image[0x10] ^= 1;
+
+        let rich = PeImage::parse(&image).unwrap().rich_header().unwrap();
+        assert!(!rich.checksum_valid);
+    }
+
+    #[test]
+    fn rich_header_is_optional() {
+        let image = test_image(false);
+        assert!(PeImage::parse(&image).unwrap().rich_header().is_none());
+    }
+
+    #[test]
+    fn exports_are_listed_by_ordinal() {
+        let image = test_image(false);
+        let pe = PeImage::parse(&image).unwrap();
+
+        assert_eq!(pe.export_name().as_deref(), Some("test.dll"));
+
+        let exports: Vec<_> = pe
+            .exports()
+            .unwrap()
+            .into_iter()
+            .map(|export| (export.name, export.ordinal, export.rva, export.forwarder))
+            .collect();
+        assert_eq!(
+            exports,
+            vec![
+                (Some("Alpha".to_string()), 1, 0x1100, None),
+                (None, 3, 0x1110, None),
+                (
+                    Some("Beta".to_string()),
+                    4,
+                    0x1070,
+                    Some("other.Beta".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_of_an_image_without_export_directory() {
+        let mut image = test_image(false);
+        put_u32(&mut image, 0x108, 0);
+        put_u32(&mut image, 0x10c, 0);
+
+        let pe = PeImage::parse(&image).unwrap();
+        assert!(pe.exports().unwrap().is_empty());
+        assert!(pe.export_name().is_none());
+    }
+
+    #[test]
+    fn exports_reject_an_overflowing_directory() {
+        let mut image = test_image(false);
+        put_u32(&mut image, 0x10c, u32::MAX);
+
+        let pe = PeImage::parse(&image).unwrap();
+        assert!(matches!(
+            pe.exports(),
+            Err(Error::Pe(pelite::Error::Overflow))
+        ));
+    }
+
+    #[test]
+    fn codeview_of_the_debug_directory() {
+        let image = test_image(false);
+
+        assert_eq!(
+            PeImage::parse(&image).unwrap().codeview(),
+            Some(CodeViewInfo {
+                guid: PDB_GUID,
+                age: 3,
+                pdb_path: PDB_PATH.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn codeview_of_an_image_without_debug_directory() {
+        let mut image = test_image(false);
+        put_u32(&mut image, 0x138, 0);
+        put_u32(&mut image, 0x13c, 0);
+
+        assert!(PeImage::parse(&image).unwrap().codeview().is_none());
+    }
+}
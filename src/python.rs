@@ -0,0 +1,223 @@
+// Python module of the cdylib, built with the python feature
+// The library has to be renamed to manualmap.pyd for Python to import it
+use crate::winapiwrapper::process::{Process, ProcessAccess};
+use crate::winapiwrapper::snapshot::Snapshot;
+use crate::{EjectOptions, Error, InjectOptions, InjectionReport};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+create_exception!(manualmap, InjectionError, PyException);
+
+fn injection_error(e: Error) -> PyErr {
+    InjectionError::new_err(e.to_string())
+}
+
+fn value_error(e: anyhow::Error) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+// A process of the system, identified by its PID
+#[pyclass(name = "Process")]
+struct PyProcess {
+    #[pyo3(get)]
+    pid: u32,
+}
+
+#[pymethods]
+impl PyProcess {
+    #[new]
+    fn new(pid: u32) -> PyResult<Self> {
+        // Fails early for PIDs that don't exist or can't be opened
+        Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)
+            .map_err(|e| injection_error(e.into()))?;
+
+        Ok(Self { pid })
+    }
+
+    // Every process whose executable has the file name, compared case-insensitively
+    #[staticmethod]
+    fn find(name: &str) -> PyResult<Vec<Self>> {
+        Ok(Process::find_by_name(name)
+            .map_err(|e| injection_error(e.into()))?
+            .into_iter()
+            .map(|pid| Self { pid })
+            .collect())
+    }
+
+    // File name of the executable
+    #[getter]
+    fn name(&self) -> PyResult<Option<String>> {
+        Ok(Snapshot::processes()
+            .map_err(|e| injection_error(e.into()))?
+            .process_entries()
+            .find(|entry| entry.pid == self.pid)
+            .map(|entry| entry.exe_file))
+    }
+
+    fn architecture(&self) -> PyResult<String> {
+        Process::from_pid(
+            self.pid,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION,
+            false,
+        )
+        .and_then(|process| process.architecture())
+        .map(|architecture| architecture.to_string())
+        .map_err(|e| injection_error(e.into()))
+    }
+
+    // (name, base, size) of the modules the loader has loaded, not including manually mapped ones
+    fn modules(&self) -> PyResult<Vec<(String, usize, u32)>> {
+        Ok(Snapshot::modules(self.pid)
+            .map_err(|e| injection_error(e.into()))?
+            .module_entries()
+            .map(|module| (module.name, module.base, module.size))
+            .collect())
+    }
+
+    // Python keyword arguments, so they aren't grouped into a struct
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (dll, method = "manualmap", execution = "createremotethread", timeout = None, hook_procedure = None))]
+    fn inject(
+        &self,
+        py: Python,
+        dll: PathBuf,
+        method: &str,
+        execution: &str,
+        timeout: Option<u32>,
+        hook_procedure: Option<String>,
+    ) -> PyResult<PyInjectionReport> {
+        inject(
+            py,
+            self.pid,
+            dll,
+            method,
+            execution,
+            timeout,
+            hook_procedure,
+        )
+    }
+
+    #[pyo3(signature = (image_base, call_entry_point = true))]
+    fn eject(&self, py: Python, image_base: usize, call_entry_point: bool) -> PyResult<()> {
+        eject(py, self.pid, image_base, call_entry_point)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Process({})", self.pid)
+    }
+}
+
+// The InjectionReport of an injection
+#[pyclass(name = "InjectionReport")]
+struct PyInjectionReport {
+    #[pyo3(get)]
+    method: String,
+    #[pyo3(get)]
+    image_base: usize,
+    #[pyo3(get)]
+    image_size: usize,
+    // (name, address, size) of the sections of manually mapped libraries
+    #[pyo3(get)]
+    sections: Vec<(String, usize, usize)>,
+    #[pyo3(get)]
+    entry_point: Option<usize>,
+    #[pyo3(get)]
+    entry_point_result: Option<u32>,
+    #[pyo3(get)]
+    loader_thread: Option<u32>,
+    #[pyo3(get)]
+    signer: Option<String>,
+}
+
+impl From<InjectionReport> for PyInjectionReport {
+    fn from(report: InjectionReport) -> Self {
+        Self {
+            method: format!("{:?}", report.method),
+            image_base: report.image_base,
+            image_size: report.image_size,
+            sections: report
+                .sections
+                .into_iter()
+                .map(|section| (section.name, section.address, section.size))
+                .collect(),
+            entry_point: report.entry_point,
+            entry_point_result: report.entry_point_result,
+            loader_thread: report.loader_thread,
+            signer: report.signer,
+        }
+    }
+}
+
+#[pymethods]
+impl PyInjectionReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "InjectionReport(method={}, image_base={:#x}, image_size={:#x})",
+            self.method, self.image_base, self.image_size
+        )
+    }
+}
+
+// Injects the library into the process, method and execution take the names the CLI accepts
+// hook_procedure is the exported hook procedure the windowshook method installs
+// The GIL is released while injecting
+#[pyfunction]
+#[pyo3(signature = (pid, dll, method = "manualmap", execution = "createremotethread", timeout = None, hook_procedure = None))]
+fn inject(
+    py: Python,
+    pid: u32,
+    dll: PathBuf,
+    method: &str,
+    execution: &str,
+    timeout: Option<u32>,
+    hook_procedure: Option<String>,
+) -> PyResult<PyInjectionReport> {
+    let method = method.parse().map_err(value_error)?;
+    let execution = execution.parse().map_err(value_error)?;
+
+    // InjectOptions isn't Send because of its observer, so it is built inside the closure
+    py.allow_threads(|| {
+        let mut options = InjectOptions {
+            method,
+            execution,
+            hook_procedure,
+            ..Default::default()
+        };
+
+        if let Some(timeout) = timeout {
+            options.timeout.millis = timeout;
+        }
+
+        crate::inject_pid(pid, dll.as_path(), &options)
+    })
+    .map(PyInjectionReport::from)
+    .map_err(injection_error)
+}
+
+// Ejects a library that was manually mapped at image_base
+#[pyfunction]
+#[pyo3(signature = (pid, image_base, call_entry_point = true))]
+fn eject(py: Python, pid: u32, image_base: usize, call_entry_point: bool) -> PyResult<()> {
+    let options = EjectOptions {
+        call_entry_point,
+        ..Default::default()
+    };
+
+    py.allow_threads(|| crate::eject_pid(pid, image_base, &options))
+        .map(|_| ())
+        .map_err(injection_error)
+}
+
+#[pymodule]
+#[pyo3(name = "manualmap")]
+fn python_module(py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<PyProcess>()?;
+    module.add_class::<PyInjectionReport>()?;
+    module.add_function(wrap_pyfunction!(inject, module)?)?;
+    module.add_function(wrap_pyfunction!(eject, module)?)?;
+    module.add("InjectionError", py.get_type::<InjectionError>())?;
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+// Diagnostic output describing each step of an injection
+// Emitted as trace records of the `log` facade with the `logging` feature, the application picks
+// the logger. Otherwise the arguments are type checked and compiled out.
+#[cfg(feature = "logging")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        log::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if false {
+            eprintln!($($arg)*)
+        }
+    };
+}
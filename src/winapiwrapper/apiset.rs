@@ -0,0 +1,131 @@
+use super::process::Process;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+// Offset of ApiSetMap inside the PEB of the current architecture
+const OFFSET_PEB_APISETMAP: usize = if size_of::<usize>() == 8 { 0x68 } else { 0x38 };
+
+// API_SET_NAMESPACE (schema version 6)
+const API_SET_SCHEMA_VERSION: u32 = 6;
+const OFFSET_NAMESPACE_SIZE: usize = 0x4;
+const OFFSET_NAMESPACE_COUNT: usize = 0xc;
+const OFFSET_NAMESPACE_ENTRY_OFFSET: usize = 0x10;
+
+// API_SET_NAMESPACE_ENTRY
+const NAMESPACE_ENTRY_SIZE: usize = 0x18;
+const OFFSET_ENTRY_NAME_OFFSET: usize = 0x4;
+const OFFSET_ENTRY_HASHED_LENGTH: usize = 0xc;
+const OFFSET_ENTRY_VALUE_OFFSET: usize = 0x10;
+const OFFSET_ENTRY_VALUE_COUNT: usize = 0x14;
+
+// API_SET_VALUE_ENTRY
+const OFFSET_VALUE_VALUE_OFFSET: usize = 0xc;
+const OFFSET_VALUE_VALUE_LENGTH: usize = 0x10;
+
+// The API set schema maps contract names (api-ms-win-*, ext-ms-win-*) to the libraries which implement them
+// It is mapped into every process by the kernel and referenced by the PEB
+// Only schema version 6 (Windows 10 and later) is supported
+// https://www.geoffchappell.com/studies/windows/win32/apisetschema/index.htm
+pub struct ApiSetMap {
+    data: Vec<u8>,
+}
+
+impl ApiSetMap {
+    pub fn from_process(process: &Process) -> anyhow::Result<Self> {
+        let address = process.read_value::<usize>(process.peb_address()? + OFFSET_PEB_APISETMAP)?;
+
+        // Read the start of the namespace header to find the size of the schema
+        let header = {
+            let mut buf = vec![0; OFFSET_NAMESPACE_SIZE + size_of::<u32>()];
+            process.read_memory(&mut buf, address)?;
+
+            Self { data: buf }
+        };
+
+        let version = header.u32_at(0)?;
+        ensure!(
+            version == API_SET_SCHEMA_VERSION,
+            "Unsupported API set schema version {}",
+            version
+        );
+
+        let mut data = vec![0; header.u32_at(OFFSET_NAMESPACE_SIZE)? as usize];
+        process.read_memory(&mut data, address)?;
+
+        Ok(Self { data })
+    }
+
+    // Resolves a contract name such as api-ms-win-core-heap-l1-1-0.dll to its default host library
+    pub fn resolve(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let name = name.to_ascii_lowercase();
+        let name = name.strip_suffix(".dll").unwrap_or(&name);
+
+        // The hashed part of the contract name excludes the minor version
+        let hashed_name = match name.rfind('-') {
+            Some(index) => &name[..index],
+            None => name,
+        };
+
+        let count = self.u32_at(OFFSET_NAMESPACE_COUNT)? as usize;
+        let entry_offset = self.u32_at(OFFSET_NAMESPACE_ENTRY_OFFSET)? as usize;
+
+        for i in 0..count {
+            let entry = entry_offset + i * NAMESPACE_ENTRY_SIZE;
+            let entry_name = self.string_at(
+                self.u32_at(entry + OFFSET_ENTRY_NAME_OFFSET)?,
+                self.u32_at(entry + OFFSET_ENTRY_HASHED_LENGTH)?,
+            )?;
+
+            if !entry_name.eq_ignore_ascii_case(hashed_name) {
+                continue;
+            }
+
+            // Contracts without a host are not implemented on this system
+            if self.u32_at(entry + OFFSET_ENTRY_VALUE_COUNT)? == 0 {
+                return Ok(None);
+            }
+
+            // The first value is the default host, the rest only apply to specific importing modules
+            let value = self.u32_at(entry + OFFSET_ENTRY_VALUE_OFFSET)? as usize;
+            let host = self.string_at(
+                self.u32_at(value + OFFSET_VALUE_VALUE_OFFSET)?,
+                self.u32_at(value + OFFSET_VALUE_VALUE_LENGTH)?,
+            )?;
+
+            return Ok(Some(host.to_ascii_lowercase()));
+        }
+
+        Ok(None)
+    }
+
+    fn u32_at(&self, offset: usize) -> anyhow::Result<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset + size_of::<u32>())
+            .ok_or_else(|| anyhow!("API set schema offset {:x} is out of bounds", offset))?;
+
+        Ok(u32::from_ne_bytes(bytes.try_into()?))
+    }
+
+    // Strings in the schema are UTF-16 and not null terminated, length is in bytes
+    fn string_at(&self, offset: u32, len: u32) -> anyhow::Result<String> {
+        let (offset, len) = (offset as usize, len as usize);
+        let bytes = self
+            .data
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("API set schema offset {:x} is out of bounds", offset))?;
+
+        let wide: Vec<u16> = bytes
+            .chunks_exact(size_of::<u16>())
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+            .collect();
+
+        Ok(String::from_utf16(&wide)?)
+    }
+}
+
+pub fn is_api_set_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    name.starts_with("api-ms-") || name.starts_with("ext-ms-")
+}
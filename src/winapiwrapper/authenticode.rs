@@ -0,0 +1,202 @@
+use super::error::{format_message, WinApiError};
+use super::processbuilder::to_wide;
+use crate::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+use winapi::ctypes::c_void;
+use winapi::um::softpub::WINTRUST_ACTION_GENERIC_VERIFY_V2;
+use winapi::um::wincrypt::{
+    CertCloseStore, CertFindCertificateInStore, CertFreeCertificateContext, CertGetNameStringW,
+    CryptMsgClose, CryptMsgGetParam, CryptQueryObject, CERT_FIND_SUBJECT_CERT, CERT_INFO,
+    CERT_NAME_SIMPLE_DISPLAY_TYPE, CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+    CERT_QUERY_FORMAT_FLAG_BINARY, CERT_QUERY_OBJECT_BLOB, CMSG_SIGNER_INFO,
+    CMSG_SIGNER_INFO_PARAM, CRYPT_DATA_BLOB, PKCS_7_ASN_ENCODING, X509_ASN_ENCODING,
+};
+use winapi::um::winnt::FILE_SHARE_READ;
+use winapi::um::wintrust::{
+    WinVerifyTrust, WINTRUST_DATA, WINTRUST_FILE_INFO, WTD_CHOICE_FILE, WTD_REVOKE_NONE,
+    WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE,
+};
+
+// Reads a file and verifies its embedded Authenticode signature, returns the contents of the file
+// and the display name of the certificate that signed it
+// The file is opened once and can't be written while it is open, so the contents are the ones
+// that were verified. Fails with Error::UntrustedImage if the signature isn't trusted.
+pub fn read_verified(path: &Path) -> anyhow::Result<(Vec<u8>, String)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)?;
+
+    verify_file(&file, path)
+        .map_err(|e| Error::UntrustedImage(format!("{}: {}", path.display(), e)))?;
+
+    // WinVerifyTrust reads through the same handle
+    file.seek(SeekFrom::Start(0))?;
+    let mut image = Vec::new();
+    file.read_to_end(&mut image)?;
+
+    let signer = signer_name(&image)?;
+
+    Ok((image, signer))
+}
+
+// Verifies the embedded Authenticode signature of an open file and its certificate chain
+// path is only used to describe the file
+// https://docs.microsoft.com/en-us/windows/win32/api/wintrust/nf-wintrust-winverifytrust
+pub fn verify_file(file: &File, path: &Path) -> anyhow::Result<()> {
+    let path = to_wide(path.as_os_str());
+
+    let mut file_info = WINTRUST_FILE_INFO {
+        cbStruct: size_of::<WINTRUST_FILE_INFO>() as u32,
+        pcwszFilePath: path.as_ptr(),
+        hFile: file.as_raw_handle() as _,
+        pgKnownSubject: ptr::null(),
+    };
+
+    let mut data = WINTRUST_DATA {
+        cbStruct: size_of::<WINTRUST_DATA>() as u32,
+        dwUIChoice: WTD_UI_NONE,
+        fdwRevocationChecks: WTD_REVOKE_NONE,
+        dwUnionChoice: WTD_CHOICE_FILE,
+        dwStateAction: WTD_STATEACTION_VERIFY,
+        ..Default::default()
+    };
+    unsafe { *data.u.pFile_mut() = &mut file_info };
+
+    let mut action = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+    let status = unsafe {
+        WinVerifyTrust(
+            ptr::null_mut(),
+            &mut action,
+            &mut data as *mut _ as *mut c_void,
+        )
+    };
+
+    // The state data has to be released whatever the result
+    data.dwStateAction = WTD_STATEACTION_CLOSE;
+    unsafe {
+        WinVerifyTrust(
+            ptr::null_mut(),
+            &mut action,
+            &mut data as *mut _ as *mut c_void,
+        )
+    };
+
+    // WinVerifyTrust returns an HRESULT rather than setting the last error
+    ensure!(
+        status == 0,
+        WinApiError::FunctionCallFailure {
+            function: "WinVerifyTrust".to_string(),
+            code: status as u32,
+            message: format_message(status as u32),
+        }
+    );
+
+    Ok(())
+}
+
+// Returns the display name of the certificate that signed the image
+// This doesn't verify the signature, use verify_file for that
+pub fn signer_name(image: &[u8]) -> anyhow::Result<String> {
+    let blob = CRYPT_DATA_BLOB {
+        cbData: image.len() as u32,
+        pbData: image.as_ptr() as *mut u8,
+    };
+
+    let mut encoding = 0;
+    let mut content_type = 0;
+    let mut format_type = 0;
+    let mut store = ptr::null_mut();
+    let mut msg = ptr::null_mut();
+    let ret = unsafe {
+        CryptQueryObject(
+            CERT_QUERY_OBJECT_BLOB,
+            &blob as *const CRYPT_DATA_BLOB as *const c_void,
+            CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+            CERT_QUERY_FORMAT_FLAG_BINARY,
+            0,
+            &mut encoding,
+            &mut content_type,
+            &mut format_type,
+            &mut store,
+            &mut msg,
+            ptr::null_mut(),
+        )
+    };
+
+    ensure!(ret != 0, function_call_failure!("CryptQueryObject"));
+
+    let name = (|| {
+        let mut size = 0;
+        let ret =
+            unsafe { CryptMsgGetParam(msg, CMSG_SIGNER_INFO_PARAM, 0, ptr::null_mut(), &mut size) };
+        ensure!(ret != 0, function_call_failure!("CryptMsgGetParam"));
+
+        // u64 elements keep the structure aligned
+        let mut buffer = vec![0_u64; (size as usize).div_ceil(size_of::<u64>())];
+        let ret = unsafe {
+            CryptMsgGetParam(
+                msg,
+                CMSG_SIGNER_INFO_PARAM,
+                0,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut size,
+            )
+        };
+        ensure!(ret != 0, function_call_failure!("CryptMsgGetParam"));
+
+        let signer_info = unsafe { &*(buffer.as_ptr() as *const CMSG_SIGNER_INFO) };
+        let cert_info = CERT_INFO {
+            Issuer: signer_info.Issuer,
+            SerialNumber: signer_info.SerialNumber,
+            ..Default::default()
+        };
+
+        let context = unsafe {
+            CertFindCertificateInStore(
+                store,
+                X509_ASN_ENCODING | PKCS_7_ASN_ENCODING,
+                0,
+                CERT_FIND_SUBJECT_CERT,
+                &cert_info as *const _ as *const c_void,
+                ptr::null(),
+            )
+        };
+        ensure!(
+            !context.is_null(),
+            function_call_failure!("CertFindCertificateInStore")
+        );
+
+        let mut name = vec![0_u16; 0x100];
+        let len = unsafe {
+            CertGetNameStringW(
+                context,
+                CERT_NAME_SIMPLE_DISPLAY_TYPE,
+                0,
+                ptr::null_mut(),
+                name.as_mut_ptr(),
+                name.len() as u32,
+            )
+        } as usize;
+
+        unsafe { CertFreeCertificateContext(context) };
+
+        // The length includes the null terminator
+        name.truncate(len.saturating_sub(1));
+
+        Ok(String::from_utf16(&name)?)
+    })();
+
+    unsafe {
+        CryptMsgClose(msg);
+        CertCloseStore(store, 0);
+    }
+
+    name
+}
@@ -0,0 +1,314 @@
+use super::process::{Process, UnreadableMemory};
+use pelite::image::{
+    IMAGE_DATA_DIRECTORY, IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT, IMAGE_DIRECTORY_ENTRY_IMPORT,
+    IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_FILE_HEADER, IMAGE_IMPORT_DESCRIPTOR,
+    IMAGE_NT_HEADERS32, IMAGE_NT_HEADERS64, IMAGE_NT_HEADERS_SIGNATURE,
+    IMAGE_NT_OPTIONAL_HDR32_MAGIC, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_SECTION_HEADER,
+};
+use pelite::Pod;
+use std::fs;
+use std::mem::size_of;
+use std::path::Path;
+
+// Enough to read the headers of any image
+const PAGE_SIZE: usize = 0x1000;
+
+// Used if the headers don't specify a file alignment
+const DEFAULT_FILE_ALIGNMENT: usize = 0x200;
+
+// Options for dumping a module from the memory of a process
+pub struct DumpOptions {
+    // Copy the import name table back into the IAT so the imports are resolved again on load
+    // Otherwise the IAT keeps the addresses that were resolved in the target
+    pub rebuild_imports: bool,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            rebuild_imports: true,
+        }
+    }
+}
+
+// The parts of the headers that differ between PE32 and PE32+
+struct Headers {
+    is_64bit: bool,
+    nt_headers: usize,
+    size_of_image: usize,
+    size_of_headers: usize,
+    file_alignment: usize,
+    number_of_sections: usize,
+    section_table: usize,
+    number_of_directories: usize,
+    data_directories: usize,
+}
+
+impl Headers {
+    fn parse(image: &[u8]) -> anyhow::Result<Self> {
+        let dos_header = read_pod::<IMAGE_DOS_HEADER>(image, 0)?;
+        ensure!(
+            dos_header.e_magic == IMAGE_DOS_SIGNATURE,
+            "Bad DOS signature"
+        );
+
+        let nt_headers = dos_header.e_lfanew as usize;
+        ensure!(
+            read_pod::<u32>(image, nt_headers)? == IMAGE_NT_HEADERS_SIGNATURE,
+            "Bad NT signature"
+        );
+
+        let file_header = read_pod::<IMAGE_FILE_HEADER>(image, nt_headers + 4)?;
+        let optional_header = nt_headers + 4 + size_of::<IMAGE_FILE_HEADER>();
+        let section_table = optional_header + file_header.SizeOfOptionalHeader as usize;
+
+        let headers = match read_pod::<u16>(image, optional_header)? {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => {
+                let optional = read_pod::<IMAGE_NT_HEADERS32>(image, nt_headers)?.OptionalHeader;
+
+                Self {
+                    is_64bit: false,
+                    nt_headers,
+                    size_of_image: optional.SizeOfImage as usize,
+                    size_of_headers: optional.SizeOfHeaders as usize,
+                    file_alignment: optional.FileAlignment as usize,
+                    number_of_sections: file_header.NumberOfSections as usize,
+                    section_table,
+                    number_of_directories: optional.NumberOfRvaAndSizes as usize,
+                    data_directories: nt_headers + size_of::<IMAGE_NT_HEADERS32>(),
+                }
+            }
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => {
+                let optional = read_pod::<IMAGE_NT_HEADERS64>(image, nt_headers)?.OptionalHeader;
+
+                Self {
+                    is_64bit: true,
+                    nt_headers,
+                    size_of_image: optional.SizeOfImage as usize,
+                    size_of_headers: optional.SizeOfHeaders as usize,
+                    file_alignment: optional.FileAlignment as usize,
+                    number_of_sections: file_header.NumberOfSections as usize,
+                    section_table,
+                    number_of_directories: optional.NumberOfRvaAndSizes as usize,
+                    data_directories: nt_headers + size_of::<IMAGE_NT_HEADERS64>(),
+                }
+            }
+            magic => bail!("Unknown optional header magic {:x}", magic),
+        };
+
+        ensure!(
+            headers.section_table + headers.number_of_sections * size_of::<IMAGE_SECTION_HEADER>()
+                <= headers.size_of_headers,
+            "Section table is outside of the headers"
+        );
+
+        Ok(headers)
+    }
+
+    fn data_directory(&self, image: &[u8], index: usize) -> anyhow::Result<IMAGE_DATA_DIRECTORY> {
+        if index >= self.number_of_directories {
+            return Ok(IMAGE_DATA_DIRECTORY::zeroed());
+        }
+
+        read_pod(
+            image,
+            self.data_directories + index * size_of::<IMAGE_DATA_DIRECTORY>(),
+        )
+    }
+
+    fn section(&self, image: &[u8], index: usize) -> anyhow::Result<IMAGE_SECTION_HEADER> {
+        read_pod(
+            image,
+            self.section_table + index * size_of::<IMAGE_SECTION_HEADER>(),
+        )
+    }
+}
+
+// Reads the image mapped at base and converts it back to the file layout
+// Sections are moved from their virtual addresses to file aligned offsets. The image base in the
+// headers is set to base, since the relocations have already been applied for it.
+pub fn dump_module(
+    process: &Process,
+    base: usize,
+    options: &DumpOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut headers = vec![0; PAGE_SIZE];
+    process.read_memory(&mut headers, base)?;
+    let size_of_image = Headers::parse(&headers)?.size_of_image;
+
+    let mut image = read_image(process, base, size_of_image)?;
+    let headers = Headers::parse(&image)?;
+
+    set_image_base(&mut image, &headers, base)?;
+
+    if options.rebuild_imports {
+        rebuild_imports(&mut image, &headers)?;
+    }
+
+    let file_alignment = match headers.file_alignment {
+        0 => DEFAULT_FILE_ALIGNMENT,
+        alignment => alignment,
+    };
+
+    ensure!(
+        headers.size_of_headers <= image.len(),
+        "Headers are larger than the image"
+    );
+
+    let mut file = image[..headers.size_of_headers].to_vec();
+
+    for index in 0..headers.number_of_sections {
+        let mut section = headers.section(&image, index)?;
+
+        let start = (section.VirtualAddress as usize).min(image.len());
+        let virtual_size = match section.VirtualSize {
+            0 => section.SizeOfRawData,
+            size => size,
+        } as usize;
+        let end = (start + virtual_size).min(image.len());
+
+        // Trailing zeroes, such as uninitialized data, don't need to be stored in the file
+        let data = &image[start..end];
+        let data = &data[..data.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1)];
+
+        file.resize(align(file.len(), file_alignment), 0);
+
+        section.PointerToRawData = if data.is_empty() {
+            0
+        } else {
+            file.len() as u32
+        };
+        section.SizeOfRawData = align(data.len(), file_alignment) as u32;
+
+        file.extend_from_slice(data);
+        file.resize(align(file.len(), file_alignment), 0);
+
+        write_pod(
+            &mut file,
+            headers.section_table + index * size_of::<IMAGE_SECTION_HEADER>(),
+            &section,
+        )?;
+    }
+
+    trace!(
+        "Dumped image at {:x} with size {:x} to {:x} bytes",
+        base,
+        headers.size_of_image,
+        file.len()
+    );
+
+    Ok(file)
+}
+
+pub fn dump_module_to_file(
+    process: &Process,
+    base: usize,
+    path: &Path,
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
+    fs::write(path, dump_module(process, base, options)?)?;
+
+    Ok(())
+}
+
+// Pages that can't be read, such as guard pages or decommitted discardable sections, are zeroed
+fn read_image(process: &Process, base: usize, size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut image = Vec::with_capacity(size);
+    process.dump_range(base, size, &mut image, UnreadableMemory::ZeroFill)?;
+
+    Ok(image)
+}
+
+fn set_image_base(image: &mut [u8], headers: &Headers, base: usize) -> anyhow::Result<()> {
+    if headers.is_64bit {
+        let mut nt_headers = read_pod::<IMAGE_NT_HEADERS64>(image, headers.nt_headers)?;
+        nt_headers.OptionalHeader.ImageBase = base as u64;
+        write_pod(image, headers.nt_headers, &nt_headers)
+    } else {
+        let mut nt_headers = read_pod::<IMAGE_NT_HEADERS32>(image, headers.nt_headers)?;
+        nt_headers.OptionalHeader.ImageBase = base as u32;
+        write_pod(image, headers.nt_headers, &nt_headers)
+    }
+}
+
+// Copies the import name table of every descriptor over its IAT
+// Descriptors without an import name table keep their resolved addresses
+fn rebuild_imports(image: &mut [u8], headers: &Headers) -> anyhow::Result<()> {
+    let import_directory = headers.data_directory(image, IMAGE_DIRECTORY_ENTRY_IMPORT)?;
+    if import_directory.VirtualAddress == 0 {
+        return Ok(());
+    }
+
+    let thunk_size = if headers.is_64bit { 8 } else { 4 };
+    let mut descriptor_offset = import_directory.VirtualAddress as usize;
+
+    loop {
+        let mut descriptor = read_pod::<IMAGE_IMPORT_DESCRIPTOR>(image, descriptor_offset)?;
+        if descriptor.is_null() {
+            break;
+        }
+
+        if descriptor.OriginalFirstThunk == 0 {
+            trace!(
+                "Import descriptor at {:x} has no import name table, keeping its IAT",
+                descriptor_offset
+            );
+        } else {
+            for index in 0.. {
+                let name_thunk = descriptor.OriginalFirstThunk as usize + index * thunk_size;
+                let iat_thunk = descriptor.FirstThunk as usize + index * thunk_size;
+
+                ensure!(
+                    name_thunk + thunk_size <= image.len() && iat_thunk + thunk_size <= image.len(),
+                    "Import thunk is outside of the image"
+                );
+
+                let thunk = image[name_thunk..name_thunk + thunk_size].to_vec();
+                image[iat_thunk..iat_thunk + thunk_size].copy_from_slice(&thunk);
+
+                if thunk.iter().all(|&b| b == 0) {
+                    break;
+                }
+            }
+
+            // The IAT is no longer bound to the addresses of the imported libraries
+            descriptor.TimeDateStamp = 0;
+            write_pod(image, descriptor_offset, &descriptor)?;
+        }
+
+        descriptor_offset += size_of::<IMAGE_IMPORT_DESCRIPTOR>();
+    }
+
+    if headers.number_of_directories > IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT {
+        write_pod(
+            image,
+            headers.data_directories
+                + IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT * size_of::<IMAGE_DATA_DIRECTORY>(),
+            &IMAGE_DATA_DIRECTORY::zeroed(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+fn read_pod<T: Pod>(buf: &[u8], offset: usize) -> anyhow::Result<T> {
+    let mut value = T::zeroed();
+    let bytes = buf
+        .get(offset..offset + size_of::<T>())
+        .ok_or_else(|| anyhow!("Offset {:x} is outside of the image", offset))?;
+    value.as_bytes_mut().copy_from_slice(bytes);
+
+    Ok(value)
+}
+
+fn write_pod<T: Pod>(buf: &mut [u8], offset: usize, value: &T) -> anyhow::Result<()> {
+    buf.get_mut(offset..offset + size_of::<T>())
+        .ok_or_else(|| anyhow!("Offset {:x} is outside of the image", offset))?
+        .copy_from_slice(value.as_bytes());
+
+    Ok(())
+}
@@ -1,16 +1,74 @@
+use std::ptr;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::winbase::{
+    FormatMessageW, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+};
+
 #[derive(Error, Debug)]
 pub enum WinApiError {
-    #[error("Function call to {0} failed [GetLastError() = 0x{1:x}]")]
-    FunctionCallFailure(String, u32),
+    #[error("Function call to {function} failed [GetLastError() = 0x{code:x}]: {message}")]
+    FunctionCallFailure {
+        function: String,
+        code: u32,
+        message: String,
+    },
+    #[error("Function call to {function} failed [NTSTATUS = 0x{status:x}]")]
+    NtFunctionCallFailure { function: String, status: u32 },
     #[error("Bad or invalid parameter {0}: {1}")]
     BadParameter(String, String),
 }
 
+impl WinApiError {
+    // Must be called directly after the failing function so that the last error is not overwritten
+    pub fn last_error(function: &str) -> Self {
+        let code = unsafe { GetLastError() };
+
+        Self::FunctionCallFailure {
+            function: function.to_string(),
+            code,
+            message: format_message(code),
+        }
+    }
+}
+
+// Retrieves the system message for an error code
+// https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-formatmessagew
+pub fn format_message(code: u32) -> String {
+    let mut buf = vec![0_u16; 0x200];
+    let len = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            ptr::null(),
+            code,
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+            ptr::null_mut(),
+        )
+    } as usize;
+
+    if len == 0 {
+        return String::from("Unknown error");
+    }
+
+    buf.truncate(len);
+
+    // System messages end with a line break
+    String::from_utf16_lossy(&buf).trim_end().to_string()
+}
+
 macro_rules! function_call_failure {
     ($fn_name:expr) => {
-        crate::winapiwrapper::error::WinApiError::FunctionCallFailure(
-            $fn_name.to_string(),
-            unsafe { winapi::um::errhandlingapi::GetLastError() },
-        )
+        crate::winapiwrapper::error::WinApiError::last_error($fn_name)
+    };
+}
+
+// Native API functions return an NTSTATUS instead of setting the last error
+macro_rules! nt_function_call_failure {
+    ($fn_name:expr, $status:expr) => {
+        crate::winapiwrapper::error::WinApiError::NtFunctionCallFailure {
+            function: $fn_name.to_string(),
+            status: $status as u32,
+        }
     };
 }
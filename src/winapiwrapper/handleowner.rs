@@ -0,0 +1,71 @@
+use std::ptr;
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::handleapi::{CloseHandle, DuplicateHandle};
+use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::winbase::{WAIT_ABANDONED, WAIT_FAILED, WAIT_OBJECT_0};
+use winapi::um::winnt::{DUPLICATE_SAME_ACCESS, HANDLE};
+
+// Outcome of waiting on an object
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitResult {
+    Signaled,
+    Timeout,
+    // A mutex whose owning thread exited without releasing it
+    Abandoned,
+}
+
+// Implemented by wrappers which own a handle to a kernel object
+pub trait HandleOwner {
+    fn handle(&self) -> HANDLE;
+
+    // Pseudo handles, such as the one returned by GetCurrentProcess, need not be closed
+    fn is_pseudo_handle(&self) -> bool {
+        false
+    }
+
+    // Waits up to `timeout` milliseconds (INFINITE to block) for the object to be signaled
+    // The handle needs SYNCHRONIZE
+    fn wait(&self, timeout: u32) -> anyhow::Result<WaitResult> {
+        let ret = unsafe { WaitForSingleObject(self.handle(), timeout) };
+
+        match ret {
+            WAIT_OBJECT_0 => Ok(WaitResult::Signaled),
+            WAIT_TIMEOUT => Ok(WaitResult::Timeout),
+            WAIT_ABANDONED => Ok(WaitResult::Abandoned),
+            WAIT_FAILED => Err(function_call_failure!("WaitForSingleObject").into()),
+            _ => bail!("Unexpected WaitForSingleObject result {:x}", ret),
+        }
+    }
+
+    // Returns a new handle with the same access to the same object, the caller owns it
+    // Duplicating a pseudo handle yields a real handle
+    fn try_duplicate(&self) -> anyhow::Result<HANDLE> {
+        let mut handle = ptr::null_mut();
+        let ret = unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                self.handle(),
+                GetCurrentProcess(),
+                &mut handle,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+
+        ensure!(ret != 0, function_call_failure!("DuplicateHandle"));
+
+        Ok(handle)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        if !self.is_pseudo_handle() {
+            let ret = unsafe { CloseHandle(self.handle()) };
+
+            ensure!(ret != 0, function_call_failure!("CloseHandle"));
+        }
+
+        Ok(())
+    }
+}
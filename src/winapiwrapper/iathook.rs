@@ -0,0 +1,109 @@
+use super::module::{self, Module};
+use super::process::Process;
+use super::virtualmem::ProtectFlag;
+use std::path::Path;
+
+// An IAT slot of a module in another process that was redirected to a new target
+// The original value is written back by unhook, dropping the hook leaves it in place
+pub struct IatHook {
+    iat_address: usize,
+    original: usize,
+    target: usize,
+    is_64bit: bool,
+}
+
+impl IatHook {
+    // Redirects the import of library!function in module to target, e.g.
+    // user32.dll!MessageBoxW to a function in a mapped image
+    // The library name is compared case-insensitively and the extension may be omitted
+    // The process handle needs PROCESS_VM_OPERATION, PROCESS_VM_READ and PROCESS_VM_WRITE
+    pub fn hook(
+        process: &Process,
+        module: &Module,
+        library: &str,
+        function: &str,
+        target: usize,
+    ) -> anyhow::Result<Self> {
+        let library = Path::new(library)
+            .with_extension("dll")
+            .to_str()
+            .ok_or_else(|| anyhow!("Failed to convert Path to str"))?
+            .to_ascii_lowercase();
+
+        let entry = module
+            .imports()?
+            .into_iter()
+            .filter(|descriptor| descriptor.library.eq_ignore_ascii_case(&library))
+            .flat_map(|descriptor| descriptor.entries)
+            .find(|entry| entry.name.as_deref() == Some(function))
+            .ok_or_else(|| anyhow!("Module does not import {}!{}", library, function))?;
+
+        let hook = Self {
+            iat_address: entry.iat_address,
+            original: entry.resolved,
+            target,
+            is_64bit: module::remote_image_is_64bit(process, module.info()?.lpBaseOfDll as usize)?,
+        };
+
+        hook.write_slot(process, target)?;
+
+        trace!(
+            "Hooked {}!{} at {:x}: {:x} -> {:x}",
+            library,
+            function,
+            hook.iat_address,
+            hook.original,
+            target
+        );
+
+        Ok(hook)
+    }
+
+    // Restores the original value of the slot
+    // Fails without writing if the slot no longer holds our target, e.g. because it was hooked again
+    pub fn unhook(self, process: &Process) -> anyhow::Result<()> {
+        let current = self.read_slot(process)?;
+        ensure!(
+            current == self.target,
+            "IAT slot at {:x} was changed to {:x}",
+            self.iat_address,
+            current
+        );
+
+        self.write_slot(process, self.original)
+    }
+
+    pub fn iat_address(&self) -> usize {
+        self.iat_address
+    }
+
+    // The address the slot held before it was hooked, calling it reaches the real import
+    pub fn original(&self) -> usize {
+        self.original
+    }
+
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    fn read_slot(&self, process: &Process) -> anyhow::Result<usize> {
+        Ok(if self.is_64bit {
+            process.read_value::<u64>(self.iat_address)? as usize
+        } else {
+            process.read_value::<u32>(self.iat_address)? as usize
+        })
+    }
+
+    // The IAT is commonly read-only once the loader has resolved it
+    fn write_slot(&self, process: &Process, value: usize) -> anyhow::Result<()> {
+        let bytes = if self.is_64bit {
+            (value as u64).to_le_bytes().to_vec()
+        } else {
+            (value as u32).to_le_bytes().to_vec()
+        };
+
+        process.write_memory_protected(&bytes, self.iat_address, ProtectFlag::PAGE_READWRITE)?;
+
+        Ok(())
+    }
+}
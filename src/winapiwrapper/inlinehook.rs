@@ -0,0 +1,376 @@
+use super::process::{Architecture, Process};
+use super::virtualmem::{AllocType, FreeType, ProtectFlag, VirtualMem};
+use std::convert::TryFrom;
+
+// Enough bytes for the longest jump plus the longest instruction that can straddle it
+const PROLOGUE_READ_LEN: usize = 0x20;
+
+// x86 instructions can't be longer than this
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+// jmp rel32
+const JMP_REL32_LEN: usize = 5;
+
+// jmp [rip+0] followed by the 64-bit target
+const JMP_ABS64_LEN: usize = 14;
+
+// A function in another process whose first instructions were replaced with a jump to a detour
+// The replaced instructions are moved to a trampoline, calling the trampoline runs the original
+// function. Hooking doesn't suspend the target, threads executing the prologue while it is
+// patched may crash, suspend the process first if that matters.
+pub struct InlineHook {
+    function: usize,
+    original: Vec<u8>,
+    trampoline: usize,
+}
+
+impl InlineHook {
+    // The process handle needs PROCESS_VM_OPERATION, PROCESS_VM_READ and PROCESS_VM_WRITE
+    pub fn hook(process: &Process, function: usize, detour: usize) -> anyhow::Result<Self> {
+        let is_64bit = match process.architecture()? {
+            Architecture::X86 => false,
+            Architecture::X64 => true,
+            architecture => bail!("Inline hooks are not supported on {}", architecture),
+        };
+
+        let jmp_len = if is_64bit && rel32(function + JMP_REL32_LEN, detour).is_none() {
+            JMP_ABS64_LEN
+        } else {
+            JMP_REL32_LEN
+        };
+
+        let mut code = [0_u8; PROLOGUE_READ_LEN];
+        process.read_memory(&mut code, function)?;
+
+        // Whole instructions are moved, so the stolen bytes may be longer than the jump
+        let mut stolen_len = 0;
+        while stolen_len < jmp_len {
+            stolen_len += instruction_len(&code[stolen_len..], is_64bit)?;
+        }
+
+        let original = code[..stolen_len].to_vec();
+
+        let mut trampoline_mem = VirtualMem::alloc(
+            process,
+            0,
+            stolen_len + JMP_ABS64_LEN,
+            AllocType::MEM_COMMIT | AllocType::MEM_RESERVE,
+            ProtectFlag::PAGE_EXECUTE_READWRITE,
+        )?;
+
+        // rel32 jumps wrap around in 32-bit code, but the trampoline still has to be addressable
+        ensure!(
+            is_64bit || trampoline_mem.address() + trampoline_mem.size() <= u32::MAX as usize,
+            "Trampoline for WOW64 process was allocated at {:x}, outside of the 32-bit address space",
+            trampoline_mem.address()
+        );
+
+        let trampoline = trampoline_mem.address();
+        let mut trampoline_code = original.clone();
+        trampoline_code.extend(jmp(
+            trampoline + stolen_len,
+            function + stolen_len,
+            is_64bit,
+        ));
+
+        trampoline_mem.write_memory(&trampoline_code, 0)?;
+        process.flush_instruction_cache(trampoline, trampoline_code.len())?;
+
+        // Pad the rest of the stolen instructions so the disassembly of the function stays sane
+        let mut patch = jmp(function, detour, is_64bit);
+        patch.resize(stolen_len, 0xcc);
+
+        process.write_memory_protected(&patch, function, ProtectFlag::PAGE_EXECUTE_READWRITE)?;
+        process.flush_instruction_cache(function, patch.len())?;
+        trampoline_mem.set_free_on_drop(false);
+
+        trace!(
+            "Hooked {:x} -> {:x} with trampoline at {:x}",
+            function,
+            detour,
+            trampoline
+        );
+
+        Ok(Self {
+            function,
+            original,
+            trampoline,
+        })
+    }
+
+    // Writes the original bytes back and frees the trampoline
+    // The detour must not be running or about to call the trampoline
+    pub fn unhook(self, process: &Process) -> anyhow::Result<()> {
+        process.write_memory_protected(
+            &self.original,
+            self.function,
+            ProtectFlag::PAGE_EXECUTE_READWRITE,
+        )?;
+        process.flush_instruction_cache(self.function, self.original.len())?;
+        process.virtual_free(self.trampoline, 0, FreeType::MEM_RELEASE)?;
+
+        trace!("Unhooked {:x}", self.function);
+
+        Ok(())
+    }
+
+    pub fn function(&self) -> usize {
+        self.function
+    }
+
+    // Runs the original function, detours call this instead of the hooked function
+    pub fn trampoline(&self) -> usize {
+        self.trampoline
+    }
+
+    // The instructions that were overwritten by the jump
+    pub fn original_bytes(&self) -> &[u8] {
+        &self.original
+    }
+}
+
+// Encodes a jump from `from` to `to`, 64-bit code uses an absolute jump if a rel32 can't reach
+fn jmp(from: usize, to: usize, is_64bit: bool) -> Vec<u8> {
+    let rel = if is_64bit {
+        rel32(from + JMP_REL32_LEN, to)
+    } else {
+        Some(to.wrapping_sub(from + JMP_REL32_LEN) as i32)
+    };
+
+    match rel {
+        Some(rel) => {
+            let mut code = vec![0xe9];
+            code.extend_from_slice(&rel.to_le_bytes());
+            code
+        }
+        None => {
+            let mut code = vec![0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+            code.extend_from_slice(&(to as u64).to_le_bytes());
+            code
+        }
+    }
+}
+
+// The displacement from the end of a rel32 instruction to `to`, if it fits
+fn rel32(next: usize, to: usize) -> Option<i32> {
+    i32::try_from((to as i64).wrapping_sub(next as i64)).ok()
+}
+
+// Length of the instruction at the start of code
+// Only the encodings that show up in function prologues need to be decoded. Relative branches
+// and RIP-relative operands would point elsewhere once moved to the trampoline and are rejected.
+fn instruction_len(code: &[u8], is_64bit: bool) -> anyhow::Result<usize> {
+    let mut len = 0;
+    let mut operand_size_16 = false;
+    let mut address_size_override = false;
+    let mut rex_w = false;
+
+    let next = |len: &mut usize| -> anyhow::Result<u8> {
+        ensure!(*len < MAX_INSTRUCTION_LEN, "Instruction is too long");
+        let byte = *code
+            .get(*len)
+            .ok_or_else(|| anyhow!("Instruction is truncated"))?;
+        *len += 1;
+
+        Ok(byte)
+    };
+
+    // Legacy prefixes
+    let mut opcode = loop {
+        match next(&mut len)? {
+            0x66 => operand_size_16 = true,
+            0x67 => address_size_override = true,
+            0xf0 | 0xf2 | 0xf3 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => (),
+            byte => break byte,
+        }
+    };
+
+    if is_64bit && (0x40..=0x4f).contains(&opcode) {
+        rex_w = opcode & 0x8 != 0;
+        opcode = next(&mut len)?;
+    }
+
+    ensure!(
+        is_64bit || !address_size_override,
+        "16-bit addressing is not supported"
+    );
+
+    let imm_z = if operand_size_16 { 2 } else { 4 };
+
+    // (has ModRM, immediate size)
+    let (has_modrm, imm_len) = match opcode {
+        0x0f => {
+            let opcode = next(&mut len)?;
+            match opcode {
+                0x38 => {
+                    next(&mut len)?;
+                    (true, 0)
+                }
+                0x3a => {
+                    next(&mut len)?;
+                    (true, 1)
+                }
+                0x80..=0x8f => bail!("Relative jumps can't be relocated"),
+                0x05..=0x09 | 0x0b | 0x0e | 0x30..=0x37 | 0x77 | 0xa0..=0xa2 | 0xa8..=0xaa => {
+                    (false, 0)
+                }
+                0xc8..=0xcf => (false, 0),
+                0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => (true, 1),
+                _ => (true, 0),
+            }
+        }
+        0x70..=0x7f | 0xe0..=0xe3 | 0xe8 | 0xe9 | 0xeb => {
+            bail!("Relative branches can't be relocated")
+        }
+        0xc2 | 0xc3 | 0xca | 0xcb | 0xcc => bail!("Function is too short to be hooked"),
+        0x9a | 0xea => bail!("Far branches can't be relocated"),
+        0x62 | 0xc4 | 0xc5 if is_64bit => bail!("VEX and EVEX instructions are not supported"),
+        0x00..=0x3f if opcode & 0x7 < 0x4 => (true, 0),
+        0x00..=0x3f if opcode & 0x7 == 0x4 => (false, 1),
+        0x00..=0x3f if opcode & 0x7 == 0x5 => (false, imm_z),
+        0x62 | 0x63 | 0x84..=0x8f | 0xc4 | 0xc5 | 0xd0..=0xd3 | 0xd8..=0xdf | 0xfe | 0xff => {
+            (true, 0)
+        }
+        0x69 | 0x81 | 0xc7 => (true, imm_z),
+        0x6b | 0x80 | 0x82 | 0x83 | 0xc0 | 0xc1 | 0xc6 => (true, 1),
+        0xf6 | 0xf7 => {
+            // Only test has an immediate
+            let reg = (code.get(len).copied().unwrap_or(0) >> 3) & 0x7;
+            let imm_len = match (opcode, reg) {
+                (0xf6, 0) | (0xf6, 1) => 1,
+                (0xf7, 0) | (0xf7, 1) => imm_z,
+                _ => 0,
+            };
+            (true, imm_len)
+        }
+        0x68 | 0xa9 => (false, imm_z),
+        0x6a | 0xa8 | 0xb0..=0xb7 | 0xcd | 0xd4 | 0xd5 | 0xe4..=0xe7 => (false, 1),
+        0xb8..=0xbf if rex_w => (false, 8),
+        0xb8..=0xbf => (false, imm_z),
+        0xc8 => (false, 3),
+        // mov with a memory offset, the offset has the size of an address
+        0xa0..=0xa3 => match (is_64bit, address_size_override) {
+            (true, false) => (false, 8),
+            _ => (false, 4),
+        },
+        _ => (false, 0),
+    };
+
+    if has_modrm {
+        let modrm = next(&mut len)?;
+        let mode = modrm >> 6;
+        let rm = modrm & 0x7;
+
+        if mode != 3 && rm == 4 {
+            let sib = next(&mut len)?;
+            if mode == 0 && sib & 0x7 == 5 {
+                len += 4;
+            }
+        }
+
+        match (mode, rm) {
+            (0, 5) if is_64bit => bail!("RIP-relative operand can't be relocated"),
+            (0, 5) | (2, _) => len += 4,
+            (1, _) => len += 1,
+            _ => (),
+        }
+    }
+
+    len += imm_len;
+
+    ensure!(len <= MAX_INSTRUCTION_LEN, "Instruction is too long");
+    ensure!(len <= code.len(), "Instruction is truncated");
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instruction_len_of_prologues() {
+        let cases: &[(&[u8], bool, usize)] = &[
+            // push ebp
+            (&[0x55], false, 1),
+            // mov edi, edi
+            (&[0x8b, 0xff], false, 2),
+            // sub rsp, 0x28
+            (&[0x48, 0x83, 0xec, 0x28], true, 4),
+            // mov [rsp + 8], rbx
+            (&[0x48, 0x89, 0x5c, 0x24, 0x08], true, 5),
+            // nop dword [rax + rax]
+            (&[0x0f, 0x1f, 0x44, 0x00, 0x00], true, 5),
+            // jmp [0x00000000], absolute on x86
+            (&[0xff, 0x25, 0x00, 0x00, 0x00, 0x00], false, 6),
+        ];
+
+        for &(code, is_64bit, len) in cases {
+            assert_eq!(
+                instruction_len(code, is_64bit).unwrap(),
+                len,
+                "{:02x?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn instruction_len_ignores_following_bytes() {
+        assert_eq!(instruction_len(&[0x55, 0x8b, 0xec], false).unwrap(), 1);
+    }
+
+    #[test]
+    fn instruction_len_rejects_relative_operands() {
+        let cases: &[(&[u8], bool)] = &[
+            // call rel32
+            (&[0xe8, 0x00, 0x00, 0x00, 0x00], true),
+            (&[0xe8, 0x00, 0x00, 0x00, 0x00], false),
+            // jmp rel32
+            (&[0xe9, 0x00, 0x00, 0x00, 0x00], true),
+            (&[0xe9, 0x00, 0x00, 0x00, 0x00], false),
+            // jmp [rip + 0]
+            (&[0xff, 0x25, 0x00, 0x00, 0x00, 0x00], true),
+        ];
+
+        for &(code, is_64bit) in cases {
+            assert!(instruction_len(code, is_64bit).is_err(), "{:02x?}", code);
+        }
+    }
+
+    #[test]
+    fn instruction_len_rejects_truncated_instructions() {
+        assert!(instruction_len(&[0x48, 0x83, 0xec], true).is_err());
+    }
+
+    #[test]
+    fn jmp_rel32() {
+        // 0x2000 - (0x1000 + 5)
+        assert_eq!(
+            jmp(0x1000, 0x2000, false),
+            vec![0xe9, 0xfb, 0x0f, 0x00, 0x00]
+        );
+        assert_eq!(
+            jmp(0x2000, 0x1000, true),
+            vec![0xe9, 0xfb, 0xef, 0xff, 0xff]
+        );
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn jmp_abs64_out_of_rel32_range() {
+        let to = 0x7fff_1234_5678_usize;
+        let mut expected = vec![0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+        expected.extend_from_slice(&(to as u64).to_le_bytes());
+
+        assert_eq!(jmp(0x1000, to, true), expected);
+        assert_eq!(jmp(0x1000, to, true).len(), JMP_ABS64_LEN);
+    }
+
+    #[test]
+    fn rel32_range() {
+        assert_eq!(rel32(0x1005, 0x1000), Some(-5));
+        assert_eq!(rel32(0x1000, 0x1000 + i32::MAX as usize), Some(i32::MAX));
+        assert_eq!(rel32(0x1000, 0x1000 + i32::MAX as usize + 1), None);
+    }
+}
@@ -1,8 +1,24 @@
 #[macro_use]
 pub mod error;
+pub mod apiset;
+#[cfg(feature = "authenticode")]
+pub mod authenticode;
+pub mod dump;
+pub mod handleowner;
+pub mod iathook;
+pub mod inlinehook;
 pub mod module;
+pub mod ntdll;
+pub mod peb;
+pub mod privileges;
 pub mod process;
+pub mod processbuilder;
+pub mod regkey;
+pub mod resource;
+pub mod scanner;
+pub mod section;
 pub mod snapshot;
 pub mod thread;
+pub mod token;
 pub mod virtualmem;
 pub mod window;
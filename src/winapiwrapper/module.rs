@@ -1,5 +1,18 @@
-use super::process::{Process, ProcessAccess};
-use pelite::{pe64::exports::Export, PeFile};
+use super::apiset::{is_api_set_name, ApiSetMap};
+use super::handleowner::HandleOwner;
+use super::process::{Process, ProcessAccess, ProcessKey};
+use super::resource::{self, ResourceId};
+use crate::injection::execution::{ExecutionMethod, ExecutionTimeout};
+use crate::injection::exportcache::{self, ExportKey};
+use crate::injection::registry::MappedModule;
+use pelite::image::{
+    IMAGE_DEBUG_TYPE_CODEVIEW, IMAGE_DIRECTORY_ENTRY_DEBUG, IMAGE_DIRECTORY_ENTRY_EXPORT,
+    IMAGE_DIRECTORY_ENTRY_IMPORT,
+};
+use pelite::{pe64::exports::Export, PeFile, Pod};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::convert::TryInto;
 use std::ffi::CString;
 use std::fs::OpenOptions;
 use std::io::Read;
@@ -11,7 +24,10 @@ use winapi::um::psapi::{
     self, EnumProcessModulesEx, GetModuleFileNameExA, GetModuleInformation, MODULEINFO,
 };
 use winapi::um::sysinfoapi::GetSystemDirectoryA;
-use winapi::um::winnt::LPSTR;
+use winapi::um::winnt::{
+    IMAGE_NT_OPTIONAL_HDR32_MAGIC, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_ORDINAL_FLAG32,
+    IMAGE_ORDINAL_FLAG64, LPCSTR, LPSTR,
+};
 
 bitflags! {
     pub struct ModulesFilterFlag: u32 {
@@ -22,10 +38,37 @@ bitflags! {
     }
 }
 
+// Export and forwarder names are well below this limit
+const MAX_EXPORT_NAME_LEN: usize = 0x200;
+
+// sizeof(IMAGE_DEBUG_DIRECTORY)
+const DEBUG_DIRECTORY_SIZE: usize = 28;
+
+// CvSignature of CV_INFO_PDB70, "RSDS"
+const CODEVIEW_PDB70_SIGNATURE: u32 = 0x5344_5352;
+
+// Exports can be looked up either by name or by ordinal
+enum ExportId<'a> {
+    Name(&'a str),
+    Ordinal(u16),
+}
+
+// What cached exports of a module in another process are checked against
+struct CacheIdentity {
+    process: ProcessKey,
+    // Path of the module, or the name of a manually mapped module
+    module: String,
+    size: usize,
+}
+
 pub struct Module {
     handle: HMODULE,
     pid_owning: u32,
     is_external: bool,
+    // Set for modules manually mapped by this crate, which the loader doesn't know about
+    mapped: Option<MappedModule>,
+    // Queried on the first cached export lookup rather than for every export
+    cache_identity: RefCell<Option<CacheIdentity>>,
 }
 
 impl Module {
@@ -39,33 +82,82 @@ impl Module {
     }
 
     pub fn find_or_load_external(pid: u32, path: &Path) -> anyhow::Result<Self> {
-        let process =
-            Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)?;
-
-        let path = fix_module_path(path, process.is_wow64()?)?;
-        let file_name = path
-            .file_name()
-            .ok_or_else(|| anyhow!("Expected a filename"))?
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to convert"))?;
+        let (module, path) = Self::find_external_resolved(pid, path)?;
 
         // Return the already loaded module if it exists
-        if let Some(module) = process.module_by_name(&file_name)? {
+        if let Some(module) = module {
             return Ok(module);
         }
 
         // TODO: Manual map external libraries when stable
-        match crate::injection::loadlibrary::inject_library(pid, &path) {
+        match crate::injection::loadlibrary::inject_library(
+            pid,
+            &path,
+            ExecutionMethod::CreateRemoteThread,
+            ExecutionTimeout::default(),
+            false,
+        ) {
             Ok(base) => Ok(unsafe { Self::from_handle(base as HMODULE, pid, true) }),
             Err(e) => Err(e),
         }
     }
 
+    // Like find_or_load_external but never loads the library into the process
+    pub fn find_external(pid: u32, path: &Path) -> anyhow::Result<Option<Self>> {
+        Ok(Self::find_external_resolved(pid, path)?.0)
+    }
+
+    // Also returns the path the library would be loaded from
+    pub fn find_external_resolved(
+        pid: u32,
+        path: &Path,
+    ) -> anyhow::Result<(Option<Self>, PathBuf)> {
+        let process = Process::from_pid(
+            pid,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        // API set contracts are redirected to their host library by the loader
+        let path = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if is_api_set_name(name) => {
+                match ApiSetMap::from_process(&process)?.resolve(name)? {
+                    Some(host) => PathBuf::from(host),
+                    None => bail!("API set {} has no host library", name),
+                }
+            }
+            _ => path.to_path_buf(),
+        };
+
+        let path = fix_module_path(&path, process.is_wow64()?)?;
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Expected a filename"))?
+            .to_str()
+            .ok_or_else(|| anyhow!("Failed to convert"))?;
+
+        Ok((process.module_by_name(file_name)?, path))
+    }
+
+    /// # Safety
+    /// `handle` must be the base of a module loaded in the process identified by `pid_owning`
     pub unsafe fn from_handle(handle: HMODULE, pid_owning: u32, is_external: bool) -> Self {
         Self {
             handle,
             pid_owning,
             is_external,
+            mapped: None,
+            cache_identity: RefCell::new(None),
+        }
+    }
+
+    pub fn from_mapped(mapped: MappedModule) -> Self {
+        Self {
+            handle: mapped.base as HMODULE,
+            pid_owning: mapped.pid,
+            is_external: true,
+            mapped: Some(mapped),
+            cache_identity: RefCell::new(None),
         }
     }
 
@@ -73,14 +165,72 @@ impl Module {
     // For forwarded exports
     pub fn proc_address(&self, proc_name: &str) -> anyhow::Result<usize> {
         match self.is_external {
-            true => self.proc_address_external(proc_name),
+            true => self.cached_proc_address(ExportId::Name(proc_name)),
             false => self.proc_address_internal(proc_name),
         }
     }
 
+    pub fn proc_address_by_ordinal(&self, ordinal: u16) -> anyhow::Result<usize> {
+        match self.is_external {
+            true => self.cached_proc_address(ExportId::Ordinal(ordinal)),
+            false => self.proc_address_by_ordinal_internal(ordinal),
+        }
+    }
+
+    // Exports of modules in other processes are remembered across lookups and injections
+    fn cached_proc_address(&self, export_id: ExportId) -> anyhow::Result<usize> {
+        let base = self.handle as usize;
+        let key = match export_id {
+            ExportId::Name(proc_name) => ExportKey::Name(proc_name.to_string()),
+            ExportId::Ordinal(ordinal) => ExportKey::Ordinal(ordinal),
+        };
+
+        if self.cache_identity.borrow().is_none() {
+            let identity = self.query_cache_identity()?;
+            *self.cache_identity.borrow_mut() = Some(identity);
+        }
+
+        let identity = self.cache_identity.borrow();
+        let identity = identity.as_ref().unwrap();
+
+        if let Some(address) = exportcache::find(identity.process, base, &identity.module, &key) {
+            return Ok(address);
+        }
+
+        // Mapped modules have no path, so their exports have to be read from memory
+        let address = match self.mapped {
+            Some(_) => self.proc_address_remote(export_id)?,
+            None => self.proc_address_external(export_id)?,
+        };
+
+        // Forwarded exports resolve into another module, which may be unloaded independently
+        if (base..base + identity.size).contains(&address) {
+            exportcache::insert(identity.process, base, &identity.module, key, address);
+        }
+
+        Ok(address)
+    }
+
+    fn query_cache_identity(&self) -> anyhow::Result<CacheIdentity> {
+        Ok(CacheIdentity {
+            process: ProcessKey::from_pid(self.pid_owning)?,
+            module: self.path()?.to_string_lossy().into_owned(),
+            size: self.info()?.SizeOfImage as usize,
+        })
+    }
+
     fn proc_address_internal(&self, proc_name: &str) -> anyhow::Result<usize> {
-        let proc_name = CString::new(proc_name)?.into_raw();
-        let addr = unsafe { GetProcAddress(self.handle, proc_name) };
+        let proc_name = CString::new(proc_name)?;
+        let addr = unsafe { GetProcAddress(self.handle, proc_name.as_ptr()) };
+
+        ensure!(!addr.is_null(), function_call_failure!("GetProcAddress"),);
+
+        Ok(addr as usize)
+    }
+
+    // GetProcAddress treats a name pointer with a zero high word as an ordinal
+    fn proc_address_by_ordinal_internal(&self, ordinal: u16) -> anyhow::Result<usize> {
+        let addr = unsafe { GetProcAddress(self.handle, ordinal as usize as LPCSTR) };
 
         ensure!(!addr.is_null(), function_call_failure!("GetProcAddress"),);
 
@@ -89,7 +239,7 @@ impl Module {
 
     // We load system modules from disk because we know the file location
     // And the proc offset will be the same
-    fn proc_address_external(&self, proc_name: &str) -> anyhow::Result<usize> {
+    fn proc_address_external(&self, export_id: ExportId) -> anyhow::Result<usize> {
         let proc = Process::from_pid(
             self.pid_owning,
             ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION,
@@ -109,35 +259,319 @@ impl Module {
         // TODO: Cache pe inside the Module struct - running it for every proc_address is expensive
         let exports_by = PeFile::from_bytes(&dll_bytes)?.exports()?.by()?;
 
-        ensure!(
-            exports_by.check_sorted()?,
-            "Function exports table is not sorted"
-        );
+        let export = match export_id {
+            ExportId::Name(proc_name) => {
+                ensure!(
+                    exports_by.check_sorted()?,
+                    "Function exports table is not sorted"
+                );
+
+                exports_by.name(proc_name)?
+            }
+            ExportId::Ordinal(ordinal) => exports_by.ordinal(ordinal)?,
+        };
 
         let base_address = self.info()?.lpBaseOfDll as usize;
 
-        match exports_by.name(proc_name)? {
+        match export {
             Export::Symbol(&rva) => Ok(rva as usize + base_address),
             Export::Forward(name) => {
-                // TODO: Check for ordinal forwarded exports
+                // Forwarders are formatted as <dll>.<name> or <dll>.#<ordinal>
+                // e.g. kernel32!HeapAlloc -> NTDLL.RtlAllocateHeap
                 let name = name.to_str()?;
+                let (dll, fwd_proc) = name
+                    .rsplit_once('.')
+                    .ok_or_else(|| anyhow!("Forwarded export {} is malformed", name))?;
+
+                let lib = Self::find_or_load_external(
+                    self.pid_owning,
+                    Path::new(&dll.to_ascii_lowercase()),
+                )?;
+
+                match fwd_proc.strip_prefix('#') {
+                    Some(ordinal) => lib.proc_address_by_ordinal(ordinal.parse()?),
+                    None => lib.proc_address(fwd_proc),
+                }
+            }
+        }
+    }
 
-                let v: Vec<&str> = name.split('.').take(2).collect();
-                ensure!(
-                    v.len() == 2,
-                    "Named forwarded export was not formatted properly"
-                );
+    // Walks the export directory of the image in the target's memory rather than the file on disk
+    // Unlike proc_address this also works for manually mapped modules
+    pub fn remote_proc_address(&self, proc_name: &str) -> anyhow::Result<usize> {
+        self.proc_address_remote(ExportId::Name(proc_name))
+    }
+
+    pub fn remote_proc_address_by_ordinal(&self, ordinal: u16) -> anyhow::Result<usize> {
+        self.proc_address_remote(ExportId::Ordinal(ordinal))
+    }
+
+    fn proc_address_remote(&self, export_id: ExportId) -> anyhow::Result<usize> {
+        let process = Process::from_pid(
+            self.pid_owning,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        let base = self.handle as usize;
+        let exports = RemoteExportDirectory::read(&process, base)?;
+
+        let index = match export_id {
+            ExportId::Name(proc_name) => {
+                // The name table is sorted, so binary search it
+                let mut low = 0;
+                let mut high = exports.number_of_names;
+                let mut found = None;
+
+                while low < high {
+                    let mid = (low + high) / 2;
+                    let name_rva = process.read_value::<u32>(exports.address_of_names + mid * 4)?;
+                    let name =
+                        process.read_cstring(base + name_rva as usize, MAX_EXPORT_NAME_LEN)?;
+
+                    match name.as_bytes().cmp(proc_name.as_bytes()) {
+                        Ordering::Less => low = mid + 1,
+                        Ordering::Greater => high = mid,
+                        Ordering::Equal => {
+                            found = Some(mid);
+                            break;
+                        }
+                    }
+                }
+
+                let name_index =
+                    found.ok_or_else(|| anyhow!("Export {} was not found", proc_name))?;
+
+                process.read_value::<u16>(exports.address_of_name_ordinals + name_index * 2)? as u32
+            }
+            ExportId::Ordinal(ordinal) => (ordinal as u32)
+                .checked_sub(exports.ordinal_base)
+                .ok_or_else(|| anyhow!("Export ordinal {} was not found", ordinal))?,
+        };
+
+        ensure!(
+            index < exports.number_of_functions,
+            "Export index out of range"
+        );
+
+        let function_rva =
+            process.read_value::<u32>(exports.address_of_functions + index as usize * 4)?;
+
+        ensure!(function_rva != 0, "Export has no address");
+
+        let name = match exports.forwarder(&process, function_rva)? {
+            Some(name) => name,
+            None => return Ok(base + function_rva as usize),
+        };
+
+        let (dll, fwd_proc) = name
+            .rsplit_once('.')
+            .ok_or_else(|| anyhow!("Forwarded export {} is malformed", name))?;
+
+        let lib =
+            Self::find_or_load_external(self.pid_owning, Path::new(&dll.to_ascii_lowercase()))?;
+
+        match fwd_proc.strip_prefix('#') {
+            Some(ordinal) => lib.proc_address_by_ordinal(ordinal.parse()?),
+            None => lib.proc_address(fwd_proc),
+        }
+    }
+
+    // Lists every export of the module by reading its export directory from the owning process
+    // Unused slots in the function table are skipped
+    pub fn exports(&self) -> anyhow::Result<Vec<ExportEntry>> {
+        let process = Process::from_pid(
+            self.pid_owning,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        let base = self.handle as usize;
+        let exports = RemoteExportDirectory::read(&process, base)?;
 
-                let (dll, fwd_proc_name) =
-                    (v.get(0).unwrap().to_ascii_lowercase(), v.get(1).unwrap());
-                let lib = Self::find_or_load_external(self.pid_owning, Path::new(&dll))?;
+        let functions = read_table::<u32>(
+            &process,
+            exports.address_of_functions,
+            exports.number_of_functions as usize,
+        )?;
+        let name_rvas =
+            read_table::<u32>(&process, exports.address_of_names, exports.number_of_names)?;
+        let name_ordinals = read_table::<u16>(
+            &process,
+            exports.address_of_name_ordinals,
+            exports.number_of_names,
+        )?;
+
+        // Map function indices to their names, exports by ordinal only have no name
+        let mut names = vec![None; functions.len()];
+        for (&name_rva, &index) in name_rvas.iter().zip(&name_ordinals) {
+            if let Some(name) = names.get_mut(index as usize) {
+                *name = Some(process.read_cstring(base + name_rva as usize, MAX_EXPORT_NAME_LEN)?);
+            }
+        }
 
-                lib.proc_address_external(fwd_proc_name)
+        let mut entries = Vec::new();
+        for (index, (&rva, name)) in functions.iter().zip(names).enumerate() {
+            if rva == 0 {
+                continue;
             }
+
+            entries.push(ExportEntry {
+                name,
+                ordinal: (exports.ordinal_base + index as u32) as u16,
+                rva,
+                forwarder: exports.forwarder(&process, rva)?,
+            });
         }
+
+        Ok(entries)
+    }
+
+    // Lists the import descriptors of the module with the values currently in its IAT
+    // Libraries bound without an import name table only report the resolved addresses
+    pub fn imports(&self) -> anyhow::Result<Vec<ImportDescriptor>> {
+        let process = Process::from_pid(
+            self.pid_owning,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        let base = self.handle as usize;
+        let (import_dir_rva, _) =
+            remote_data_directory(&process, base, IMAGE_DIRECTORY_ENTRY_IMPORT)?;
+
+        if import_dir_rva == 0 {
+            return Ok(Vec::new());
+        }
+
+        let is_64bit = remote_image_is_64bit(&process, base)?;
+        let (thunk_size, ordinal_flag) = if is_64bit {
+            (8, IMAGE_ORDINAL_FLAG64)
+        } else {
+            (4, IMAGE_ORDINAL_FLAG32 as u64)
+        };
+
+        let read_thunk = |address: usize| -> anyhow::Result<u64> {
+            Ok(if is_64bit {
+                process.read_value::<u64>(address)?
+            } else {
+                process.read_value::<u32>(address)? as u64
+            })
+        };
+
+        let mut descriptors = Vec::new();
+
+        // IMAGE_IMPORT_DESCRIPTOR, the table ends with a zeroed descriptor
+        let mut descriptor = base + import_dir_rva as usize;
+        loop {
+            let original_first_thunk = process.read_value::<u32>(descriptor)?;
+            let name_rva = process.read_value::<u32>(descriptor + 0xc)?;
+            let first_thunk = process.read_value::<u32>(descriptor + 0x10)?;
+
+            if name_rva == 0 && first_thunk == 0 {
+                break;
+            }
+
+            let library = process.read_cstring(base + name_rva as usize, MAX_EXPORT_NAME_LEN)?;
+
+            let mut entries = Vec::new();
+            for index in 0.. {
+                let iat_address = base + first_thunk as usize + index * thunk_size;
+                let resolved = read_thunk(iat_address)?;
+
+                if resolved == 0 {
+                    break;
+                }
+
+                let (name, ordinal) = if original_first_thunk == 0 {
+                    (None, None)
+                } else {
+                    let thunk =
+                        read_thunk(base + original_first_thunk as usize + index * thunk_size)?;
+                    if thunk & ordinal_flag != 0 {
+                        (None, Some(thunk as u16))
+                    } else {
+                        // IMAGE_IMPORT_BY_NAME, the name follows the hint
+                        let name =
+                            process.read_cstring(base + thunk as usize + 2, MAX_EXPORT_NAME_LEN)?;
+                        (Some(name), None)
+                    }
+                };
+
+                entries.push(ImportEntry {
+                    name,
+                    ordinal,
+                    iat_address,
+                    resolved: resolved as usize,
+                });
+            }
+
+            descriptors.push(ImportDescriptor { library, entries });
+            descriptor += 0x14;
+        }
+
+        Ok(descriptors)
+    }
+
+    // Reads a resource of the module from the owning process
+    pub fn resource(
+        &self,
+        resource_type: &ResourceId,
+        name: &ResourceId,
+    ) -> anyhow::Result<Vec<u8>> {
+        let process = Process::from_pid(
+            self.pid_owning,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        resource::read_resource(&process, self.handle as usize, resource_type, name)
+    }
+
+    // Reads the CodeView record of the module's debug directory from the owning process
+    // Returns None if the module has no PDB information, e.g. because it was built without it
+    pub fn codeview(&self) -> anyhow::Result<Option<CodeViewInfo>> {
+        let process = Process::from_pid(
+            self.pid_owning,
+            ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION | ProcessAccess::PROCESS_VM_READ,
+            false,
+        )?;
+
+        let base = self.handle as usize;
+        let (debug_dir_rva, debug_dir_size) =
+            remote_data_directory(&process, base, IMAGE_DIRECTORY_ENTRY_DEBUG)?;
+
+        // IMAGE_DEBUG_DIRECTORY, the loader maps the data at AddressOfRawData
+        for index in 0..debug_dir_size as usize / DEBUG_DIRECTORY_SIZE {
+            let entry = base + debug_dir_rva as usize + index * DEBUG_DIRECTORY_SIZE;
+            let debug_type = process.read_value::<u32>(entry + 0xc)?;
+            let size_of_data = process.read_value::<u32>(entry + 0x10)?;
+            let address_of_raw_data = process.read_value::<u32>(entry + 0x14)?;
+
+            if debug_type != IMAGE_DEBUG_TYPE_CODEVIEW || address_of_raw_data == 0 {
+                continue;
+            }
+
+            let mut data = vec![0; size_of_data as usize];
+            process.read_memory(&mut data, base + address_of_raw_data as usize)?;
+
+            if let Some(info) = CodeViewInfo::parse(&data) {
+                return Ok(Some(info));
+            }
+        }
+
+        Ok(None)
     }
 
     pub fn info(&self) -> anyhow::Result<MODULEINFO> {
+        if let Some(mapped) = &self.mapped {
+            return Ok(MODULEINFO {
+                lpBaseOfDll: mapped.base as LPVOID,
+                SizeOfImage: mapped.size as u32,
+                EntryPoint: 0 as LPVOID,
+            });
+        }
+
         let process = Process::from_pid(
             self.pid_owning,
             ProcessAccess::PROCESS_QUERY_INFORMATION | ProcessAccess::PROCESS_VM_READ,
@@ -164,7 +598,12 @@ impl Module {
         Ok(info)
     }
 
+    // Mapped modules only have the name they were registered under
     pub fn path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(mapped) = &self.mapped {
+            return Ok(PathBuf::from(&mapped.name));
+        }
+
         let proc = Process::from_pid(
             self.pid_owning,
             ProcessAccess::PROCESS_QUERY_INFORMATION | ProcessAccess::PROCESS_VM_READ,
@@ -202,6 +641,199 @@ impl Module {
     }
 }
 
+// An export of a module
+#[derive(Clone, Debug)]
+pub struct ExportEntry {
+    pub name: Option<String>,
+    pub ordinal: u16,
+    pub rva: u32,
+    // "library.Function" or "library.#ordinal" if the export is forwarded to another library
+    pub forwarder: Option<String>,
+}
+
+// A library imported by a module
+#[derive(Clone, Debug)]
+pub struct ImportDescriptor {
+    pub library: String,
+    pub entries: Vec<ImportEntry>,
+}
+
+// A function imported by a module, name and ordinal are None if the module has no import name table
+#[derive(Clone, Debug)]
+pub struct ImportEntry {
+    pub name: Option<String>,
+    pub ordinal: Option<u16>,
+    // Address of the IAT slot in the owning process
+    pub iat_address: usize,
+    // Value currently stored in the IAT slot
+    pub resolved: usize,
+}
+
+// The CV_INFO_PDB70 record that identifies the PDB a module was built with
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeViewInfo {
+    // Raw GUID, Data1 to Data3 are little endian
+    pub guid: [u8; 16],
+    pub age: u32,
+    // As written by the linker, usually an absolute path on the build machine
+    pub pdb_path: String,
+}
+
+impl CodeViewInfo {
+    // Parses the data of a CodeView debug directory entry
+    // Only the PDB 7.0 format is supported, older formats have no GUID
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        if u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) != CODEVIEW_PDB70_SIGNATURE {
+            return None;
+        }
+
+        let path = data.get(24..)?;
+        let path = &path[..path.iter().position(|&c| c == 0).unwrap_or(path.len())];
+
+        Some(Self {
+            guid: data.get(4..20)?.try_into().ok()?,
+            age: u32::from_le_bytes(data.get(20..24)?.try_into().ok()?),
+            pdb_path: String::from_utf8_lossy(path).into_owned(),
+        })
+    }
+
+    // The GUID in registry format, e.g. {01234567-89AB-CDEF-0123-456789ABCDEF}
+    pub fn guid_string(&self) -> String {
+        let g = &self.guid;
+        format!(
+            "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9],
+            g[10],
+            g[11],
+            g[12],
+            g[13],
+            g[14],
+            g[15]
+        )
+    }
+
+    // The directory a symbol server stores the PDB under, the GUID without separators followed
+    // by the age in hex, e.g. <symbol path>/<pdb name>/<symbol_key>/<pdb name>
+    pub fn symbol_key(&self) -> String {
+        let guid: String = self
+            .guid_string()
+            .chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .collect();
+
+        format!("{}{:X}", guid, self.age)
+    }
+}
+
+// The fields of IMAGE_EXPORT_DIRECTORY needed to walk it, addresses are absolute
+struct RemoteExportDirectory {
+    base: usize,
+    rva: u32,
+    size: u32,
+    ordinal_base: u32,
+    number_of_functions: u32,
+    number_of_names: usize,
+    address_of_functions: usize,
+    address_of_names: usize,
+    address_of_name_ordinals: usize,
+}
+
+impl RemoteExportDirectory {
+    fn read(process: &Process, base: usize) -> anyhow::Result<Self> {
+        let (rva, size) = remote_data_directory(process, base, IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+
+        ensure!(rva != 0, "Module has no export directory");
+
+        let export_dir = base + rva as usize;
+        let read_u32 = |offset: usize| process.read_value::<u32>(export_dir + offset);
+
+        Ok(Self {
+            base,
+            rva,
+            size,
+            ordinal_base: read_u32(0x10)?,
+            number_of_functions: read_u32(0x14)?,
+            number_of_names: read_u32(0x18)? as usize,
+            address_of_functions: base + read_u32(0x1c)? as usize,
+            address_of_names: base + read_u32(0x20)? as usize,
+            address_of_name_ordinals: base + read_u32(0x24)? as usize,
+        })
+    }
+
+    // Exports pointing into the export directory are forwarder strings
+    fn forwarder(&self, process: &Process, function_rva: u32) -> anyhow::Result<Option<String>> {
+        if function_rva < self.rva || function_rva - self.rva >= self.size {
+            return Ok(None);
+        }
+
+        Ok(Some(process.read_cstring(
+            self.base + function_rva as usize,
+            MAX_EXPORT_NAME_LEN,
+        )?))
+    }
+}
+
+pub(super) fn remote_image_is_64bit(process: &Process, base: usize) -> anyhow::Result<bool> {
+    let nt_headers = base + process.read_value::<u32>(base + 0x3c)? as usize;
+    match process.read_value::<u16>(nt_headers + 0x18)? {
+        IMAGE_NT_OPTIONAL_HDR32_MAGIC => Ok(false),
+        IMAGE_NT_OPTIONAL_HDR64_MAGIC => Ok(true),
+        magic => bail!("Unknown optional header magic {:x}", magic),
+    }
+}
+
+// Reads a data directory of an image mapped in the process, returns (rva, size)
+// Images without the directory return (0, 0)
+pub(super) fn remote_data_directory(
+    process: &Process,
+    base: usize,
+    index: usize,
+) -> anyhow::Result<(u32, u32)> {
+    let nt_headers = base + process.read_value::<u32>(base + 0x3c)? as usize;
+    let optional_header = nt_headers + 0x18;
+    let (number_of_directories, data_directories) =
+        match process.read_value::<u16>(optional_header)? {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => (
+                process.read_value::<u32>(optional_header + 0x5c)?,
+                optional_header + 0x60,
+            ),
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => (
+                process.read_value::<u32>(optional_header + 0x6c)?,
+                optional_header + 0x70,
+            ),
+            magic => bail!("Unknown optional header magic {:x}", magic),
+        };
+
+    if index >= number_of_directories as usize {
+        return Ok((0, 0));
+    }
+
+    let directory = data_directories + index * 8;
+
+    Ok((
+        process.read_value::<u32>(directory)?,
+        process.read_value::<u32>(directory + 4)?,
+    ))
+}
+
+// Reads an array of len elements
+fn read_table<T: Pod + Clone + Default>(
+    process: &Process,
+    address: usize,
+    len: usize,
+) -> anyhow::Result<Vec<T>> {
+    let mut table = vec![T::default(); len];
+    if len != 0 {
+        process.read_memory(table.as_mut_slice().as_bytes_mut(), address)?;
+    }
+
+    Ok(table)
+}
+
 // Modules struct
 // Iterates through the loaded modules in a process
 pub struct Modules {
@@ -320,7 +952,7 @@ pub fn is_system_module(module_path: &Path) -> anyhow::Result<bool> {
 }
 
 // Applies transformations to the path, such as
-// c runtime path correction, system path to wow64 path correction
+// system path to wow64 path correction
 // Input paths must be lowercase
 // TODO: file name to full path - imports only contain dll file name so we need to search
 // https://docs.microsoft.com/en-us/windows/win32/dlls/dynamic-link-library-search-order
@@ -328,24 +960,10 @@ fn fix_module_path(path: &Path, is_wow64_module: bool) -> anyhow::Result<PathBuf
     let mut path = path.to_path_buf();
     path.set_extension("dll");
 
-    let file_name = path
-        .file_name()
-        .ok_or_else(|| anyhow!("Module path did not contain a filename"))?
-        .to_str()
-        .ok_or_else(|| anyhow!("Failed to convert OsStr to str"))?
-        .to_ascii_lowercase();
-
     // Steps
     // If just file name, find file and convert to full path
-    // If file_name is a crt library, set path to C:\Windows\System32\downlevel\<lib>
     // If is_system_module && is_wow64_module, prepend wow64_dir to path
 
-    if file_name.starts_with("api-ms-win-crt-") {
-        path.push(get_system_dir()?);
-        path.push("downlevel\\");
-        path.push(file_name);
-    }
-
     if is_wow64_module && is_system_module(&path)? {
         path = system_module_path_to_wow64_path(&path)?;
     }
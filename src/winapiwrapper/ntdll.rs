@@ -0,0 +1,115 @@
+use super::module::Module;
+use std::mem;
+use winapi::shared::ntdef::{NTSTATUS, PLARGE_INTEGER, PVOID};
+use winapi::um::winnt::HANDLE;
+
+// Native API functions which winapi does not provide bindings for
+// https://docs.microsoft.com/en-us/windows/win32/api/winternl/nf-winternl-ntqueryinformationprocess
+pub type FnNtQueryInformationProcess =
+    unsafe extern "system" fn(HANDLE, u32, PVOID, u32, *mut u32) -> NTSTATUS;
+
+// https://docs.microsoft.com/en-us/windows/win32/devnotes/ntcreatethreadex
+pub type FnNtCreateThreadEx = unsafe extern "system" fn(
+    *mut HANDLE,
+    u32,
+    PVOID,
+    HANDLE,
+    PVOID,
+    PVOID,
+    u32,
+    usize,
+    usize,
+    usize,
+    PVOID,
+) -> NTSTATUS;
+
+pub type FnNtQueueApcThread =
+    unsafe extern "system" fn(HANDLE, PVOID, PVOID, PVOID, PVOID) -> NTSTATUS;
+
+pub type FnNtSuspendProcess = unsafe extern "system" fn(HANDLE) -> NTSTATUS;
+
+pub type FnNtResumeProcess = unsafe extern "system" fn(HANDLE) -> NTSTATUS;
+
+// Only exported by the ntdll of WOW64 processes, they take 64-bit addresses so that
+// 32-bit processes can access the memory of 64-bit processes
+pub type FnNtWow64ReadVirtualMemory64 =
+    unsafe extern "system" fn(HANDLE, u64, PVOID, u64, *mut u64) -> NTSTATUS;
+
+pub type FnNtWow64WriteVirtualMemory64 =
+    unsafe extern "system" fn(HANDLE, u64, PVOID, u64, *mut u64) -> NTSTATUS;
+
+pub type FnNtWow64QueryInformationProcess64 =
+    unsafe extern "system" fn(HANDLE, u32, PVOID, u32, *mut u32) -> NTSTATUS;
+
+// https://docs.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-zwcreatesection
+pub type FnNtCreateSection = unsafe extern "system" fn(
+    *mut HANDLE,
+    u32,
+    PVOID,
+    PLARGE_INTEGER,
+    u32,
+    u32,
+    HANDLE,
+) -> NTSTATUS;
+
+// https://docs.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-zwmapviewofsection
+pub type FnNtMapViewOfSection = unsafe extern "system" fn(
+    HANDLE,
+    HANDLE,
+    *mut PVOID,
+    usize,
+    usize,
+    PLARGE_INTEGER,
+    *mut usize,
+    u32,
+    u32,
+    u32,
+) -> NTSTATUS;
+
+pub type FnNtUnmapViewOfSection = unsafe extern "system" fn(HANDLE, PVOID) -> NTSTATUS;
+
+// SECTION_INHERIT value, the view isn't mapped into child processes
+pub const VIEW_UNMAP: u32 = 2;
+
+// NtCreateThreadEx flags
+pub const THREAD_CREATE_FLAGS_CREATE_SUSPENDED: u32 = 0x1;
+
+// PROCESSINFOCLASS values
+pub const PROCESS_BASIC_INFORMATION_CLASS: u32 = 0;
+// Returns the address of the 32-bit PEB, or null if the process isn't running under WOW64
+pub const PROCESS_WOW64_INFORMATION_CLASS: u32 = 26;
+// Returns a PS_PROTECTION byte, added in Windows 8.1
+pub const PROCESS_PROTECTION_INFORMATION_CLASS: u32 = 61;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct ProcessBasicInformation {
+    pub exit_status: NTSTATUS,
+    pub peb_base_address: usize,
+    pub affinity_mask: usize,
+    pub base_priority: i32,
+    pub unique_process_id: usize,
+    pub inherited_from_unique_process_id: usize,
+}
+
+// PROCESS_BASIC_INFORMATION of a 64-bit process as returned to a 32-bit process
+#[repr(C)]
+#[derive(Default)]
+pub struct ProcessBasicInformation64 {
+    pub exit_status: NTSTATUS,
+    pub peb_base_address: u64,
+    pub affinity_mask: u64,
+    pub base_priority: i32,
+    pub unique_process_id: u64,
+    pub inherited_from_unique_process_id: u64,
+}
+
+/// Resolves an export of ntdll in the current process
+///
+/// # Safety
+/// `T` must be the function pointer type matching the signature of the export
+pub unsafe fn resolve<T: Copy>(name: &str) -> anyhow::Result<T> {
+    let address = Module::find_or_load_internal("ntdll.dll")?.proc_address(name)?;
+
+    Ok(mem::transmute_copy::<usize, T>(&address))
+}
@@ -0,0 +1,191 @@
+use super::process::Process;
+use pelite::Pod;
+
+// Start of the PEB, up to and including ProcessParameters
+// https://www.geoffchappell.com/studies/windows/km/ntoskrnl/inc/api/pebteb/peb/index.htm
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PebHeader32 {
+    _inherited_address_space: u8,
+    _read_image_file_exec_options: u8,
+    being_debugged: u8,
+    _bit_field: u8,
+    _mutant: u32,
+    image_base_address: u32,
+    ldr: u32,
+    process_parameters: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PebHeader64 {
+    _inherited_address_space: u8,
+    _read_image_file_exec_options: u8,
+    being_debugged: u8,
+    _bit_field: u8,
+    _padding: [u8; 4],
+    _mutant: u64,
+    image_base_address: u64,
+    ldr: u64,
+    process_parameters: u64,
+}
+
+// Offsets into RTL_USER_PROCESS_PARAMETERS
+// https://www.geoffchappell.com/studies/windows/km/ntoskrnl/inc/api/pebteb/rtl_user_process_parameters.htm
+const OFFSET_PARAMS_CURRENT_DIRECTORY32: usize = 0x24;
+const OFFSET_PARAMS_COMMAND_LINE32: usize = 0x40;
+const OFFSET_PARAMS_ENVIRONMENT32: usize = 0x48;
+const OFFSET_PARAMS_ENVIRONMENT_SIZE32: usize = 0x290;
+
+const OFFSET_PARAMS_CURRENT_DIRECTORY64: usize = 0x38;
+const OFFSET_PARAMS_COMMAND_LINE64: usize = 0x70;
+const OFFSET_PARAMS_ENVIRONMENT64: usize = 0x80;
+const OFFSET_PARAMS_ENVIRONMENT_SIZE64: usize = 0x3f0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UnicodeString32 {
+    length: u16,
+    _maximum_length: u16,
+    buffer: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UnicodeString64 {
+    length: u16,
+    _maximum_length: u16,
+    _padding: u32,
+    buffer: u64,
+}
+
+unsafe impl Pod for PebHeader32 {}
+unsafe impl Pod for PebHeader64 {}
+unsafe impl Pod for UnicodeString32 {}
+unsafe impl Pod for UnicodeString64 {}
+
+// Process environment block of a process
+// For WOW64 processes this is the 32-bit PEB, which is the one the process's own code uses
+#[derive(Clone, Copy, Debug)]
+pub struct Peb {
+    pub address: usize,
+    pub is_wow64: bool,
+    pub being_debugged: bool,
+    pub image_base_address: usize,
+    // PEB_LDR_DATA
+    pub ldr: usize,
+    // RTL_USER_PROCESS_PARAMETERS
+    pub process_parameters: usize,
+}
+
+impl Peb {
+    pub fn from_process(process: &Process) -> anyhow::Result<Self> {
+        if let Some(address) = process.peb32_address()? {
+            let header = process.read_value::<PebHeader32>(address)?;
+
+            return Ok(Self {
+                address,
+                is_wow64: true,
+                being_debugged: header.being_debugged != 0,
+                image_base_address: header.image_base_address as usize,
+                ldr: header.ldr as usize,
+                process_parameters: header.process_parameters as usize,
+            });
+        }
+
+        let address = process.peb_address()?;
+        let header = process.read_value::<PebHeader64>(address)?;
+
+        Ok(Self {
+            address,
+            is_wow64: false,
+            being_debugged: header.being_debugged != 0,
+            image_base_address: header.image_base_address as usize,
+            ldr: header.ldr as usize,
+            process_parameters: header.process_parameters as usize,
+        })
+    }
+
+    pub fn command_line(&self, process: &Process) -> anyhow::Result<String> {
+        read_parameter_string(
+            process,
+            self,
+            OFFSET_PARAMS_COMMAND_LINE32,
+            OFFSET_PARAMS_COMMAND_LINE64,
+        )
+    }
+
+    // DosPath of CurrentDirectory, it ends with a backslash
+    pub fn current_directory(&self, process: &Process) -> anyhow::Result<String> {
+        read_parameter_string(
+            process,
+            self,
+            OFFSET_PARAMS_CURRENT_DIRECTORY32,
+            OFFSET_PARAMS_CURRENT_DIRECTORY64,
+        )
+    }
+
+    // Environment variables formatted as NAME=VALUE
+    pub fn environment(&self, process: &Process) -> anyhow::Result<Vec<String>> {
+        let (address, size) = if self.is_wow64 {
+            (
+                process.read_value::<u32>(self.process_parameters + OFFSET_PARAMS_ENVIRONMENT32)?
+                    as usize,
+                process
+                    .read_value::<u32>(self.process_parameters + OFFSET_PARAMS_ENVIRONMENT_SIZE32)?
+                    as usize,
+            )
+        } else {
+            (
+                process.read_value::<u64>(self.process_parameters + OFFSET_PARAMS_ENVIRONMENT64)?
+                    as usize,
+                process
+                    .read_value::<u64>(self.process_parameters + OFFSET_PARAMS_ENVIRONMENT_SIZE64)?
+                    as usize,
+            )
+        };
+
+        if address == 0 || size == 0 {
+            return Ok(Vec::new());
+        }
+
+        // The block is a sequence of null-terminated strings ending with an empty string
+        let block = read_wide(process, address, size)?;
+
+        block
+            .split(|&c| c == 0)
+            .take_while(|entry| !entry.is_empty())
+            .map(|entry| Ok(String::from_utf16(entry)?))
+            .collect()
+    }
+}
+
+// Reads a UNICODE_STRING of RTL_USER_PROCESS_PARAMETERS
+fn read_parameter_string(
+    process: &Process,
+    peb: &Peb,
+    offset32: usize,
+    offset64: usize,
+) -> anyhow::Result<String> {
+    let (length, buffer) = if peb.is_wow64 {
+        let string = process.read_value::<UnicodeString32>(peb.process_parameters + offset32)?;
+        (string.length as usize, string.buffer as usize)
+    } else {
+        let string = process.read_value::<UnicodeString64>(peb.process_parameters + offset64)?;
+        (string.length as usize, string.buffer as usize)
+    };
+
+    if length == 0 {
+        return Ok(String::new());
+    }
+
+    Ok(String::from_utf16(&read_wide(process, buffer, length)?)?)
+}
+
+// Reads a UTF-16 buffer, len is in bytes
+fn read_wide(process: &Process, address: usize, len: usize) -> anyhow::Result<Vec<u16>> {
+    let mut buf = vec![0_u16; len / 2];
+    process.read_memory(buf.as_mut_slice().as_bytes_mut(), address)?;
+
+    Ok(buf)
+}
@@ -0,0 +1,14 @@
+use super::token::{Token, TokenAccess};
+use winapi::um::winnt::SE_DEBUG_NAME;
+
+// Opening processes owned by other users or running at a higher integrity level requires SeDebugPrivilege
+pub fn enable_debug_privilege() -> anyhow::Result<()> {
+    enable_privilege(SE_DEBUG_NAME)
+}
+
+// Enables a privilege, e.g. "SeDebugPrivilege", in the token of the current process
+// The privilege has to be held by the token, enabling it fails otherwise
+pub fn enable_privilege(name: &str) -> anyhow::Result<()> {
+    Token::from_current(TokenAccess::TOKEN_ADJUST_PRIVILEGES | TokenAccess::TOKEN_QUERY)?
+        .adjust_privilege(name, true)
+}
@@ -1,20 +1,199 @@
 use super::error::WinApiError;
+use super::handleowner::{HandleOwner, WaitResult};
 use super::module::{Module, Modules, ModulesFilterFlag};
-use super::virtualmem::ProtectFlag;
-use std::mem::size_of;
+use super::ntdll;
+use super::peb::Peb;
+use super::scanner;
+use super::snapshot::Snapshot;
+use super::thread::{Thread, ThreadAccess, Threads};
+use super::virtualmem::{FreeType, MemoryRegion, MemoryRegions, ProtectFlag};
+use crate::injection::cancellation::CancellationToken;
+use crate::injection::registry;
+use pelite::Pod;
+use std::fmt;
+use std::io::Write;
+use std::mem::{self, size_of};
 use std::ops::Drop;
 use std::path::Path;
 use std::path::PathBuf;
+use std::ptr;
 use winapi::ctypes::c_void;
-use winapi::shared::minwindef::{HMODULE, LPCVOID, LPVOID};
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::memoryapi::{ReadProcessMemory, VirtualProtectEx, WriteProcessMemory};
+use winapi::shared::minwindef::{BOOL, FILETIME, HMODULE, LPCVOID, LPVOID};
+use winapi::shared::ntdef::{NT_SUCCESS, PVOID};
+use winapi::shared::ntstatus::STATUS_INVALID_INFO_CLASS;
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::memoryapi::{
+    ReadProcessMemory, VirtualFreeEx, VirtualProtectEx, WriteProcessMemory,
+};
+use winapi::um::minwinbase::STILL_ACTIVE;
 use winapi::um::processthreadsapi::{
-    GetCurrentProcess, GetCurrentProcessId, GetProcessId, OpenProcess,
+    FlushInstructionCache, GetCurrentProcess, GetCurrentProcessId, GetExitCodeProcess,
+    GetProcessId, GetProcessTimes, OpenProcess, TerminateProcess,
 };
 use winapi::um::psapi::{EnumProcesses, GetModuleFileNameExA};
-use winapi::um::winnt::{self, HANDLE, IMAGE_FILE_MACHINE_UNKNOWN, LPSTR};
-use winapi::um::wow64apiset::IsWow64Process2;
+use winapi::um::sysinfoapi::{GetNativeSystemInfo, SYSTEM_INFO};
+use winapi::um::winnt::{
+    self, ProcessControlFlowGuardPolicy, ProcessDynamicCodePolicy, ProcessSignaturePolicy,
+    ProcessStrictHandleCheckPolicy, CFG_CALL_TARGET_INFO, CFG_CALL_TARGET_VALID, HANDLE,
+    IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64, IMAGE_FILE_MACHINE_ARMNT,
+    IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_UNKNOWN, LPSTR, PROCESSOR_ARCHITECTURE_AMD64,
+    PROCESSOR_ARCHITECTURE_ARM64, PROCESS_MITIGATION_BINARY_SIGNATURE_POLICY,
+    PROCESS_MITIGATION_CONTROL_FLOW_GUARD_POLICY, PROCESS_MITIGATION_DYNAMIC_CODE_POLICY,
+    PROCESS_MITIGATION_STRICT_HANDLE_CHECK_POLICY,
+};
+use winapi::um::wow64apiset::IsWow64Process;
+
+// Remote reads that must not fault are split at this granularity
+const PAGE_SIZE: usize = 0x1000;
+
+// Size of the reads used when dumping remote memory
+const DUMP_CHUNK_SIZE: usize = 0x10000;
+
+// CFG tracks call targets at this granularity
+const CFG_CALL_TARGET_ALIGNMENT: usize = 16;
+
+// Call targets passed to a single SetProcessValidCallTargets call
+const CFG_CALL_TARGET_BATCH: usize = 0x1000;
+
+// What dump_range does with pages that can't be read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnreadableMemory {
+    // Write zeroes in place of the page
+    ZeroFill,
+    Fail,
+}
+
+// Instruction set of a process or image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Architecture {
+    X86,
+    X64,
+    Arm,
+    Arm64,
+    Unknown(u16),
+}
+
+impl Architecture {
+    // Maps an IMAGE_FILE_MACHINE_* value, as found in the file header of PE images
+    pub fn from_machine(machine: u16) -> Self {
+        match machine {
+            IMAGE_FILE_MACHINE_I386 => Architecture::X86,
+            IMAGE_FILE_MACHINE_AMD64 => Architecture::X64,
+            IMAGE_FILE_MACHINE_ARMNT => Architecture::Arm,
+            IMAGE_FILE_MACHINE_ARM64 => Architecture::Arm64,
+            machine => Architecture::Unknown(machine),
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Architecture::X86 => write!(f, "x86"),
+            Architecture::X64 => write!(f, "x64"),
+            Architecture::Arm => write!(f, "ARM"),
+            Architecture::Arm64 => write!(f, "ARM64"),
+            Architecture::Unknown(machine) => write!(f, "unknown machine {:#x}", machine),
+        }
+    }
+}
+
+// Protection of a protected process (PP) or protected process light (PPL), from PS_PROTECTION
+// Unprotected processes can only open them with limited rights, such as
+// PROCESS_QUERY_LIMITED_INFORMATION, whatever privileges they hold
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProtectionLevel {
+    pub light: bool,
+    pub signer: ProtectionSigner,
+}
+
+impl ProtectionLevel {
+    // Maps the PS_PROTECTION byte, the type is in the low 3 bits and the signer in the high 4
+    // Returns None for unprotected processes
+    pub fn from_raw(protection: u8) -> Option<Self> {
+        let light = match protection & 0x7 {
+            0 => return None,
+            1 => true,
+            _ => false,
+        };
+
+        Some(Self {
+            light,
+            signer: ProtectionSigner::from_raw(protection >> 4),
+        })
+    }
+}
+
+impl fmt::Display for ProtectionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let typ = if self.light { "PPL" } else { "PP" };
+        write!(f, "{} {}", self.signer, typ)
+    }
+}
+
+// PS_PROTECTED_SIGNER, a process can only open processes with a signer that it dominates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtectionSigner {
+    Authenticode,
+    CodeGen,
+    Antimalware,
+    Lsa,
+    Windows,
+    WinTcb,
+    WinSystem,
+    App,
+    Unknown(u8),
+}
+
+impl ProtectionSigner {
+    pub fn from_raw(signer: u8) -> Self {
+        match signer {
+            1 => ProtectionSigner::Authenticode,
+            2 => ProtectionSigner::CodeGen,
+            3 => ProtectionSigner::Antimalware,
+            4 => ProtectionSigner::Lsa,
+            5 => ProtectionSigner::Windows,
+            6 => ProtectionSigner::WinTcb,
+            7 => ProtectionSigner::WinSystem,
+            8 => ProtectionSigner::App,
+            signer => ProtectionSigner::Unknown(signer),
+        }
+    }
+}
+
+impl fmt::Display for ProtectionSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtectionSigner::Authenticode => write!(f, "Authenticode"),
+            ProtectionSigner::CodeGen => write!(f, "CodeGen"),
+            ProtectionSigner::Antimalware => write!(f, "Antimalware"),
+            ProtectionSigner::Lsa => write!(f, "Lsa"),
+            ProtectionSigner::Windows => write!(f, "Windows"),
+            ProtectionSigner::WinTcb => write!(f, "WinTcb"),
+            ProtectionSigner::WinSystem => write!(f, "WinSystem"),
+            ProtectionSigner::App => write!(f, "App"),
+            ProtectionSigner::Unknown(signer) => write!(f, "unknown signer {}", signer),
+        }
+    }
+}
+
+// Mitigation policies of a process that get in the way of injection
+// Policies the system doesn't know, like CFG before Windows 8.1, are reported as disabled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Mitigations {
+    // Arbitrary code guard, no memory can be made executable after it was written, which
+    // includes allocations by other processes
+    pub dynamic_code_prohibited: bool,
+    // Control Flow Guard, indirect calls have to target registered call targets
+    pub cfg_enabled: bool,
+    // Using an invalid handle raises an exception instead of returning an error
+    pub strict_handle_checks: bool,
+    // The loader only maps images signed by Microsoft, or also by the Store and WHQL with
+    // store_signed_only
+    pub microsoft_signed_only: bool,
+    pub store_signed_only: bool,
+}
 
 // ProcessAccess flags
 // https://docs.microsoft.com/en-us/windows/win32/procthread/process-security-and-access-rights
@@ -41,6 +220,19 @@ bitflags! {
     }
 }
 
+// Identifies a process, unlike its pid, which is reused once the process exits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessKey {
+    pub pid: u32,
+    pub creation_time: u64,
+}
+
+impl ProcessKey {
+    pub fn from_pid(pid: u32) -> anyhow::Result<Self> {
+        Process::from_pid(pid, ProcessAccess::PROCESS_QUERY_LIMITED_INFORMATION, false)?.key()
+    }
+}
+
 // Process struct
 pub struct Process {
     handle: HANDLE,
@@ -48,6 +240,8 @@ pub struct Process {
 }
 
 impl Process {
+    /// # Safety
+    /// `handle` must be a valid process handle which is closed when the `Process` is dropped
     pub unsafe fn from_handle(handle: HANDLE, is_external: bool) -> Self {
         Self {
             handle,
@@ -68,10 +262,32 @@ impl Process {
         })
     }
 
+    // PIDs of every process whose executable has the file name, compared case-insensitively
+    pub fn find_by_name(name: &str) -> anyhow::Result<Vec<u32>> {
+        let snapshot = Snapshot::processes()?;
+
+        Ok(snapshot
+            .process_entries()
+            .filter(|entry| entry.exe_file.eq_ignore_ascii_case(name))
+            .map(|entry| entry.pid)
+            .collect())
+    }
+
     pub fn from_current() -> Self {
         unsafe { Process::from_handle(GetCurrentProcess(), false) }
     }
 
+    // Pseudo handles are valid everywhere in the current process and are copied as is
+    pub fn try_clone(&self) -> anyhow::Result<Self> {
+        let handle = if self.is_pseudo_handle() {
+            self.handle
+        } else {
+            self.try_duplicate()?
+        };
+
+        Ok(unsafe { Process::from_handle(handle, self.is_external) })
+    }
+
     pub fn pid(&self) -> anyhow::Result<u32> {
         let pid = unsafe { GetProcessId(self.handle) };
 
@@ -84,6 +300,35 @@ impl Process {
         self.is_external
     }
 
+    // Creation time as a FILETIME value
+    pub fn creation_time(&self) -> anyhow::Result<u64> {
+        let mut creation = FILETIME::default();
+        let mut exit = FILETIME::default();
+        let mut kernel = FILETIME::default();
+        let mut user = FILETIME::default();
+
+        let ret = unsafe {
+            GetProcessTimes(
+                self.handle,
+                &mut creation,
+                &mut exit,
+                &mut kernel,
+                &mut user,
+            )
+        };
+
+        ensure!(ret != 0, function_call_failure!("GetProcessTimes"),);
+
+        Ok((creation.dwHighDateTime as u64) << 32 | creation.dwLowDateTime as u64)
+    }
+
+    pub fn key(&self) -> anyhow::Result<ProcessKey> {
+        Ok(ProcessKey {
+            pid: self.pid()?,
+            creation_time: self.creation_time()?,
+        })
+    }
+
     pub fn write_memory(&self, data: &[u8], address: usize) -> anyhow::Result<usize> {
         ensure!(
             address != 0,
@@ -106,6 +351,8 @@ impl Process {
 
         ensure!(ret != 0, function_call_failure!("WriteProcessMemory"),);
 
+        trace!("Wrote {:x} bytes to {:x}", num_bytes_written, address);
+
         Ok(num_bytes_written)
     }
 
@@ -135,6 +382,261 @@ impl Process {
         Ok(num_bytes_read)
     }
 
+    // Reads a plain data structure from the process memory
+    pub fn read_value<T: Pod>(&self, address: usize) -> anyhow::Result<T> {
+        let mut value = T::zeroed();
+        self.read_memory(value.as_bytes_mut(), address)?;
+
+        Ok(value)
+    }
+
+    // Reads a null-terminated string of at most max_len bytes
+    pub fn read_cstring(&self, address: usize, max_len: usize) -> anyhow::Result<String> {
+        let buf = self.read_until_null::<u8>(address, max_len)?;
+
+        Ok(String::from_utf8(buf)?)
+    }
+
+    // Reads a null-terminated UTF-16 string of at most max_len characters
+    pub fn read_utf16_string(&self, address: usize, max_len: usize) -> anyhow::Result<String> {
+        let buf = self.read_until_null::<u16>(address, max_len)?;
+
+        Ok(String::from_utf16(&buf)?)
+    }
+
+    // Reads are split at page boundaries, so strings followed by an unmapped page can be read
+    fn read_until_null<T: Pod + Copy + Default + PartialEq>(
+        &self,
+        address: usize,
+        max_len: usize,
+    ) -> anyhow::Result<Vec<T>> {
+        let mut buf = Vec::new();
+        let mut chunk = Vec::new();
+
+        while buf.len() < max_len {
+            let current = address + buf.len() * size_of::<T>();
+            let page_remaining = PAGE_SIZE - current % PAGE_SIZE;
+            let len = (page_remaining / size_of::<T>())
+                .max(1)
+                .min(max_len - buf.len());
+
+            chunk.resize(len, T::default());
+            self.read_memory(chunk.as_mut_slice().as_bytes_mut(), current)?;
+
+            match chunk.iter().position(|c| *c == T::default()) {
+                Some(pos) => {
+                    buf.extend_from_slice(&chunk[..pos]);
+
+                    return Ok(buf);
+                }
+                None => buf.extend_from_slice(&chunk),
+            }
+        }
+
+        bail!(
+            "String at {:x} is not null-terminated within {} characters",
+            address,
+            max_len
+        )
+    }
+
+    pub fn write_value<T: Pod>(&self, value: &T, address: usize) -> anyhow::Result<usize> {
+        self.write_memory(value.as_bytes(), address)
+    }
+
+    // Streams size bytes starting at address into writer
+    // Chunks that fail to read are retried page by page, so only the unreadable pages are affected
+    // Returns the number of bytes that were zero-filled
+    pub fn dump_range<W: Write>(
+        &self,
+        address: usize,
+        size: usize,
+        mut writer: W,
+        unreadable: UnreadableMemory,
+    ) -> anyhow::Result<usize> {
+        let mut zero_filled = 0;
+        let mut chunk = vec![0; DUMP_CHUNK_SIZE];
+        let mut offset = 0;
+
+        while offset < size {
+            let current = address + offset;
+            let len = (DUMP_CHUNK_SIZE - current % PAGE_SIZE).min(size - offset);
+            let buf = &mut chunk[..len];
+
+            if self.read_memory(buf, current).is_err() {
+                let mut page_offset = 0;
+
+                while page_offset < len {
+                    let page = current + page_offset;
+                    let page_len = (PAGE_SIZE - page % PAGE_SIZE).min(len - page_offset);
+                    let page_buf = &mut buf[page_offset..page_offset + page_len];
+
+                    if let Err(e) = self.read_memory(page_buf, page) {
+                        match unreadable {
+                            UnreadableMemory::ZeroFill => {
+                                trace!("Page at {:x} is not readable", page);
+
+                                page_buf.fill(0);
+                                zero_filled += page_len;
+                            }
+                            UnreadableMemory::Fail => return Err(e),
+                        }
+                    }
+
+                    page_offset += page_len;
+                }
+            }
+
+            writer.write_all(buf)?;
+            offset += len;
+        }
+
+        Ok(zero_filled)
+    }
+
+    // Same as read_memory but takes a 64-bit address
+    // A 32-bit build can't pass these to ReadProcessMemory, so it goes through the WOW64 native API
+    pub fn read_memory64(&self, buffer: &mut [u8], address: u64) -> anyhow::Result<usize> {
+        if cfg!(target_pointer_width = "64") {
+            return self.read_memory(buffer, address as usize);
+        }
+
+        ensure!(
+            address != 0,
+            WinApiError::BadParameter("address".to_string(), "null pointer".to_string())
+        );
+
+        let nt_wow64_read_virtual_memory64 = unsafe {
+            ntdll::resolve::<ntdll::FnNtWow64ReadVirtualMemory64>("NtWow64ReadVirtualMemory64")?
+        };
+
+        let mut num_bytes_read = 0;
+        let status = unsafe {
+            nt_wow64_read_virtual_memory64(
+                self.handle,
+                address,
+                buffer.as_mut_ptr() as PVOID,
+                buffer.len() as u64,
+                &mut num_bytes_read,
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtWow64ReadVirtualMemory64", status)
+        );
+
+        Ok(num_bytes_read as usize)
+    }
+
+    pub fn read_value64<T: Pod>(&self, address: u64) -> anyhow::Result<T> {
+        let mut value = T::zeroed();
+        self.read_memory64(value.as_bytes_mut(), address)?;
+
+        Ok(value)
+    }
+
+    // Same as write_memory but takes a 64-bit address, see read_memory64
+    pub fn write_memory64(&self, data: &[u8], address: u64) -> anyhow::Result<usize> {
+        if cfg!(target_pointer_width = "64") {
+            return self.write_memory(data, address as usize);
+        }
+
+        ensure!(
+            address != 0,
+            WinApiError::BadParameter("address".to_string(), "null pointer".to_string())
+        );
+
+        let nt_wow64_write_virtual_memory64 = unsafe {
+            ntdll::resolve::<ntdll::FnNtWow64WriteVirtualMemory64>("NtWow64WriteVirtualMemory64")?
+        };
+
+        let mut num_bytes_written = 0;
+        let status = unsafe {
+            nt_wow64_write_virtual_memory64(
+                self.handle,
+                address,
+                data.as_ptr() as PVOID,
+                data.len() as u64,
+                &mut num_bytes_written,
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtWow64WriteVirtualMemory64", status)
+        );
+
+        Ok(num_bytes_written as usize)
+    }
+
+    pub fn write_value64<T: Pod>(&self, value: &T, address: u64) -> anyhow::Result<usize> {
+        self.write_memory64(value.as_bytes(), address)
+    }
+
+    pub fn virtual_free(
+        &self,
+        address: usize,
+        size: usize,
+        freetype: FreeType,
+    ) -> anyhow::Result<()> {
+        let ret = unsafe { VirtualFreeEx(self.handle, address as LPVOID, size, freetype.bits()) };
+
+        ensure!(ret != 0, function_call_failure!("VirtualFreeEx"),);
+
+        trace!("Freed {:x} ({:?})", address, freetype);
+
+        Ok(())
+    }
+
+    // Unmaps a view of a section, such as one mapped by Section::map_view
+    pub fn unmap_view(&self, address: usize) -> anyhow::Result<()> {
+        let nt_unmap_view_of_section =
+            unsafe { ntdll::resolve::<ntdll::FnNtUnmapViewOfSection>("NtUnmapViewOfSection")? };
+
+        let status = unsafe { nt_unmap_view_of_section(self.handle, address as PVOID) };
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtUnmapViewOfSection", status),
+        );
+
+        trace!("Unmapped view at {:x}", address);
+
+        Ok(())
+    }
+
+    // The region containing address, None if address is outside of the user address space
+    pub fn memory_region(&self, address: usize) -> Option<MemoryRegion> {
+        MemoryRegions::from_address(self, address).next()
+    }
+
+    pub fn memory_regions(&self) -> MemoryRegions<'_> {
+        MemoryRegions::new(self)
+    }
+
+    // Searches all readable memory for a pattern such as "48 8B ?? ?? E8"
+    pub fn scan(&self, pattern: &str) -> anyhow::Result<Vec<usize>> {
+        self.scan_cancellable(pattern, &CancellationToken::default())
+    }
+
+    // Scanning all memory can take long, this fails with Error::Aborted once the token is cancelled
+    pub fn scan_cancellable(
+        &self,
+        pattern: &str,
+        cancellation: &CancellationToken,
+    ) -> anyhow::Result<Vec<usize>> {
+        scanner::scan_regions(self, &pattern.parse()?, self.memory_regions(), cancellation)
+    }
+
+    pub fn scan_module(&self, module: &Module, pattern: &str) -> anyhow::Result<Vec<usize>> {
+        scanner::scan_module(
+            self,
+            &pattern.parse()?,
+            module,
+            &CancellationToken::default(),
+        )
+    }
+
     pub fn virtual_protect(
         &self,
         address: usize,
@@ -158,10 +660,169 @@ impl Process {
 
         ensure!(ret != 0, function_call_failure!("VirtualProtectEx"),);
 
+        trace!(
+            "Protected {:x} bytes at {:x} as {:?}",
+            size,
+            address,
+            protect
+        );
+
         Ok(old_protect)
     }
 
-    // FIXME: Won't work for manually mapped modules
+    // Writes to pages that may not be writable, such as code or a read-only IAT
+    // The pages are switched to `protect` for the write and their old protection is restored
+    pub fn write_memory_protected(
+        &self,
+        data: &[u8],
+        address: usize,
+        protect: ProtectFlag,
+    ) -> anyhow::Result<usize> {
+        let old_protect = self.virtual_protect(address, data.len(), protect)?;
+        let result = self.write_memory(data, address);
+        self.virtual_protect(
+            address,
+            data.len(),
+            ProtectFlag::from_bits_truncate(old_protect),
+        )?;
+
+        result
+    }
+
+    // Must be called after modifying code that may already have been executed
+    pub fn flush_instruction_cache(&self, address: usize, size: usize) -> anyhow::Result<()> {
+        let ret = unsafe { FlushInstructionCache(self.handle, address as LPCVOID, size) };
+
+        ensure!(ret != 0, function_call_failure!("FlushInstructionCache"),);
+
+        Ok(())
+    }
+
+    // Whether the process enforces Control Flow Guard on indirect calls
+    // The handle needs PROCESS_QUERY_INFORMATION
+    pub fn is_cfg_enabled(&self) -> anyhow::Result<bool> {
+        let policy = PROCESS_MITIGATION_CONTROL_FLOW_GUARD_POLICY {
+            Flags: self.mitigation_policy(ProcessControlFlowGuardPolicy)?,
+        };
+
+        Ok(policy.EnableControlFlowGuard() != 0)
+    }
+
+    // The handle needs PROCESS_QUERY_INFORMATION
+    pub fn mitigations(&self) -> anyhow::Result<Mitigations> {
+        let dynamic_code = PROCESS_MITIGATION_DYNAMIC_CODE_POLICY {
+            Flags: self.mitigation_policy(ProcessDynamicCodePolicy)?,
+        };
+        let strict_handle_check = PROCESS_MITIGATION_STRICT_HANDLE_CHECK_POLICY {
+            Flags: self.mitigation_policy(ProcessStrictHandleCheckPolicy)?,
+        };
+        let signature = PROCESS_MITIGATION_BINARY_SIGNATURE_POLICY {
+            Flags: self.mitigation_policy(ProcessSignaturePolicy)?,
+        };
+
+        Ok(Mitigations {
+            dynamic_code_prohibited: dynamic_code.ProhibitDynamicCode() != 0,
+            cfg_enabled: self.is_cfg_enabled()?,
+            strict_handle_checks: strict_handle_check.RaiseExceptionOnInvalidHandleReference() != 0,
+            microsoft_signed_only: signature.MicrosoftSignedOnly() != 0,
+            store_signed_only: signature.StoreSignedOnly() != 0,
+        })
+    }
+
+    // Returns the flags of a policy whose structure is a single DWORD of flags, which is all of
+    // the ones queried here, or 0 if the system doesn't know the policy
+    // GetProcessMitigationPolicy was added in Windows 8, older versions have no policies.
+    fn mitigation_policy(&self, policy: u32) -> anyhow::Result<u32> {
+        type FnGetProcessMitigationPolicy =
+            unsafe extern "system" fn(HANDLE, u32, PVOID, usize) -> BOOL;
+
+        let kernel32 = Module::find_or_load_internal("kernel32.dll")?;
+        let address = match kernel32.proc_address("GetProcessMitigationPolicy") {
+            Ok(address) => address,
+            Err(_) => return Ok(0),
+        };
+
+        let get_process_mitigation_policy =
+            unsafe { mem::transmute::<usize, FnGetProcessMitigationPolicy>(address) };
+
+        let mut flags: u32 = 0;
+        let ret = unsafe {
+            get_process_mitigation_policy(
+                self.handle,
+                policy,
+                &mut flags as *mut _ as PVOID,
+                size_of::<u32>(),
+            )
+        };
+
+        if ret == 0 && unsafe { GetLastError() } == ERROR_INVALID_PARAMETER {
+            return Ok(0);
+        }
+
+        ensure!(
+            ret != 0,
+            function_call_failure!("GetProcessMitigationPolicy"),
+        );
+
+        Ok(flags)
+    }
+
+    // Marks offsets into the allocation at region as valid targets for indirect calls, or as
+    // invalid ones again if valid is false
+    // Offsets are rounded down to the 16 bytes CFG tracks. The handle needs PROCESS_VM_OPERATION.
+    pub fn set_valid_call_targets(
+        &self,
+        region: usize,
+        size: usize,
+        offsets: &[usize],
+        valid: bool,
+    ) -> anyhow::Result<()> {
+        type FnSetProcessValidCallTargets =
+            unsafe extern "system" fn(HANDLE, PVOID, usize, u32, *mut CFG_CALL_TARGET_INFO) -> BOOL;
+
+        let kernelbase = Module::find_or_load_internal("kernelbase.dll")?;
+        let set_process_valid_call_targets = unsafe {
+            mem::transmute::<usize, FnSetProcessValidCallTargets>(
+                kernelbase.proc_address("SetProcessValidCallTargets")?,
+            )
+        };
+
+        let mut targets: Vec<CFG_CALL_TARGET_INFO> = offsets
+            .iter()
+            .map(|&offset| CFG_CALL_TARGET_INFO {
+                Offset: offset & !(CFG_CALL_TARGET_ALIGNMENT - 1),
+                Flags: if valid { CFG_CALL_TARGET_VALID } else { 0 },
+            })
+            .collect();
+
+        for batch in targets.chunks_mut(CFG_CALL_TARGET_BATCH) {
+            let ret = unsafe {
+                set_process_valid_call_targets(
+                    self.handle,
+                    region as PVOID,
+                    size,
+                    batch.len() as u32,
+                    batch.as_mut_ptr(),
+                )
+            };
+
+            ensure!(
+                ret != 0,
+                function_call_failure!("SetProcessValidCallTargets"),
+            );
+        }
+
+        trace!(
+            "Marked {} call targets valid in {:x} bytes at {:x}",
+            offsets.len(),
+            size,
+            region
+        );
+
+        Ok(())
+    }
+
+    // Modules manually mapped by this crate are looked up before the loader's modules
     pub fn module_by_name(&self, name: &str) -> anyhow::Result<Option<Module>> {
         let name = Path::new(name)
             .with_extension("dll")
@@ -169,6 +830,10 @@ impl Process {
             .ok_or_else(|| anyhow!("Failed to convert Path to str"))?
             .to_ascii_lowercase();
 
+        if let Some(mapped) = registry::find_by_name(self.key()?, &name) {
+            return Ok(Some(Module::from_mapped(mapped)));
+        }
+
         // kernel32.dll is a weird module in wow64 processes
         // Seems like it is excluded from TH32CS_SNAPMODULE32 even though it is 32-bit
         let filter_flags = if name.contains("kernel32.dll") || !self.is_wow64()? {
@@ -191,31 +856,284 @@ impl Process {
     }
 
     pub fn is_wow64(&self) -> anyhow::Result<bool> {
-        let mut process_machine = 0;
-        let mut native_machine = 0;
-        let ret =
-            unsafe { IsWow64Process2(self.handle, &mut process_machine, &mut native_machine) };
-
-        ensure!(ret != 0, function_call_failure!("IsWow64Process2"),);
+        let (process_machine, _native_machine) = self.wow64_machines()?;
 
         Ok(process_machine != IMAGE_FILE_MACHINE_UNKNOWN)
     }
 
-    pub fn handle(&self) -> HANDLE {
-        self.handle
+    // The architecture of the code running in the process, for WOW64 processes this is the emulated one
+    pub fn architecture(&self) -> anyhow::Result<Architecture> {
+        let (process_machine, native_machine) = self.wow64_machines()?;
+
+        Ok(Architecture::from_machine(match process_machine {
+            IMAGE_FILE_MACHINE_UNKNOWN => native_machine,
+            machine => machine,
+        }))
+    }
+
+    // Returns (process machine, native machine) as reported by IsWow64Process2
+    // The process machine is IMAGE_FILE_MACHINE_UNKNOWN if the process isn't running under WOW64
+    // IsWow64Process2 was added in Windows 10 1511, older versions fall back to IsWow64Process
+    fn wow64_machines(&self) -> anyhow::Result<(u16, u16)> {
+        type FnIsWow64Process2 = unsafe extern "system" fn(HANDLE, *mut u16, *mut u16) -> BOOL;
+
+        let kernel32 = Module::find_or_load_internal("kernel32.dll")?;
+
+        if let Ok(address) = kernel32.proc_address("IsWow64Process2") {
+            let is_wow64_process2 = unsafe { mem::transmute::<usize, FnIsWow64Process2>(address) };
+
+            let mut process_machine = 0;
+            let mut native_machine = 0;
+            let ret = unsafe {
+                is_wow64_process2(self.handle, &mut process_machine, &mut native_machine)
+            };
+
+            ensure!(ret != 0, function_call_failure!("IsWow64Process2"),);
+
+            return Ok((process_machine, native_machine));
+        }
+
+        let mut is_wow64 = 0;
+        let ret = unsafe { IsWow64Process(self.handle, &mut is_wow64) };
+
+        ensure!(ret != 0, function_call_failure!("IsWow64Process"),);
+
+        // Only x86 is emulated on systems without IsWow64Process2
+        let native_machine = {
+            let mut info = SYSTEM_INFO::default();
+            unsafe { GetNativeSystemInfo(&mut info) };
+
+            match unsafe { info.u.s().wProcessorArchitecture } {
+                PROCESSOR_ARCHITECTURE_AMD64 => IMAGE_FILE_MACHINE_AMD64,
+                PROCESSOR_ARCHITECTURE_ARM64 => IMAGE_FILE_MACHINE_ARM64,
+                _ => IMAGE_FILE_MACHINE_I386,
+            }
+        };
+
+        match is_wow64 {
+            0 => Ok((IMAGE_FILE_MACHINE_UNKNOWN, native_machine)),
+            _ => Ok((IMAGE_FILE_MACHINE_I386, native_machine)),
+        }
+    }
+
+    // The handle needs PROCESS_TERMINATE
+    pub fn terminate(&self, exit_code: u32) -> anyhow::Result<()> {
+        let ret = unsafe { TerminateProcess(self.handle, exit_code) };
+
+        ensure!(ret != 0, function_call_failure!("TerminateProcess"),);
+
+        Ok(())
     }
 
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        // -1 is the pseudo handle for the current process and need not be closed
-        if self.handle as isize != 1 {
-            let ret = unsafe { CloseHandle(self.handle) };
+    // Returns None while the process is still running
+    // A process which exited with STILL_ACTIVE as its exit code is indistinguishable from a running one
+    pub fn exit_code(&self) -> anyhow::Result<Option<u32>> {
+        let mut code = 0;
+        let ret = unsafe { GetExitCodeProcess(self.handle, &mut code) };
 
-            ensure!(ret != 0, function_call_failure!("CloseHandle"));
+        ensure!(ret != 0, function_call_failure!("GetExitCodeProcess"),);
+
+        match code {
+            STILL_ACTIVE => Ok(None),
+            code => Ok(Some(code)),
+        }
+    }
+
+    // Waits up to `timeout` milliseconds (INFINITE to block) for the process to exit
+    // Returns the exit code, or None if the timeout elapsed, the handle needs SYNCHRONIZE
+    pub fn wait_for_exit(&self, timeout: u32) -> anyhow::Result<Option<u32>> {
+        if self.wait(timeout)? == WaitResult::Timeout {
+            return Ok(None);
+        }
+
+        let mut code = 0;
+        let ret = unsafe { GetExitCodeProcess(self.handle, &mut code) };
+
+        ensure!(ret != 0, function_call_failure!("GetExitCodeProcess"),);
+
+        Ok(Some(code))
+    }
+
+    // Suspends every thread of the process, the handle needs PROCESS_SUSPEND_RESUME
+    // Like SuspendThread this increments the suspend count, so calls must be paired with resume
+    pub fn suspend(&self) -> anyhow::Result<()> {
+        match unsafe { ntdll::resolve::<ntdll::FnNtSuspendProcess>("NtSuspendProcess") } {
+            Ok(nt_suspend_process) => {
+                let status = unsafe { nt_suspend_process(self.handle) };
+
+                ensure!(
+                    NT_SUCCESS(status),
+                    nt_function_call_failure!("NtSuspendProcess", status),
+                );
+
+                Ok(())
+            }
+            Err(_e) => self.for_each_thread(|thread| thread.suspend().map(|_| ())),
+        }
+    }
+
+    pub fn resume(&self) -> anyhow::Result<()> {
+        match unsafe { ntdll::resolve::<ntdll::FnNtResumeProcess>("NtResumeProcess") } {
+            Ok(nt_resume_process) => {
+                let status = unsafe { nt_resume_process(self.handle) };
+
+                ensure!(
+                    NT_SUCCESS(status),
+                    nt_function_call_failure!("NtResumeProcess", status),
+                );
+
+                Ok(())
+            }
+            Err(_e) => self.for_each_thread(|thread| thread.resume().map(|_| ())),
+        }
+    }
+
+    // Fallback for when the native process functions can't be resolved
+    // Threads which exit between taking the snapshot and opening them are skipped
+    fn for_each_thread<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(&Thread) -> anyhow::Result<()>,
+    {
+        for tid in Threads::new(self.pid()?)? {
+            if let Ok(thread) = Thread::from_tid(tid, ThreadAccess::THREAD_SUSPEND_RESUME, false) {
+                f(&thread)?;
+            }
         }
 
         Ok(())
     }
 
+    // Address of the PEB matching the architecture of the current process
+    pub fn peb_address(&self) -> anyhow::Result<usize> {
+        let nt_query_information_process = unsafe {
+            ntdll::resolve::<ntdll::FnNtQueryInformationProcess>("NtQueryInformationProcess")?
+        };
+
+        let mut info = ntdll::ProcessBasicInformation::default();
+        let status = unsafe {
+            nt_query_information_process(
+                self.handle,
+                ntdll::PROCESS_BASIC_INFORMATION_CLASS,
+                &mut info as *mut ntdll::ProcessBasicInformation as PVOID,
+                size_of::<ntdll::ProcessBasicInformation>() as u32,
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtQueryInformationProcess", status)
+        );
+
+        Ok(info.peb_base_address)
+    }
+
+    // Returns None if the process isn't protected
+    // The handle only needs PROCESS_QUERY_LIMITED_INFORMATION, which protected processes can be
+    // opened with. Windows versions before 8.1 don't know the information class and have no
+    // protected processes light, their protected processes are reported as unprotected.
+    pub fn protection(&self) -> anyhow::Result<Option<ProtectionLevel>> {
+        let nt_query_information_process = unsafe {
+            ntdll::resolve::<ntdll::FnNtQueryInformationProcess>("NtQueryInformationProcess")?
+        };
+
+        let mut protection = 0_u8;
+        let status = unsafe {
+            nt_query_information_process(
+                self.handle,
+                ntdll::PROCESS_PROTECTION_INFORMATION_CLASS,
+                &mut protection as *mut u8 as PVOID,
+                size_of::<u8>() as u32,
+                ptr::null_mut(),
+            )
+        };
+
+        if status == STATUS_INVALID_INFO_CLASS {
+            return Ok(None);
+        }
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtQueryInformationProcess", status)
+        );
+
+        Ok(ProtectionLevel::from_raw(protection))
+    }
+
+    pub fn peb32_address(&self) -> anyhow::Result<Option<usize>> {
+        let nt_query_information_process = unsafe {
+            ntdll::resolve::<ntdll::FnNtQueryInformationProcess>("NtQueryInformationProcess")?
+        };
+
+        let mut address = 0_usize;
+        let status = unsafe {
+            nt_query_information_process(
+                self.handle,
+                ntdll::PROCESS_WOW64_INFORMATION_CLASS,
+                &mut address as *mut usize as PVOID,
+                size_of::<usize>() as u32,
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtQueryInformationProcess", status)
+        );
+
+        Ok(match address {
+            0 => None,
+            address => Some(address),
+        })
+    }
+
+    // Address of the 64-bit PEB, also works from a 32-bit process inspecting a 64-bit one
+    pub fn peb64_address(&self) -> anyhow::Result<u64> {
+        if cfg!(target_pointer_width = "64") {
+            return Ok(self.peb_address()? as u64);
+        }
+
+        let nt_wow64_query_information_process64 = unsafe {
+            ntdll::resolve::<ntdll::FnNtWow64QueryInformationProcess64>(
+                "NtWow64QueryInformationProcess64",
+            )?
+        };
+
+        let mut info = ntdll::ProcessBasicInformation64::default();
+        let status = unsafe {
+            nt_wow64_query_information_process64(
+                self.handle,
+                ntdll::PROCESS_BASIC_INFORMATION_CLASS,
+                &mut info as *mut ntdll::ProcessBasicInformation64 as PVOID,
+                size_of::<ntdll::ProcessBasicInformation64>() as u32,
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtWow64QueryInformationProcess64", status)
+        );
+
+        Ok(info.peb_base_address)
+    }
+
+    pub fn peb(&self) -> anyhow::Result<Peb> {
+        Peb::from_process(self)
+    }
+
+    pub fn command_line(&self) -> anyhow::Result<String> {
+        self.peb()?.command_line(self)
+    }
+
+    pub fn environment(&self) -> anyhow::Result<Vec<String>> {
+        self.peb()?.environment(self)
+    }
+
+    pub fn current_directory(&self) -> anyhow::Result<PathBuf> {
+        Ok(PathBuf::from(self.peb()?.current_directory(self)?))
+    }
+
     pub fn path(&self) -> anyhow::Result<PathBuf> {
         let mut buf: Vec<u8> = vec![0; 0x200];
         let ret = unsafe {
@@ -238,6 +1156,27 @@ impl Process {
     }
 }
 
+impl HandleOwner for Process {
+    fn handle(&self) -> HANDLE {
+        self.handle
+    }
+
+    // -1 is the pseudo handle for the current process
+    fn is_pseudo_handle(&self) -> bool {
+        self.handle as isize == -1
+    }
+}
+
+impl Clone for Process {
+    fn clone(&self) -> Self {
+        self.try_clone().unwrap()
+    }
+}
+
+// Process handles can be used from any thread, every method only passes the handle to the system
+unsafe impl Send for Process {}
+unsafe impl Sync for Process {}
+
 impl Drop for Process {
     fn drop(&mut self) {
         self.close().unwrap()
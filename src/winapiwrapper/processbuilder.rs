@@ -0,0 +1,196 @@
+use super::process::Process;
+use super::thread::Thread;
+use std::ffi::OsStr;
+use std::mem::size_of;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use winapi::um::processthreadsapi::{CreateProcessW, PROCESS_INFORMATION, STARTUPINFOW};
+use winapi::um::winbase;
+
+// Process creation flags
+// https://docs.microsoft.com/en-us/windows/win32/procthread/process-creation-flags
+bitflags! {
+    pub struct ProcessCreationFlags: u32 {
+        const CREATE_BREAKAWAY_FROM_JOB = winbase::CREATE_BREAKAWAY_FROM_JOB;
+        const CREATE_NEW_CONSOLE = winbase::CREATE_NEW_CONSOLE;
+        const CREATE_NEW_PROCESS_GROUP = winbase::CREATE_NEW_PROCESS_GROUP;
+        const CREATE_NO_WINDOW = winbase::CREATE_NO_WINDOW;
+        const CREATE_SUSPENDED = winbase::CREATE_SUSPENDED;
+        const CREATE_UNICODE_ENVIRONMENT = winbase::CREATE_UNICODE_ENVIRONMENT;
+        const DETACHED_PROCESS = winbase::DETACHED_PROCESS;
+    }
+}
+
+// Launches a process with CreateProcessW
+// https://docs.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-createprocessw
+pub struct ProcessBuilder {
+    path: PathBuf,
+    args: Vec<String>,
+    current_dir: Option<PathBuf>,
+    flags: ProcessCreationFlags,
+}
+
+impl ProcessBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            args: Vec::new(),
+            current_dir: None,
+            flags: ProcessCreationFlags::empty(),
+        }
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn flags(mut self, flags: ProcessCreationFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    // The primary thread doesn't run until it is resumed, no code of the target has executed
+    // at that point and only ntdll and the image itself are mapped
+    pub fn suspended(self, suspended: bool) -> Self {
+        match suspended {
+            true => self.flags(ProcessCreationFlags::CREATE_SUSPENDED),
+            false => self,
+        }
+    }
+
+    // Returns the process and its primary thread
+    pub fn spawn(&self) -> anyhow::Result<(Process, Thread)> {
+        let application_name = to_wide(self.path.as_os_str());
+        let current_dir = self
+            .current_dir
+            .as_ref()
+            .map(|dir| to_wide(dir.as_os_str()));
+
+        // CreateProcessW may modify the command line, so it has to be a mutable buffer
+        let mut command_line = self.command_line();
+
+        let mut startup_info = STARTUPINFOW {
+            cb: size_of::<STARTUPINFOW>() as u32,
+            ..Default::default()
+        };
+        let mut process_info = PROCESS_INFORMATION::default();
+
+        let ret = unsafe {
+            CreateProcessW(
+                application_name.as_ptr(),
+                command_line.as_mut_ptr(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+                self.flags.bits(),
+                ptr::null_mut(),
+                current_dir.as_ref().map_or(ptr::null(), |dir| dir.as_ptr()),
+                &mut startup_info,
+                &mut process_info,
+            )
+        };
+
+        ensure!(ret != 0, function_call_failure!("CreateProcessW"),);
+
+        unsafe {
+            Ok((
+                Process::from_handle(process_info.hProcess, true),
+                Thread::from_handle(process_info.hThread),
+            ))
+        }
+    }
+
+    // By convention the first argument is the program itself
+    fn command_line(&self) -> Vec<u16> {
+        let program = self.path.to_string_lossy();
+        let command_line = std::iter::once(program.as_ref())
+            .chain(self.args.iter().map(String::as_str))
+            .map(quote_arg)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        to_wide(OsStr::new(&command_line))
+    }
+}
+
+pub(crate) fn to_wide(str: &OsStr) -> Vec<u16> {
+    str.encode_wide().chain(Some(0)).collect()
+}
+
+// Quotes an argument so that CommandLineToArgvW and the CRT parse it back unchanged
+// Backslashes are only special when they precede a quote
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(&[' ', '\t', '\n', '\u{b}', '"'][..]) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // The backslashes were already pushed once, double them and escape the quote
+                quoted.extend(std::iter::repeat_n('\\', backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+
+        quoted.push(c);
+    }
+
+    // Backslashes before the closing quote have to be doubled as well
+    quoted.extend(std::iter::repeat_n('\\', backslashes));
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_args_are_not_quoted() {
+        assert_eq!(quote_arg("foo"), "foo");
+        assert_eq!(quote_arg(r"C:\dir\foo.dll"), r"C:\dir\foo.dll");
+    }
+
+    #[test]
+    fn empty_and_spaced_args_are_quoted() {
+        assert_eq!(quote_arg(""), r#""""#);
+        assert_eq!(quote_arg("foo bar"), r#""foo bar""#);
+        assert_eq!(quote_arg("foo\tbar"), "\"foo\tbar\"");
+    }
+
+    #[test]
+    fn quotes_are_escaped() {
+        assert_eq!(quote_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote_arg(r#"a\"b"#), r#""a\\\"b""#);
+    }
+
+    #[test]
+    fn trailing_backslashes_are_doubled() {
+        assert_eq!(quote_arg(r"C:\my dir\"), r#""C:\my dir\\""#);
+        // Backslashes not before a quote are left alone
+        assert_eq!(quote_arg(r"a\\b c"), r#""a\\b c""#);
+    }
+}
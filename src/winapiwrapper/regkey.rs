@@ -0,0 +1,125 @@
+use super::error::{format_message, WinApiError};
+use std::iter;
+use std::mem::size_of;
+use std::ptr;
+use winapi::shared::minwindef::{DWORD, HKEY};
+use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_NO_MORE_ITEMS, ERROR_SUCCESS};
+use winapi::um::winnt::{KEY_READ, REG_SZ};
+use winapi::um::winreg::{
+    RegCloseKey, RegEnumValueW, RegGetValueW, RegOpenKeyExW, RRF_RT_REG_DWORD,
+};
+
+pub use winapi::um::winreg::HKEY_LOCAL_MACHINE;
+
+// An open registry key, closed when dropped
+pub struct RegKey {
+    handle: HKEY,
+}
+
+impl RegKey {
+    // Opens a subkey of a predefined key such as HKEY_LOCAL_MACHINE for reading
+    // Returns None if the subkey doesn't exist
+    pub fn open(root: HKEY, subkey: &str) -> anyhow::Result<Option<Self>> {
+        let subkey = to_wide(subkey);
+        let mut handle = ptr::null_mut();
+        let status =
+            unsafe { RegOpenKeyExW(root, subkey.as_ptr(), 0, KEY_READ, &mut handle) } as u32;
+
+        match status {
+            ERROR_SUCCESS => Ok(Some(Self { handle })),
+            ERROR_FILE_NOT_FOUND => Ok(None),
+            _ => Err(registry_failure("RegOpenKeyExW", status).into()),
+        }
+    }
+
+    // Returns None if the value doesn't exist
+    pub fn dword(&self, name: &str) -> anyhow::Result<Option<u32>> {
+        let name = to_wide(name);
+        let mut value: DWORD = 0;
+        let mut size = size_of::<DWORD>() as DWORD;
+        let status = unsafe {
+            RegGetValueW(
+                self.handle,
+                ptr::null(),
+                name.as_ptr(),
+                RRF_RT_REG_DWORD,
+                ptr::null_mut(),
+                &mut value as *mut DWORD as _,
+                &mut size,
+            )
+        } as u32;
+
+        match status {
+            ERROR_SUCCESS => Ok(Some(value)),
+            ERROR_FILE_NOT_FOUND => Ok(None),
+            _ => Err(registry_failure("RegGetValueW", status).into()),
+        }
+    }
+
+    // Every REG_SZ value of the key as (name, data), values of other types are skipped
+    pub fn strings(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut strings = Vec::new();
+
+        for index in 0.. {
+            // Value names are at most 16383 characters, the data of the values read here is short
+            let mut name = vec![0_u16; 0x4000];
+            let mut name_len = name.len() as DWORD;
+            let mut data = vec![0_u16; 0x400];
+            let mut data_size = (data.len() * size_of::<u16>()) as DWORD;
+            let mut value_type = 0;
+
+            let status = unsafe {
+                RegEnumValueW(
+                    self.handle,
+                    index,
+                    name.as_mut_ptr(),
+                    &mut name_len,
+                    ptr::null_mut(),
+                    &mut value_type,
+                    data.as_mut_ptr() as _,
+                    &mut data_size,
+                )
+            } as u32;
+
+            match status {
+                ERROR_SUCCESS => (),
+                ERROR_NO_MORE_ITEMS => break,
+                _ => return Err(registry_failure("RegEnumValueW", status).into()),
+            }
+
+            if value_type != REG_SZ {
+                continue;
+            }
+
+            // The data may or may not include the null terminator
+            let data = &data[..data_size as usize / size_of::<u16>()];
+            let data_len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+
+            strings.push((
+                String::from_utf16_lossy(&name[..name_len as usize]),
+                String::from_utf16_lossy(&data[..data_len]),
+            ));
+        }
+
+        Ok(strings)
+    }
+}
+
+impl Drop for RegKey {
+    fn drop(&mut self) {
+        unsafe { RegCloseKey(self.handle) };
+    }
+}
+
+fn to_wide(str: &str) -> Vec<u16> {
+    str.encode_utf16().chain(iter::once(0)).collect()
+}
+
+// Registry functions return the error code instead of setting the last error
+fn registry_failure(function: &str, code: u32) -> WinApiError {
+    WinApiError::FunctionCallFailure {
+        function: function.to_string(),
+        code,
+        message: format_message(code),
+    }
+}
@@ -0,0 +1,148 @@
+use super::module::remote_data_directory;
+use super::process::Process;
+use pelite::image::IMAGE_DIRECTORY_ENTRY_RESOURCE;
+use std::convert::TryInto;
+
+// High bit of IMAGE_RESOURCE_DIRECTORY_ENTRY's Name and OffsetToData
+const RESOURCE_NAME_IS_STRING: u32 = 0x8000_0000;
+const RESOURCE_DATA_IS_DIRECTORY: u32 = 0x8000_0000;
+
+// sizeof(IMAGE_RESOURCE_DIRECTORY) and sizeof(IMAGE_RESOURCE_DIRECTORY_ENTRY)
+const RESOURCE_DIRECTORY_SIZE: usize = 16;
+const RESOURCE_DIRECTORY_ENTRY_SIZE: usize = 8;
+
+// Standard resource types
+pub const RT_ICON: u16 = 3;
+pub const RT_STRING: u16 = 6;
+pub const RT_RCDATA: u16 = 10;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+pub const RT_MANIFEST: u16 = 24;
+
+// Resource types and names are either integers (MAKEINTRESOURCE) or strings
+// Strings are compared case-insensitively like FindResource does
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResourceId {
+    Id(u16),
+    Name(String),
+}
+
+// Reads a resource of the image mapped at base, using the first language it is available in
+// The resource directory only holds RVAs, so it needs no fixups when the image is relocated.
+// FindResource and LoadResource parse the headers at the HMODULE they are given rather than
+// asking the loader, so they also work on manually mapped images. This reads the resource from
+// outside the process instead, without running code in the target.
+pub fn read_resource(
+    process: &Process,
+    base: usize,
+    resource_type: &ResourceId,
+    name: &ResourceId,
+) -> anyhow::Result<Vec<u8>> {
+    let (rva, size) = remote_data_directory(process, base, IMAGE_DIRECTORY_ENTRY_RESOURCE)?;
+    ensure!(rva != 0, "Module at {:x} has no resources", base);
+
+    let mut directory = vec![0; size as usize];
+    process.read_memory(&mut directory, base + rva as usize)?;
+
+    let type_directory = find_directory(&directory, 0, resource_type)?;
+    let name_directory = find_directory(&directory, type_directory, name)?;
+
+    // The third level is the language
+    let (_, data_entry) = entries(&directory, name_directory)?
+        .next()
+        .ok_or_else(|| anyhow!("Resource {:?} has no languages", name))??;
+
+    ensure!(
+        data_entry & RESOURCE_DATA_IS_DIRECTORY == 0,
+        "Resource {:?} is a directory",
+        name
+    );
+
+    // IMAGE_RESOURCE_DATA_ENTRY, the data is addressed by RVA
+    let data_rva = read_u32(&directory, data_entry as usize)?;
+    let data_size = read_u32(&directory, data_entry as usize + 4)?;
+
+    let mut data = vec![0; data_size as usize];
+    process.read_memory(&mut data, base + data_rva as usize)?;
+
+    trace!(
+        "Read resource {:?}/{:?} with size {:x} at {:x}",
+        resource_type,
+        name,
+        data_size,
+        base + data_rva as usize
+    );
+
+    Ok(data)
+}
+
+// Returns the offset of the subdirectory of the directory at offset named id
+fn find_directory(directory: &[u8], offset: usize, id: &ResourceId) -> anyhow::Result<usize> {
+    for entry in entries(directory, offset)? {
+        let (name, data) = entry?;
+
+        let matches = match (id, name & RESOURCE_NAME_IS_STRING != 0) {
+            (ResourceId::Id(id), false) => name == *id as u32,
+            (ResourceId::Name(id), true) => {
+                read_name(directory, (name & !RESOURCE_NAME_IS_STRING) as usize)?
+                    .eq_ignore_ascii_case(id)
+            }
+            _ => false,
+        };
+
+        if matches {
+            ensure!(
+                data & RESOURCE_DATA_IS_DIRECTORY != 0,
+                "Resource {:?} is not a directory",
+                id
+            );
+
+            return Ok((data & !RESOURCE_DATA_IS_DIRECTORY) as usize);
+        }
+    }
+
+    bail!("Resource {:?} not found", id)
+}
+
+// (Name, OffsetToData) of the entries of the directory at offset
+fn entries(
+    directory: &[u8],
+    offset: usize,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(u32, u32)>> + '_> {
+    let count =
+        read_u16(directory, offset + 12)? as usize + read_u16(directory, offset + 14)? as usize;
+    let first = offset + RESOURCE_DIRECTORY_SIZE;
+
+    Ok((0..count).map(move |index| {
+        let entry = first + index * RESOURCE_DIRECTORY_ENTRY_SIZE;
+        Ok((read_u32(directory, entry)?, read_u32(directory, entry + 4)?))
+    }))
+}
+
+// IMAGE_RESOURCE_DIR_STRING_U, a length prefixed UTF-16 string
+fn read_name(directory: &[u8], offset: usize) -> anyhow::Result<String> {
+    let len = read_u16(directory, offset)? as usize;
+    let name = (0..len)
+        .map(|index| read_u16(directory, offset + 2 + index * 2))
+        .collect::<anyhow::Result<Vec<u16>>>()?;
+
+    Ok(String::from_utf16_lossy(&name))
+}
+
+fn read_u16(directory: &[u8], offset: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_le_bytes(
+        directory
+            .get(offset..offset + 2)
+            .ok_or_else(|| anyhow!("Offset {:x} is outside of the resource directory", offset))?
+            .try_into()?,
+    ))
+}
+
+fn read_u32(directory: &[u8], offset: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(
+        directory
+            .get(offset..offset + 4)
+            .ok_or_else(|| anyhow!("Offset {:x} is outside of the resource directory", offset))?
+            .try_into()?,
+    ))
+}
@@ -0,0 +1,167 @@
+use super::module::Module;
+use super::process::Process;
+use super::virtualmem::{MemState, MemoryRegion, ProtectFlag};
+use crate::injection::cancellation::CancellationToken;
+use std::str::FromStr;
+
+// Size of the reads used when scanning remote memory
+const CHUNK_SIZE: usize = 0x10000;
+
+// A byte pattern with wildcards, e.g. "48 8B ?? ?? E8"
+// Both ? and ?? are accepted as wildcards
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(data)
+                .all(|(pattern_byte, &byte)| pattern_byte.is_none_or(|b| b == byte))
+    }
+
+    // Returns the offsets of every match in data
+    pub fn find_all(&self, data: &[u8]) -> Vec<usize> {
+        if self.is_empty() || data.len() < self.len() {
+            return Vec::new();
+        }
+
+        (0..=data.len() - self.len())
+            .filter(|&i| self.matches(&data[i..]))
+            .collect()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let bytes = str
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                _ => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_e| anyhow!("Invalid byte {} in pattern {}", token, str)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        ensure!(!bytes.is_empty(), "Pattern is empty");
+
+        Ok(Self { bytes })
+    }
+}
+
+// Whether the region can be read without faulting
+fn is_readable(region: &MemoryRegion) -> bool {
+    let readable = ProtectFlag::PAGE_READONLY
+        | ProtectFlag::PAGE_READWRITE
+        | ProtectFlag::PAGE_WRITECOPY
+        | ProtectFlag::PAGE_EXECUTE_READ
+        | ProtectFlag::PAGE_EXECUTE_READWRITE
+        | ProtectFlag::PAGE_EXECUTE_WRITECOPY;
+
+    region.state.contains(MemState::MEM_COMMIT)
+        && region.protect.intersects(readable)
+        && !region.protect.contains(ProtectFlag::PAGE_GUARD)
+}
+
+// Scans the readable parts of the given regions
+// Returns the absolute addresses of every match, fails with Error::Aborted once cancelled
+pub fn scan_regions<I>(
+    process: &Process,
+    pattern: &Pattern,
+    regions: I,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<Vec<usize>>
+where
+    I: IntoIterator<Item = MemoryRegion>,
+{
+    let mut matches = Vec::new();
+
+    for region in regions.into_iter().filter(is_readable) {
+        cancellation.check()?;
+
+        match scan_range(process, pattern, region.base, region.size, cancellation) {
+            Ok(region_matches) => matches.extend(region_matches),
+            Err(_e) => continue, // The region may have been freed or protected since it was queried
+        }
+    }
+
+    cancellation.check()?;
+
+    Ok(matches)
+}
+
+// Scans the memory occupied by a module in the process
+pub fn scan_module(
+    process: &Process,
+    pattern: &Pattern,
+    module: &Module,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<Vec<usize>> {
+    let info = module.info()?;
+    let start = info.lpBaseOfDll as usize;
+    let end = start + info.SizeOfImage as usize;
+
+    // Sections can be inaccessible, so only scan the readable regions of the image
+    let regions = process
+        .memory_regions()
+        .skip_while(|region| region.base + region.size <= start)
+        .take_while(|region| region.base < end)
+        .map(|region| {
+            let base = region.base.max(start);
+            MemoryRegion {
+                base,
+                size: (region.base + region.size).min(end) - base,
+                ..region
+            }
+        });
+
+    scan_regions(process, pattern, regions, cancellation)
+}
+
+// Reads the range in chunks, consecutive chunks overlap so matches spanning two chunks are found
+// Stops early once cancelled, scan_regions then fails
+fn scan_range(
+    process: &Process,
+    pattern: &Pattern,
+    address: usize,
+    size: usize,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<Vec<usize>> {
+    let mut matches = Vec::new();
+    let mut buf = vec![0_u8; CHUNK_SIZE.max(pattern.len()).min(size)];
+    let mut offset = 0;
+
+    while offset + pattern.len() <= size && !cancellation.is_cancelled() {
+        let len = buf.len().min(size - offset);
+        process.read_memory(&mut buf[..len], address + offset)?;
+
+        matches.extend(
+            pattern
+                .find_all(&buf[..len])
+                .into_iter()
+                .map(|i| address + offset + i),
+        );
+
+        if offset + len == size {
+            break;
+        }
+
+        offset += len + 1 - pattern.len();
+    }
+
+    Ok(matches)
+}
@@ -0,0 +1,109 @@
+use super::handleowner::HandleOwner;
+use super::ntdll;
+use super::process::Process;
+use super::virtualmem::{ProtectFlag, VirtualMem};
+use std::{mem, ptr};
+use winapi::shared::ntdef::{LARGE_INTEGER, NT_SUCCESS, PVOID};
+use winapi::um::winnt::{HANDLE, MEM_TOP_DOWN, SECTION_ALL_ACCESS, SEC_COMMIT};
+
+// A section object backed by the paging file
+// Views of the same section share their memory, so data written to a view mapped into our own
+// process shows up in the views mapped into other processes without writing to them.
+pub struct Section {
+    handle: HANDLE,
+    size: usize,
+}
+
+impl Section {
+    // Creates a committed section, protect is the most permissive protection views can have
+    pub fn create(size: usize, protect: ProtectFlag) -> anyhow::Result<Self> {
+        let nt_create_section =
+            unsafe { ntdll::resolve::<ntdll::FnNtCreateSection>("NtCreateSection")? };
+
+        let mut max_size: LARGE_INTEGER = unsafe { mem::zeroed() };
+        unsafe { *max_size.QuadPart_mut() = size as i64 };
+
+        let mut handle = ptr::null_mut();
+        let status = unsafe {
+            nt_create_section(
+                &mut handle,
+                SECTION_ALL_ACCESS,
+                ptr::null_mut(),
+                &mut max_size,
+                protect.bits(),
+                SEC_COMMIT,
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtCreateSection", status),
+        );
+
+        trace!("Created section with size {:x}", size);
+
+        Ok(Self { handle, size })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Maps a view of the whole section into the process at address, or wherever there is room
+    // if address is 0. Dropping the returned VirtualMem unmaps the view.
+    pub fn map_view<'a>(
+        &self,
+        process: &'a Process,
+        address: usize,
+        top_down: bool,
+        protect: ProtectFlag,
+    ) -> anyhow::Result<VirtualMem<'a>> {
+        let nt_map_view_of_section =
+            unsafe { ntdll::resolve::<ntdll::FnNtMapViewOfSection>("NtMapViewOfSection")? };
+
+        let mut base = address as PVOID;
+        let mut view_size = 0;
+        let status = unsafe {
+            nt_map_view_of_section(
+                self.handle,
+                process.handle(),
+                &mut base,
+                0,
+                0,
+                ptr::null_mut(),
+                &mut view_size,
+                ntdll::VIEW_UNMAP,
+                if top_down { MEM_TOP_DOWN } else { 0 },
+                protect.bits(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtMapViewOfSection", status),
+        );
+
+        trace!(
+            "Mapped view of {:x} bytes at {:x} as {:?}",
+            view_size,
+            base as usize,
+            protect
+        );
+
+        Ok(unsafe { VirtualMem::from_view(process, base as usize, self.size, protect) })
+    }
+}
+
+impl HandleOwner for Section {
+    fn handle(&self) -> HANDLE {
+        self.handle
+    }
+}
+
+// Views keep the section alive, so it can be closed while they are mapped
+impl Drop for Section {
+    fn drop(&mut self) {
+        self.close().unwrap();
+    }
+}
@@ -1,7 +1,12 @@
-use winapi::um::handleapi::CloseHandle;
+use super::handleowner::HandleOwner;
+use std::mem::size_of;
 use winapi::um::handleapi::INVALID_HANDLE_VALUE;
 use winapi::um::tlhelp32::CreateToolhelp32Snapshot;
-use winapi::um::tlhelp32::{self, Thread32First, Thread32Next, THREADENTRY32};
+use winapi::um::tlhelp32::{
+    self, Heap32ListFirst, Heap32ListNext, Module32FirstW, Module32NextW, Process32FirstW,
+    Process32NextW, Thread32First, Thread32Next, HEAPLIST32, HF32_DEFAULT, MODULEENTRY32W,
+    PROCESSENTRY32W, THREADENTRY32,
+};
 use winapi::um::winnt::HANDLE;
 
 // CreateToolhelp32Snapshot flags
@@ -24,6 +29,8 @@ pub struct Snapshot {
 }
 
 impl Snapshot {
+    /// # Safety
+    /// `handle` must be a valid snapshot handle which is closed when the `Snapshot` is dropped
     pub unsafe fn from_handle(handle: HANDLE) -> Self {
         Self { handle }
     }
@@ -38,18 +45,67 @@ impl Snapshot {
         Ok(unsafe { Self::from_handle(h) })
     }
 
-    pub fn close(&self) -> anyhow::Result<()> {
-        // -1 is the pseudo handle for the current process and need not be closed
-        if self.handle as isize != 1 {
-            let ret = unsafe { CloseHandle(self.handle) };
+    // Snapshot of every process in the system
+    pub fn processes() -> anyhow::Result<Self> {
+        Self::from_pid(0, SnapshotFlags::TH32CS_SNAPPROCESS)
+    }
+
+    // Snapshot of the heaps of a process
+    pub fn heaps(pid: u32) -> anyhow::Result<Self> {
+        Self::from_pid(pid, SnapshotFlags::TH32CS_SNAPHEAPLIST)
+    }
+
+    // The snapshot must include TH32CS_SNAPHEAPLIST
+    pub fn heap_list_entries(&self) -> SnapshotHeapListEntries<'_> {
+        SnapshotHeapListEntries {
+            snapshot: self,
+            is_first: true,
+        }
+    }
+
+    // Snapshot of every thread in the system, thread snapshots can't be limited to a process
+    pub fn threads() -> anyhow::Result<Self> {
+        Self::from_pid(0, SnapshotFlags::TH32CS_SNAPTHREAD)
+    }
+
+    // The snapshot must include TH32CS_SNAPTHREAD
+    // Only threads owned by `pid` are returned, or every thread if it is None
+    pub fn thread_entries(&self, pid: Option<u32>) -> SnapshotThreadEntries<'_> {
+        SnapshotThreadEntries {
+            snapshot: self,
+            pid,
+            is_first: true,
+        }
+    }
+
+    // Snapshot of the modules the loader has loaded into a process
+    // Includes the 32-bit modules of WOW64 processes, but not manually mapped ones
+    pub fn modules(pid: u32) -> anyhow::Result<Self> {
+        Self::from_pid(
+            pid,
+            SnapshotFlags::TH32CS_SNAPMODULE | SnapshotFlags::TH32CS_SNAPMODULE32,
+        )
+    }
 
-            ensure!(ret != 0, function_call_failure!("CloseHandle"));
+    // The snapshot must include TH32CS_SNAPMODULE or TH32CS_SNAPMODULE32
+    pub fn module_entries(&self) -> SnapshotModuleEntries<'_> {
+        SnapshotModuleEntries {
+            snapshot: self,
+            is_first: true,
         }
+    }
 
-        Ok(())
+    // The snapshot must include TH32CS_SNAPPROCESS
+    pub fn process_entries(&self) -> SnapshotProcessEntries<'_> {
+        SnapshotProcessEntries {
+            snapshot: self,
+            is_first: true,
+        }
     }
+}
 
-    pub fn handle(&self) -> HANDLE {
+impl HandleOwner for Snapshot {
+    fn handle(&self) -> HANDLE {
         self.handle
     }
 }
@@ -60,29 +116,221 @@ impl Drop for Snapshot {
     }
 }
 
+// A process in a snapshot
+#[derive(Clone, Debug)]
+pub struct ProcessEntry {
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub thread_count: u32,
+    pub base_priority: i32,
+    // File name of the executable, without the directory
+    pub exe_file: String,
+}
+
+impl From<&PROCESSENTRY32W> for ProcessEntry {
+    fn from(entry: &PROCESSENTRY32W) -> Self {
+        Self {
+            pid: entry.th32ProcessID,
+            parent_pid: entry.th32ParentProcessID,
+            thread_count: entry.cntThreads,
+            base_priority: entry.pcPriClassBase,
+            exe_file: from_wide(&entry.szExeFile),
+        }
+    }
+}
+
+// SnapshotProcessEntries
+pub struct SnapshotProcessEntries<'a> {
+    snapshot: &'a Snapshot,
+    is_first: bool,
+}
+
+impl Iterator for SnapshotProcessEntries<'_> {
+    type Item = ProcessEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut process_entry = PROCESSENTRY32W {
+            dwSize: size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+
+        let ret = if self.is_first {
+            self.is_first = false;
+
+            unsafe { Process32FirstW(self.snapshot.handle, &mut process_entry) }
+        } else {
+            unsafe { Process32NextW(self.snapshot.handle, &mut process_entry) }
+        };
+
+        match ret {
+            0 => None,
+            _ => Some(ProcessEntry::from(&process_entry)),
+        }
+    }
+}
+
+// A module in a snapshot
+#[derive(Clone, Debug)]
+pub struct ModuleEntry {
+    pub pid: u32,
+    pub base: usize,
+    pub size: u32,
+    // File name of the module, without the directory
+    pub name: String,
+    pub path: String,
+}
+
+impl From<&MODULEENTRY32W> for ModuleEntry {
+    fn from(entry: &MODULEENTRY32W) -> Self {
+        Self {
+            pid: entry.th32ProcessID,
+            base: entry.modBaseAddr as usize,
+            size: entry.modBaseSize,
+            name: from_wide(&entry.szModule),
+            path: from_wide(&entry.szExePath),
+        }
+    }
+}
+
+// SnapshotModuleEntries
+pub struct SnapshotModuleEntries<'a> {
+    snapshot: &'a Snapshot,
+    is_first: bool,
+}
+
+impl Iterator for SnapshotModuleEntries<'_> {
+    type Item = ModuleEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut module_entry = MODULEENTRY32W {
+            dwSize: size_of::<MODULEENTRY32W>() as u32,
+            ..Default::default()
+        };
+
+        let ret = if self.is_first {
+            self.is_first = false;
+
+            unsafe { Module32FirstW(self.snapshot.handle, &mut module_entry) }
+        } else {
+            unsafe { Module32NextW(self.snapshot.handle, &mut module_entry) }
+        };
+
+        match ret {
+            0 => None,
+            _ => Some(ModuleEntry::from(&module_entry)),
+        }
+    }
+}
+
+// A thread in a snapshot
+#[derive(Clone, Debug)]
+pub struct ThreadEntry {
+    pub tid: u32,
+    pub owner_pid: u32,
+    pub base_priority: i32,
+}
+
+impl From<&THREADENTRY32> for ThreadEntry {
+    fn from(entry: &THREADENTRY32) -> Self {
+        Self {
+            tid: entry.th32ThreadID,
+            owner_pid: entry.th32OwnerProcessID,
+            base_priority: entry.tpBasePri,
+        }
+    }
+}
+
 // SnapshotThreadEntries
-pub struct SnapshotThreadEntries {
-    snapshot: Snapshot,
+pub struct SnapshotThreadEntries<'a> {
+    snapshot: &'a Snapshot,
+    pid: Option<u32>,
     is_first: bool,
 }
 
-impl Iterator for SnapshotThreadEntries {
-    type Item = THREADENTRY32;
+impl Iterator for SnapshotThreadEntries<'_> {
+    type Item = ThreadEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut thread_entry = THREADENTRY32::default();
+        loop {
+            let mut thread_entry = THREADENTRY32 {
+                dwSize: size_of::<THREADENTRY32>() as u32,
+                ..Default::default()
+            };
+
+            let ret = if self.is_first {
+                self.is_first = false;
+
+                unsafe { Thread32First(self.snapshot.handle, &mut thread_entry) }
+            } else {
+                unsafe { Thread32Next(self.snapshot.handle, &mut thread_entry) }
+            };
+
+            if ret == 0 {
+                return None;
+            }
+
+            if self
+                .pid
+                .is_none_or(|pid| pid == thread_entry.th32OwnerProcessID)
+            {
+                return Some(ThreadEntry::from(&thread_entry));
+            }
+        }
+    }
+}
+
+// A heap of the process the snapshot was taken of
+#[derive(Clone, Debug)]
+pub struct HeapListEntry {
+    pub pid: u32,
+    pub heap_id: usize,
+    // The default heap of the process, returned by GetProcessHeap
+    pub is_default: bool,
+}
+
+impl From<&HEAPLIST32> for HeapListEntry {
+    fn from(entry: &HEAPLIST32) -> Self {
+        Self {
+            pid: entry.th32ProcessID,
+            heap_id: entry.th32HeapID,
+            is_default: entry.dwFlags & HF32_DEFAULT != 0,
+        }
+    }
+}
+
+// SnapshotHeapListEntries
+pub struct SnapshotHeapListEntries<'a> {
+    snapshot: &'a Snapshot,
+    is_first: bool,
+}
+
+impl Iterator for SnapshotHeapListEntries<'_> {
+    type Item = HeapListEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut heap_list = HEAPLIST32 {
+            dwSize: size_of::<HEAPLIST32>(),
+            ..Default::default()
+        };
 
         let ret = if self.is_first {
             self.is_first = false;
 
-            unsafe { Thread32First(self.snapshot.handle, &mut thread_entry) }
+            unsafe { Heap32ListFirst(self.snapshot.handle, &mut heap_list) }
         } else {
-            unsafe { Thread32Next(self.snapshot.handle, &mut thread_entry) }
+            unsafe { Heap32ListNext(self.snapshot.handle, &mut heap_list) }
         };
 
         match ret {
             0 => None,
-            _ => Some(thread_entry),
+            _ => Some(HeapListEntry::from(&heap_list)),
         }
     }
 }
+
+// Null-terminated UTF-16 string of a fixed-size buffer
+fn from_wide(buffer: &[u16]) -> String {
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+
+    String::from_utf16_lossy(&buffer[..len])
+}
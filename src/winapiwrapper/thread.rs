@@ -1,15 +1,23 @@
+use super::handleowner::HandleOwner;
+use super::module::Module;
+use super::ntdll;
 use super::process::Process;
-use super::snapshot::{Snapshot, SnapshotFlags};
+use super::snapshot::Snapshot;
 use std::ffi::c_void;
-use std::mem::size_of;
+use std::mem;
 use std::ptr;
 use winapi::ctypes::c_void as winapic_void;
-use winapi::shared::minwindef::TRUE;
-use winapi::um::processthreadsapi::{CreateRemoteThread, GetExitCodeThread};
-use winapi::um::synchapi::WaitForSingleObject;
-use winapi::um::tlhelp32::{Thread32First, Thread32Next, THREADENTRY32};
-use winapi::um::winbase::{self, WAIT_FAILED};
-use winapi::um::winnt::{self, HANDLE};
+use winapi::shared::ntdef::{NT_SUCCESS, PVOID};
+use winapi::um::processthreadsapi::{
+    CreateRemoteThread, GetExitCodeThread, GetThreadContext, GetThreadId, OpenThread, ResumeThread,
+    SetThreadContext, SuspendThread, TerminateThread,
+};
+use winapi::um::winbase::{
+    self, GetEnabledXStateFeatures, InitializeContext, Wow64GetThreadContext, Wow64SetThreadContext,
+};
+use winapi::um::winnt::{
+    self, CONTEXT, CONTEXT_ALL, CONTEXT_XSTATE, HANDLE, WOW64_CONTEXT, XSTATE_MASK_AVX,
+};
 
 pub type StartRoutine = unsafe extern "system" fn(*mut winapic_void) -> u32;
 
@@ -46,12 +54,106 @@ bitflags! {
     }
 }
 
+// Functions that can be used to create a thread in another process
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadCreationMethod {
+    CreateRemoteThread,
+    // Unlike CreateRemoteThread, this works across sessions
+    NtCreateThreadEx,
+}
+
+// 32-bit APC routines queued from a 64-bit process have to be encoded so that
+// wow64 switches to 32-bit mode before calling them
+pub fn encode_wow64_apc_routine(routine: usize) -> usize {
+    ((0 - routine as isize) << 2) as usize
+}
+
+// The instruction pointer of the native CONTEXT, which is Rip on 64-bit builds and Eip on 32-bit ones
+#[cfg(target_pointer_width = "64")]
+pub fn instruction_pointer(context: &mut CONTEXT) -> &mut u64 {
+    &mut context.Rip
+}
+
+#[cfg(target_pointer_width = "32")]
+pub fn instruction_pointer(context: &mut CONTEXT) -> &mut u32 {
+    &mut context.Eip
+}
+
+// GetThreadContext requires the CONTEXT structure to be 16 byte aligned
+#[repr(C, align(16))]
+#[derive(Default)]
+struct AlignedContext(CONTEXT);
+
+// A CONTEXT with every register, including the extended state (AVX and later) that doesn't fit
+// the fixed size structure and follows it in the buffer
+pub struct ExtendedContext {
+    // Holds the context and its extended state
+    buffer: Vec<u8>,
+    // Points into the buffer, InitializeContext aligns it
+    context: *mut CONTEXT,
+}
+
+impl ExtendedContext {
+    pub fn context(&self) -> &CONTEXT {
+        unsafe { &*self.context }
+    }
+
+    pub fn context_mut(&mut self) -> &mut CONTEXT {
+        unsafe { &mut *self.context }
+    }
+
+    // The CONTEXT followed by its extended state, which is located relative to the CONTEXT
+    // A copy placed at the same offset modulo 64 keeps the alignment the XSAVE area requires
+    pub fn as_bytes(&self) -> &[u8] {
+        let offset = self.context as usize - self.buffer.as_ptr() as usize;
+        &self.buffer[offset..]
+    }
+}
+
+// Resumes the thread when dropped, so that no error path leaves it suspended
+pub struct SuspendGuard<'a> {
+    thread: &'a Thread,
+    resumed: bool,
+}
+
+impl SuspendGuard<'_> {
+    // Returns the previous suspend count
+    pub fn resume(mut self) -> anyhow::Result<u32> {
+        self.resumed = true;
+        self.thread.resume()
+    }
+}
+
+impl Drop for SuspendGuard<'_> {
+    fn drop(&mut self) {
+        if !self.resumed {
+            if let Err(e) = self.thread.resume() {
+                trace!("Failed to resume thread: {}", e);
+            }
+        }
+    }
+}
+
 // Thread struct
 pub struct Thread {
     handle: HANDLE,
 }
 
 impl Thread {
+    /// # Safety
+    /// `handle` must be a valid thread handle which is closed when the `Thread` is dropped
+    pub unsafe fn from_handle(handle: HANDLE) -> Self {
+        Self { handle }
+    }
+
+    pub fn from_tid(tid: u32, access: ThreadAccess, inherit: bool) -> anyhow::Result<Self> {
+        let handle = unsafe { OpenThread(access.bits(), inherit as i32, tid) };
+
+        ensure!(!handle.is_null(), function_call_failure!("OpenThread"),);
+
+        Ok(Self { handle })
+    }
+
     pub fn spawn_remote(
         process: &Process,
         stack_size: Option<usize>,
@@ -90,6 +192,89 @@ impl Thread {
         Ok(Self { handle })
     }
 
+    pub fn spawn_remote_nt(
+        process: &Process,
+        stack_size: Option<usize>,
+        routine: StartRoutine,
+        param: Option<*mut c_void>,
+        creation_flags: ThreadCreationFlags,
+    ) -> anyhow::Result<Self> {
+        let nt_create_thread_ex =
+            unsafe { ntdll::resolve::<ntdll::FnNtCreateThreadEx>("NtCreateThreadEx")? };
+
+        let flags = if creation_flags.contains(ThreadCreationFlags::CREATE_SUSPENDED) {
+            ntdll::THREAD_CREATE_FLAGS_CREATE_SUSPENDED
+        } else {
+            0
+        };
+
+        let mut handle = ptr::null_mut();
+        let status = unsafe {
+            nt_create_thread_ex(
+                &mut handle,
+                winnt::THREAD_ALL_ACCESS,
+                ptr::null_mut(),
+                process.handle(),
+                routine as *mut winapic_void,
+                param.unwrap_or(ptr::null_mut()) as *mut winapic_void,
+                flags,
+                0,
+                stack_size.unwrap_or(0),
+                0,
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtCreateThreadEx", status)
+        );
+
+        Ok(Self { handle })
+    }
+
+    pub fn spawn_remote_using(
+        method: ThreadCreationMethod,
+        process: &Process,
+        routine: StartRoutine,
+        param: Option<*mut c_void>,
+        creation_flags: ThreadCreationFlags,
+    ) -> anyhow::Result<Self> {
+        match method {
+            ThreadCreationMethod::CreateRemoteThread => {
+                Self::spawn_remote(process, None, routine, param, creation_flags, None)
+            }
+            ThreadCreationMethod::NtCreateThreadEx => {
+                Self::spawn_remote_nt(process, None, routine, param, creation_flags)
+            }
+        }
+    }
+
+    // Queues a user APC with up to three arguments on the thread
+    // When queued from a 64-bit process, the routine of WOW64 threads must be encoded with
+    // encode_wow64_apc_routine
+    pub fn queue_apc(&self, routine: usize, args: [usize; 3]) -> anyhow::Result<()> {
+        let nt_queue_apc_thread =
+            unsafe { ntdll::resolve::<ntdll::FnNtQueueApcThread>("NtQueueApcThread")? };
+
+        let status = unsafe {
+            nt_queue_apc_thread(
+                self.handle,
+                routine as PVOID,
+                args[0] as PVOID,
+                args[1] as PVOID,
+                args[2] as PVOID,
+            )
+        };
+
+        ensure!(
+            NT_SUCCESS(status),
+            nt_function_call_failure!("NtQueueApcThread", status)
+        );
+
+        Ok(())
+    }
+
     pub fn exit_code(&self) -> anyhow::Result<u32> {
         let mut code = 0;
         let ret = unsafe { GetExitCodeThread(self.handle, &mut code) };
@@ -98,33 +283,158 @@ impl Thread {
         Ok(code)
     }
 
-    pub fn wait(&self, timeout: u32) -> anyhow::Result<u32> {
-        let ret = unsafe { WaitForSingleObject(self.handle, timeout) };
-        ensure!(
-            ret != WAIT_FAILED,
-            function_call_failure!("WaitForSingleObject"),
-        );
+    pub fn tid(&self) -> anyhow::Result<u32> {
+        let tid = unsafe { GetThreadId(self.handle) };
+        ensure!(tid != 0, function_call_failure!("GetThreadId"),);
+
+        Ok(tid)
+    }
+
+    // Returns the previous suspend count
+    pub fn suspend(&self) -> anyhow::Result<u32> {
+        let ret = unsafe { SuspendThread(self.handle) };
+        ensure!(ret != u32::MAX, function_call_failure!("SuspendThread"),);
+
+        Ok(ret)
+    }
+
+    // Suspends the thread until the guard is dropped or resumed
+    pub fn suspend_guarded(&self) -> anyhow::Result<SuspendGuard<'_>> {
+        self.suspend()?;
+
+        Ok(SuspendGuard {
+            thread: self,
+            resumed: false,
+        })
+    }
+
+    // Retrieves the context of a 64-bit thread, the thread should be suspended
+    // `flags` selects which parts of the context are retrieved (e.g. CONTEXT_FULL)
+    pub fn context(&self, flags: u32) -> anyhow::Result<CONTEXT> {
+        let mut context = AlignedContext::default();
+        context.0.ContextFlags = flags;
+
+        let ret = unsafe { GetThreadContext(self.handle, &mut context.0) };
+        ensure!(ret != 0, function_call_failure!("GetThreadContext"),);
+
+        Ok(context.0)
+    }
+
+    pub fn set_context(&self, context: &CONTEXT) -> anyhow::Result<()> {
+        let context = AlignedContext(*context);
+
+        let ret = unsafe { SetThreadContext(self.handle, &context.0) };
+        ensure!(ret != 0, function_call_failure!("SetThreadContext"),);
+
+        Ok(())
+    }
+
+    // Retrieves every register of a 64-bit thread, the thread should be suspended
+    // The extended state is only included if the system enables AVX, otherwise the vector
+    // registers are part of the floating point state of the CONTEXT.
+    pub fn extended_context(&self) -> anyhow::Result<ExtendedContext> {
+        let features = unsafe { GetEnabledXStateFeatures() };
+        let flags = match features & XSTATE_MASK_AVX {
+            0 => CONTEXT_ALL,
+            _ => CONTEXT_ALL | CONTEXT_XSTATE,
+        };
+
+        // The first call fails and returns the size of the buffer
+        let mut size = 0;
+        unsafe { InitializeContext(ptr::null_mut(), flags, ptr::null_mut(), &mut size) };
+        ensure!(size != 0, function_call_failure!("InitializeContext"));
+
+        let mut buffer = vec![0_u8; size as usize];
+        let mut context = ptr::null_mut();
+        let ret = unsafe {
+            InitializeContext(buffer.as_mut_ptr() as PVOID, flags, &mut context, &mut size)
+        };
+        ensure!(ret != 0, function_call_failure!("InitializeContext"));
+
+        if flags & CONTEXT_XSTATE == CONTEXT_XSTATE {
+            set_xstate_features_mask(context, features)?;
+        }
+
+        let ret = unsafe { GetThreadContext(self.handle, context) };
+        ensure!(ret != 0, function_call_failure!("GetThreadContext"),);
+
+        Ok(ExtendedContext { buffer, context })
+    }
+
+    pub fn set_extended_context(&self, context: &ExtendedContext) -> anyhow::Result<()> {
+        let ret = unsafe { SetThreadContext(self.handle, context.context) };
+        ensure!(ret != 0, function_call_failure!("SetThreadContext"),);
+
+        Ok(())
+    }
+
+    // Same as context but for threads running under WOW64
+    pub fn wow64_context(&self, flags: u32) -> anyhow::Result<WOW64_CONTEXT> {
+        let mut context = WOW64_CONTEXT {
+            ContextFlags: flags,
+            ..Default::default()
+        };
+
+        let ret = unsafe { Wow64GetThreadContext(self.handle, &mut context) };
+        ensure!(ret != 0, function_call_failure!("Wow64GetThreadContext"),);
+
+        Ok(context)
+    }
+
+    pub fn set_wow64_context(&self, context: &WOW64_CONTEXT) -> anyhow::Result<()> {
+        let ret = unsafe { Wow64SetThreadContext(self.handle, context) };
+        ensure!(ret != 0, function_call_failure!("Wow64SetThreadContext"),);
+
+        Ok(())
+    }
+
+    // Termination is asynchronous, wait on the thread before freeing anything it used
+    pub fn terminate(&self, exit_code: u32) -> anyhow::Result<()> {
+        let ret = unsafe { TerminateThread(self.handle, exit_code) };
+        ensure!(ret != 0, function_call_failure!("TerminateThread"),);
+
+        Ok(())
+    }
+
+    // Returns the previous suspend count
+    pub fn resume(&self) -> anyhow::Result<u32> {
+        let ret = unsafe { ResumeThread(self.handle) };
+        ensure!(ret != u32::MAX, function_call_failure!("ResumeThread"),);
 
         Ok(ret)
     }
 }
 
+impl HandleOwner for Thread {
+    fn handle(&self) -> HANDLE {
+        self.handle
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        self.close().unwrap();
+    }
+}
+
 // Threads struct
 // Iterates over a process's threads using a snapshot
 pub struct Threads {
-    snapshot: Snapshot,
-    is_first: bool,
+    thread_ids: Vec<u32>,
 }
 
 impl Threads {
-    #[allow(dead_code)]
     pub fn new(pid: u32) -> anyhow::Result<Self> {
-        let snapshot = Snapshot::from_pid(pid, SnapshotFlags::TH32CS_SNAPTHREAD)?;
+        let snapshot = Snapshot::threads()?;
+        let mut thread_ids = snapshot
+            .thread_entries(Some(pid))
+            .map(|entry| entry.tid)
+            .collect::<Vec<_>>();
 
-        Ok(Self {
-            snapshot,
-            is_first: true,
-        })
+        // Reverse so that the iterator pops from the front, not the back
+        thread_ids.reverse();
+
+        Ok(Self { thread_ids })
     }
 }
 
@@ -132,23 +442,22 @@ impl Iterator for Threads {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut entry = THREADENTRY32::default();
-        entry.dwSize = size_of::<THREADENTRY32>() as u32;
+        self.thread_ids.pop()
+    }
+}
 
-        let ret = unsafe {
-            match self.is_first {
-                true => {
-                    self.is_first = false;
-                    Thread32First(self.snapshot.handle(), &mut entry)
-                }
-                false => Thread32Next(self.snapshot.handle(), &mut entry),
-            }
-        };
+// Selects the extended features that GetThreadContext saves, winapi has no binding for it
+fn set_xstate_features_mask(context: *mut CONTEXT, features: u64) -> anyhow::Result<()> {
+    type FnSetXStateFeaturesMask = unsafe extern "system" fn(*mut CONTEXT, u64) -> i32;
 
-        if ret == TRUE {
-            Some(entry.th32ThreadID)
-        } else {
-            None
-        }
-    }
+    let set_xstate_features_mask = unsafe {
+        mem::transmute::<usize, FnSetXStateFeaturesMask>(
+            Module::find_or_load_internal("kernel32.dll")?.proc_address("SetXStateFeaturesMask")?,
+        )
+    };
+
+    let ret = unsafe { set_xstate_features_mask(context, features) };
+    ensure!(ret != 0, function_call_failure!("SetXStateFeaturesMask"));
+
+    Ok(())
 }
@@ -0,0 +1,222 @@
+use super::handleowner::HandleOwner;
+use super::process::Process;
+use std::mem::size_of;
+use std::ptr;
+use winapi::shared::sddl::ConvertSidToStringSidW;
+use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, ERROR_NOT_ALL_ASSIGNED};
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::processthreadsapi::OpenProcessToken;
+use winapi::um::securitybaseapi::{
+    AdjustTokenPrivileges, GetSidSubAuthority, GetSidSubAuthorityCount, GetTokenInformation,
+};
+use winapi::um::winbase::{LocalFree, LookupPrivilegeValueW};
+use winapi::um::winnt::{
+    self, TokenElevation, TokenIntegrityLevel, TokenUser, HANDLE, LUID, PSID,
+    SECURITY_MANDATORY_HIGH_RID, SECURITY_MANDATORY_LOW_RID, SECURITY_MANDATORY_MEDIUM_RID,
+    SECURITY_MANDATORY_SYSTEM_RID, SE_PRIVILEGE_ENABLED, TOKEN_ELEVATION, TOKEN_INFORMATION_CLASS,
+    TOKEN_MANDATORY_LABEL, TOKEN_PRIVILEGES, TOKEN_USER,
+};
+
+// Access rights for access-token objects
+// https://docs.microsoft.com/en-us/windows/win32/secauthz/access-rights-for-access-token-objects
+bitflags! {
+    pub struct TokenAccess: u32 {
+        const DELETE = winnt::DELETE;
+        const READ_CONTROL = winnt::READ_CONTROL;
+        const WRITE_DAC = winnt::WRITE_DAC;
+        const WRITE_OWNER = winnt::WRITE_OWNER;
+        const TOKEN_ADJUST_DEFAULT = winnt::TOKEN_ADJUST_DEFAULT;
+        const TOKEN_ADJUST_GROUPS = winnt::TOKEN_ADJUST_GROUPS;
+        const TOKEN_ADJUST_PRIVILEGES = winnt::TOKEN_ADJUST_PRIVILEGES;
+        const TOKEN_ADJUST_SESSIONID = winnt::TOKEN_ADJUST_SESSIONID;
+        const TOKEN_ALL_ACCESS = winnt::TOKEN_ALL_ACCESS;
+        const TOKEN_ASSIGN_PRIMARY = winnt::TOKEN_ASSIGN_PRIMARY;
+        const TOKEN_DUPLICATE = winnt::TOKEN_DUPLICATE;
+        const TOKEN_EXECUTE = winnt::TOKEN_EXECUTE;
+        const TOKEN_IMPERSONATE = winnt::TOKEN_IMPERSONATE;
+        const TOKEN_QUERY = winnt::TOKEN_QUERY;
+        const TOKEN_QUERY_SOURCE = winnt::TOKEN_QUERY_SOURCE;
+        const TOKEN_READ = winnt::TOKEN_READ;
+        const TOKEN_WRITE = winnt::TOKEN_WRITE;
+    }
+}
+
+// Mandatory integrity level of a token, ordered from least to most trusted
+// https://docs.microsoft.com/en-us/windows/win32/secauthz/mandatory-integrity-control
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IntegrityLevel {
+    Untrusted,
+    Low,
+    Medium,
+    High,
+    System,
+}
+
+impl IntegrityLevel {
+    // Levels between the well-known RIDs, such as medium plus, are rounded down
+    fn from_rid(rid: u32) -> Self {
+        match rid {
+            rid if rid < SECURITY_MANDATORY_LOW_RID => IntegrityLevel::Untrusted,
+            rid if rid < SECURITY_MANDATORY_MEDIUM_RID => IntegrityLevel::Low,
+            rid if rid < SECURITY_MANDATORY_HIGH_RID => IntegrityLevel::Medium,
+            rid if rid < SECURITY_MANDATORY_SYSTEM_RID => IntegrityLevel::High,
+            _ => IntegrityLevel::System,
+        }
+    }
+}
+
+// Token struct
+pub struct Token {
+    handle: HANDLE,
+}
+
+impl Token {
+    /// # Safety
+    /// `handle` must be a valid token handle which is closed when the `Token` is dropped
+    pub unsafe fn from_handle(handle: HANDLE) -> Self {
+        Self { handle }
+    }
+
+    // The process handle needs PROCESS_QUERY_INFORMATION or PROCESS_QUERY_LIMITED_INFORMATION
+    pub fn from_process(process: &Process, access: TokenAccess) -> anyhow::Result<Self> {
+        let mut handle = ptr::null_mut();
+        let ret = unsafe { OpenProcessToken(process.handle(), access.bits(), &mut handle) };
+
+        ensure!(ret != 0, function_call_failure!("OpenProcessToken"),);
+
+        Ok(Self { handle })
+    }
+
+    pub fn from_current(access: TokenAccess) -> anyhow::Result<Self> {
+        Self::from_process(&Process::from_current(), access)
+    }
+
+    pub fn integrity_level(&self) -> anyhow::Result<IntegrityLevel> {
+        let buf = self.information(TokenIntegrityLevel)?;
+        let label = unsafe { &*(buf.as_ptr() as *const TOKEN_MANDATORY_LABEL) };
+
+        // The RID is the last sub authority of the label SID
+        let rid = unsafe {
+            let sid = label.Label.Sid;
+            let count = *GetSidSubAuthorityCount(sid);
+            ensure!(count != 0, "Integrity label has no sub authorities");
+
+            *GetSidSubAuthority(sid, count as u32 - 1)
+        };
+
+        Ok(IntegrityLevel::from_rid(rid))
+    }
+
+    pub fn is_elevated(&self) -> anyhow::Result<bool> {
+        let buf = self.information(TokenElevation)?;
+        let elevation = unsafe { &*(buf.as_ptr() as *const TOKEN_ELEVATION) };
+
+        Ok(elevation.TokenIsElevated != 0)
+    }
+
+    // The SID of the user the token belongs to in string form, e.g. "S-1-5-18"
+    pub fn user_sid(&self) -> anyhow::Result<String> {
+        let buf = self.information(TokenUser)?;
+        let user = unsafe { &*(buf.as_ptr() as *const TOKEN_USER) };
+
+        sid_to_string(user.User.Sid)
+    }
+
+    // Enables or disables a privilege, e.g. "SeDebugPrivilege"
+    // The token must be opened with TOKEN_ADJUST_PRIVILEGES and hold the privilege
+    pub fn adjust_privilege(&self, name: &str, enable: bool) -> anyhow::Result<()> {
+        let mut privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            ..Default::default()
+        };
+        privileges.Privileges[0].Luid = lookup_privilege_value(name)?;
+        privileges.Privileges[0].Attributes = if enable { SE_PRIVILEGE_ENABLED } else { 0 };
+
+        let ret = unsafe {
+            AdjustTokenPrivileges(
+                self.handle,
+                0,
+                &mut privileges,
+                size_of::<TOKEN_PRIVILEGES>() as u32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        ensure!(ret != 0, function_call_failure!("AdjustTokenPrivileges"),);
+
+        // AdjustTokenPrivileges succeeds even if the token doesn't hold the privilege
+        ensure!(
+            unsafe { GetLastError() } != ERROR_NOT_ALL_ASSIGNED,
+            function_call_failure!("AdjustTokenPrivileges")
+        );
+
+        Ok(())
+    }
+
+    // Queries variable sized token information, the buffer is pointer aligned
+    fn information(&self, class: TOKEN_INFORMATION_CLASS) -> anyhow::Result<Vec<usize>> {
+        let mut size = 0;
+        let ret = unsafe { GetTokenInformation(self.handle, class, ptr::null_mut(), 0, &mut size) };
+
+        ensure!(
+            ret != 0 || unsafe { GetLastError() } == ERROR_INSUFFICIENT_BUFFER,
+            function_call_failure!("GetTokenInformation"),
+        );
+
+        let mut buf = vec![0_usize; (size as usize).div_ceil(size_of::<usize>())];
+        let ret = unsafe {
+            GetTokenInformation(
+                self.handle,
+                class,
+                buf.as_mut_ptr() as _,
+                (buf.len() * size_of::<usize>()) as u32,
+                &mut size,
+            )
+        };
+
+        ensure!(ret != 0, function_call_failure!("GetTokenInformation"),);
+
+        Ok(buf)
+    }
+}
+
+impl HandleOwner for Token {
+    fn handle(&self) -> HANDLE {
+        self.handle
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        self.close().unwrap()
+    }
+}
+
+fn lookup_privilege_value(name: &str) -> anyhow::Result<LUID> {
+    let name = name.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+
+    let mut luid = LUID::default();
+    let ret = unsafe { LookupPrivilegeValueW(ptr::null(), name.as_ptr(), &mut luid) };
+
+    ensure!(ret != 0, function_call_failure!("LookupPrivilegeValueW"),);
+
+    Ok(luid)
+}
+
+fn sid_to_string(sid: PSID) -> anyhow::Result<String> {
+    let mut str = ptr::null_mut();
+    let ret = unsafe { ConvertSidToStringSidW(sid, &mut str) };
+
+    ensure!(ret != 0, function_call_failure!("ConvertSidToStringSidW"),);
+
+    let string = unsafe {
+        let len = (0..).take_while(|&i| *str.offset(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(str, len))
+    };
+
+    // The string is allocated with LocalAlloc
+    unsafe { LocalFree(str as _) };
+
+    Ok(string)
+}
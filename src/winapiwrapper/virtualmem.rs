@@ -1,15 +1,21 @@
 use super::error::WinApiError;
+use super::handleowner::HandleOwner;
 use super::process::Process;
+use pelite::Pod;
+use std::mem::size_of;
 use std::ops::Drop;
-use winapi::shared::minwindef::LPVOID;
-use winapi::um::memoryapi::{VirtualAllocEx, VirtualFreeEx};
-use winapi::um::winnt;
+use winapi::shared::minwindef::{LPCVOID, LPVOID};
+use winapi::um::memoryapi::{VirtualAllocEx, VirtualQueryEx};
+use winapi::um::winnt::{self, MEMORY_BASIC_INFORMATION};
 
 pub struct VirtualMem<'a> {
     process: &'a Process,
     address: usize,
     size: usize,
+    protect: ProtectFlag,
     free_on_drop: bool,
+    // A view of a section, which is unmapped rather than freed
+    is_view: bool,
 }
 
 impl<'a> VirtualMem<'a> {
@@ -32,38 +38,60 @@ impl<'a> VirtualMem<'a> {
 
         ensure!(!mem.is_null(), function_call_failure!("VirtualAllocEx"));
 
+        trace!(
+            "Allocated {:x} bytes at {:x} as {:?}",
+            size,
+            mem as usize,
+            protect
+        );
+
         Ok(Self {
             process,
             address: mem as usize,
             size,
+            protect,
             free_on_drop: true,
+            is_view: false,
         })
     }
 
+    /// # Safety
+    /// `address` must be the base of a view mapped into `process` which is unmapped when the
+    /// `VirtualMem` is dropped
+    pub unsafe fn from_view(
+        process: &'a Process,
+        address: usize,
+        size: usize,
+        protect: ProtectFlag,
+    ) -> Self {
+        Self {
+            process,
+            address,
+            size,
+            protect,
+            free_on_drop: true,
+            is_view: true,
+        }
+    }
+
+    // Views are always unmapped as a whole, freetype only applies to allocated memory
     pub fn free(&mut self, freetype: FreeType) -> anyhow::Result<()> {
         ensure!(
             self.address() != 0,
             WinApiError::BadParameter("self.address".to_string(), "null pointer".to_string())
         );
 
+        if self.is_view {
+            return self.process.unmap_view(self.address);
+        }
+
         let size = if freetype.contains(FreeType::MEM_RELEASE) {
             0
         } else {
             self.size
         };
 
-        let ret = unsafe {
-            VirtualFreeEx(
-                self.process.handle(),
-                self.address as LPVOID,
-                size,
-                freetype.bits(),
-            )
-        };
-
-        ensure!(ret != 0, function_call_failure!("VirtualFreeEx"),);
-
-        Ok(())
+        self.process.virtual_free(self.address, size, freetype)
     }
 
     pub fn set_free_on_drop(&mut self, free_on_drop: bool) {
@@ -78,6 +106,16 @@ impl<'a> VirtualMem<'a> {
         self.size
     }
 
+    pub fn is_view(&self) -> bool {
+        self.is_view
+    }
+
+    // The protection the region was allocated with, or the last protection
+    // applied to the whole region with virtual_protect
+    pub fn protection(&self) -> ProtectFlag {
+        self.protect
+    }
+
     pub fn write_memory(&self, data: &[u8], offset: usize) -> anyhow::Result<usize> {
         self.process.write_memory(data, self.address + offset)
     }
@@ -86,25 +124,125 @@ impl<'a> VirtualMem<'a> {
         self.process.read_memory(data, self.address + offset)
     }
 
-    pub fn virtual_protect(
+    // Reads the memory at offset back and compares it against data
+    // Returns the index into data of the first byte that differs and the byte that was read
+    pub fn compare_memory(
         &self,
+        data: &[u8],
+        offset: usize,
+    ) -> anyhow::Result<Option<(usize, u8)>> {
+        let mut actual = vec![0; data.len()];
+        self.read_memory(&mut actual, offset)?;
+
+        Ok(data
+            .iter()
+            .zip(&actual)
+            .position(|(expected, actual)| expected != actual)
+            .map(|index| (index, actual[index])))
+    }
+
+    pub fn read_value<T: Pod>(&self, offset: usize) -> anyhow::Result<T> {
+        self.process.read_value(self.address + offset)
+    }
+
+    pub fn write_value<T: Pod>(&self, value: &T, offset: usize) -> anyhow::Result<usize> {
+        self.process.write_value(value, self.address + offset)
+    }
+
+    pub fn virtual_protect(
+        &mut self,
         offset: usize,
         size: usize,
         protect: ProtectFlag,
     ) -> anyhow::Result<u32> {
-        self.process
-            .virtual_protect(self.address + offset, size, protect)
+        let old_protect = self
+            .process
+            .virtual_protect(self.address + offset, size, protect)?;
+
+        if offset == 0 && size >= self.size {
+            self.protect = protect;
+        }
+
+        Ok(old_protect)
     }
 }
 
 impl Drop for VirtualMem<'_> {
     fn drop(&mut self) {
+        // The target may have exited, which the error that dropped us already reports
         if self.free_on_drop {
-            self.free(FreeType::MEM_RELEASE).unwrap();
+            if let Err(e) = self.free(FreeType::MEM_RELEASE) {
+                trace!("Failed to free memory at {:x}: {}", self.address, e);
+            }
         }
     }
 }
 
+// A region of pages with the same state, protection and type
+// https://docs.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-memory_basic_information
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub base: usize,
+    // Base of the allocation the region belongs to
+    pub allocation_base: usize,
+    pub size: usize,
+    pub state: MemState,
+    pub protect: ProtectFlag,
+    pub typ: MemType,
+}
+
+// MemoryRegions struct
+// Walks the address space of a process with VirtualQueryEx
+pub struct MemoryRegions<'a> {
+    process: &'a Process,
+    address: usize,
+}
+
+impl<'a> MemoryRegions<'a> {
+    pub fn new(process: &'a Process) -> Self {
+        Self::from_address(process, 0)
+    }
+
+    // Starts with the region containing address
+    pub fn from_address(process: &'a Process, address: usize) -> Self {
+        Self { process, address }
+    }
+}
+
+impl Iterator for MemoryRegions<'_> {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let ret = unsafe {
+            VirtualQueryEx(
+                self.process.handle(),
+                self.address as LPCVOID,
+                &mut info,
+                size_of::<MEMORY_BASIC_INFORMATION>(),
+            )
+        };
+
+        // Fails once the address is past the end of the user address space
+        if ret == 0 {
+            return None;
+        }
+
+        let region = MemoryRegion {
+            base: info.BaseAddress as usize,
+            allocation_base: info.AllocationBase as usize,
+            size: info.RegionSize,
+            state: MemState::from_bits_truncate(info.State),
+            protect: ProtectFlag::from_bits_truncate(info.Protect),
+            typ: MemType::from_bits_truncate(info.Type),
+        };
+
+        self.address = region.base.checked_add(region.size)?;
+
+        Some(region)
+    }
+}
+
 // AllocType flags
 // https://docs.microsoft.com/en-us/windows/win32/api/memoryapi/nf-memoryapi-virtualalloc
 bitflags! {
@@ -113,6 +251,25 @@ bitflags! {
         const MEM_RESERVE = winnt::MEM_RESERVE;
         const MEM_RESET = winnt::MEM_RESET;
         const MEM_RESET_UNDO = winnt::MEM_RESET_UNDO;
+        const MEM_TOP_DOWN = winnt::MEM_TOP_DOWN;
+    }
+}
+
+// Memory region state
+bitflags! {
+    pub struct MemState: u32 {
+        const MEM_COMMIT = winnt::MEM_COMMIT;
+        const MEM_FREE = winnt::MEM_FREE;
+        const MEM_RESERVE = winnt::MEM_RESERVE;
+    }
+}
+
+// Memory region type
+bitflags! {
+    pub struct MemType: u32 {
+        const MEM_IMAGE = winnt::MEM_IMAGE;
+        const MEM_MAPPED = winnt::MEM_MAPPED;
+        const MEM_PRIVATE = winnt::MEM_PRIVATE;
     }
 }
 
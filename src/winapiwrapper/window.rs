@@ -14,8 +14,27 @@ struct EnumWindowsState<'a> {
     window_found: Option<Window>,
 }
 
+#[repr(C)]
+struct EnumProcessWindowsState {
+    pid: u32,
+    window_found: Option<Window>,
+}
+
+unsafe extern "system" fn enum_process_windows_callback(hwnd: HWND, param: LPARAM) -> BOOL {
+    let state = param as *mut EnumProcessWindowsState;
+    let window = Window::from_handle(hwnd);
+
+    if window.pid() == (*state).pid {
+        (*state).window_found = Some(window);
+
+        0
+    } else {
+        1
+    }
+}
+
 unsafe extern "system" fn enum_windows_callback(hwnd: HWND, param: LPARAM) -> BOOL {
-    let mut state = param as *mut EnumWindowsState;
+    let state = param as *mut EnumWindowsState;
     let window = Window::from_handle(hwnd);
 
     if window.name().as_c_str() == (*state).window_name {
@@ -28,6 +47,8 @@ unsafe extern "system" fn enum_windows_callback(hwnd: HWND, param: LPARAM) -> BO
 }
 
 impl Window {
+    /// # Safety
+    /// `handle` must be a valid window handle
     pub unsafe fn from_handle(handle: HWND) -> Self {
         Self { handle }
     }
@@ -35,7 +56,7 @@ impl Window {
     pub fn find(window_name: &str) -> anyhow::Result<Option<Self>> {
         let window_name = CString::new(window_name)?;
 
-        let state = EnumWindowsState {
+        let mut state = EnumWindowsState {
             window_name: window_name.as_c_str(),
             window_found: None,
         };
@@ -43,13 +64,30 @@ impl Window {
         unsafe {
             EnumWindows(
                 Some(enum_windows_callback),
-                &state as *const EnumWindowsState as isize,
+                &mut state as *mut EnumWindowsState as LPARAM,
             )
         };
 
         Ok(state.window_found)
     }
 
+    // Finds a top-level window owned by the process
+    pub fn find_by_pid(pid: u32) -> Option<Self> {
+        let mut state = EnumProcessWindowsState {
+            pid,
+            window_found: None,
+        };
+
+        unsafe {
+            EnumWindows(
+                Some(enum_process_windows_callback),
+                &mut state as *mut EnumProcessWindowsState as LPARAM,
+            )
+        };
+
+        state.window_found
+    }
+
     pub fn name(&self) -> CString {
         const BUF_LEN: usize = 0x100;
 
@@ -71,4 +109,9 @@ impl Window {
 
         p
     }
+
+    // The thread that created the window and receives its messages
+    pub fn thread_id(&self) -> u32 {
+        unsafe { GetWindowThreadProcessId(self.handle, std::ptr::null_mut()) }
+    }
 }